@@ -0,0 +1,171 @@
+//! Benchmarks for the common operations, with std collections as baseline
+//!
+//! Run with `cargo bench`. Pass a filter to only run benchmarks whose name contains it,
+//! e.g. `cargo bench -- get`.
+use std::collections::{BTreeMap, HashMap};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use radixdb::{
+    bench_support::{self, Dataset},
+    store::MemStore,
+    RadixTree,
+};
+
+fn bench_dataset(c: &mut Criterion, name: &str, elems: Dataset) {
+    let tree = elems.iter().map(|(k, v)| (k, v)).collect::<RadixTree>();
+    let btree = elems.iter().cloned().collect::<BTreeMap<_, _>>();
+    let hash = elems.iter().cloned().collect::<HashMap<_, _>>();
+    let keys = elems.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group(format!("{}/build", name));
+    group.sample_size(10);
+    group.bench_function("radixtree", |b| {
+        b.iter(|| elems.iter().map(|(k, v)| (k, v)).collect::<RadixTree>())
+    });
+    group.bench_function("btreemap", |b| {
+        b.iter(|| elems.iter().cloned().collect::<BTreeMap<_, _>>())
+    });
+    group.bench_function("hashmap", |b| {
+        b.iter(|| elems.iter().cloned().collect::<HashMap<_, _>>())
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group(format!("{}/get", name));
+    group.sample_size(10);
+    group.bench_function("radixtree", |b| {
+        b.iter(|| {
+            keys.iter()
+                .filter_map(|k| tree.get(k))
+                .map(|v| v.len())
+                .sum::<usize>()
+        })
+    });
+    group.bench_function("btreemap", |b| {
+        b.iter(|| {
+            keys.iter()
+                .filter_map(|k| btree.get(k))
+                .map(|v| v.len())
+                .sum::<usize>()
+        })
+    });
+    group.bench_function("hashmap", |b| {
+        b.iter(|| {
+            keys.iter()
+                .filter_map(|k| hash.get(k))
+                .map(|v| v.len())
+                .sum::<usize>()
+        })
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group(format!("{}/iter", name));
+    group.sample_size(10);
+    group.bench_function("radixtree", |b| {
+        b.iter(|| tree.iter().map(|(k, _)| k.len()).sum::<usize>())
+    });
+    group.bench_function("btreemap", |b| {
+        b.iter(|| btree.keys().map(|k| k.len()).sum::<usize>())
+    });
+    group.bench_function("radixtree_values", |b| b.iter(|| tree.values().count()));
+    group.finish();
+
+    // combine two trees with every other element
+    let (even, odd): (Vec<_>, Vec<_>) = elems.iter().enumerate().partition(|(i, _)| i % 2 == 0);
+    let even_tree = even.iter().map(|(_, (k, v))| (k, v)).collect::<RadixTree>();
+    let odd_tree = odd.iter().map(|(_, (k, v))| (k, v)).collect::<RadixTree>();
+    let even_btree = even
+        .iter()
+        .map(|(_, x)| (*x).clone())
+        .collect::<BTreeMap<_, _>>();
+    let odd_btree = odd
+        .iter()
+        .map(|(_, x)| (*x).clone())
+        .collect::<BTreeMap<_, _>>();
+    let mut group = c.benchmark_group(format!("{}/combine", name));
+    group.sample_size(10);
+    group.bench_function("radixtree", |b| {
+        b.iter(|| even_tree.outer_combine(&odd_tree, |a, _| Some(a.to_owned())))
+    });
+    group.bench_function("btreemap", |b| {
+        b.iter(|| {
+            let mut res = even_btree.clone();
+            res.extend(odd_btree.clone());
+            res
+        })
+    });
+    group.finish();
+
+    let store = MemStore::default();
+    let attached = tree.try_attached(store.clone()).unwrap();
+    let mut group = c.benchmark_group(format!("{}/attached", name));
+    group.sample_size(10);
+    group.bench_function("attach", |b| {
+        b.iter(|| tree.try_attached(store.clone()).unwrap())
+    });
+    group.bench_function("get", |b| {
+        b.iter(|| {
+            keys.iter()
+                .filter(|k| attached.try_get(k).unwrap().is_some())
+                .count()
+        })
+    });
+    group.bench_function("iter", |b| b.iter(|| attached.try_iter().count()));
+    group.finish();
+
+    // scan the prefixes of every 1000th key
+    let prefixes = keys
+        .iter()
+        .step_by(1000)
+        .map(|k| k[..k.len() / 2].to_vec())
+        .collect::<Vec<_>>();
+    let mut group = c.benchmark_group(format!("{}/scan_prefix", name));
+    group.sample_size(10);
+    group.bench_function("radixtree", |b| {
+        b.iter(|| {
+            prefixes
+                .iter()
+                .map(|p| tree.scan_prefix(p).count())
+                .sum::<usize>()
+        })
+    });
+    group.bench_function("btreemap", |b| {
+        b.iter(|| {
+            prefixes
+                .iter()
+                .map(|p| {
+                    btree
+                        .range(p.clone()..)
+                        .take_while(|(k, _)| k.starts_with(p))
+                        .count()
+                })
+                .sum::<usize>()
+        })
+    });
+    group.finish();
+}
+
+/// combine single entry trees, without and with a common prefix
+fn smoke(c: &mut Criterion) {
+    let mut group = c.benchmark_group("smoke");
+    for (name, a, b) in [("disjoint", "a", "b"), ("common_prefix", "aa", "ab")] {
+        let a = RadixTree::single(a, "1");
+        let b = RadixTree::single(b, "2");
+        group.bench_function(name, |bench| {
+            bench.iter(|| {
+                let mut r = a.clone();
+                r.outer_combine_with(&b, |_, _| {});
+                r
+            })
+        });
+    }
+    group.finish();
+}
+
+fn datasets(c: &mut Criterion) {
+    bench_dataset(c, "numbers", bench_support::numbers(100_000));
+    bench_dataset(c, "namespaced", bench_support::namespaced(100_000));
+    bench_dataset(c, "random", bench_support::random(100_000, 16, 16, 0));
+}
+
+criterion_group!(benches, smoke, datasets);
+criterion_main!(benches);
@@ -1,7 +1,7 @@
 use radixdb::{radixtree, RadixTree};
 
 fn print_dirs(depth: usize, indent: usize, tree: RadixTree) {
-    for child in tree.group_by(|key, _| key[depth..].iter().any(|x| *x == b'/')) {
+    for child in tree.group_by(|key, _| key[depth..].contains(&b'/')) {
         println!("{}{:?}", " ".repeat(indent), child);
     }
 }
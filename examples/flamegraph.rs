@@ -50,7 +50,7 @@ fn do_test(_store: DynBlobStore) -> anyhow::Result<()> {
     let t0 = Instant::now();
     let mut n = 0;
     for key in elems.keys() {
-        if elems.get(key).is_some() {
+        if elems.contains_key(key) {
             n += 1;
         }
     }
@@ -130,6 +130,7 @@ fn browser_compare() -> anyhow::Result<()> {
     let path = dir.path().join("large2.rdb");
     let file = fs::OpenOptions::new()
         .create(true)
+        .truncate(true)
         .read(true)
         .write(true)
         .open(&path)?;
@@ -0,0 +1,60 @@
+//! A counting global allocator, to check allocation budgets of hot paths in tests and benchmarks
+//!
+//! Allocations are counted per thread, so tests running in parallel do not disturb each other.
+//! To use it, register the allocator in the test or benchmark binary:
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOC: radixdb::alloc_count::CountingAllocator = radixdb::alloc_count::CountingAllocator;
+//! ```
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count() {
+    // during thread teardown the counter might be gone already
+    let _ = ALLOCATIONS.try_with(|x| x.set(x.get() + 1));
+}
+
+/// Global allocator that delegates to the system allocator and counts allocations per thread
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Number of allocations and reallocations done on the current thread so far
+///
+/// This is only meaningful if [CountingAllocator] is the global allocator.
+pub fn allocations() -> usize {
+    ALLOCATIONS.with(|x| x.get())
+}
+
+/// Run `f`, returning its result and the number of allocations it did on the current thread
+pub fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = allocations();
+    let res = f();
+    (res, allocations() - before)
+}
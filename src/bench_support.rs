@@ -0,0 +1,41 @@
+//! Dataset generators for benchmarks
+//!
+//! These are the datasets used by the benchmarks in this crate, so numbers can be reproduced
+//! and compared with other data structures downstream. All generators are deterministic.
+use crate::util::SplitMix64;
+
+/// Key value pairs as used by the benchmarks
+pub type Dataset = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Decimal numbers `0..n` as both key and value
+///
+/// Keys are short and share prefixes, but are not inserted in key order.
+pub fn numbers(n: u64) -> Dataset {
+    (0..n)
+        .map(|i| {
+            let text = i.to_string().into_bytes();
+            (text.clone(), text)
+        })
+        .collect()
+}
+
+/// Namespaced identifiers such as `ns12::module3::item45`, with small values
+///
+/// Keys are long and have very long common prefixes, which is the best case for a radix tree.
+pub fn namespaced(n: u64) -> Dataset {
+    (0..n)
+        .map(|i| {
+            let key = format!("ns{}::module{}::item{}", i % 16, (i / 16) % 64, i);
+            (key.into_bytes(), i.to_be_bytes().to_vec())
+        })
+        .collect()
+}
+
+/// Random keys of length `key_len` with values of length `value_len`, given a seed
+///
+/// Keys have hardly any common prefixes, which is the worst case for a radix tree.
+pub fn random(n: u64, key_len: usize, value_len: usize, seed: u64) -> Dataset {
+    let mut rng = SplitMix64::new(seed);
+    let mut bytes = |len: usize| (0..len).map(|_| rng.next_u64() as u8).collect::<Vec<_>>();
+    (0..n).map(|_| (bytes(key_len), bytes(value_len))).collect()
+}
@@ -0,0 +1,570 @@
+//! A persistent key value store in a single file, with named trees
+//!
+//! This wires up [PagedFileStore], a [ReadCache] and [Roots] with defaults. For anything else, use them
+//! directly.
+//!
+//! ## Example
+//!
+//! ```rust
+//! # use radixdb::db::RadixDb;
+//! # let dir = tempfile::tempdir()?;
+//! # let path = dir.path().join("example.rdb");
+//! let mut db = RadixDb::open(&path)?;
+//! let mut tree = db.tree("default")?;
+//! tree.try_insert("hello", "world")?;
+//! db.set_tree("default", &mut tree)?;
+//! db.commit()?;
+//! drop(db);
+//! let db = RadixDb::open(&path)?;
+//! assert!(db.tree("default")?.try_contains_key("hello")?);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+#[cfg(feature = "merkle")]
+use crate::node::MerkleNode;
+use crate::{
+    node::Change,
+    roots::Roots,
+    store::{MemoryBudget, PagedFileStore, ReadCache},
+    RadixTree,
+};
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+/// Page size of files created by [RadixDb], which is also the limit for the size of a single blob
+pub const PAGE_SIZE: u64 = 1 << 20;
+
+/// Memory for the read cache of [RadixDb::open]
+pub const CACHE_SIZE: u64 = 64 << 20;
+
+/// The store of the trees of a [RadixDb], the file behind a cache of recently used blobs
+pub type DbStore = ReadCache<PagedFileStore>;
+
+/// A file with named trees that are committed together
+///
+/// Changes to trees are written to the file by [RadixDb::set_tree], but only become visible after
+/// reopening once [RadixDb::commit] succeeds. Opening a file after a crash discards everything written
+/// after the last commit, see [PagedFileStore::recover].
+///
+/// Blobs that are no longer reachable from the committed trees stay in the file until [RadixDb::compact].
+pub struct RadixDb {
+    path: PathBuf,
+    file: PagedFileStore,
+    budget: MemoryBudget,
+    store: DbStore,
+    roots: Roots<DbStore>,
+    watchers: Vec<Watcher>,
+}
+
+struct Watcher {
+    name: Vec<u8>,
+    filter: WatchFilter,
+    sender: Sender<Change>,
+}
+
+/// The keys of a tree a watcher is interested in, see [RadixDb::watch_filtered]
+///
+/// A key matches if it starts with one of the prefixes, and matches the regular expression if there is one.
+/// Only the parts of the trees under the prefixes are compared, and values are only loaded for matching
+/// keys, so narrow filters are cheap even for large commits.
+#[derive(Debug, Clone)]
+pub struct WatchFilter {
+    /// sorted prefixes, none of which is a prefix of another
+    prefixes: Vec<Vec<u8>>,
+    #[cfg(feature = "regex")]
+    regex: Option<regex::bytes::Regex>,
+}
+
+impl WatchFilter {
+    /// All keys starting with one of `prefixes`
+    pub fn prefixes(prefixes: impl IntoIterator<Item = impl AsRef<[u8]>>) -> Self {
+        let mut prefixes = prefixes
+            .into_iter()
+            .map(|p| p.as_ref().to_vec())
+            .collect::<Vec<_>>();
+        prefixes.sort();
+        // keys under a prefix that starts with another prefix are already covered by the shorter one
+        prefixes.dedup_by(|long, short| long.starts_with(short));
+        Self {
+            prefixes,
+            #[cfg(feature = "regex")]
+            regex: None,
+        }
+    }
+
+    /// Only keys that also match `regex`
+    #[cfg(feature = "regex")]
+    pub fn with_regex(self, regex: regex::bytes::Regex) -> Self {
+        Self {
+            regex: Some(regex),
+            ..self
+        }
+    }
+
+    #[cfg(feature = "regex")]
+    fn matches(&self, key: &[u8]) -> bool {
+        match &self.regex {
+            Some(regex) => regex.is_match(key),
+            None => true,
+        }
+    }
+
+    #[cfg(not(feature = "regex"))]
+    fn matches(&self, _key: &[u8]) -> bool {
+        true
+    }
+}
+
+impl RadixDb {
+    /// Open or create a database file
+    ///
+    /// A new file gets an empty committed table of trees right away, so every file created by [RadixDb]
+    /// has a committed root. Opening a file that contains data but no committed root fails, instead of
+    /// discarding the data.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::open_with_budget(path, MemoryBudget::new(CACHE_SIZE))
+    }
+
+    /// Open or create a database file, caching recently used blobs as long as they fit in `budget`
+    pub fn open_with_budget(path: impl AsRef<Path>, budget: MemoryBudget) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let store = PagedFileStore::new(file.try_clone()?, PAGE_SIZE)?;
+        let store = match (store.root(), store.last_id()) {
+            (Some(_), _) => {
+                drop(store);
+                PagedFileStore::recover(file, PAGE_SIZE)?
+            }
+            (None, None) => {
+                let id = Roots::empty(store.clone()).try_commit()?;
+                store.commit_root(&id)?;
+                store
+            }
+            (None, Some(_)) => {
+                anyhow::bail!(
+                    "file contains data but no committed root, it was not created by RadixDb"
+                )
+            }
+        };
+        let cache = ReadCache::with_budget(store.clone(), budget.clone());
+        let roots = Roots::try_load(cache.clone(), store.root())?;
+        Ok(Self {
+            path,
+            file: store,
+            budget,
+            store: cache,
+            roots,
+            watchers: Vec::new(),
+        })
+    }
+
+    /// The tree with the given name as of the last [RadixDb::set_tree], or an empty tree
+    pub fn tree(&self, name: impl AsRef<[u8]>) -> anyhow::Result<RadixTree<DbStore>> {
+        Ok(match self.roots.try_get_tree(name)? {
+            Some(tree) => tree,
+            None => RadixTree::empty(self.store.clone()),
+        })
+    }
+
+    /// Write `tree` to the file and make it the tree with the given name on the next commit
+    pub fn set_tree(
+        &mut self,
+        name: impl AsRef<[u8]>,
+        tree: &mut RadixTree<DbStore>,
+    ) -> anyhow::Result<()> {
+        self.roots.try_snapshot(name, tree)?;
+        Ok(())
+    }
+
+    /// Remove the tree with the given name on the next commit
+    pub fn remove_tree(&mut self, name: impl AsRef<[u8]>) -> anyhow::Result<()> {
+        self.roots.try_remove_root(name)
+    }
+
+    /// Repair the tree with the given name to match a known good root hash, see [RadixTree::try_repair_from]
+    ///
+    /// If the root of the tree itself can not be read, the entire tree is fetched. Like [RadixDb::set_tree],
+    /// the repaired tree is written to the file and becomes visible with the next commit.
+    #[cfg(feature = "merkle")]
+    pub fn repair_tree(
+        &mut self,
+        name: impl AsRef<[u8]>,
+        root_hash: &[u8; 32],
+        fetch: impl FnMut(&[u8]) -> anyhow::Result<MerkleNode>,
+    ) -> anyhow::Result<()> {
+        let tree = self
+            .tree(&name)
+            .unwrap_or_else(|_| RadixTree::empty(self.store.clone()));
+        let mut tree = tree.try_repair_from(root_hash, fetch)?;
+        self.set_tree(name, &mut tree)
+    }
+
+    /// Names of all trees in order
+    pub fn names(&self) -> anyhow::Result<Vec<Vec<u8>>> {
+        self.roots.try_names().collect()
+    }
+
+    /// Receive a [Change] for every entry under `prefix` of the tree with the given name that is
+    /// changed by a commit
+    ///
+    /// Changes are computed by comparing the committed trees, so an entry that is modified and then
+    /// restored before a commit produces no event. The watch ends when the receiver is dropped.
+    pub fn watch(&mut self, name: impl AsRef<[u8]>, prefix: impl AsRef<[u8]>) -> Receiver<Change> {
+        self.watch_filtered(name, WatchFilter::prefixes([prefix]))
+    }
+
+    /// Like [RadixDb::watch], for the keys matching `filter`
+    pub fn watch_filtered(
+        &mut self,
+        name: impl AsRef<[u8]>,
+        filter: WatchFilter,
+    ) -> Receiver<Change> {
+        let (sender, receiver) = channel();
+        self.watchers.push(Watcher {
+            name: name.as_ref().to_vec(),
+            filter,
+            sender,
+        });
+        receiver
+    }
+
+    /// Atomically commit all trees set since the last commit
+    ///
+    /// The changes for watchers are computed before the commit is made durable, so an error means that
+    /// nothing was committed. Once the commit is durable, sending the changes can not fail.
+    pub fn commit(&mut self) -> anyhow::Result<()> {
+        let changes = self.watched_changes()?;
+        let id = self.roots.try_commit()?;
+        self.file.commit_root(&id)?;
+        self.notify(changes);
+        Ok(())
+    }
+
+    /// Reclaim the space of blobs that are no longer reachable from the committed trees
+    ///
+    /// The committed trees are copied to a new file next to the database file, which then replaces it.
+    /// Until the rename, the old file stays intact, so a crash leaves either the old or the compacted
+    /// file. Fails if there are changes that are not committed yet.
+    ///
+    /// Trees returned by [RadixDb::tree] before compacting still refer to the old file, so they must be
+    /// loaded again before they are changed and passed to [RadixDb::set_tree].
+    pub fn compact(&mut self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.roots.is_dirty(),
+            "can not compact with changes that are not committed"
+        );
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(".compact");
+        let path = PathBuf::from(name);
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let store = PagedFileStore::new(file, PAGE_SIZE)?;
+        let id = self.roots.try_compact(store.clone())?.try_commit()?;
+        store.commit_root(&id)?;
+        fs::rename(&path, &self.path)?;
+        let cache = ReadCache::with_budget(store.clone(), self.budget.clone());
+        self.roots = Roots::try_load(cache.clone(), Some(&id))?;
+        self.file = store;
+        self.store = cache;
+        Ok(())
+    }
+
+    /// The changes for every watcher between the committed trees and the trees to be committed
+    fn watched_changes(&self) -> anyhow::Result<Vec<Vec<Change>>> {
+        if self.watchers.is_empty() {
+            return Ok(Vec::new());
+        }
+        let old = Roots::try_load(self.store.clone(), self.file.root())?;
+        let tree = |roots: &Roots<DbStore>, name: &[u8]| {
+            anyhow::Ok(match roots.try_get_tree(name)? {
+                Some(tree) => tree,
+                None => RadixTree::empty(self.store.clone()),
+            })
+        };
+        self.watchers
+            .iter()
+            .map(|watcher| {
+                let (name, filter) = (&watcher.name, &watcher.filter);
+                if old.try_get_root(name)? == self.roots.try_get_root(name)? {
+                    return Ok(Vec::new());
+                }
+                let (a, b) = (tree(&old, name)?, tree(&self.roots, name)?);
+                let mut res = Vec::new();
+                // the prefixes are sorted and disjoint, so the changes are in key order
+                for prefix in &filter.prefixes {
+                    let a = a.try_filter_prefix(prefix, prefix)?;
+                    let b = b.try_filter_prefix(prefix, prefix)?;
+                    a.try_diff(&b, |key, old, new| {
+                        if !filter.matches(key) {
+                            return Ok(());
+                        }
+                        let key = key.to_vec();
+                        res.push(match (old, new) {
+                            (Some(old), Some(new)) => Change::Modified(
+                                key,
+                                old.load(&self.store)?.to_vec(),
+                                new.load(&self.store)?.to_vec(),
+                            ),
+                            (Some(old), None) => {
+                                Change::Removed(key, old.load(&self.store)?.to_vec())
+                            }
+                            (None, Some(new)) => {
+                                Change::Added(key, new.load(&self.store)?.to_vec())
+                            }
+                            (None, None) => unreachable!("at least one of the values is present"),
+                        });
+                        Ok(())
+                    })?;
+                }
+                Ok(res)
+            })
+            .collect()
+    }
+
+    /// Send the changes computed by [RadixDb::watched_changes], removing watchers that were dropped
+    fn notify(&mut self, changes: Vec<Vec<Change>>) {
+        let mut changes = changes.into_iter();
+        self.watchers.retain(|watcher| {
+            changes
+                .next()
+                .unwrap_or_default()
+                .into_iter()
+                .all(|change| watcher.sender.send(change).is_ok())
+        });
+    }
+
+    /// The underlying store
+    pub fn store(&self) -> &DbStore {
+        &self.store
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn radix_db() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("db.rdb");
+        let mut db = RadixDb::open(&path)?;
+        assert!(db.names()?.is_empty());
+        let mut a = db.tree("a")?;
+        let mut b = db.tree("b")?;
+        for i in 0..1000u32 {
+            a.try_insert(i.to_be_bytes(), [1u8; 10])?;
+            b.try_insert(i.to_string(), [2u8; 10])?;
+        }
+        db.set_tree("a", &mut a)?;
+        db.set_tree("b", &mut b)?;
+        db.commit()?;
+        // not committed, so lost on reopen
+        a.try_remove(0u32.to_be_bytes())?;
+        db.set_tree("a", &mut a)?;
+        db.remove_tree("b")?;
+        drop(db);
+        let db = RadixDb::open(&path)?;
+        assert_eq!(db.names()?, vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(db.tree("a")?.try_detached()?.len(), 1000);
+        assert_eq!(db.tree("b")?.try_detached()?.len(), 1000);
+        assert!(db.tree("c")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn radix_db_open_without_root() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("db.rdb");
+        // a store file with data, but without a committed root
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let store = PagedFileStore::new(file, PAGE_SIZE)?;
+        let mut tree = RadixTree::empty(store.clone());
+        tree.try_insert("a", "b")?;
+        let id = tree.try_reattach()?;
+        drop(store);
+        let len = fs::metadata(&path)?.len();
+        assert!(RadixDb::open(&path).is_err());
+        assert_eq!(fs::metadata(&path)?.len(), len);
+        let file = fs::OpenOptions::new().read(true).write(true).open(&path)?;
+        let store = PagedFileStore::new(file, PAGE_SIZE)?;
+        let tree = RadixTree::try_load(store, Some(&id))?;
+        assert!(tree.try_contains_key("a")?);
+        Ok(())
+    }
+
+    #[test]
+    fn radix_db_crash_before_first_commit() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("db.rdb");
+        let mut db = RadixDb::open(&path)?;
+        let mut a = db.tree("a")?;
+        a.try_insert("x", "y")?;
+        db.set_tree("a", &mut a)?;
+        // dropped without commit, like a crash
+        drop(db);
+        let db = RadixDb::open(&path)?;
+        assert!(db.names()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn radix_db_compact() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("db.rdb");
+        let mut db = RadixDb::open_with_budget(&path, MemoryBudget::new(1 << 16))?;
+        let mut sizes = Vec::new();
+        for round in 0..10u8 {
+            // every round replaces all values, so everything written before is garbage
+            let mut a = db.tree("a")?;
+            for i in 0..1000u32 {
+                a.try_insert(i.to_be_bytes(), [round; 100])?;
+            }
+            db.set_tree("a", &mut a)?;
+            db.commit()?;
+            db.compact()?;
+            sizes.push(fs::metadata(&path)?.len());
+        }
+        assert!(sizes.iter().all(|size| *size <= sizes[0]), "{:?}", sizes);
+        // changes that are not committed prevent compacting
+        let mut a = db.tree("a")?;
+        a.try_insert("x", "y")?;
+        db.set_tree("a", &mut a)?;
+        assert!(db.compact().is_err());
+        drop(db);
+        let db = RadixDb::open(&path)?;
+        let a = db.tree("a")?.try_detached()?;
+        assert_eq!(a.len(), 1000);
+        assert!(a.values().all(|v| v.as_ref() == [9u8; 100]));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "merkle")]
+    fn radix_db_repair_tree() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("db.rdb");
+        let peer = (0..100u32)
+            .map(|i| (i.to_be_bytes(), [1u8; 10]))
+            .collect::<RadixTree>();
+        let mut db = RadixDb::open(&path)?;
+        let mut a = peer.try_attached(db.store().clone())?;
+        a.try_insert(5u32.to_be_bytes(), "x")?;
+        db.set_tree("a", &mut a)?;
+        db.commit()?;
+        db.repair_tree("a", &peer.root_hash(), |path| {
+            peer.merkle_node(path)
+                .ok_or_else(|| anyhow::anyhow!("no node"))
+        })?;
+        db.commit()?;
+        drop(db);
+        let db = RadixDb::open(&path)?;
+        assert_eq!(db.tree("a")?.try_detached()?, peer);
+        Ok(())
+    }
+
+    #[test]
+    fn radix_db_watch() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut db = RadixDb::open(dir.path().join("db.rdb"))?;
+        let users = db.watch("a", "user/");
+        let other = db.watch("b", "");
+        let mut a = db.tree("a")?;
+        a.try_insert("user/1", "x")?;
+        a.try_insert("user/2", "y")?;
+        a.try_insert("group/1", "z")?;
+        db.set_tree("a", &mut a)?;
+        // nothing is sent before the commit
+        assert!(users.try_iter().next().is_none());
+        db.commit()?;
+        assert_eq!(
+            users.try_iter().collect::<Vec<_>>(),
+            vec![
+                Change::Added(b"user/1".to_vec(), b"x".to_vec()),
+                Change::Added(b"user/2".to_vec(), b"y".to_vec()),
+            ]
+        );
+        a.try_insert("user/1", "w")?;
+        a.try_remove("user/2")?;
+        a.try_remove("group/1")?;
+        db.set_tree("a", &mut a)?;
+        db.commit()?;
+        assert_eq!(
+            users.try_iter().collect::<Vec<_>>(),
+            vec![
+                Change::Modified(b"user/1".to_vec(), b"x".to_vec(), b"w".to_vec()),
+                Change::Removed(b"user/2".to_vec(), b"y".to_vec()),
+            ]
+        );
+        assert!(other.try_iter().next().is_none());
+        drop(users);
+        a.try_insert("user/3", "v")?;
+        db.set_tree("a", &mut a)?;
+        db.commit()?;
+        assert_eq!(db.watchers.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn radix_db_watch_filtered() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut db = RadixDb::open(dir.path().join("db.rdb"))?;
+        // overlapping prefixes do not produce duplicate changes
+        let filter = WatchFilter::prefixes(["user/", "group/", "user/1"]);
+        let changes = db.watch_filtered("a", filter);
+        let mut a = db.tree("a")?;
+        for key in ["user/1", "user/2", "group/1", "other/1"] {
+            a.try_insert(key, "x")?;
+        }
+        db.set_tree("a", &mut a)?;
+        db.commit()?;
+        let keys = changes
+            .try_iter()
+            .map(|change| match change {
+                Change::Added(key, _) => key,
+                _ => panic!("only additions"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![b"group/1".to_vec(), b"user/1".to_vec(), b"user/2".to_vec()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "regex")]
+    fn radix_db_watch_regex() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut db = RadixDb::open(dir.path().join("db.rdb"))?;
+        let regex = regex::bytes::Regex::new("^user/[0-9]+$")?;
+        let changes = db.watch_filtered("a", WatchFilter::prefixes([""]).with_regex(regex));
+        let mut a = db.tree("a")?;
+        for key in ["user/1", "user/x", "group/1"] {
+            a.try_insert(key, "x")?;
+        }
+        db.set_tree("a", &mut a)?;
+        db.commit()?;
+        assert_eq!(
+            changes.try_iter().collect::<Vec<_>>(),
+            vec![Change::Added(b"user/1".to_vec(), b"x".to_vec())]
+        );
+        Ok(())
+    }
+}
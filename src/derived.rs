@@ -0,0 +1,117 @@
+//! A tree derived from another tree by transforming each entry, that can be refreshed incrementally
+//!
+//! ## Example
+//!
+//! ```rust
+//! # use radixdb::{derived::DerivedTree, radixtree};
+//! // keep only the part of each value before the first comma
+//! let first_field = |_: &[u8], v: &[u8]| v.split(|c| *c == b',').next().map(|x| x.to_vec());
+//! let mut source = radixtree! { "a" => "1,x", "b" => "2,y" };
+//! let mut derived = DerivedTree::new(&source, first_field);
+//! assert_eq!(derived.tree().get("a").unwrap().as_ref(), b"1");
+//! source.insert("b", "3,z");
+//! source.remove("a");
+//! derived.refresh(&source);
+//! assert_eq!(derived.tree().get("b").unwrap().as_ref(), b"3");
+//! assert!(!derived.tree().contains_key("a"));
+//! ```
+use crate::RadixTree;
+
+/// A tree with the same keys as a source tree, with values computed from the source entries
+///
+/// The transform gets the key and value of a source entry, and returns the derived value, or None to
+/// skip the entry. [DerivedTree::refresh] applies the transform only to the entries that changed since
+/// the last refresh. Changes are found by comparing with a clone of the source from the last refresh,
+/// so a source that is modified in place or cloned and modified shares most of its nodes with that
+/// clone, and unchanged subtrees are skipped without visiting their entries.
+pub struct DerivedTree<F> {
+    /// the source as of the last refresh
+    source: RadixTree,
+    /// the derived tree
+    tree: RadixTree,
+    f: F,
+}
+
+impl<F: Fn(&[u8], &[u8]) -> Option<Vec<u8>>> DerivedTree<F> {
+    /// Materialize the derived tree for `source`
+    pub fn new(source: &RadixTree, f: F) -> Self {
+        let mut res = Self {
+            source: RadixTree::default(),
+            tree: RadixTree::default(),
+            f,
+        };
+        res.refresh(source);
+        res
+    }
+
+    /// The derived tree, as of the last refresh
+    pub fn tree(&self) -> &RadixTree {
+        &self.tree
+    }
+
+    /// Update the derived tree for a new version of the source
+    ///
+    /// Returns the number of source entries that changed since the last refresh.
+    pub fn refresh(&mut self, source: &RadixTree) -> u64 {
+        let Self { tree, f, .. } = self;
+        let mut changes = 0;
+        self.source.diff(source, |key, _, new| {
+            changes += 1;
+            match new.and_then(|value| f(key, value.data().unwrap())) {
+                Some(derived) => tree.insert(key, derived),
+                None => {
+                    tree.remove(key);
+                }
+            }
+        });
+        self.source = source.clone();
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    fn arb_entries() -> impl Strategy<Value = BTreeMap<Vec<u8>, Vec<u8>>> {
+        proptest::collection::btree_map(
+            proptest::collection::vec(b'0'..=b'9', 0..6),
+            proptest::collection::vec(b'0'..=b'2', 0..3),
+            0..20,
+        )
+    }
+
+    /// the value doubled, skipping entries with empty values
+    fn double(_: &[u8], v: &[u8]) -> Option<Vec<u8>> {
+        Some([v, v].concat()).filter(|x| !x.is_empty())
+    }
+
+    fn expected(entries: &BTreeMap<Vec<u8>, Vec<u8>>) -> RadixTree {
+        entries
+            .iter()
+            .filter_map(|(k, v)| double(k, v).map(|v| (k.clone(), v)))
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn derived_refresh(a in arb_entries(), b in arb_entries()) {
+            let mut source = a.iter().collect::<RadixTree>();
+            let mut derived = DerivedTree::new(&source, double);
+            prop_assert_eq!(derived.tree(), &expected(&a));
+            for k in a.keys().filter(|k| !b.contains_key(*k)) {
+                source.remove(k);
+            }
+            for (k, v) in &b {
+                source.insert(k, v);
+            }
+            let changed = a.keys().chain(b.keys()).filter(|k| a.get(*k) != b.get(*k)).collect::<std::collections::BTreeSet<_>>();
+            prop_assert_eq!(derived.refresh(&source), changed.len() as u64);
+            prop_assert_eq!(derived.tree(), &expected(&b));
+            // nothing changed since the last refresh
+            prop_assert_eq!(derived.refresh(&source), 0);
+        }
+    }
+}
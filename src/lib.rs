@@ -198,8 +198,21 @@
 //!             00  | value: 0 byte long literal ""
 //!               80| children: None
 //! ```
+#[cfg(feature = "alloc-count")]
+pub mod alloc_count;
+pub mod bench_support;
+#[cfg(all(not(target_arch = "wasm32"), feature = "paged-file-store"))]
+pub mod db;
+pub mod derived;
+pub mod merge;
 pub mod node;
+#[cfg(feature = "custom-store")]
+pub mod roots;
+pub mod set;
 pub mod store;
+#[cfg(feature = "custom-store")]
+pub mod transaction;
+pub mod typed;
 mod util;
 use node::TreeNode;
 use store::{BlobStore, Detached};
@@ -208,6 +221,10 @@ use util::{Hex, Lit};
 #[cfg(test)]
 mod tests;
 
+#[cfg(all(test, feature = "alloc-count"))]
+#[global_allocator]
+static ALLOC: alloc_count::CountingAllocator = alloc_count::CountingAllocator;
+
 #[cfg(test)]
 #[macro_use]
 extern crate maplit;
@@ -218,6 +235,8 @@ pub struct RadixTree<S: BlobStore = Detached> {
     node: TreeNode<S>,
     /// The associated store
     store: S,
+    /// Cached number of entries
+    len: node::LenCache,
 }
 
 /// A macro to generate a radix tree from key value pairs, similar to the [maplit](https://docs.rs/maplit/1.0.2/maplit/) crate.
@@ -0,0 +1,195 @@
+//! Combine functions for [RadixTree::outer_combine_with], for using a tree as replicated state
+//!
+//! Entries that are only in one of the trees are always kept, so the functions here only decide
+//! between two values for the same key. All of them except [add_u64] are commutative, associative
+//! and idempotent, so replicas that merge each other's trees in any order converge.
+//!
+//! ## Example
+//!
+//! ```rust
+//! # use radixdb::{merge, RadixTree};
+//! let mut a = RadixTree::default();
+//! a.insert("color", merge::lww_value(1, b"red"));
+//! let mut b = RadixTree::default();
+//! b.insert("color", merge::lww_value(2, b"blue"));
+//! a.outer_combine_with(&b, merge::lww);
+//! let value = a.get("color").unwrap();
+//! assert_eq!(merge::lww_data(&value), Some((2, &b"blue"[..])));
+//! ```
+use crate::node::{Value, ValueRef};
+#[cfg(doc)]
+use crate::RadixTree;
+
+/// Keep the larger value, comparing bytes lexicographically
+///
+/// For fixed size big endian unsigned integers this is the numeric maximum.
+pub fn max(a: &mut Value, b: &ValueRef) {
+    if let Some(b_data) = b.data() {
+        if b_data > a.as_ref() {
+            a.set(Some(b));
+        }
+    }
+}
+
+/// Keep the smaller value, comparing bytes lexicographically
+///
+/// For fixed size big endian unsigned integers this is the numeric minimum.
+pub fn min(a: &mut Value, b: &ValueRef) {
+    if let Some(b_data) = b.data() {
+        if b_data < a.as_ref() {
+            a.set(Some(b));
+        }
+    }
+}
+
+/// Encode a value for [lww], with the timestamp as 8 byte big endian prefix
+pub fn lww_value(timestamp: u64, data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(8 + data.len());
+    res.extend_from_slice(&timestamp.to_be_bytes());
+    res.extend_from_slice(data);
+    res
+}
+
+/// Decode a value created with [lww_value], or None if it is too short
+pub fn lww_data(value: &[u8]) -> Option<(u64, &[u8])> {
+    let (timestamp, data) = value.split_at_checked(8)?;
+    Some((u64::from_be_bytes(timestamp.try_into().unwrap()), data))
+}
+
+/// Last writer wins, for values created with [lww_value]
+///
+/// The value with the later timestamp is kept. Values with the same timestamp are ordered by their
+/// data, so all replicas pick the same one.
+pub fn lww(a: &mut Value, b: &ValueRef) {
+    // the big endian timestamp prefix makes this the same as ordering by (timestamp, data)
+    max(a, b)
+}
+
+/// Add values that are 8 byte big endian integers, wrapping on overflow
+///
+/// Other values are left unchanged. Unlike the other functions this is not idempotent, so each
+/// delta must be merged exactly once. For a counter that replicas can merge repeatedly, give every
+/// replica its own key under a common prefix, merge with [max], and sum the values.
+pub fn add_u64(a: &mut Value, b: &ValueRef) {
+    if let (Ok(x), Some(Ok(y))) = (
+        <[u8; 8]>::try_from(a.as_ref()),
+        b.data().map(<[u8; 8]>::try_from),
+    ) {
+        let sum = u64::from_be_bytes(x).wrapping_add(u64::from_be_bytes(y));
+        *a = sum.to_be_bytes().to_vec().into();
+    }
+}
+
+/// Value of a removed entry for [observed_remove]
+pub const TOMBSTONE: [u8; 1] = [0];
+
+/// Encode a live value for [observed_remove]
+pub fn live_value(data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(1 + data.len());
+    res.push(1);
+    res.extend_from_slice(data);
+    res
+}
+
+/// The data of a value created with [live_value], or None for a tombstone
+pub fn live_data(value: &[u8]) -> Option<&[u8]> {
+    match value.split_first() {
+        Some((1, data)) => Some(data),
+        _ => None,
+    }
+}
+
+/// Observed remove semantics, where a removal wins over the entry it has observed
+///
+/// Adding an element inserts a [live_value] under a key that is unique to this add, e.g.
+/// `element` followed by a replica id and a sequence number. Removing an element replaces all keys
+/// of the element that the replica has seen with [TOMBSTONE]. The element is present if any key
+/// with its prefix is live, so a concurrent add of the same element survives the removal.
+///
+/// Two live values for the same key are resolved like [max], so replicas still converge.
+pub fn observed_remove(a: &mut Value, b: &ValueRef) {
+    if a.as_ref() == TOMBSTONE {
+        return;
+    }
+    if b.data() == Some(&TOMBSTONE[..]) {
+        a.set(Some(b));
+    } else {
+        max(a, b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RadixTree;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    fn arb_tree() -> impl Strategy<Value = RadixTree> {
+        proptest::collection::btree_map(
+            proptest::collection::vec(b'0'..=b'3', 0..4),
+            proptest::collection::vec(0u8..3, 0..10),
+            0..10,
+        )
+        .prop_map(|m| m.into_iter().collect())
+    }
+
+    fn merged(a: &RadixTree, b: &RadixTree, f: fn(&mut Value, &ValueRef)) -> RadixTree {
+        let mut res = a.clone();
+        res.outer_combine_with(b, f);
+        res
+    }
+
+    proptest! {
+        #[test]
+        fn merge_converges(a in arb_tree(), b in arb_tree(), c in arb_tree()) {
+            for f in [max, min, lww, observed_remove] {
+                prop_assert_eq!(merged(&a, &b, f), merged(&b, &a, f));
+                prop_assert_eq!(merged(&a, &a, f), a.clone());
+                prop_assert_eq!(merged(&merged(&a, &b, f), &c, f), merged(&a, &merged(&b, &c, f), f));
+            }
+        }
+    }
+
+    #[test]
+    fn add_u64_counter() {
+        let mut a = RadixTree::default();
+        a.insert("n", 40u64.to_be_bytes());
+        a.insert("x", "text");
+        let mut b = RadixTree::default();
+        b.insert("n", 2u64.to_be_bytes());
+        b.insert("x", 1u64.to_be_bytes());
+        a.outer_combine_with(&b, add_u64);
+        assert_eq!(a.get("n").unwrap().as_ref(), 42u64.to_be_bytes());
+        assert_eq!(a.get("x").unwrap().as_ref(), b"text");
+    }
+
+    #[test]
+    fn observed_remove_set() {
+        let elements = |t: &RadixTree| {
+            let mut res = BTreeMap::new();
+            for (k, v) in t.iter() {
+                let element = k.as_ref()[0];
+                *res.entry(element).or_insert(false) |= live_data(&v).is_some();
+            }
+            res.into_iter()
+                .filter_map(|(k, live)| if live { Some(k) } else { None })
+                .collect::<Vec<_>>()
+        };
+        let mut a = RadixTree::default();
+        a.insert("x-a1", live_value(b""));
+        let mut b = a.clone();
+        // b removes x after observing a's add, while a concurrently adds x again
+        b.insert("x-a1", TOMBSTONE);
+        a.insert("x-a2", live_value(b""));
+        a.insert("y-a3", live_value(b""));
+        b.insert("y-a3", TOMBSTONE);
+        let mut ab = a.clone();
+        ab.outer_combine_with(&b, observed_remove);
+        let mut ba = b.clone();
+        ba.outer_combine_with(&a, observed_remove);
+        assert_eq!(ab, ba);
+        assert_eq!(elements(&ab), vec![b'x']);
+        assert_eq!(lww_data(&[0; 7]), None);
+    }
+}
@@ -0,0 +1,356 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use super::{
+    common_prefix, prefix_stats, subtree_stats, value_len, OuterJoin, PrefixStats, TreeNodeIter,
+    TreeNodeRef, ValueRef,
+};
+use crate::{
+    store::{blob_store::UnwrapSafeExt, BlobStore},
+    RadixTree,
+};
+
+/// Changes between two trees for all keys sharing a common prefix, see [RadixTree::diff_summary]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffStats {
+    /// entries that are only in the new tree
+    pub added: PrefixStats,
+    /// entries that are only in the old tree
+    pub removed: PrefixStats,
+    /// entries with a different value in the new tree, with the size of the new entry
+    pub changed: PrefixStats,
+}
+
+/// The change of a single entry between two trees, see [RadixTree::changes]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// key and value of an entry that is only in the new tree
+    Added(Vec<u8>, Vec<u8>),
+    /// key and value of an entry that is only in the old tree
+    Removed(Vec<u8>, Vec<u8>),
+    /// key, old value and new value of an entry with a different value in the new tree
+    Modified(Vec<u8>, Vec<u8>, Vec<u8>),
+}
+
+/// true if two nodes share their children, so the subtrees below them are identical
+fn same_children<S: BlobStore>(a: &TreeNodeRef<S>, b: &TreeNodeRef<S>) -> bool {
+    match (a.dispatch(), b.dispatch()) {
+        (Ok(a), Ok(b)) => match (a.get_children(), b.get_children()) {
+            (Ok(a), Ok(b)) => Arc::ptr_eq(a, b),
+            (Err(a), Err(b)) => a == b,
+            _ => false,
+        },
+        (Ok(a), Err(b)) | (Err(b), Ok(a)) => {
+            a.get_children().err() == Some(b.children_ref().slice())
+        }
+        (Err(a), Err(b)) => a.children_ref().slice() == b.children_ref().slice(),
+    }
+}
+
+/// true if two values are equal, without loading them if they refer to the same blob
+fn same_value<S: BlobStore>(a: &ValueRef<S>, b: &ValueRef<S>, store: &S) -> Result<bool, S::Error> {
+    Ok(match (a.read(), b.read()) {
+        (Ok(a), Ok(b)) => a == b,
+        (Err(a), Err(b)) if a == b => true,
+        _ => a.load(store)? == b.load(store)?,
+    })
+}
+
+/// callbacks for the differences found by [diff_nodes]
+trait DiffVisitor<S: BlobStore> {
+    /// an entry that is only in the old tree, only in the new tree, or has different values
+    fn entry(
+        &mut self,
+        key: &[u8],
+        old: Option<&ValueRef<S>>,
+        new: Option<&ValueRef<S>>,
+        store: &S,
+    ) -> Result<(), S::Error>;
+
+    /// a subtree that is only in the old or only in the new tree, with `key` the key before the node
+    ///
+    /// By default, this reports each entry of the subtree separately.
+    fn subtree(
+        &mut self,
+        node: &TreeNodeRef<S>,
+        key: &mut Vec<u8>,
+        added: bool,
+        store: &S,
+    ) -> Result<(), S::Error> {
+        let base = key.len();
+        key.extend_from_slice(&node.load_prefix(store)?);
+        if let Some(value) = node.value_opt() {
+            if added {
+                self.entry(key, None, Some(&value), store)?;
+            } else {
+                self.entry(key, Some(&value), None, store)?;
+            }
+        }
+        if let Some(mut children) = node.load_children(store)? {
+            while let Some(child) = children.next() {
+                self.subtree(&child, key, added, store)?;
+            }
+        }
+        key.truncate(base);
+        Ok(())
+    }
+}
+
+/// statistics of the differences by key prefix of length `depth`, see [RadixTree::try_diff_summary]
+struct DiffSummary {
+    depth: usize,
+    res: BTreeMap<Vec<u8>, DiffStats>,
+}
+
+impl DiffSummary {
+    fn stats(&mut self, group: &[u8], added: bool) -> &mut PrefixStats {
+        let stats = self.res.entry(group.to_vec()).or_default();
+        if added {
+            &mut stats.added
+        } else {
+            &mut stats.removed
+        }
+    }
+}
+
+impl<S: BlobStore> DiffVisitor<S> for DiffSummary {
+    fn entry(
+        &mut self,
+        key: &[u8],
+        old: Option<&ValueRef<S>>,
+        new: Option<&ValueRef<S>>,
+        store: &S,
+    ) -> Result<(), S::Error> {
+        let value = new.or(old).expect("entry must be in one of the trees");
+        let bytes = (key.len() + value_len(value, store)?) as u64;
+        let group = &key[..key.len().min(self.depth)];
+        let stats = match (old, new) {
+            (Some(_), Some(_)) => &mut self.res.entry(group.to_vec()).or_default().changed,
+            (_, new) => self.stats(group, new.is_some()),
+        };
+        stats.add(PrefixStats { entries: 1, bytes });
+        Ok(())
+    }
+
+    fn subtree(
+        &mut self,
+        node: &TreeNodeRef<S>,
+        key: &mut Vec<u8>,
+        added: bool,
+        store: &S,
+    ) -> Result<(), S::Error> {
+        if key.len() >= self.depth {
+            let stats = subtree_stats(node, store, key.len())?;
+            self.stats(&key[..self.depth], added).add(stats);
+        } else {
+            let mut stats = Vec::new();
+            prefix_stats(node, store, key, self.depth, &mut stats)?;
+            for (group, stats) in stats {
+                self.stats(&group, added).add(stats);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// find the differences from `a` to `b`, with `key` the key before both nodes
+///
+/// Shared subtrees are skipped without loading them, so the cost depends on the size of the change.
+fn diff_nodes<S: BlobStore>(
+    a: &TreeNodeRef<S>,
+    b: &TreeNodeRef<S>,
+    store: &S,
+    key: &mut Vec<u8>,
+    v: &mut impl DiffVisitor<S>,
+) -> Result<(), S::Error> {
+    let ap = a.load_prefix(store)?;
+    let bp = b.load_prefix(store)?;
+    let n = common_prefix(ap.as_ref(), bp.as_ref());
+    let base = key.len();
+    if n == ap.len() && n == bp.len() {
+        // prefixes are identical
+        key.extend_from_slice(&ap);
+        match (a.value_opt(), b.value_opt()) {
+            (Some(av), Some(bv)) => {
+                if !same_value(&av, &bv, store)? {
+                    v.entry(key, Some(&av), Some(&bv), store)?;
+                }
+            }
+            (Some(av), None) => v.entry(key, Some(&av), None, store)?,
+            (None, Some(bv)) => v.entry(key, None, Some(&bv), store)?,
+            (None, None) => {}
+        }
+        if !same_children(a, b) {
+            let ac = a.load_children(store)?;
+            let bc = b.load_children(store)?;
+            diff_children(ac, bc, store, key, v)?;
+        }
+    } else if n == ap.len() {
+        // a is a prefix of b
+        key.extend_from_slice(&ap);
+        if let Some(av) = a.value_opt() {
+            v.entry(key, Some(&av), None, store)?;
+        }
+        let ac = a.load_children(store)?;
+        let bc = [b.clone_shortened(store, n)?];
+        diff_children(ac, TreeNodeIter::from_slice(&bc), store, key, v)?;
+    } else if n == bp.len() {
+        // b is a prefix of a
+        key.extend_from_slice(&bp);
+        if let Some(bv) = b.value_opt() {
+            v.entry(key, None, Some(&bv), store)?;
+        }
+        let ac = [a.clone_shortened(store, n)?];
+        let bc = b.load_children(store)?;
+        diff_children(TreeNodeIter::from_slice(&ac), bc, store, key, v)?;
+    } else {
+        // the two nodes are disjoint, report them in key order
+        if ap[n] < bp[n] {
+            v.subtree(a, key, false, store)?;
+            v.subtree(b, key, true, store)?;
+        } else {
+            v.subtree(b, key, true, store)?;
+            v.subtree(a, key, false, store)?;
+        }
+    }
+    key.truncate(base);
+    Ok(())
+}
+
+fn diff_children<S: BlobStore>(
+    ac: Option<TreeNodeIter<'_, S>>,
+    bc: Option<TreeNodeIter<'_, S>>,
+    store: &S,
+    key: &mut Vec<u8>,
+    v: &mut impl DiffVisitor<S>,
+) -> Result<(), S::Error> {
+    match (ac, bc) {
+        (Some(ac), Some(bc)) => {
+            let mut iter = OuterJoin::<S, S, S::Error>::new(ac, bc);
+            while let Some(x) = iter.next() {
+                match x? {
+                    (Some(a), Some(b)) => diff_nodes(&a, &b, store, key, v)?,
+                    (Some(a), None) => v.subtree(&a, key, false, store)?,
+                    (None, Some(b)) => v.subtree(&b, key, true, store)?,
+                    (None, None) => panic!(),
+                }
+            }
+        }
+        (Some(mut ac), None) => {
+            while let Some(a) = ac.next() {
+                v.subtree(&a, key, false, store)?;
+            }
+        }
+        (None, Some(mut bc)) => {
+            while let Some(b) = bc.next() {
+                v.subtree(&b, key, true, store)?;
+            }
+        }
+        (None, None) => {}
+    }
+    Ok(())
+}
+
+/// a [DiffVisitor] that passes each entry to a function
+struct DiffEntries<F>(F);
+
+impl<S: BlobStore, F> DiffVisitor<S> for DiffEntries<F>
+where
+    F: FnMut(&[u8], Option<&ValueRef<S>>, Option<&ValueRef<S>>) -> Result<(), S::Error>,
+{
+    fn entry(
+        &mut self,
+        key: &[u8],
+        old: Option<&ValueRef<S>>,
+        new: Option<&ValueRef<S>>,
+        _store: &S,
+    ) -> Result<(), S::Error> {
+        (self.0)(key, old, new)
+    }
+}
+
+impl RadixTree {
+    pub fn diff_summary(&self, that: &RadixTree, depth: usize) -> Vec<(Vec<u8>, DiffStats)> {
+        self.try_diff_summary(that, depth).unwrap_safe()
+    }
+
+    pub fn diff(
+        &self,
+        that: &RadixTree,
+        mut f: impl FnMut(&[u8], Option<&ValueRef>, Option<&ValueRef>),
+    ) {
+        self.try_diff(that, |k, a, b| Ok(f(k, a, b))).unwrap_safe()
+    }
+
+    pub fn changes(&self, that: &RadixTree) -> Vec<Change> {
+        self.try_changes(that).unwrap_safe()
+    }
+}
+
+impl<S: BlobStore + Clone> RadixTree<S> {
+    /// Summary of the changes from this tree to `that`, by key prefix of length `depth`
+    ///
+    /// Only prefixes with changes are reported, in key order. Keys shorter than `depth` are reported under
+    /// the complete key. Subtrees shared between both trees, e.g. because `that` is a modified clone of this
+    /// tree, are skipped without visiting their entries.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_diff_summary(
+        &self,
+        that: &RadixTree<S>,
+        depth: usize,
+    ) -> Result<Vec<(Vec<u8>, DiffStats)>, S::Error> {
+        let mut res = DiffSummary {
+            depth,
+            res: BTreeMap::new(),
+        };
+        diff_nodes(
+            &self.node.as_ref(),
+            &that.node.as_ref(),
+            &self.store,
+            &mut Vec::new(),
+            &mut res,
+        )?;
+        Ok(res.res.into_iter().collect())
+    }
+
+    /// Call `f` for each entry that differs between this tree and `that`, in key order
+    ///
+    /// `f` gets the key, the value in this tree and the value in `that`. At least one of the values
+    /// is present, and if both are, they are different. Like [RadixTree::try_diff_summary], shared
+    /// subtrees are skipped.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_diff(
+        &self,
+        that: &RadixTree<S>,
+        f: impl FnMut(&[u8], Option<&ValueRef<S>>, Option<&ValueRef<S>>) -> Result<(), S::Error>,
+    ) -> Result<(), S::Error> {
+        diff_nodes(
+            &self.node.as_ref(),
+            &that.node.as_ref(),
+            &self.store,
+            &mut Vec::new(),
+            &mut DiffEntries(f),
+        )
+    }
+
+    /// All entries that differ between this tree and `that`, in key order
+    ///
+    /// This is [RadixTree::try_diff] collecting the changes, so shared subtrees are skipped as well.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_changes(&self, that: &RadixTree<S>) -> Result<Vec<Change>, S::Error> {
+        let mut res = Vec::new();
+        let store = &self.store;
+        self.try_diff(that, |key, old, new| {
+            let key = key.to_vec();
+            res.push(match (old, new) {
+                (Some(old), Some(new)) => {
+                    Change::Modified(key, old.load(store)?.to_vec(), new.load(store)?.to_vec())
+                }
+                (Some(old), None) => Change::Removed(key, old.load(store)?.to_vec()),
+                (None, Some(new)) => Change::Added(key, new.load(store)?.to_vec()),
+                (None, None) => unreachable!("at least one of the values is present"),
+            });
+            Ok(())
+        })?;
+        Ok(res)
+    }
+}
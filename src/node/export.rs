@@ -0,0 +1,910 @@
+use std::{
+    io::{self, BufWriter, Read, Write},
+    sync::Arc,
+};
+
+#[cfg(feature = "spill")]
+use super::{assemble, common_prefix, is_no_store, Value};
+use super::{TreeNode, TreeNodeIter, TreeNodeRef};
+use crate::{
+    store::{blob_store::UnwrapSafeExt, BlobStore, Detached, UnwrapSafeIterExt},
+    util::fnv1a,
+    RadixTree,
+};
+
+/// Index entry for one part of a parallel export
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExportPart {
+    /// first key of the part, None if the part is empty
+    pub first_key: Option<Vec<u8>>,
+    /// number of entries
+    pub entries: u64,
+    /// offset of the part when concatenating all parts in order
+    pub offset: u64,
+    /// size of the part in bytes
+    pub bytes: u64,
+}
+
+/// write an entry in export format, returning the number of bytes written
+///
+/// An entry is the key and the value, each prefixed with the length as u32 big endian.
+fn export_entry(target: &mut impl Write, key: &[u8], value: &[u8]) -> io::Result<u64> {
+    for data in [key, value] {
+        let len = u32::try_from(data.len())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        target.write_all(&len.to_be_bytes())?;
+        target.write_all(data)?;
+    }
+    Ok(8 + key.len() as u64 + value.len() as u64)
+}
+
+/// write a LEB128 encoded integer, returning the number of bytes written
+fn write_varint(target: &mut impl Write, mut value: u64) -> io::Result<u64> {
+    let mut n = 1;
+    while value >= 0x80 {
+        target.write_all(&[(value as u8) | 0x80])?;
+        value >>= 7;
+        n += 1;
+    }
+    target.write_all(&[value as u8])?;
+    Ok(n)
+}
+
+/// read a LEB128 encoded integer, returning None at the end of the data
+fn read_varint(source: &mut impl Read) -> io::Result<Option<u64>> {
+    let mut res = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        if source.read(&mut byte)? == 0 {
+            return if shift == 0 {
+                Ok(None)
+            } else {
+                Err(io::ErrorKind::UnexpectedEof.into())
+            };
+        }
+        res |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(res));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "varint too long",
+    ))
+}
+
+/// magic bytes and format version at the start of [RadixTree::to_bytes]
+pub(super) const TREE_BYTES_HEADER: &[u8] = b"rdxt\x01";
+
+/// write a node and all its children in the format of [RadixTree::to_bytes], passing the data to `emit`
+///
+/// A node is the prefix, the value and the children. The prefix is the length followed by the data.
+/// The value is 0 for no value, or the length plus one followed by the data. The children are the
+/// number of children followed by each child. All integers are LEB128 encoded.
+fn write_node_bytes<S: BlobStore, E: From<S::Error>>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+    emit: &mut impl FnMut(&[u8]) -> Result<(), E>,
+) -> Result<(), E> {
+    fn varint<E>(emit: &mut impl FnMut(&[u8]) -> Result<(), E>, value: usize) -> Result<(), E> {
+        let mut buf = Vec::with_capacity(10);
+        // writing to a vec can not fail
+        write_varint(&mut buf, value as u64).unwrap();
+        emit(&buf)
+    }
+    let prefix = node.load_prefix(store)?;
+    varint(emit, prefix.len())?;
+    emit(&prefix)?;
+    match node.value_opt() {
+        Some(value) => {
+            let value = value.load(store)?;
+            varint(emit, value.len() + 1)?;
+            emit(&value)?;
+        }
+        None => varint(emit, 0)?,
+    }
+    let mut children = Vec::new();
+    if let Some(mut iter) = node.load_children(store)? {
+        while let Some(child) = iter.next() {
+            children.push(child.to_owned());
+        }
+    }
+    varint(emit, children.len())?;
+    for child in &children {
+        write_node_bytes(&TreeNodeRef::owned(child), store, emit)?;
+    }
+    Ok(())
+}
+
+/// maximum nesting depth accepted by [RadixTree::from_bytes] and [RadixTree::read_from]
+///
+/// Reading, and dropping, a node recurses once per level, so deeper input could overflow the stack.
+/// Every level of a canonical tree adds at least one byte to the keys below it, so this only rejects
+/// trees with very long keys that share prefixes with many other keys.
+pub(super) const MAX_TREE_BYTES_DEPTH: usize = 1024;
+
+/// read a node written by [write_node_bytes], counting its entries in `len`
+///
+/// Rejects nodes that are not canonical, so reading and writing again gives the same bytes, and nodes
+/// nested deeper than [MAX_TREE_BYTES_DEPTH]. `depth` is 0 for the root.
+fn read_node_bytes(
+    source: &mut impl Read,
+    depth: usize,
+    len: &mut u64,
+) -> anyhow::Result<TreeNode<Detached>> {
+    anyhow::ensure!(depth <= MAX_TREE_BYTES_DEPTH, "tree nested too deeply");
+    let root = depth == 0;
+    fn take(source: &mut impl Read, len: usize) -> anyhow::Result<Vec<u8>> {
+        // read_to_end grows the buffer as data arrives, so a bogus length can not exhaust memory
+        let mut res = Vec::new();
+        source.take(len as u64).read_to_end(&mut res)?;
+        anyhow::ensure!(res.len() == len, "truncated node");
+        Ok(res)
+    }
+    fn varint(source: &mut impl Read) -> anyhow::Result<usize> {
+        let value = read_varint(source)?.ok_or_else(|| anyhow::anyhow!("truncated node"))?;
+        Ok(usize::try_from(value)?)
+    }
+    let mut res = TreeNode::EMPTY;
+    let prefix_len = varint(source)?;
+    res.set_prefix_slice(&take(source, prefix_len)?);
+    let value_len = varint(source)?;
+    if value_len > 0 {
+        res.set_value_slice(Some(&take(source, value_len - 1)?));
+        *len += 1;
+    }
+    let child_count = varint(source)?;
+    let mut children = Vec::new();
+    for _ in 0..child_count {
+        let child = read_node_bytes(source, depth + 1, len)?;
+        if let Some(prev) = children.last().and_then(TreeNode::first_prefix_byte) {
+            anyhow::ensure!(
+                child.first_prefix_byte() > Some(prev),
+                "children not sorted by first byte"
+            );
+        }
+        children.push(child);
+    }
+    if !children.is_empty() {
+        res.set_children_arc(Arc::new(children));
+    }
+    anyhow::ensure!(root || prefix_len > 0, "empty prefix in child node");
+    anyhow::ensure!(
+        res.has_value() || child_count > 1 || (root && child_count == 0 && prefix_len == 0),
+        "node is not canonical"
+    );
+    Ok(res)
+}
+
+/// read a tree written by [RadixTree::write_to], without reading past its end
+fn read_tree_bytes(source: &mut impl Read) -> anyhow::Result<RadixTree> {
+    let mut header = [0u8; TREE_BYTES_HEADER.len()];
+    source
+        .read_exact(&mut header)
+        .map_err(|_| anyhow::anyhow!("unknown tree format"))?;
+    anyhow::ensure!(header == TREE_BYTES_HEADER, "unknown tree format");
+    let mut len = 0;
+    let node = read_node_bytes(source, 0, &mut len)?;
+    let res = RadixTree::new(node, Detached);
+    res.len.set(len);
+    Ok(res)
+}
+
+/// Manifest of a chunked export, see [RadixTree::export_chunked]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChunkManifest {
+    /// size of every chunk except the last one
+    pub chunk_size: u64,
+    /// total size of all chunks
+    pub bytes: u64,
+    /// 64 bit FNV-1a checksum of each chunk
+    pub checksums: Vec<u64>,
+}
+
+impl ChunkManifest {
+    /// size of the chunk with the given index
+    fn chunk_len(&self, index: usize) -> u64 {
+        let start = self.chunk_size * index as u64;
+        self.chunk_size.min(self.bytes.saturating_sub(start))
+    }
+}
+
+/// A resumable import of a chunked export, see [RadixTree::import_chunked]
+///
+/// Chunks have to be added in order. Adding a chunk that does not match the manifest fails without
+/// changing the state, so after an error or an interrupted transfer, continue with [ChunkedImport::next_chunk].
+#[derive(Debug, Clone)]
+pub struct ChunkedImport {
+    manifest: ChunkManifest,
+    data: Vec<u8>,
+    chunks: usize,
+}
+
+impl ChunkedImport {
+    /// Index of the next chunk to add
+    pub fn next_chunk(&self) -> usize {
+        self.chunks
+    }
+
+    /// True if all chunks have been added
+    pub fn is_complete(&self) -> bool {
+        self.chunks == self.manifest.checksums.len()
+    }
+
+    /// Add the chunk with the given index, checking it against the manifest
+    pub fn add(&mut self, index: usize, chunk: &[u8]) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            index == self.chunks,
+            "expected chunk {}, got chunk {}",
+            self.chunks,
+            index
+        );
+        anyhow::ensure!(!self.is_complete(), "all chunks have already been added");
+        anyhow::ensure!(
+            chunk.len() as u64 == self.manifest.chunk_len(index),
+            "chunk {} has the wrong size",
+            index
+        );
+        anyhow::ensure!(
+            fnv1a(chunk) == self.manifest.checksums[index],
+            "checksum mismatch for chunk {}",
+            index
+        );
+        self.data.extend_from_slice(chunk);
+        self.chunks += 1;
+        Ok(())
+    }
+
+    /// Build the tree once all chunks have been added
+    pub fn finish(self) -> anyhow::Result<RadixTree> {
+        anyhow::ensure!(
+            self.is_complete(),
+            "missing chunks {}..{}",
+            self.chunks,
+            self.manifest.checksums.len()
+        );
+        RadixTree::from_bytes(&self.data)
+    }
+}
+
+/// Reader for a key list written by [RadixTree::try_export_keys_front_coded]
+///
+/// Reads single bytes from the source, so this should be given a buffered reader.
+pub struct FrontCodedKeyReader<R> {
+    source: R,
+    key: Vec<u8>,
+}
+
+impl<R: Read> FrontCodedKeyReader<R> {
+    pub fn new(source: R) -> Self {
+        Self {
+            source,
+            key: Vec::new(),
+        }
+    }
+
+    fn next0(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let shared = match read_varint(&mut self.source)? {
+            Some(shared) => shared as usize,
+            None => return Ok(None),
+        };
+        let len = read_varint(&mut self.source)?.ok_or(io::ErrorKind::UnexpectedEof)? as usize;
+        if shared > self.key.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "shared prefix longer than previous key",
+            ));
+        }
+        self.key.truncate(shared);
+        let mut suffix = (&mut self.source).take(len as u64);
+        if suffix.read_to_end(&mut self.key)? < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Some(self.key.clone()))
+    }
+}
+
+impl<R: Read> Iterator for FrontCodedKeyReader<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next0().transpose()
+    }
+}
+
+/// read a single entry in export format, returning None at the end of the data
+fn read_entry(source: &mut impl Read) -> io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+    fn take(source: &mut impl Read) -> io::Result<Vec<u8>> {
+        let mut len = [0u8; 4];
+        source.read_exact(&mut len)?;
+        let mut res = vec![0u8; u32::from_be_bytes(len) as usize];
+        source.read_exact(&mut res)?;
+        Ok(res)
+    }
+    let mut first = [0u8];
+    if source.read(&mut first)? == 0 {
+        return Ok(None);
+    }
+    let key = take(&mut first.chain(&mut *source))?;
+    let value = take(source)?;
+    Ok(Some((key, value)))
+}
+
+/// write a tree to an anonymous temporary file in export format, and rewind it for reading
+#[cfg(feature = "spill")]
+fn spill_run(tree: &RadixTree) -> anyhow::Result<std::fs::File> {
+    use std::io::{Seek, SeekFrom};
+    let mut file = tempfile::tempfile()?;
+    export_part(tree, &mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+/// merge sorted runs of entries, keeping only the entry from the last run for keys in several runs
+#[cfg(feature = "spill")]
+fn merge_runs<'a>(
+    mut runs: Vec<Box<dyn Iterator<Item = anyhow::Result<(Vec<u8>, Vec<u8>)>> + 'a>>,
+) -> impl Iterator<Item = anyhow::Result<(Vec<u8>, Vec<u8>)>> + 'a {
+    use std::{cmp::Reverse, collections::BinaryHeap};
+    // the next key of each run, smallest key first and for the same key the earliest run first
+    let mut heap = BinaryHeap::new();
+    let mut values = vec![Vec::new(); runs.len()];
+    let mut error = None;
+    for (i, run) in runs.iter_mut().enumerate() {
+        match run.next() {
+            Some(Ok((key, value))) => {
+                heap.push(Reverse((key, i)));
+                values[i] = value;
+            }
+            Some(Err(cause)) => error = Some(cause),
+            None => {}
+        }
+    }
+    std::iter::from_fn(move || {
+        if let Some(cause) = error.take() {
+            heap.clear();
+            return Some(Err(cause));
+        }
+        let Reverse((key, mut i)) = heap.pop()?;
+        let mut value = std::mem::take(&mut values[i]);
+        loop {
+            match runs[i].next() {
+                Some(Ok((next, next_value))) => {
+                    heap.push(Reverse((next, i)));
+                    values[i] = next_value;
+                }
+                Some(Err(cause)) => {
+                    heap.clear();
+                    return Some(Err(cause));
+                }
+                None => {}
+            }
+            // later runs replace the value of earlier ones
+            match heap.peek() {
+                Some(Reverse((next, j))) if *next == key => {
+                    i = *j;
+                    heap.pop();
+                    value = std::mem::take(&mut values[i]);
+                }
+                _ => break,
+            }
+        }
+        Some(Ok((key, value)))
+    })
+}
+
+/// builds a tree from entries in strictly increasing key order
+///
+/// A node is complete as soon as an entry with a key outside of it is added. Complete nodes are written
+/// to the store right away, so only the open nodes along the path to the last key are kept in memory.
+#[cfg(feature = "spill")]
+struct SortedBuilder<S: BlobStore> {
+    store: S,
+    /// the last key that was added
+    key: Vec<u8>,
+    /// the open nodes along the path to the last key, from the root down
+    ///
+    /// Each node has the offset in the key where its prefix starts, its value and its complete children.
+    /// The prefix ends where the prefix of the next node starts, or at the end of the key.
+    path: Vec<(usize, Option<Vec<u8>>, Vec<TreeNode<S>>)>,
+    len: u64,
+}
+
+#[cfg(feature = "spill")]
+impl<S: BlobStore> SortedBuilder<S> {
+    fn new(store: S) -> Self {
+        Self {
+            store,
+            key: Vec::new(),
+            path: vec![(0, None, Vec::new())],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, key: &[u8], value: Vec<u8>) -> Result<(), S::Error> {
+        debug_assert!(
+            self.len == 0 || key > self.key.as_slice(),
+            "keys not sorted"
+        );
+        self.len += 1;
+        if key.is_empty() {
+            // only possible for the first key, which is the value of the root
+            self.path[0].1 = Some(value);
+            return Ok(());
+        }
+        let n = common_prefix(&self.key, key);
+        // close all nodes that end after the common prefix
+        let mut end = self.key.len();
+        while end > n {
+            let (start, value, children) = self.path.pop().expect("root ends at 0");
+            if start >= n {
+                let node = self.close(&self.key[start..end], value, children)?;
+                self.path.last_mut().expect("root ends at 0").2.push(node);
+                end = start;
+            } else {
+                // the key diverges within the prefix, so split the node
+                let node = self.close(&self.key[n..end], value, children)?;
+                self.path.push((start, None, vec![node]));
+                end = n;
+            }
+        }
+        self.path.push((n, Some(value), Vec::new()));
+        self.key = key.to_vec();
+        Ok(())
+    }
+
+    /// build a complete node and write it to the store
+    fn close(
+        &self,
+        prefix: &[u8],
+        value: Option<Vec<u8>>,
+        children: Vec<TreeNode<S>>,
+    ) -> Result<TreeNode<S>, S::Error> {
+        let node = assemble(prefix, value.map(Value::from), children, &self.store)?;
+        if is_no_store::<S>() {
+            return Ok(node);
+        }
+        let mut data = Vec::new();
+        node.serialize(&mut data, &self.store)?;
+        Ok(TreeNode::deserialize(&data)?)
+    }
+
+    fn finish(mut self) -> Result<RadixTree<S>, S::Error> {
+        let mut end = self.key.len();
+        while self.path.len() > 1 {
+            let (start, value, children) = self.path.pop().expect("root ends at 0");
+            let node = self.close(&self.key[start..end], value, children)?;
+            self.path.last_mut().expect("root ends at 0").2.push(node);
+            end = start;
+        }
+        let (_, value, children) = self.path.pop().expect("root ends at 0");
+        let node = self.close(&[], value, children)?;
+        let res = RadixTree::new(node, self.store);
+        res.len.set(self.len);
+        Ok(res)
+    }
+}
+
+/// build a tree from entries in export format
+fn import_part(mut data: &[u8]) -> anyhow::Result<RadixTree> {
+    fn take<'a>(data: &mut &'a [u8]) -> anyhow::Result<&'a [u8]> {
+        anyhow::ensure!(data.len() >= 4, "truncated entry");
+        let (len, rest) = data.split_at(4);
+        let len = u32::from_be_bytes(len.try_into()?) as usize;
+        anyhow::ensure!(rest.len() >= len, "truncated entry");
+        let (res, rest) = rest.split_at(len);
+        *data = rest;
+        Ok(res)
+    }
+    let mut res = RadixTree::default();
+    while !data.is_empty() {
+        let key = take(&mut data)?;
+        let value = take(&mut data)?;
+        res.insert(key, value);
+    }
+    Ok(res)
+}
+
+/// export all entries of a tree in key order
+fn export_part<S: BlobStore + Clone>(
+    tree: &RadixTree<S>,
+    target: impl Write,
+) -> anyhow::Result<ExportPart>
+where
+    anyhow::Error: From<S::Error>,
+{
+    let mut target = BufWriter::new(target);
+    let mut res = ExportPart::default();
+    for entry in tree.try_iter() {
+        let (key, value) = entry?;
+        let value = value.load(&tree.store)?;
+        res.bytes += export_entry(&mut target, &key, &value)?;
+        res.entries += 1;
+        if res.first_key.is_none() {
+            res.first_key = Some(key.to_vec());
+        }
+    }
+    target.flush()?;
+    Ok(res)
+}
+
+/// Iterator over all keys in front coded form
+///
+/// Each key is given as the length of the prefix it shares with the previous key, and the remaining suffix.
+/// The first key has a shared prefix length of 0.
+pub struct FrontCodedKeyIter<S: BlobStore = Detached> {
+    path: Vec<u8>,
+    /// length of the part of path that is unchanged since the last key
+    shared: usize,
+    stack: Vec<(usize, Option<TreeNodeIter<'static, S>>)>,
+    store: S,
+}
+
+impl<S: BlobStore> FrontCodedKeyIter<S> {
+    fn new(iter: TreeNodeIter<'static, S>, store: S) -> Self {
+        Self {
+            path: Vec::new(),
+            shared: 0,
+            stack: vec![(0, Some(iter))],
+            store,
+        }
+    }
+
+    fn next0(&mut self) -> Result<Option<(usize, Vec<u8>)>, S::Error> {
+        while !self.stack.is_empty() {
+            let (last_prefix_len, iter_opt) = &mut self.stack.last_mut().unwrap();
+            let last_prefix_len = *last_prefix_len;
+            if let Some(iter) = iter_opt {
+                if let Some(node) = iter.next() {
+                    let has_value = node.value_opt().is_some();
+                    let prefix = node.load_prefix(&self.store)?;
+                    let prefix_len = prefix.len();
+                    let children = node.load_children_owned(&self.store)?;
+                    self.path.extend_from_slice(prefix.as_ref());
+                    self.stack.push((prefix_len, children));
+                    if has_value {
+                        let res = (self.shared, self.path[self.shared..].to_vec());
+                        self.shared = self.path.len();
+                        return Ok(Some(res));
+                    }
+                } else {
+                    *iter_opt = None;
+                }
+            } else {
+                self.path.truncate(self.path.len() - last_prefix_len);
+                self.shared = self.shared.min(self.path.len());
+                self.stack.pop();
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<S: BlobStore> Iterator for FrontCodedKeyIter<S> {
+    type Item = Result<(usize, Vec<u8>), S::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next0() {
+            Ok(Some(x)) => Some(Ok(x)),
+            Ok(None) => None,
+            Err(cause) => {
+                // ensure that the next call to next will terminate
+                self.stack.clear();
+                Some(Err(cause))
+            }
+        }
+    }
+}
+
+impl RadixTree {
+    pub fn keys_front_coded(&self) -> impl Iterator<Item = (usize, Vec<u8>)> {
+        self.try_keys_front_coded().unwrap_safe_iter()
+    }
+
+    pub fn par_export<W: Write>(
+        &self,
+        n_workers: usize,
+        sink_factory: impl Fn(usize) -> W + Sync,
+    ) -> anyhow::Result<Vec<ExportPart>> {
+        self.try_par_export(n_workers, sink_factory)
+    }
+
+    pub fn export_keys_front_coded(&self, target: impl Write) -> anyhow::Result<u64> {
+        self.try_export_keys_front_coded(target)
+    }
+
+    /// Serialize the tree to a self contained byte vector
+    ///
+    /// Unlike the store format, the result contains no ids and does not depend on how the tree was
+    /// built or stored, so equal trees give equal bytes. Read it using [RadixTree::from_bytes].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.try_to_bytes().unwrap_safe()
+    }
+
+    pub fn write_to(&self, target: impl Write) -> anyhow::Result<u64> {
+        self.try_write_to(target)
+    }
+
+    pub fn export_chunked(
+        &self,
+        sink: impl FnMut(usize, &[u8]) -> io::Result<()>,
+        chunk_size: usize,
+    ) -> anyhow::Result<ChunkManifest> {
+        self.try_export_chunked(sink, chunk_size)
+    }
+}
+
+impl RadixTree {
+    /// Read a tree written by [RadixTree::to_bytes]
+    ///
+    /// Fails for data with an unknown format version, and for data that is truncated, has trailing
+    /// bytes, does not describe a canonical tree or is nested deeper than 1024 levels.
+    pub fn from_bytes(mut data: &[u8]) -> anyhow::Result<RadixTree> {
+        let res = read_tree_bytes(&mut data)?;
+        anyhow::ensure!(data.is_empty(), "trailing data after tree");
+        Ok(res)
+    }
+
+    /// Read a tree written by [RadixTree::write_to] or [RadixTree::to_bytes]
+    ///
+    /// Reads exactly the bytes of the tree, so the source can contain more data after it. The data is
+    /// read in small pieces, so the source should be buffered. This uses the same parser as
+    /// [RadixTree::from_bytes], so untrusted input is safe to read and fails the same way.
+    pub fn read_from(mut source: impl Read) -> anyhow::Result<RadixTree> {
+        read_tree_bytes(&mut source)
+    }
+
+    /// Start a resumable import of a tree exported using [RadixTree::export_chunked]
+    pub fn import_chunked(manifest: ChunkManifest) -> ChunkedImport {
+        ChunkedImport {
+            data: Vec::new(),
+            manifest,
+            chunks: 0,
+        }
+    }
+
+    /// Import entries in the format written by [RadixTree::par_export], decoding each reader on its own thread.
+    ///
+    /// The shards do not have to be disjoint. For duplicate keys, the value from the last reader wins.
+    pub fn par_import<R: Read + Send>(readers: Vec<R>) -> anyhow::Result<RadixTree> {
+        let trees = std::thread::scope(|scope| {
+            let handles = readers
+                .into_iter()
+                .map(|mut reader| {
+                    scope.spawn(move || {
+                        let mut data = Vec::new();
+                        reader.read_to_end(&mut data)?;
+                        import_part(&data)
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        })?;
+        let mut res = RadixTree::default();
+        for tree in trees {
+            // shards from one export are disjoint and just get added as a whole, for overlapping
+            // shards the value of the later shard replaces the earlier one
+            res.outer_combine_with(&tree, |a, b| a.set(Some(b)));
+        }
+        Ok(res)
+    }
+}
+
+impl<S: BlobStore> RadixTree<S> {
+    /// Build a tree in `store` from entries in any order, with a bound on the memory used for buffering.
+    ///
+    /// Entries are collected in a tree until the total size of their keys and values exceeds `mem_budget`.
+    /// The tree is then written as a sorted run to an anonymous temporary file. At the end, the runs and
+    /// the remaining entries are merged in key order, and the merged entries are added to the result in
+    /// a single pass. Each node is written to the store as soon as all entries below it have been added,
+    /// so only the path to the last entry is kept in memory. As with [FromIterator], later entries replace
+    /// earlier ones with the same key.
+    ///
+    /// With [Detached], nothing is written and the result is built in memory.
+    #[cfg(feature = "spill")]
+    pub fn from_unsorted_iter_with_spill<K, V>(
+        iter: impl IntoIterator<Item = (K, V)>,
+        mem_budget: usize,
+        store: S,
+    ) -> anyhow::Result<Self>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+        anyhow::Error: From<S::Error>,
+    {
+        let mut runs = Vec::new();
+        let mut current = RadixTree::default();
+        let mut current_bytes = 0;
+        for (key, value) in iter {
+            let (key, value) = (key.as_ref(), value.as_ref());
+            if let Some(old) = current.get(key) {
+                current_bytes -= key.len() + old.len();
+            }
+            current_bytes += key.len() + value.len();
+            current.insert(key, value);
+            if current_bytes > mem_budget {
+                runs.push(spill_run(&current)?);
+                current = RadixTree::default();
+                current_bytes = 0;
+            }
+        }
+        let mut sources = runs
+            .into_iter()
+            .map(|run| {
+                let mut run = io::BufReader::new(run);
+                Box::new(std::iter::from_fn(move || {
+                    read_entry(&mut run)
+                        .transpose()
+                        .map(|entry| entry.map_err(anyhow::Error::new))
+                })) as Box<dyn Iterator<Item = anyhow::Result<(Vec<u8>, Vec<u8>)>>>
+            })
+            .collect::<Vec<_>>();
+        sources.push(Box::new(
+            current.iter().map(|(k, v)| Ok((k.to_vec(), v.to_vec()))),
+        ));
+        let mut builder = SortedBuilder::new(store);
+        for entry in merge_runs(sources) {
+            let (key, value) = entry?;
+            builder.push(&key, value)?;
+        }
+        Ok(builder.finish()?)
+    }
+}
+
+impl<S: BlobStore + Clone> RadixTree<S> {
+    /// Iterate over all keys in order, as the length of the prefix shared with the previous key and the remaining suffix.
+    ///
+    /// This avoids building each full key, e.g. when writing a front coded index.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    pub(super) fn try_keys_front_coded(&self) -> FrontCodedKeyIter<S> {
+        FrontCodedKeyIter::new(
+            TreeNodeIter::from_arc(Arc::new(vec![self.node.clone()])),
+            self.store.clone(),
+        )
+    }
+
+    /// Write all keys in order as a front coded key list, returning the number of bytes written.
+    ///
+    /// Each key is written as the length of the prefix shared with the previous key and the length of the
+    /// remaining suffix, both LEB128 encoded, followed by the suffix. Use [FrontCodedKeyReader] to read it.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_export_keys_front_coded(&self, target: impl Write) -> anyhow::Result<u64>
+    where
+        anyhow::Error: From<S::Error>,
+    {
+        let mut target = BufWriter::new(target);
+        let mut bytes = 0;
+        for entry in self.try_keys_front_coded() {
+            let (shared, suffix) = entry?;
+            bytes += write_varint(&mut target, shared as u64)?;
+            bytes += write_varint(&mut target, suffix.len() as u64)?;
+            target.write_all(&suffix)?;
+            bytes += suffix.len() as u64;
+        }
+        target.flush()?;
+        Ok(bytes)
+    }
+
+    /// Serialize the tree to a self contained byte vector, loading all values, see [RadixTree::to_bytes]
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_to_bytes(&self) -> Result<Vec<u8>, S::Error> {
+        let mut res = TREE_BYTES_HEADER.to_vec();
+        write_node_bytes(&self.node.as_ref(), &self.store, &mut |data| {
+            res.extend_from_slice(data);
+            Ok::<_, S::Error>(())
+        })?;
+        Ok(res)
+    }
+
+    /// Write the tree in the format of [RadixTree::to_bytes], returning the number of bytes written
+    ///
+    /// Nodes are written depth first as they are loaded, so the serialized tree is never kept in memory.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_write_to(&self, target: impl Write) -> anyhow::Result<u64>
+    where
+        anyhow::Error: From<S::Error>,
+    {
+        let mut target = BufWriter::new(target);
+        target.write_all(TREE_BYTES_HEADER)?;
+        let mut bytes = TREE_BYTES_HEADER.len() as u64;
+        write_node_bytes(&self.node.as_ref(), &self.store, &mut |data| {
+            target.write_all(data)?;
+            bytes += data.len() as u64;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        target.flush()?;
+        Ok(bytes)
+    }
+
+    /// Write the tree in the format of [RadixTree::to_bytes], split into chunks of `chunk_size` bytes
+    ///
+    /// Chunk `i` is passed to `sink` as `sink(i, chunk)`. Only the last chunk can be smaller. The returned
+    /// manifest contains a checksum for each chunk, so each chunk can be verified on its own using
+    /// [ChunkedImport]. The export is deterministic, so to resume a transfer the export can be repeated,
+    /// skipping the chunks that have already been transferred.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_export_chunked(
+        &self,
+        mut sink: impl FnMut(usize, &[u8]) -> io::Result<()>,
+        chunk_size: usize,
+    ) -> anyhow::Result<ChunkManifest>
+    where
+        anyhow::Error: From<S::Error>,
+    {
+        anyhow::ensure!(chunk_size > 0, "chunk size must not be 0");
+        let mut manifest = ChunkManifest {
+            chunk_size: chunk_size as u64,
+            ..Default::default()
+        };
+        let mut chunk = Vec::with_capacity(chunk_size);
+        let mut emit = |mut data: &[u8]| {
+            manifest.bytes += data.len() as u64;
+            while !data.is_empty() {
+                let n = (chunk_size - chunk.len()).min(data.len());
+                chunk.extend_from_slice(&data[..n]);
+                data = &data[n..];
+                if chunk.len() == chunk_size {
+                    sink(manifest.checksums.len(), &chunk)?;
+                    manifest.checksums.push(fnv1a(&chunk));
+                    chunk.clear();
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        emit(TREE_BYTES_HEADER)?;
+        write_node_bytes(&self.node.as_ref(), &self.store, &mut emit)?;
+        if !chunk.is_empty() {
+            sink(manifest.checksums.len(), &chunk)?;
+            manifest.checksums.push(fnv1a(&chunk));
+        }
+        Ok(manifest)
+    }
+
+    /// Export all entries, using `n_workers` threads that each write a key range.
+    ///
+    /// Part `i` is written to the sink returned by `sink_factory(i)`. Concatenating the sinks in order gives
+    /// the complete export in key order. The returned index has one entry per part, with the first key and
+    /// the offset of the part in the concatenated export.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_par_export<W, F>(
+        &self,
+        n_workers: usize,
+        sink_factory: F,
+    ) -> anyhow::Result<Vec<ExportPart>>
+    where
+        W: Write,
+        F: Fn(usize) -> W + Sync,
+        anyhow::Error: From<S::Error>,
+    {
+        let parts = self.try_split_even(n_workers.max(1))?;
+        let sink_factory = &sink_factory;
+        let mut index = std::thread::scope(|scope| {
+            let handles = parts
+                .iter()
+                .enumerate()
+                .map(|(i, part)| scope.spawn(move || export_part(part, sink_factory(i))))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        })?;
+        let mut offset = 0;
+        for part in &mut index {
+            part.offset = offset;
+            offset += part.bytes;
+        }
+        Ok(index)
+    }
+}
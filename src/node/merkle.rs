@@ -0,0 +1,426 @@
+use super::{assemble, TreeNode, TreeNodeRef, Value};
+use crate::{
+    store::{blob_store::UnwrapSafeExt, BlobStore},
+    Hex, RadixTree,
+};
+
+/// A hash function for merkle hashes of trees, see [RadixTree::try_root_hash_with]
+///
+/// The hash of a tree depends on the hash function, so all parties comparing hashes or checking
+/// proofs have to use the same one.
+pub trait MerkleHasher: Default {
+    fn update(&mut self, data: &[u8]);
+
+    fn finish(self) -> [u8; 32];
+
+    fn digest(data: &[u8]) -> [u8; 32] {
+        let mut hasher = Self::default();
+        hasher.update(data);
+        hasher.finish()
+    }
+}
+
+/// SHA-256, the hash function of [RadixTree::root_hash]
+#[derive(Debug, Clone, Default)]
+pub struct Sha256Hasher(sha2::Sha256);
+
+impl MerkleHasher for Sha256Hasher {
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(&mut self.0, data)
+    }
+
+    fn finish(self) -> [u8; 32] {
+        sha2::Digest::finalize(self.0).into()
+    }
+}
+
+/// BLAKE3, which is considerably faster than SHA-256 for large values
+#[cfg(feature = "blake3")]
+#[derive(Debug, Clone, Default)]
+pub struct Blake3Hasher(blake3::Hasher);
+
+#[cfg(feature = "blake3")]
+impl MerkleHasher for Blake3Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(self) -> [u8; 32] {
+        self.0.finalize().into()
+    }
+}
+
+/// One node on the path from the root to an entry, see [InclusionProof]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProofNode {
+    pub prefix: Vec<u8>,
+    /// hash of the value, if the node has a value
+    pub value_hash: Option<[u8; 32]>,
+    /// merkle hashes of all children, including the one on the path
+    pub children: Vec<[u8; 32]>,
+    /// index of the child on the path, None for the node containing the entry
+    pub next: Option<usize>,
+}
+
+/// Proof that a tree with a given [RadixTree::root_hash] contains an entry, see [RadixTree::prove]
+///
+/// The size of the proof is proportional to the depth of the entry and the number of children of the
+/// nodes on the path, not to the size of the tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InclusionProof {
+    /// nodes from the root to the node containing the entry
+    pub nodes: Vec<ProofNode>,
+}
+
+impl InclusionProof {
+    /// True if this proves that the tree with merkle hash `root_hash` maps `key` to `value`
+    pub fn verify(&self, root_hash: &[u8; 32], key: &[u8], value: &[u8]) -> bool {
+        self.verify_with::<Sha256Hasher>(root_hash, key, value)
+    }
+
+    /// [InclusionProof::verify] for a proof made with [RadixTree::try_prove_with]
+    pub fn verify_with<H: MerkleHasher>(
+        &self,
+        root_hash: &[u8; 32],
+        key: &[u8],
+        value: &[u8],
+    ) -> bool {
+        let Some(last) = self.nodes.last() else {
+            return false;
+        };
+        if last.next.is_some() || last.value_hash != Some(H::digest(value)) {
+            return false;
+        }
+        if self
+            .nodes
+            .iter()
+            .flat_map(|x| x.prefix.iter())
+            .ne(key.iter())
+        {
+            return false;
+        }
+        let mut hash = None;
+        for node in self.nodes.iter().rev() {
+            match (node.next, hash) {
+                (Some(i), Some(child)) if node.children.get(i) == Some(&child) => {}
+                (None, None) => {}
+                _ => return false,
+            }
+            hash = Some(merkle_node_hash::<H>(
+                &node.prefix,
+                node.value_hash.as_ref(),
+                &node.children,
+            ));
+        }
+        hash.as_ref() == Some(root_hash)
+    }
+}
+
+/// A node with its value and the hashes of its children, for repairing a tree from a peer, see
+/// [RadixTree::try_repair_from]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleNode {
+    pub prefix: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+    /// first prefix byte and merkle hash of each child
+    pub children: Vec<(u8, [u8; 32])>,
+}
+
+impl MerkleNode {
+    /// The merkle hash of the node, the same as that of the subtree it was taken from
+    pub fn hash(&self) -> [u8; 32] {
+        let value_hash = self.value.as_ref().map(|v| Sha256Hasher::digest(v));
+        let children = self.children.iter().map(|(_, h)| *h).collect::<Vec<_>>();
+        merkle_node_hash::<Sha256Hasher>(&self.prefix, value_hash.as_ref(), &children)
+    }
+}
+
+/// merkle hash of a node, given the prefix, the hash of the value and the hashes of the children
+///
+/// Lengths are included, so no two different nodes have the same encoded form.
+fn merkle_node_hash<H: MerkleHasher>(
+    prefix: &[u8],
+    value_hash: Option<&[u8; 32]>,
+    children: &[[u8; 32]],
+) -> [u8; 32] {
+    let mut hasher = H::default();
+    hasher.update(&(prefix.len() as u64).to_be_bytes());
+    hasher.update(prefix);
+    match value_hash {
+        Some(hash) => {
+            hasher.update(&[1]);
+            hasher.update(hash);
+        }
+        None => hasher.update(&[0]),
+    }
+    hasher.update(&(children.len() as u64).to_be_bytes());
+    for child in children {
+        hasher.update(child);
+    }
+    hasher.finish()
+}
+
+fn value_hash<H: MerkleHasher, S: BlobStore>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+) -> Result<Option<[u8; 32]>, S::Error> {
+    Ok(match node.value_opt() {
+        Some(value) => Some(H::digest(&value.load(store)?)),
+        None => None,
+    })
+}
+
+fn children_hashes<H: MerkleHasher, S: BlobStore>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+) -> Result<Vec<[u8; 32]>, S::Error> {
+    let mut res = Vec::new();
+    if let Some(mut iter) = node.load_children(store)? {
+        while let Some(child) = iter.next() {
+            res.push(merkle_hash::<H, S>(&child, store)?);
+        }
+    }
+    Ok(res)
+}
+
+/// merkle hash of a node and everything below it
+fn merkle_hash<H: MerkleHasher, S: BlobStore>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+) -> Result<[u8; 32], S::Error> {
+    let prefix = node.load_prefix(store)?;
+    let value_hash = value_hash::<H, S>(node, store)?;
+    let children = children_hashes::<H, S>(node, store)?;
+    Ok(merkle_node_hash::<H>(
+        &prefix,
+        value_hash.as_ref(),
+        &children,
+    ))
+}
+
+/// collect the nodes on the path to `key`, returning false if the key is not in the tree
+fn prove_node<H: MerkleHasher, S: BlobStore>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+    key: &[u8],
+    res: &mut Vec<ProofNode>,
+) -> Result<bool, S::Error> {
+    let prefix = node.load_prefix(store)?;
+    let Some(key) = key.strip_prefix(prefix.as_ref()) else {
+        return Ok(false);
+    };
+    let value_hash = value_hash::<H, S>(node, store)?;
+    let children = children_hashes::<H, S>(node, store)?;
+    let mut proof = ProofNode {
+        prefix: prefix.to_vec(),
+        value_hash,
+        children,
+        next: None,
+    };
+    let Some(first) = key.first() else {
+        res.push(proof);
+        return Ok(value_hash.is_some());
+    };
+    let Some(mut iter) = node.load_children(store)? else {
+        return Ok(false);
+    };
+    let mut index = 0;
+    while let Some(child) = iter.next() {
+        if child.first_prefix_byte() == Some(*first) {
+            proof.next = Some(index);
+            res.push(proof);
+            return prove_node::<H, S>(&child, store, key, res);
+        }
+        index += 1;
+    }
+    Ok(false)
+}
+
+/// the node with the given path, see [RadixTree::try_merkle_node]
+fn node_at<S: BlobStore>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+    path: &[u8],
+) -> Result<Option<TreeNode<S>>, S::Error> {
+    if path.is_empty() {
+        return Ok(Some(node.to_owned()));
+    }
+    let prefix = node.load_prefix(store)?;
+    let Some(rest) = path.strip_prefix(prefix.as_ref()) else {
+        return Ok(None);
+    };
+    let Some(first) = rest.first() else {
+        return Ok(None);
+    };
+    let Some(children) = node.load_children(store)? else {
+        return Ok(None);
+    };
+    let Some(child) = children.find(*first) else {
+        return Ok(None);
+    };
+    if rest.len() == 1 {
+        Ok(Some(child.to_owned()))
+    } else {
+        node_at(&child, store, rest)
+    }
+}
+
+fn merkle_node<S: BlobStore>(node: &TreeNodeRef<S>, store: &S) -> Result<MerkleNode, S::Error> {
+    let mut children = Vec::new();
+    if let Some(mut iter) = node.load_children(store)? {
+        while let Some(child) = iter.next() {
+            let first = child.first_prefix_byte().unwrap_or_default();
+            children.push((first, merkle_hash::<Sha256Hasher, S>(&child, store)?));
+        }
+    }
+    Ok(MerkleNode {
+        prefix: node.load_prefix(store)?.to_vec(),
+        value: match node.value_opt() {
+            Some(value) => Some(value.load(store)?.to_vec()),
+            None => None,
+        },
+        children,
+    })
+}
+
+/// rebuild the node with the given path and hash, using local nodes with the right hash where possible
+///
+/// `start` is the key before the prefix of the node. Local nodes that can not be read are fetched as well.
+fn repair_node<S: BlobStore>(
+    root: &TreeNodeRef<S>,
+    store: &S,
+    start: &[u8],
+    path: &[u8],
+    hash: &[u8; 32],
+    fetch: &mut impl FnMut(&[u8]) -> anyhow::Result<MerkleNode>,
+) -> anyhow::Result<TreeNode<S>>
+where
+    anyhow::Error: From<S::Error>,
+{
+    let local = node_at(root, store, path).ok().flatten();
+    if let Some(local) = local {
+        if merkle_hash::<Sha256Hasher, S>(&local.as_ref(), store).ok() == Some(*hash) {
+            return Ok(local);
+        }
+    }
+    let remote = fetch(path)?;
+    anyhow::ensure!(
+        remote.hash() == *hash,
+        "node {} does not match its hash",
+        Hex::new(path)
+    );
+    let mut start = start.to_vec();
+    start.extend_from_slice(&remote.prefix);
+    let mut children = Vec::with_capacity(remote.children.len());
+    for (first, hash) in &remote.children {
+        let mut path = start.clone();
+        path.push(*first);
+        children.push(repair_node(root, store, &start, &path, hash, fetch)?);
+    }
+    Ok(assemble(
+        &remote.prefix,
+        remote.value.map(Value::from),
+        children,
+        store,
+    )?)
+}
+
+impl RadixTree {
+    pub fn root_hash(&self) -> [u8; 32] {
+        self.try_root_hash().unwrap_safe()
+    }
+
+    pub fn prove(&self, key: impl AsRef<[u8]>) -> Option<InclusionProof> {
+        self.try_prove(key).unwrap_safe()
+    }
+
+    pub fn merkle_node(&self, path: impl AsRef<[u8]>) -> Option<MerkleNode> {
+        self.try_merkle_node(path).unwrap_safe()
+    }
+
+    pub fn root_hash_with<H: MerkleHasher>(&self) -> [u8; 32] {
+        self.try_root_hash_with::<H>().unwrap_safe()
+    }
+
+    pub fn prove_with<H: MerkleHasher>(&self, key: impl AsRef<[u8]>) -> Option<InclusionProof> {
+        self.try_prove_with::<H>(key).unwrap_safe()
+    }
+}
+
+impl<S: BlobStore + Clone> RadixTree<S> {
+    /// SHA-256 merkle hash of the tree
+    ///
+    /// The hash of a node covers its prefix, the hash of its value, and the hashes of its children, so
+    /// two trees with the same entries have the same hash, no matter how they are stored. Hashes are
+    /// not stored in the nodes, so this reads the entire tree.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_root_hash(&self) -> Result<[u8; 32], S::Error> {
+        self.try_root_hash_with::<Sha256Hasher>()
+    }
+
+    /// Merkle hash of the tree using the hash function `H`, e.g. to match an existing content addressing scheme
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_root_hash_with<H: MerkleHasher>(&self) -> Result<[u8; 32], S::Error> {
+        merkle_hash::<H, S>(&self.node.as_ref(), &self.store)
+    }
+
+    /// A proof that the tree contains `key`, that can be checked against [RadixTree::root_hash]
+    /// using [InclusionProof::verify], or None if the tree does not contain `key`
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_prove(&self, key: impl AsRef<[u8]>) -> Result<Option<InclusionProof>, S::Error> {
+        self.try_prove_with::<Sha256Hasher>(key)
+    }
+
+    /// [RadixTree::try_prove] for [RadixTree::try_root_hash_with], checked with [InclusionProof::verify_with]
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_prove_with<H: MerkleHasher>(
+        &self,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<InclusionProof>, S::Error> {
+        let mut nodes = Vec::new();
+        let found = prove_node::<H, S>(&self.node.as_ref(), &self.store, key.as_ref(), &mut nodes)?;
+        Ok(if found {
+            Some(InclusionProof { nodes })
+        } else {
+            None
+        })
+    }
+
+    /// The node with the given path, with its value and the hashes of its children
+    ///
+    /// The path of the root is empty. The path of any other node is the key up to and including the
+    /// first byte of its prefix. This serves the requests of [RadixTree::try_repair_from] on a peer.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_merkle_node(&self, path: impl AsRef<[u8]>) -> Result<Option<MerkleNode>, S::Error> {
+        let root = self.node.as_ref();
+        Ok(match node_at(&root, &self.store, path.as_ref())? {
+            Some(node) => Some(merkle_node(&node.as_ref(), &self.store)?),
+            None => None,
+        })
+    }
+
+    /// Repair this tree to match a tree with a known good [RadixTree::root_hash], e.g. of a replica
+    ///
+    /// Starting from the root, the hash of each local node is compared with the expected hash. Local
+    /// nodes with a matching hash are kept as they are. Mismatching nodes, and nodes that can not be read
+    /// from the store, are fetched from the peer with `fetch`, which gets the path of a node as defined
+    /// for [RadixTree::try_merkle_node]. Only the children of fetched nodes are compared further down, so
+    /// localized damage only fetches the nodes on the paths to it. Fetched nodes are checked against
+    /// their hash, so a peer can not inject different data.
+    ///
+    /// Hashes are not stored, so every comparison reads the local subtree. The repaired tree is returned
+    /// with the fetched nodes in memory, see [RadixTree::try_reattach].
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_repair_from(
+        &self,
+        root_hash: &[u8; 32],
+        mut fetch: impl FnMut(&[u8]) -> anyhow::Result<MerkleNode>,
+    ) -> anyhow::Result<RadixTree<S>>
+    where
+        anyhow::Error: From<S::Error>,
+    {
+        let root = self.node.as_ref();
+        let node = repair_node(&root, &self.store, &[], &[], root_hash, &mut fetch)?;
+        Ok(RadixTree::new(node, self.store.clone()))
+    }
+}
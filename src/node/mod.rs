@@ -1,24 +1,57 @@
 //! Implementation of the radix tree node and associated plumbing
 //!
 //!
-#![allow(dead_code, clippy::type_complexity, clippy::unit_arg)]
+#![allow(
+    dead_code,
+    clippy::type_complexity,
+    clippy::unit_arg,
+    clippy::too_many_arguments
+)]
 use std::{
-    any::TypeId, borrow::Borrow, cmp::Ordering, fmt, marker::PhantomData, mem::ManuallyDrop,
-    ops::Deref, slice, sync::Arc,
+    any::TypeId,
+    borrow::Borrow,
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    mem::ManuallyDrop,
+    ops::{Bound, Deref, Index, RangeBounds},
+    slice,
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc,
+    },
 };
 
 use inplace_vec_builder::InPlaceVecBuilder;
 
+#[cfg(feature = "custom-store")]
+use crate::store::ReadCache;
 use crate::{
     store::{
         blob_store::{OwnedBlob, UnwrapSafeExt},
-        Blob, BlobStore, Detached, NoError,
+        Blob, BlobStore, Detached, InfallibleStore, NoError, UnwrapSafeIter, UnwrapSafeIterExt,
+        MAX_ID_LEN,
     },
+    util::{fnv1a, SplitMix64},
     Hex, Lit, RadixTree,
 };
 use std::fmt::Debug;
+mod diff;
+mod export;
+#[cfg(feature = "merkle")]
+mod merkle;
 #[cfg(test)]
 mod tests;
+#[cfg(feature = "blake3")]
+pub use merkle::Blake3Hasher;
+#[cfg(feature = "merkle")]
+pub use merkle::{InclusionProof, MerkleHasher, MerkleNode, ProofNode, Sha256Hasher};
+pub use {
+    diff::{Change, DiffStats},
+    export::{ChunkManifest, ChunkedImport, ExportPart, FrontCodedKeyIter, FrontCodedKeyReader},
+};
 
 const PTR_SIZE: usize = std::mem::size_of::<*const u8>();
 
@@ -41,6 +74,8 @@ impl CompactOwnedBlob {
     fn copy_from_slice(data: &[u8]) -> Self {
         if data.len() > PTR_SIZE {
             let arc = Arc::new(data.to_vec());
+            #[cfg(feature = "debug-checksums")]
+            checksums::register(&arc);
             Self {
                 arc: ManuallyDrop::new(arc),
             }
@@ -51,6 +86,19 @@ impl CompactOwnedBlob {
         }
     }
 
+    /// Like [CompactOwnedBlob::copy_from_slice], but keeps the arc instead of copying the data
+    fn from_arc(data: Arc<Vec<u8>>) -> Self {
+        if data.len() > PTR_SIZE {
+            #[cfg(feature = "debug-checksums")]
+            checksums::register(&data);
+            Self {
+                arc: ManuallyDrop::new(data),
+            }
+        } else {
+            Self::copy_from_slice(&data)
+        }
+    }
+
     fn ref_count(&self, hdr: Header) -> Option<usize> {
         if hdr.is_inline() {
             None
@@ -64,11 +112,16 @@ impl CompactOwnedBlob {
             if hdr.is_inline() {
                 &self.inline[..hdr.len()]
             } else {
+                #[cfg(feature = "debug-checksums")]
+                checksums::verify(&self.arc);
                 self.arc.as_ref().as_ref()
             }
         }
     }
 
+    /// Clone according to `hdr`, which must be the header this was created with
+    ///
+    /// The arc is cloned using [Arc::clone], so this is as thread safe as cloning the arc itself.
     fn manual_clone(&self, hdr: Header) -> Self {
         unsafe {
             if hdr.is_arc() {
@@ -86,6 +139,8 @@ impl CompactOwnedBlob {
     fn manual_drop(&mut self, hdr: Header) {
         unsafe {
             if hdr.is_arc() {
+                #[cfg(feature = "debug-checksums")]
+                checksums::unregister(&self.arc);
                 ManuallyDrop::drop(&mut self.arc);
             }
         }
@@ -96,6 +151,46 @@ impl CompactOwnedBlob {
     }
 }
 
+/// Checksums of the arc backed blobs of all live nodes, keyed by the address of the arc
+///
+/// With the debug-checksums feature, every access to such a blob verifies its checksum, so memory
+/// corruption, e.g. by a bug in the manual reference counting, is detected close to where it happens.
+#[cfg(feature = "debug-checksums")]
+mod checksums {
+    use crate::util::fnv1a;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex, OnceLock},
+    };
+
+    fn table() -> &'static Mutex<HashMap<usize, u64>> {
+        static TABLE: OnceLock<Mutex<HashMap<usize, u64>>> = OnceLock::new();
+        TABLE.get_or_init(Default::default)
+    }
+
+    fn key(arc: &Arc<Vec<u8>>) -> usize {
+        Arc::as_ptr(arc) as usize
+    }
+
+    pub(super) fn register(arc: &Arc<Vec<u8>>) {
+        table().lock().unwrap().insert(key(arc), fnv1a(arc));
+    }
+
+    pub(super) fn verify(arc: &Arc<Vec<u8>>) {
+        let expected = table().lock().unwrap().get(&key(arc)).copied();
+        if let Some(expected) = expected {
+            assert_eq!(fnv1a(arc), expected, "blob at {:#x} was modified", key(arc));
+        }
+    }
+
+    /// forget the checksum when the last reference is about to be dropped
+    pub(super) fn unregister(arc: &Arc<Vec<u8>>) {
+        if Arc::strong_count(arc) == 1 {
+            table().lock().unwrap().remove(&key(arc));
+        }
+    }
+}
+
 /// Reference to children for use in [OwnedTreeNode].
 ///
 /// Children can be either
@@ -105,6 +200,10 @@ impl CompactOwnedBlob {
 ///
 /// Which kind of reference is valid is stored externally
 /// in the child header byte.
+///
+/// Reference counts are only changed using [Arc::clone] and dropping the arc, and children are only
+/// mutated through [Arc::make_mut]. So clones of a tree can be used and modified from different
+/// threads, and a writer never changes nodes that are visible to a reader holding another clone.
 union ChildrenRef<S> {
     arc_id: ManuallyDrop<Arc<Vec<u8>>>,
     arc_data: ManuallyDrop<Arc<Vec<TreeNode<S>>>>,
@@ -372,6 +471,7 @@ impl<'a> OwnedBlobRef<'a> {
             target.extend_from_slice(slice);
         } else {
             let id = store.write(slice)?;
+            check_id(&id, store)?;
             let len = id.len() + n;
             target.push(Header::id(len).into());
             target.extend_from_slice(&slice[..n]);
@@ -381,6 +481,19 @@ impl<'a> OwnedBlobRef<'a> {
     }
 }
 
+/// ids are stored with a one byte length header, so they must be checked even in release builds
+fn check_id<S: BlobStore>(id: &[u8], store: &S) -> Result<(), S::Error> {
+    if !id.is_empty() && id.len() <= MAX_ID_LEN {
+        Ok(())
+    } else {
+        Err(store.invalid_data(&format!(
+            "store returned an id of {} bytes, ids must be between 1 and {} bytes",
+            id.len(),
+            MAX_ID_LEN
+        )))
+    }
+}
+
 /// A self contained reference to a borrowed id or data blob that is part of some buffer
 struct BorrowedBlobRef<'a> {
     hdr: Header,
@@ -467,6 +580,19 @@ impl<'a> ValueRef<'a> {
 }
 
 impl<'a, S: BlobStore> ValueRef<'a, S> {
+    /// The data if it is stored in the value itself, or None if it has to be loaded from the store
+    pub fn data_inline_or_arc(&self) -> Option<&[u8]> {
+        self.read().ok()
+    }
+
+    /// Load the data, from the store if necessary
+    pub fn load(&self, store: &S) -> Result<OwnedBlob, S::Error> {
+        match self.read() {
+            Ok(data) => Ok(OwnedBlob::copy_from_slice(data)),
+            Err(id) => store.read(id),
+        }
+    }
+
     pub fn to_owned(&self) -> Value<S> {
         match &self.0 {
             Ok(x) => OwnedValueRef::new(*x).to_owned(),
@@ -478,14 +604,19 @@ impl<'a, S: BlobStore> ValueRef<'a, S> {
         }
     }
 
-    fn detached(&self, _store: &S) -> Result<Value, S::Error>
+    fn detached(&self, store: &S) -> Result<Value, S::Error>
     where
         S: BlobStore,
     {
         if !self.is_id() {
-            Ok(unsafe { std::mem::transmute(self.to_owned()) })
+            Ok(unsafe { std::mem::transmute::<Value<S>, Value>(self.to_owned()) })
         } else {
-            todo!()
+            let data = store.read(self.slice())?;
+            Ok(Value {
+                hdr: Header::data(data.len()),
+                data: CompactOwnedBlob::copy_from_slice(&data),
+                p: PhantomData,
+            })
         }
     }
 
@@ -583,6 +714,23 @@ impl Deref for Value {
     }
 }
 
+/// Move the data into a value without copying it, unless it is small enough to be stored inline
+impl<S: BlobStore> From<Arc<Vec<u8>>> for Value<S> {
+    fn from(data: Arc<Vec<u8>>) -> Self {
+        Self {
+            hdr: Header::data(data.len()),
+            data: CompactOwnedBlob::from_arc(data),
+            p: PhantomData,
+        }
+    }
+}
+
+impl<S: BlobStore> From<Vec<u8>> for Value<S> {
+    fn from(data: Vec<u8>) -> Self {
+        Arc::new(data).into()
+    }
+}
+
 impl Value {
     pub fn downcast<S2: BlobStore>(self) -> Value<S2> {
         unsafe { std::mem::transmute(self) }
@@ -596,13 +744,26 @@ impl<S: BlobStore> Value<S> {
         p: PhantomData,
     };
 
-    fn as_value_ref(&self) -> OwnedValueRef<S> {
+    fn copy_from_slice(data: &[u8]) -> Self {
+        Self {
+            hdr: Header::data(data.len()),
+            data: CompactOwnedBlob::copy_from_slice(data),
+            p: PhantomData,
+        }
+    }
+
+    fn as_value_ref(&self) -> OwnedValueRef<'_, S> {
         OwnedValueRef::new(OwnedBlobRef {
             hdr: self.hdr,
             data: &self.data,
         })
     }
 
+    /// The data if it is stored in the value itself, or None if it has to be loaded from the store
+    pub fn data_inline_or_arc(&self) -> Option<&[u8]> {
+        self.read().ok()
+    }
+
     pub fn load(&self, store: &S) -> Result<OwnedBlob, S::Error>
     where
         S: BlobStore,
@@ -631,6 +792,11 @@ impl<S: BlobStore> Value<S> {
         }
     }
 
+    /// true if the value has the same representation as `other`, either the same data or the same id
+    fn is_same(&self, other: OwnedBlobRef) -> bool {
+        self.hdr == other.hdr && self.data.slice(self.hdr) == other.data.slice(other.hdr)
+    }
+
     fn read(&self) -> Result<&[u8], &[u8]> {
         if self.hdr.is_data() {
             Ok(self.data.slice(self.hdr))
@@ -669,15 +835,22 @@ pub struct TreeNode<S> {
 impl PartialEq for TreeNode<Detached> {
     fn eq(&self, other: &Self) -> bool {
         self.prefix_ref().slice() == other.prefix_ref().slice()
+            && self.has_value() == other.has_value()
             && self.value_ref().slice() == other.value_ref().slice()
-            && self.get_children() == other.get_children()
+            && match (self.get_children(), other.get_children()) {
+                // shared children are equal without looking at them
+                (Ok(a), Ok(b)) => Arc::ptr_eq(a, b) || a == b,
+                // an empty children vector is the same as no children
+                (Ok(a), Err(b)) | (Err(b), Ok(a)) => a.is_empty() && b.is_empty(),
+                (a, b) => a == b,
+            }
     }
 }
 
 impl Eq for TreeNode<Detached> {}
 
 impl<S: BlobStore> TreeNode<S> {
-    fn as_ref(&self) -> TreeNodeRef<S> {
+    fn as_ref(&self) -> TreeNodeRef<'_, S> {
         TreeNodeRef::owned(self)
     }
 }
@@ -693,6 +866,81 @@ impl TreeNode<Detached> {
         self.serialize(&mut data, store)?;
         Ok(TreeNode::<S>::deserialize(&data).unwrap())
     }
+
+    /// Borrow the value for a key. A detached tree is entirely in memory, so no store is needed.
+    fn get_slice(&self, key: &[u8]) -> Option<&[u8]> {
+        let rest = key.strip_prefix(self.prefix_ref().slice())?;
+        if let Some(c) = rest.first() {
+            let children = self.get_children().ok()?;
+            let i = children
+                .binary_search_by_key(&Some(*c), |child| child.first_prefix_byte())
+                .ok()?;
+            children[i].get_slice(rest)
+        } else if self.has_value() {
+            Some(self.value_ref().slice())
+        } else {
+            None
+        }
+    }
+
+    /// Child indices along the path to the node containing the value for a key, if there is one.
+    fn value_path(&self, key: &[u8]) -> Option<Vec<usize>> {
+        let mut node = self;
+        let mut rest = key.strip_prefix(node.prefix_ref().slice())?;
+        let mut path = Vec::new();
+        while let Some(c) = rest.first() {
+            let children = node.get_children().ok()?;
+            let i = children
+                .binary_search_by_key(&Some(*c), |child| child.first_prefix_byte())
+                .ok()?;
+            node = &children[i];
+            rest = rest.strip_prefix(node.prefix_ref().slice())?;
+            path.push(i);
+        }
+        Some(path).filter(|_| node.has_value())
+    }
+
+    /// Insert or replace a single entry like [TreeNode::insert], returning the node that holds the value
+    fn insert_node(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        if self.is_empty() {
+            // the only node that may have neither a value nor two children
+            self.set_prefix_slice(key);
+            self.set_value_slice(Some(value));
+            return self;
+        }
+        let n = common_prefix(self.prefix_ref().slice(), key);
+        if n < self.prefix_ref().slice().len() {
+            // key diverges within the prefix, so split it there
+            self.split(&Detached, n).unwrap_safe();
+        }
+        if n == key.len() {
+            self.set_value_slice(Some(value));
+            return self;
+        }
+        let key = &key[n..];
+        let children = self.load_children_mut(&Detached).unwrap_safe();
+        let i = match children.binary_search_by_key(&Some(key[0]), |x| x.first_prefix_byte()) {
+            Ok(i) => i,
+            Err(i) => {
+                let mut child = Self::EMPTY;
+                child.set_prefix_slice(key);
+                child.set_value_slice(Some(value));
+                children.insert(i, child);
+                return &mut children[i];
+            }
+        };
+        children[i].insert_node(key, value)
+    }
+
+    /// Follow a path of child indices as returned by [TreeNode::value_path], unsharing the nodes along the way.
+    fn node_at_path_mut(&mut self, path: &[usize]) -> &mut Self {
+        let mut node = self;
+        for i in path {
+            let children = node.get_children_mut().expect("children must be loaded");
+            node = &mut Arc::make_mut(children)[*i];
+        }
+        node
+    }
 }
 
 impl<S: BlobStore> Debug for TreeNode<S> {
@@ -781,10 +1029,11 @@ impl<S: BlobStore> TreeNode<S> {
                     if record_size == 0 {
                         record_size = len;
                     } else if record_size != len {
-                        record_size = usize::max_value()
+                        record_size = usize::MAX
                     }
                 }
                 let id = store.write(&serialized)?;
+                check_id(&id, store)?;
                 target.push(Header::id(id.len() + 1).into());
                 target.push(record_size.try_into().unwrap_or_default());
                 target.extend_from_slice(&id);
@@ -836,7 +1085,195 @@ impl<S: BlobStore> TreeNode<S> {
         })
     }
 
-    fn load_children(&self, store: &S) -> Result<Option<TreeNodeIter<S>>, S::Error> {
+    /// Insert or replace a single entry, modifying only the nodes along the path to the key
+    ///
+    /// Returns true if the key was not present before.
+    fn insert(&mut self, key: &[u8], value: Value<S>, store: &S) -> Result<bool, S::Error> {
+        let prefix = self.load_prefix(store)?;
+        let (n, prefix_len) = (common_prefix(&prefix, key), prefix.len());
+        if n < prefix_len {
+            // key diverges within the prefix, so split it there
+            self.split(store, n)?;
+        }
+        if n == key.len() {
+            let added = !self.has_value();
+            self.set_value_owned(value.as_value_ref().0);
+            return Ok(added);
+        }
+        let key = &key[n..];
+        let children = self.load_children_mut(store)?;
+        let added = match children.binary_search_by_key(&Some(key[0]), |x| x.first_prefix_byte()) {
+            Ok(i) => children[i].insert(key, value, store)?,
+            Err(i) => {
+                let mut child = Self::EMPTY;
+                child.set_prefix_slice(key);
+                child.set_value_owned(value.as_value_ref().0);
+                children.insert(i, child);
+                true
+            }
+        };
+        // only needed for an empty node, which has no prefix and gets its first child
+        self.canonicalize_loaded(store)?;
+        Ok(added)
+    }
+
+    /// Apply `f` to the child starting with `first`, returning None if there is no such child
+    ///
+    /// Children are only loaded into this node or copied if `f` returns Some, and `f` must not
+    /// modify the child when it returns None. So nothing is modified if `f` finds nothing to do.
+    /// Children that are not shared are modified in place. A child that becomes empty is removed.
+    fn modify_child<T>(
+        &mut self,
+        first: u8,
+        store: &S,
+        f: impl FnOnce(&mut Self, &S) -> Result<Option<T>, S::Error>,
+    ) -> Result<Option<T>, S::Error> {
+        let find = |children: &[Self]| {
+            children
+                .binary_search_by_key(&Some(first), |x| x.first_prefix_byte())
+                .ok()
+        };
+        let (i, res) = match self.get_children_mut() {
+            Ok(children) => {
+                let Some(i) = find(children) else {
+                    return Ok(None);
+                };
+                if let Some(children) = Arc::get_mut(children) {
+                    (i, f(&mut children[i], store)?)
+                } else {
+                    let mut child = children[i].clone();
+                    let res = f(&mut child, store)?;
+                    if res.is_some() {
+                        Arc::make_mut(children)[i] = child;
+                    }
+                    (i, res)
+                }
+            }
+            Err(id) => {
+                let mut children = Vec::new();
+                if let Some(mut iter) = TreeNodeIter::load(id, store)? {
+                    while let Some(item) = iter.next() {
+                        children.push(item.to_owned());
+                    }
+                }
+                let Some(i) = find(&children) else {
+                    return Ok(None);
+                };
+                let res = f(&mut children[i], store)?;
+                if res.is_some() {
+                    self.set_children_arc(Arc::new(children));
+                }
+                (i, res)
+            }
+        };
+        if res.is_some() {
+            let children = self.load_children_mut(store)?;
+            if children[i].is_empty() {
+                children.remove(i);
+                if children.is_empty() {
+                    self.set_children_arc_opt(None);
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Remove a single entry, modifying only the nodes along the path to the key
+    ///
+    /// Returns the removed value, or None without modifying anything if the key is not in the tree.
+    fn remove(&mut self, key: &[u8], store: &S) -> Result<Option<Value<S>>, S::Error> {
+        let n = {
+            let prefix = self.load_prefix(store)?;
+            if !key.starts_with(&prefix) {
+                return Ok(None);
+            }
+            prefix.len()
+        };
+        if n == key.len() {
+            let Some(value) = self.value_opt().map(|x| x.to_owned()) else {
+                return Ok(None);
+            };
+            self.set_value_slice(None);
+            if self.is_leaf() {
+                self.canonicalize();
+                return Ok(Some(value));
+            }
+            // a single remaining child has to be merged into this node
+            self.load_children_mut(store)?;
+            self.canonicalize_loaded(store)?;
+            return Ok(Some(value));
+        }
+        let key = &key[n..];
+        let res = self.modify_child(key[0], store, |child, store| child.remove(key, store))?;
+        if res.is_some() {
+            self.canonicalize_loaded(store)?;
+        }
+        Ok(res)
+    }
+
+    /// Detach the subtree of all entries starting with `prefix` and return it, with complete keys
+    ///
+    /// Nothing is modified if there are no such entries.
+    fn take_prefix(&mut self, prefix: &[u8], store: &S) -> Result<Self, S::Error> {
+        let own = self.load_prefix(store)?.to_vec();
+        let n = common_prefix(&own, prefix);
+        if n == prefix.len() {
+            // this node and everything below it starts with prefix
+            return Ok(std::mem::replace(self, Self::EMPTY));
+        }
+        if n < own.len() || self.is_leaf() {
+            return Ok(Self::EMPTY);
+        }
+        let res = self.modify_child(prefix[n], store, |child, store| {
+            let res = child.take_prefix(&prefix[n..], store)?;
+            Ok(Some(res).filter(|res| !res.is_empty()))
+        })?;
+        let Some(mut res) = res else {
+            return Ok(Self::EMPTY);
+        };
+        self.canonicalize_loaded(store)?;
+        let mut key = own;
+        key.extend_from_slice(&res.load_prefix(store)?);
+        res.set_prefix_slice(&key);
+        Ok(res)
+    }
+
+    /// Remove the first or last entry in a single traversal, appending its key to `key`
+    fn pop(
+        &mut self,
+        last: bool,
+        key: &mut Vec<u8>,
+        store: &S,
+    ) -> Result<Option<Value<S>>, S::Error> {
+        key.extend_from_slice(&self.load_prefix(store)?);
+        let res = if self.has_value() && (!last || self.is_leaf()) {
+            let value = self.value_opt().map(|x| x.to_owned());
+            self.set_value_slice(None);
+            if !self.is_leaf() {
+                // a single remaining child has to be merged into this node
+                self.load_children_mut(store)?;
+            }
+            value
+        } else if self.is_leaf() {
+            // empty tree
+            return Ok(None);
+        } else {
+            let children = self.load_children_mut(store)?;
+            let i = if last { children.len() - 1 } else { 0 };
+            let value = children[i].pop(last, key, store)?;
+            if children[i].is_empty() {
+                children.remove(i);
+                if children.is_empty() {
+                    self.set_children_arc_opt(None);
+                }
+            }
+            value
+        };
+        self.canonicalize_loaded(store)?;
+        Ok(res)
+    }
+
+    fn load_children(&self, store: &S) -> Result<Option<TreeNodeIter<'_, S>>, S::Error> {
         match self.get_children() {
             Ok(children) => Ok(TreeNodeIter::from_slice(children)),
             Err(id) => TreeNodeIter::load(id, store),
@@ -917,6 +1354,24 @@ impl<S: BlobStore> TreeNode<S> {
         }
     }
 
+    /// canonicalize a node whose prefix or whose single remaining child's prefix may be an id
+    fn canonicalize_loaded(&mut self, store: &S) -> Result<(), S::Error> {
+        if !self.has_value() && self.child_count() == 1 {
+            if self.prefix_ref().is_id() {
+                let prefix = self.load_prefix(store)?.to_vec();
+                self.set_prefix_slice(&prefix);
+            }
+            let children = self.get_children_mut().expect("children must be loaded");
+            let child = &mut Arc::make_mut(children)[0];
+            if child.prefix_ref().is_id() {
+                let prefix = child.load_prefix(store)?.to_vec();
+                child.set_prefix_slice(&prefix);
+            }
+        }
+        self.canonicalize();
+        Ok(())
+    }
+
     fn load_prefix(&self, store: &S) -> Result<Blob<'_>, S::Error> {
         if self.prefix_ref().is_id() {
             store.read(&self.prefix_ref().slice()[1..])
@@ -1044,6 +1499,22 @@ impl<S: BlobStore> TreeNode<S> {
         self.children.deref(self.children_hdr)
     }
 
+    /// store ids of the prefix, value and children blobs, for the fields that are stored as ids
+    fn blob_ids(&self) -> [Option<&[u8]>; 3] {
+        let prefix = self.prefix_ref();
+        let value = self.value_ref();
+        [
+            Some(prefix.slice())
+                .filter(|_| prefix.is_id())
+                .map(|id| &id[1..]),
+            Some(value.slice()).filter(|id| value.is_id() && !id.is_empty()),
+            self.get_children()
+                .err()
+                .filter(|id| !id.is_empty())
+                .map(|id| &id[1..]),
+        ]
+    }
+
     fn get_children_mut(&mut self) -> Result<&mut Arc<Vec<TreeNode<S>>>, &[u8]> {
         self.children.deref_mut(self.children_hdr)
     }
@@ -1161,16 +1632,7 @@ struct BorrowedTreeNode<'a, S: BlobStore> {
 
 impl<'a, S: BlobStore> Clone for BorrowedTreeNode<'a, S> {
     fn clone(&self) -> Self {
-        Self {
-            discriminator: 1,
-            prefix_hdr: self.prefix_hdr,
-            value_hdr: self.value_hdr,
-            children_hdr: self.children_hdr,
-            prefix: self.prefix,
-            value: self.value,
-            children: self.children,
-            p: self.p,
-        }
+        *self
     }
 }
 
@@ -1256,7 +1718,18 @@ impl<'a, S: BlobStore> BorrowedTreeNode<'a, S> {
         self.prefix_hdr.len() + self.value_hdr.len() + self.children_hdr.len() + 3
     }
 
-    fn load_prefix(&self, store: &S) -> Result<Blob, S::Error>
+    /// store ids of the prefix, value and children blobs, for the fields that are stored as ids
+    fn blob_ids(&self) -> [Option<&'a [u8]>; 3] {
+        [
+            self.prefix_ref().read().err().map(|id| &id[1..]),
+            self.value_ref().read().err().filter(|id| !id.is_empty()),
+            Some(self.children_ref().slice())
+                .filter(|id| !id.is_empty())
+                .map(|id| &id[1..]),
+        ]
+    }
+
+    fn load_prefix(&self, store: &S) -> Result<Blob<'_>, S::Error>
     where
         S: BlobStore,
     {
@@ -1266,7 +1739,7 @@ impl<'a, S: BlobStore> BorrowedTreeNode<'a, S> {
         }
     }
 
-    fn value_ref_opt(&self) -> Option<ValueRef<S>> {
+    fn value_ref_opt(&self) -> Option<ValueRef<'_, S>> {
         if self.value_hdr != Header::NONE {
             Some(ValueRef(Err(self.value_ref()), PhantomData))
         } else {
@@ -1296,7 +1769,8 @@ impl<'a, S: BlobStore> BorrowedTreeNode<'a, S> {
     pub fn read_one(rest: &'a [u8]) -> Option<(Self, &'a [u8])> {
         let prefix_hdr = Header::from(*rest.first()?);
         let len = prefix_hdr.len() + 1;
-        if rest.len() < len {
+        // a prefix id contains the first prefix byte and a non empty store id
+        if rest.len() < len || (prefix_hdr.is_id() && prefix_hdr.len() < 2) {
             return None;
         }
         let (prefix, rest) = (&rest[0], &rest[len..]);
@@ -1310,7 +1784,8 @@ impl<'a, S: BlobStore> BorrowedTreeNode<'a, S> {
 
         let children_hdr = Header::from(*rest.first()?);
         let len = children_hdr.len() + 1;
-        if rest.len() < len {
+        // children are either none, or a record size byte and a non empty store id
+        if rest.len() < len || !children_hdr.is_id() || children_hdr.len() == 1 {
             return None;
         }
         let (children, rest) = (&rest[0], &rest[len..]);
@@ -1371,7 +1846,7 @@ impl<'a, S: BlobStore> TreeNodeRef<'a, S> {
         }
     }
 
-    fn dispatch(&self) -> Result<&'a TreeNode<S>, BorrowedTreeNode<S>> {
+    fn dispatch(&self) -> Result<&'a TreeNode<S>, BorrowedTreeNode<'_, S>> {
         match self.0 {
             Ok(inner) => Ok(inner),
             Err(inner) => Err(inner),
@@ -1392,14 +1867,28 @@ impl<'a, S: BlobStore> TreeNodeRef<'a, S> {
         }
     }
 
-    fn load_prefix(&self, store: &S) -> Result<Blob, S::Error> {
+    fn load_prefix(&self, store: &S) -> Result<Blob<'_>, S::Error> {
         match &self.0 {
             Ok(owned) => owned.load_prefix(store),
             Err(borrowed) => borrowed.load_prefix(store),
         }
     }
 
-    fn load_children(&self, store: &S) -> Result<Option<TreeNodeIter<S>>, S::Error> {
+    fn first_prefix_byte(&self) -> Option<u8> {
+        match &self.0 {
+            Ok(owned) => owned.first_prefix_byte(),
+            Err(borrowed) => borrowed.first_prefix_byte(),
+        }
+    }
+
+    fn blob_ids(&self) -> [Option<&[u8]>; 3] {
+        match &self.0 {
+            Ok(owned) => owned.blob_ids(),
+            Err(borrowed) => borrowed.blob_ids(),
+        }
+    }
+
+    fn load_children(&self, store: &S) -> Result<Option<TreeNodeIter<'_, S>>, S::Error> {
         match self.dispatch() {
             Ok(owned) => owned.load_children(store),
             Err(borrowed) => borrowed.load_children(store),
@@ -1413,7 +1902,7 @@ impl<'a, S: BlobStore> TreeNodeRef<'a, S> {
         }
     }
 
-    fn value_opt(&self) -> Option<ValueRef<S>> {
+    fn value_opt(&self) -> Option<ValueRef<'_, S>> {
         match &self.0 {
             Ok(owned) => owned.value_opt().map(|x| ValueRef(Ok(x.0), PhantomData)),
             Err(borrowed) => borrowed.value_ref_opt(),
@@ -1423,7 +1912,12 @@ impl<'a, S: BlobStore> TreeNodeRef<'a, S> {
     fn clone_shortened(&self, store: &S, n: usize) -> Result<TreeNode<S>, S::Error> {
         match self.dispatch() {
             Ok(owned) => owned.clone_shortened(store, n),
-            Err(_borrowed) => todo!(),
+            Err(borrowed) => {
+                let prefix = borrowed.load_prefix(store)?;
+                let mut res = borrowed.to_owned();
+                res.set_prefix_slice(&prefix[n..]);
+                Ok(res)
+            }
         }
     }
 }
@@ -1495,7 +1989,10 @@ fn filter_prefix<S: BlobStore>(
         Ok(match x {
             FindResult::Found(res) => {
                 let mut res = res.to_owned();
-                res.set_prefix_slice(substitution);
+                // an empty tree must keep the empty prefix to stay canonical
+                if !res.is_empty() {
+                    res.set_prefix_slice(substitution);
+                }
                 res
             }
             FindResult::Prefix { tree, matching } => {
@@ -1537,7 +2034,7 @@ fn last_value<S: BlobStore>(
     })
 }
 
-/// get the first value
+/// get the first entry, with the prefixes along the path appended to `prefix`
 fn first_entry<S: BlobStore>(
     mut prefix: Vec<u8>,
     node: &TreeNodeRef<S>,
@@ -1553,7 +2050,7 @@ fn first_entry<S: BlobStore>(
     })
 }
 
-/// get the last value
+/// get the last entry, with the prefixes along the path appended to `prefix`
 fn last_entry<S: BlobStore>(
     mut prefix: Vec<u8>,
     node: &TreeNodeRef<S>,
@@ -1566,510 +2063,1866 @@ fn last_entry<S: BlobStore>(
     })
 }
 
-// common prefix of two slices.
-fn common_prefix<'a, T: Eq>(a: &'a [T], b: &'a [T]) -> usize {
-    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+/// Work done by a single lookup, see [RadixTree::try_get_traced]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Trace {
+    /// number of nodes visited, including the root
+    pub nodes_visited: u64,
+    /// number of blobs read from the store
+    pub blobs_loaded: u64,
+    /// total size of the blobs read from the store
+    pub bytes_read: u64,
 }
 
-#[cfg_attr(feature = "custom-store", visibility::make(pub))]
-/// Converter that converts nodes from one kind of store to another
-trait NodeConverter<A, B: BlobStore> {
-    fn convert_node(&self, node: &TreeNodeRef<A>, store: &A) -> Result<TreeNode<B>, A::Error>
-    where
-        A: BlobStore;
-    fn convert_node_shortened(
-        &self,
-        node: &TreeNodeRef<A>,
-        store: &A,
-        n: usize,
-    ) -> Result<TreeNode<B>, A::Error>
-    where
-        A: BlobStore;
-    fn convert_value(&self, bv: &ValueRef<A>, store: &A) -> Result<Value<B>, A::Error>
-    where
-        A: BlobStore;
+impl Trace {
+    fn load(&mut self, bytes: usize) {
+        self.blobs_loaded += 1;
+        self.bytes_read += bytes as u64;
+    }
 }
 
-/// Converter that converts from a node with NoStore to any other store.
-///
-/// This is always possible and a noop.
-#[cfg_attr(feature = "custom-store", visibility::make(pub))]
-#[derive(Clone, Copy)]
-struct DowncastConverter;
+/// Entry statistics for all keys sharing a common prefix
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrefixStats {
+    /// number of entries
+    pub entries: u64,
+    /// total size of keys and values in bytes
+    pub bytes: u64,
+}
 
-impl<B: BlobStore> NodeConverter<Detached, B> for DowncastConverter {
-    fn convert_node(
-        &self,
-        node: &TreeNodeRef<Detached>,
-        _: &Detached,
-    ) -> Result<TreeNode<B>, NoError> {
-        Ok(node.downcast())
+impl PrefixStats {
+    fn add(&mut self, that: PrefixStats) {
+        self.entries += that.entries;
+        self.bytes += that.bytes;
     }
+}
 
-    fn convert_node_shortened(
-        &self,
-        node: &TreeNodeRef<Detached>,
-        store: &Detached,
-        n: usize,
-    ) -> Result<TreeNode<B>, NoError> {
-        node.clone_shortened(store, n).map(|x| x.downcast())
-    }
+/// Node and entry counts for one level of a tree, see [RadixTree::try_depth_profile]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LevelStats {
+    /// number of nodes at this level
+    pub nodes: u64,
+    /// number of entries whose value is stored in a node at this level
+    pub entries: u64,
+    /// total size of the prefixes of the nodes at this level in bytes
+    pub prefix_bytes: u64,
+}
 
-    fn convert_value(&self, bv: &ValueRef, _: &Detached) -> Result<Value<B>, NoError> {
-        Ok(bv.downcast::<B>().to_owned())
-    }
+/// Blobs that are no longer reachable from a tree after it was modified
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PendingFree {
+    /// store ids of the unreachable blobs
+    pub ids: BTreeSet<Vec<u8>>,
+    /// total size of the unreachable blobs in bytes
+    pub bytes: u64,
 }
 
-/// Identity node converter to simplify plumbing
-#[derive(Clone, Copy)]
-#[cfg_attr(feature = "custom-store", visibility::make(pub))]
-struct IdentityConverter;
+/// Result of checking an attached tree against its store, see [RadixTree::verify]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// store ids of all blobs referenced by the tree, including the ones that failed to verify
+    pub reachable: BTreeSet<Vec<u8>>,
+    /// total size of the blobs that could be read in bytes
+    pub bytes: u64,
+    /// store ids of blobs that could not be read, with the error
+    pub missing: BTreeMap<Vec<u8>, String>,
+    /// store ids of blobs that could be read, but did not pass the check
+    pub corrupt: BTreeSet<Vec<u8>>,
+    /// store ids of children blobs that are not a valid encoding of a list of nodes, with the reason
+    pub invalid: BTreeMap<Vec<u8>, String>,
+}
 
-impl<A: BlobStore> NodeConverter<A, A> for IdentityConverter {
-    fn convert_node(&self, node: &TreeNodeRef<A>, _store: &A) -> Result<TreeNode<A>, A::Error> {
-        Ok(node.to_owned())
+impl VerifyReport {
+    /// True if every blob of the tree could be read, passed the check, and was decoded
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty() && self.invalid.is_empty()
     }
 
-    fn convert_node_shortened(
-        &self,
-        node: &TreeNodeRef<A>,
-        store: &A,
-        n: usize,
-    ) -> Result<TreeNode<A>, A::Error> {
-        node.clone_shortened(store, n)
+    /// The ids in `ids` that are not referenced by the tree
+    ///
+    /// Given all ids of a store, this gives the blobs that have been leaked. Note that the blob containing
+    /// the root node, as returned by [RadixTree::try_reattach], is not part of the tree.
+    pub fn unreachable(&self, ids: impl IntoIterator<Item = impl AsRef<[u8]>>) -> Vec<Vec<u8>> {
+        ids.into_iter()
+            .map(|id| id.as_ref().to_vec())
+            .filter(|id| !self.reachable.contains(id))
+            .collect()
     }
 
-    fn convert_value(&self, bv: &ValueRef<A>, _store: &A) -> Result<Value<A>, A::Error> {
-        Ok(bv.to_owned())
+    fn read<S: BlobStore>(
+        &mut self,
+        id: &[u8],
+        store: &S,
+        check: &impl Fn(&[u8], &[u8]) -> bool,
+    ) -> Option<OwnedBlob> {
+        self.reachable.insert(id.to_vec());
+        match store.read(id) {
+            Ok(blob) if check(id, &blob) => {
+                self.bytes += blob.len() as u64;
+                Some(blob)
+            }
+            Ok(_) => {
+                self.corrupt.insert(id.to_vec());
+                None
+            }
+            Err(cause) => {
+                self.missing.insert(id.to_vec(), format!("{:?}", cause));
+                None
+            }
+        }
     }
 }
 
-/// A converter that converts from one store to another store by just completely detaching it
-#[derive(Clone, Copy)]
-#[cfg_attr(feature = "custom-store", visibility::make(pub))]
-struct DetachConverter;
+/// length of a value, asking the store if it is stored as an id
+fn value_len<S: BlobStore>(value: &ValueRef<S>, store: &S) -> Result<usize, S::Error> {
+    Ok(match value.read() {
+        Ok(data) => data.len(),
+        Err(id) => store.blob_len(id)?,
+    })
+}
 
-impl<A: BlobStore, B: BlobStore> NodeConverter<A, B> for DetachConverter {
-    fn convert_node(&self, node: &TreeNodeRef<A>, store: &A) -> Result<TreeNode<B>, A::Error> {
-        Ok(node.detached(store)?.downcast())
-    }
-    fn convert_value(&self, value: &ValueRef<A>, store: &A) -> Result<Value<B>, A::Error> {
-        Ok(value.detached(store)?.downcast())
+/// statistics for a node and all its children
+///
+/// `key_len` is the length of the key before the prefix of the node
+fn subtree_stats<S: BlobStore>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+    key_len: usize,
+) -> Result<PrefixStats, S::Error> {
+    let key_len = key_len + node.load_prefix(store)?.len();
+    let mut res = PrefixStats::default();
+    if let Some(value) = node.value_opt() {
+        res.add(PrefixStats {
+            entries: 1,
+            bytes: (key_len + value_len(&value, store)?) as u64,
+        });
+    }
+    if let Some(mut children) = node.load_children(store)? {
+        while let Some(child) = children.next() {
+            res.add(subtree_stats(&child, store, key_len)?);
+        }
     }
-    fn convert_node_shortened(
-        &self,
-        node: &TreeNodeRef<A>,
-        store: &A,
-        n: usize,
-    ) -> Result<TreeNode<B>, A::Error> {
-        let node = node.clone_shortened(store, n)?;
-        Ok(self.convert_node(&node.as_ref(), store)?.downcast())
+    Ok(res)
+}
+
+/// collect the store ids of all blobs reachable from a node
+fn reachable_blobs<S: BlobStore>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+    res: &mut BTreeSet<Vec<u8>>,
+) -> Result<(), S::Error> {
+    let [prefix, value, children] = node.blob_ids();
+    res.extend(prefix.into_iter().chain(value).map(|id| id.to_vec()));
+    if let Some(id) = children {
+        if !res.insert(id.to_vec()) {
+            // shared subtree that has already been visited
+            return Ok(());
+        }
+    }
+    if let Some(mut children) = node.load_children(store)? {
+        while let Some(child) = children.next() {
+            reachable_blobs(&child, store, res)?;
+        }
     }
+    Ok(())
 }
 
-struct OuterJoin<'a, A: BlobStore, B: BlobStore, E> {
-    a: TreeNodeIter<'a, A>,
-    b: TreeNodeIter<'a, B>,
-    p: PhantomData<E>,
+/// check all blobs below a node, recording the results in `res`
+///
+/// `path` contains the ids of the children blobs above the node, to detect cycles.
+fn verify_node<S: BlobStore>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+    check: &impl Fn(&[u8], &[u8]) -> bool,
+    path: &mut Vec<Vec<u8>>,
+    res: &mut VerifyReport,
+) {
+    let [prefix, value, _] = node.blob_ids();
+    for id in prefix.into_iter().chain(value) {
+        if !res.reachable.contains(id) {
+            res.read(id, store, check);
+        }
+    }
+    let children_id = match node.dispatch() {
+        Ok(owned) => owned.get_children().err(),
+        Err(borrowed) => Some(borrowed.children_ref().slice()),
+    };
+    let mut iter = match children_id.filter(|id| !id.is_empty()) {
+        Some(id) => {
+            let (record_size, id) = (id[0], &id[1..]);
+            if path.iter().any(|x| x == id) {
+                // following this would loop forever
+                res.invalid
+                    .insert(id.to_vec(), "children contain a cycle".into());
+                return;
+            }
+            if res.reachable.contains(id) {
+                // shared subtree that has already been visited
+                return;
+            }
+            let Some(data) = res.read(id, store, check) else {
+                return;
+            };
+            let iter = BorrowedTreeNodeIter {
+                data,
+                offset: 0,
+                record_size,
+                p: PhantomData,
+            };
+            if let Err(reason) = iter.validate() {
+                res.invalid.insert(id.to_vec(), reason);
+                return;
+            }
+            path.push(id.to_vec());
+            TreeNodeIter::Borrowed(iter)
+        }
+        None => match node.dispatch() {
+            Ok(owned) => match owned.get_children() {
+                Ok(children) => TreeNodeIter::Owned(OwnedTreeNodeIter::new(children)),
+                Err(_) => return,
+            },
+            Err(_) => return,
+        },
+    };
+    while let Some(child) = iter.next() {
+        verify_node(&child, store, check, path, res);
+    }
+    if let TreeNodeIter::Borrowed(_) = iter {
+        path.pop();
+    }
 }
 
-impl<'a, A, B, E> OuterJoin<'a, A, B, E>
+/// copy a node and everything below it to `target`, writing each children blob as soon as it is complete
+///
+/// `copied` maps children ids in the source store to children ids in the target store, so subtrees
+/// that are shared between nodes or trees are only copied once.
+fn compact_node<S: BlobStore, S2: BlobStore>(
+    node: &TreeNode<S>,
+    store: &S,
+    target: &S2,
+    copied: &mut BTreeMap<Vec<u8>, Vec<u8>>,
+) -> anyhow::Result<TreeNode<S2>>
 where
-    A: BlobStore,
-    B: BlobStore,
-    E: From<A::Error> + From<B::Error>,
+    anyhow::Error: From<S::Error> + From<S2::Error>,
 {
-    pub fn new(a: TreeNodeIter<'a, A>, b: TreeNodeIter<'a, B>) -> Self {
-        Self {
-            a,
-            b,
-            p: PhantomData,
+    let mut res = TreeNode::EMPTY;
+    res.set_prefix_slice(&node.load_prefix(store)?);
+    res.set_value(node.load_value(store)?);
+    let id = node.get_children().err().filter(|id| !id.is_empty());
+    if let Some(new_id) = id.and_then(|id| copied.get(id)) {
+        res.set_children_id(new_id);
+        return Ok(res);
+    }
+    let mut children = Vec::new();
+    if let Some(mut iter) = node.load_children(store)? {
+        while let Some(child) = iter.next() {
+            children.push(compact_node(&child.to_owned(), store, target, copied)?);
         }
     }
-
-    // get the next a, assuming we know already that it is not an Err
-    #[allow(clippy::type_complexity)]
-    fn next_a(&mut self) -> Option<(Option<TreeNodeRef<'_, A>>, Option<TreeNodeRef<'_, B>>)> {
-        let a = self.a.next().unwrap();
-        Some((Some(a), None))
+    res.set_children_arc_opt(Some(Arc::new(children)).filter(|x| !x.is_empty()));
+    if let Some(id) = id {
+        // write the children right away, so only the current path is kept in memory
+        let mut data = Vec::new();
+        res.serialize(&mut data, target)?;
+        res = TreeNode::deserialize(&data)?;
+        let new_id = res.get_children().expect_err("children must be an id");
+        copied.insert(id.to_vec(), new_id.to_vec());
     }
+    Ok(res)
+}
 
-    // get the next b, assuming we know already that it is not an Err
-    #[allow(clippy::type_complexity)]
-    fn next_b(&mut self) -> Option<(Option<TreeNodeRef<'_, A>>, Option<TreeNodeRef<'_, B>>)> {
-        let b = self.b.next().unwrap();
-        Some((None, Some(b)))
+/// collect the store ids of all blobs reachable from a node that are not in `keep`
+fn unreachable_blobs<S: BlobStore>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+    keep: &BTreeSet<Vec<u8>>,
+    res: &mut PendingFree,
+) -> Result<(), S::Error> {
+    let [prefix, value, children] = node.blob_ids();
+    for id in prefix.into_iter().chain(value).chain(children) {
+        if !keep.contains(id) && !res.ids.contains(id) {
+            res.bytes += store.read(id)?.len() as u64;
+            res.ids.insert(id.to_vec());
+        }
     }
-
-    // get the next a and b, assuming we know already that neither is an Err
-    #[allow(clippy::type_complexity)]
-    fn next_ab(&mut self) -> Option<(Option<TreeNodeRef<'_, A>>, Option<TreeNodeRef<'_, B>>)> {
-        let a = self.a.next();
-        let b = self.b.next();
-        Some((a, b))
+    if children.map(|id| keep.contains(id)).unwrap_or_default() {
+        // everything below a reachable children blob is reachable as well
+        return Ok(());
+    }
+    if let Some(mut children) = node.load_children(store)? {
+        while let Some(child) = children.next() {
+            unreachable_blobs(&child, store, keep, res)?;
+        }
     }
+    Ok(())
+}
 
-    fn next0(
-        &mut self,
-    ) -> Result<Option<(Option<TreeNodeRef<'_, A>>, Option<TreeNodeRef<'_, B>>)>, E> {
-        Ok(
-            #[allow(clippy::manual_map)]
-            if let (Some(ak), Some(bk)) = (
-                self.a.first_prefix_byte_opt(),
-                self.b.first_prefix_byte_opt(),
-            ) {
-                match ak.cmp(&bk) {
-                    Ordering::Less => self.next_a(),
-                    Ordering::Greater => self.next_b(),
-                    Ordering::Equal => self.next_ab(),
+/// the value of the longest key that is a prefix of `key`, with the length of that key
+///
+/// Like [find], but remembers the deepest node with a value along the path. The first `offset`
+/// bytes of the key have already been matched.
+fn longest_prefix_match<S: BlobStore>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+    key: &[u8],
+    offset: usize,
+) -> Result<Option<(usize, Value<S>)>, S::Error> {
+    let prefix = node.load_prefix(store)?;
+    if !key[offset..].starts_with(&prefix) {
+        return Ok(None);
+    }
+    let n = offset + prefix.len();
+    if n < key.len() {
+        if let Some(children) = node.load_children(store)? {
+            if let Some(child) = children.find(key[n]) {
+                if let Some(res) = longest_prefix_match(&child, store, key, n)? {
+                    return Ok(Some(res));
                 }
-            } else if let Some(a) = self.a.next() {
-                Some((Some(a), None))
-            } else if let Some(b) = self.b.next() {
-                Some((None, Some(b)))
-            } else {
-                None
-            },
-        )
+            }
+        }
     }
+    Ok(node.value_opt().map(|value| (n, value.to_owned())))
+}
 
-    fn next(
-        &mut self,
-    ) -> Option<Result<(Option<TreeNodeRef<'_, A>>, Option<TreeNodeRef<'_, B>>), E>> {
-        self.next0().transpose()
+/// get the value for a key, recording the nodes visited and blobs loaded in `trace`
+fn get_traced<S: BlobStore>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+    key: &[u8],
+    trace: &mut Trace,
+) -> Result<Option<Value<S>>, S::Error> {
+    trace.nodes_visited += 1;
+    let prefix = node.load_prefix(store)?;
+    if node.blob_ids()[0].is_some() {
+        trace.load(prefix.len());
+    }
+    if !key.starts_with(&prefix) {
+        return Ok(None);
+    }
+    let key = &key[prefix.len()..];
+    if key.is_empty() {
+        return Ok(node.value_opt().map(|x| x.to_owned()));
+    }
+    let children = match node.load_children(store)? {
+        Some(children) => children,
+        None => return Ok(None),
+    };
+    // borrowed children are always read from the store
+    if let TreeNodeIter::Borrowed(children) = &children {
+        trace.load(children.data.len());
+    }
+    match children.find(key[0]) {
+        Some(child) => get_traced(&child, store, key, trace),
+        None => Ok(None),
     }
 }
 
-fn cmp<A: BlobStore, B: BlobStore>(
-    a: &InPlaceVecBuilder<'_, TreeNode<A>>,
-    b: &mut TreeNodeIter<'_, B>,
-) -> Option<Ordering> {
-    let ap = a.source_slice().get(0).map(|x| x.first_prefix_byte());
-    let bp = b.first_prefix_byte_opt();
-    match (ap, bp) {
-        (Some(a), Some(b)) => Some(a.cmp(&b)),
-        (Some(_), None) => Some(Ordering::Less),
-        (None, Some(_)) => Some(Ordering::Greater),
-        (None, None) => None,
+/// statistics for all distinct key prefixes of length `depth`
+///
+/// Keys shorter than `depth` are counted under the complete key.
+fn prefix_stats<S: BlobStore>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+    path: &mut Vec<u8>,
+    depth: usize,
+    res: &mut Vec<(Vec<u8>, PrefixStats)>,
+) -> Result<(), S::Error> {
+    let prefix = node.load_prefix(store)?;
+    let base = path.len();
+    if base + prefix.len() >= depth {
+        let mut key = path.clone();
+        key.extend_from_slice(&prefix[..depth - base]);
+        res.push((key, subtree_stats(node, store, base)?));
+    } else {
+        path.extend_from_slice(&prefix);
+        if let Some(value) = node.value_opt() {
+            let bytes = (path.len() + value_len(&value, store)?) as u64;
+            res.push((path.clone(), PrefixStats { entries: 1, bytes }));
+        }
+        if let Some(mut children) = node.load_children(store)? {
+            while let Some(child) = children.next() {
+                prefix_stats(&child, store, path, depth, res)?;
+            }
+        }
+        path.truncate(base);
     }
+    Ok(())
 }
 
-unsafe fn extend_lifetime<T>(value: &T) -> &'static T {
-    std::mem::transmute(value)
+/// add the nodes of a subtree to the per level statistics, with `node` at `level`
+fn depth_profile<S: BlobStore>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+    level: usize,
+    res: &mut Vec<LevelStats>,
+) -> Result<(), S::Error> {
+    if res.len() == level {
+        res.push(LevelStats::default());
+    }
+    let stats = &mut res[level];
+    stats.nodes += 1;
+    stats.prefix_bytes += node.load_prefix(store)?.len() as u64;
+    if node.value_opt().is_some() {
+        stats.entries += 1;
+    }
+    if let Some(mut children) = node.load_children(store)? {
+        while let Some(child) = children.next() {
+            depth_profile(&child, store, level + 1, res)?;
+        }
+    }
+    Ok(())
 }
 
-struct OwnedTreeNodeIter<'a, S>(Option<Arc<Vec<TreeNode<S>>>>, slice::Iter<'a, TreeNode<S>>);
-
-impl<S: BlobStore> OwnedTreeNodeIter<'static, S> {
-    fn new_owned(owner: Arc<Vec<TreeNode<S>>>) -> Self {
-        let iter = unsafe { extend_lifetime(owner.as_ref()) }.iter();
-        Self(Some(owner.clone()), iter)
+/// for each key, whether it is contained in the tree, in a single descent for all keys
+///
+/// The first `offset` bytes of all keys have already been matched. Keys with a common path are
+/// looked up together, so sorting the keys makes this efficient, but is not required.
+fn contains_many<S: BlobStore, K: AsRef<[u8]>>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+    keys: &[K],
+    offset: usize,
+    res: &mut [bool],
+) -> Result<(), S::Error> {
+    let prefix = node.load_prefix(store)?;
+    let n = offset + prefix.len();
+    let mut children = None;
+    let mut i = 0;
+    while i < keys.len() {
+        let key = keys[i].as_ref();
+        if !key[offset..].starts_with(&prefix) {
+            i += 1;
+            continue;
+        }
+        if key.len() == n {
+            res[i] = node.value_opt().is_some();
+            i += 1;
+            continue;
+        }
+        // all following keys that continue with the same byte are handled by the same child
+        let c = key[n];
+        let same_child = |k: &[u8]| k.len() > n && k[offset..n] == prefix[..] && k[n] == c;
+        let mut j = i + 1;
+        while j < keys.len() && same_child(keys[j].as_ref()) {
+            j += 1;
+        }
+        if children.is_none() {
+            children = Some(node.load_children(store)?);
+        }
+        if let Some(Some(children)) = &children {
+            if let Some(child) = children.find(c) {
+                contains_many(&child, store, &keys[i..j], n, &mut res[i..j])?;
+            }
+        }
+        i = j;
     }
+    Ok(())
 }
 
-fn is_no_store<S: 'static>() -> bool {
-    TypeId::of::<S>() == TypeId::of::<Detached>()
+/// tree of all distinct key prefixes of length `width`, with empty values
+///
+/// Keys shorter than `width` are included completely. Traversal stops at `width`, so entries
+/// below that depth are never visited.
+fn project_keys<S: BlobStore>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+    width: usize,
+) -> Result<TreeNode<Detached>, S::Error> {
+    let prefix = node.load_prefix(store)?;
+    if prefix.len() >= width {
+        return Ok(TreeNode::single(&prefix[..width], &[]));
+    }
+    let mut res = TreeNode::<Detached>::EMPTY;
+    res.set_prefix_slice(&prefix);
+    if node.value_opt().is_some() {
+        res.set_value_slice(Some(&[]));
+    }
+    let mut children = Vec::new();
+    if let Some(mut iter) = node.load_children(store)? {
+        while let Some(child) = iter.next() {
+            children.push(project_keys(&child, store, width - prefix.len())?);
+        }
+    }
+    res.set_children_arc_opt(Some(Arc::new(children)));
+    res.canonicalize();
+    Ok(res)
 }
 
-impl<'a, S: BlobStore> OwnedTreeNodeIter<'a, S> {
-    fn new(slice: &'a [TreeNode<S>]) -> Self {
-        Self(None, slice.iter())
+/// number of entries in a node and all its children, without loading values
+fn count_entries<S: BlobStore>(node: &TreeNodeRef<S>, store: &S) -> Result<u64, S::Error> {
+    let mut res = if node.value_opt().is_some() { 1 } else { 0 };
+    if let Some(mut children) = node.load_children(store)? {
+        while let Some(child) = children.next() {
+            res += count_entries(&child, store)?;
+        }
     }
+    Ok(res)
+}
 
-    fn into_owned(mut self) -> Option<Arc<Vec<TreeNode<S>>>> {
-        if let Some(arc) = self.0.as_ref() {
-            Some(arc.clone())
-        } else if self.is_empty() {
-            None
-        } else {
-            let mut res = Vec::with_capacity(self.1.len());
-            while let Some(x) = self.next() {
-                res.push(x.to_owned());
-            }
-            Some(Arc::new(res))
+/// entry with the given index in key order
+fn nth_entry<S: BlobStore>(
+    mut prefix: Vec<u8>,
+    node: &TreeNodeRef<S>,
+    store: &S,
+    mut index: u64,
+) -> Result<Option<(Vec<u8>, Value<S>)>, S::Error> {
+    prefix.extend_from_slice(&node.load_prefix(store)?);
+    if let Some(value) = node.value_opt() {
+        if index == 0 {
+            return Ok(Some((prefix, value.to_owned())));
         }
+        index -= 1;
     }
-
-    fn detached(mut self, store: &S) -> Result<Option<Arc<Vec<TreeNode<Detached>>>>, S::Error> {
-        Ok(if self.is_empty() {
-            None
-        } else if self.0.is_some() && is_no_store::<S>() {
-            unsafe { std::mem::transmute(self.0.clone()) }
-        } else {
-            let mut res = Vec::with_capacity(self.1.len());
-            while let Some(x) = self.next() {
-                res.push(x.detached(store)?);
+    if let Some(mut children) = node.load_children(store)? {
+        while let Some(child) = children.next() {
+            let count = count_entries(&child, store)?;
+            if index < count {
+                return nth_entry(prefix, &child, store, index);
             }
-            Some(Arc::new(res))
-        })
-    }
-
-    fn is_empty(&self) -> bool {
-        self.1.as_slice().is_empty()
-    }
-
-    fn find(&self, prefix: u8) -> Option<&TreeNode<S>> {
-        let slice = self.1.as_slice();
-        if slice.len() == 256 {
-            Some(&slice[prefix as usize])
-        } else {
-            slice
-                .binary_search_by_key(&Some(prefix), |x| x.first_prefix_byte())
-                .ok()
-                .map(|i| &slice[i])
+            index -= count;
         }
     }
+    Ok(None)
+}
 
-    fn first_prefix_byte_opt(&mut self) -> Option<Option<u8>> {
-        self.1.as_slice().get(0).map(|x| x.first_prefix_byte())
+/// number of entries with a key smaller than `key`
+///
+/// Only the children along the path of the key are descended into, the others are counted as a whole.
+fn rank<S: BlobStore>(node: &TreeNodeRef<S>, store: &S, key: &[u8]) -> Result<u64, S::Error> {
+    let prefix = node.load_prefix(store)?;
+    let n = common_prefix(&prefix, key);
+    if n < prefix.len() {
+        // key diverges from the prefix, so the node is either entirely before or after it
+        return Ok(if n < key.len() && key[n] > prefix[n] {
+            count_entries(node, store)?
+        } else {
+            0
+        });
     }
-
-    fn next(&mut self) -> Option<&TreeNode<S>> {
-        self.1.next()
+    let key = &key[n..];
+    if key.is_empty() {
+        return Ok(0);
     }
-
-    fn last(&mut self) -> Option<&TreeNode<S>> {
-        self.1.as_slice().last()
+    let mut res = if node.value_opt().is_some() { 1 } else { 0 };
+    if let Some(mut children) = node.load_children(store)? {
+        while let Some(child) = children.next() {
+            match child.first_prefix_byte().cmp(&Some(key[0])) {
+                Ordering::Less => res += count_entries(&child, store)?,
+                Ordering::Equal => {
+                    res += rank(&child, store, key)?;
+                    break;
+                }
+                Ordering::Greater => break,
+            }
+        }
     }
+    Ok(res)
 }
 
-struct BorrowedTreeNodeIter<S> {
-    data: OwnedBlob,
-    offset: usize,
-    record_size: u8,
-    p: PhantomData<S>,
+/// a random entry, picked by descending from the node and choosing uniformly between the value and
+/// the children at each level, together with the probability of picking it
+///
+/// Only the nodes along the path are loaded, so the cost is proportional to the depth of the tree.
+/// Returns None if the node has no entries.
+fn random_entry<S: BlobStore>(
+    mut prefix: Vec<u8>,
+    node: &TreeNodeRef<S>,
+    store: &S,
+    rng: &mut SplitMix64,
+) -> Result<Option<(Vec<u8>, f64)>, S::Error> {
+    prefix.extend_from_slice(&node.load_prefix(store)?);
+    let value = u64::from(node.value_opt().is_some());
+    let children = node.load_children(store)?;
+    let count = value
+        + children
+            .as_ref()
+            .map(|x| x.remaining() as u64)
+            .unwrap_or_default();
+    if count == 0 {
+        return Ok(None);
+    }
+    let choice = rng.next_u64() % count;
+    if choice < value {
+        return Ok(Some((prefix, 1.0 / count as f64)));
+    }
+    let child = children
+        .as_ref()
+        .and_then(|x| x.get((choice - value) as usize));
+    Ok(match child {
+        Some(child) => {
+            random_entry(prefix, &child, store, rng)?.map(|(k, p)| (k, p / count as f64))
+        }
+        None => None,
+    })
 }
 
-impl<S: BlobStore> BorrowedTreeNodeIter<S> {
-    fn load(id: &[u8], store: &S) -> Result<Option<Self>, S::Error> {
-        Ok(if id.is_empty() {
-            None
-        } else {
-            let (record_size, data) = (id[0], store.read(&id[1..])?);
-            Some(Self {
-                data,
-                offset: 0,
-                record_size,
-                p: PhantomData,
-            })
-        })
-    }
-
-    #[allow(clippy::wrong_self_convention)]
-    fn to_owned(self) -> Option<Arc<Vec<TreeNode<S>>>> {
-        if self.is_empty() {
-            None
-        } else {
-            todo!()
-        }
-    }
-
-    fn detached(self, _store: &S) -> Result<Option<Arc<Vec<TreeNode<Detached>>>>, S::Error> {
-        Ok(if self.is_empty() { None } else { todo!() })
-    }
-
-    fn is_empty(&self) -> bool {
-        self.offset == self.data.len()
-    }
-
-    fn find(&self, prefix: u8) -> Option<BorrowedTreeNode<S>> {
-        let _elems = if self.record_size != 0 {
-            (self.data.len() - self.offset) / (self.record_size as usize)
-        } else {
-            0
-        };
-        let mut offset = self.offset;
-        while let Some(node) = BorrowedTreeNode::<S>::read(&self.data[offset..]) {
-            match node.first_prefix_byte().cmp(&Some(prefix)) {
-                Ordering::Less => offset += node.bytes_len(),
-                Ordering::Equal => return Some(node),
-                Ordering::Greater => return None,
+/// build a canonical node from a prefix, an optional value and children
+///
+/// The children must be non-empty and sorted by their first prefix byte.
+fn assemble<S: BlobStore>(
+    prefix: &[u8],
+    value: Option<Value<S>>,
+    mut children: Vec<TreeNode<S>>,
+    store: &S,
+) -> Result<TreeNode<S>, S::Error> {
+    Ok(if value.is_none() && children.len() <= 1 {
+        match children.pop() {
+            Some(mut child) => {
+                let mut merged = prefix.to_vec();
+                merged.extend_from_slice(&child.load_prefix(store)?);
+                child.set_prefix_slice(&merged);
+                child
             }
+            None => TreeNode::EMPTY,
         }
-        None
-    }
-
-    fn first_prefix_byte_opt(&mut self) -> Option<Option<u8>> {
-        BorrowedTreeNode::<S>::read(&self.data[self.offset..]).map(|x| x.first_prefix_byte())
-    }
-
-    fn next(&mut self) -> Option<BorrowedTreeNode<S>> {
-        if let Some(node) = BorrowedTreeNode::read(&self.data[self.offset..]) {
-            self.offset += node.bytes_len();
-            Some(node)
-        } else {
-            None
+    } else {
+        let mut res = TreeNode::EMPTY;
+        res.set_prefix_slice(prefix);
+        if let Some(value) = value {
+            res.set_value_owned(value.as_value_ref().0);
         }
-    }
+        if !children.is_empty() {
+            res.set_children_arc(Arc::new(children));
+        }
+        res
+    })
+}
 
-    fn last(&mut self) -> Option<BorrowedTreeNode<S>> {
-        let mut offset = self.offset;
-        let mut last = None;
-        // todo: special case for when we have a record size!
-        while offset < self.data.len() {
-            if let Some(x) = BorrowedTreeNode::<S>::read(&self.data[offset..]) {
-                last = Some(x);
-                offset += x.bytes_len();
-            } else {
-                break;
+/// split a node into the entries with keys smaller than `key` and the rest
+///
+/// Subtrees that are not affected by the split are shared with the original node.
+fn split_at_key<S: BlobStore>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+    key: &[u8],
+) -> Result<(TreeNode<S>, TreeNode<S>), S::Error> {
+    let prefix = node.load_prefix(store)?;
+    let n = common_prefix(&prefix, key);
+    if n == prefix.len() && n < key.len() {
+        // the split point is below this node
+        let c = key[n];
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        if let Some(mut children) = node.load_children(store)? {
+            while let Some(child) = children.next() {
+                match child.first_prefix_byte().cmp(&Some(c)) {
+                    Ordering::Less => left.push(child.to_owned()),
+                    Ordering::Greater => right.push(child.to_owned()),
+                    Ordering::Equal => {
+                        let (l, r) = split_at_key(&child, store, &key[n..])?;
+                        if !l.is_empty() {
+                            left.push(l);
+                        }
+                        if !r.is_empty() {
+                            right.push(r);
+                        }
+                    }
+                }
             }
         }
-        last
+        let value = node.value_opt().map(|x| x.to_owned());
+        Ok((
+            assemble(&prefix, value, left, store)?,
+            assemble(&prefix, None, right, store)?,
+        ))
+    } else if n < prefix.len() && n < key.len() && prefix[n] < key[n] {
+        // the entire node is before the split point
+        Ok((node.to_owned(), TreeNode::EMPTY))
+    } else {
+        // the entire node is at or after the split point
+        Ok((TreeNode::EMPTY, node.to_owned()))
     }
 }
 
-enum TreeNodeIter<'a, S> {
-    Owned(OwnedTreeNodeIter<'a, S>),
-    Borrowed(BorrowedTreeNodeIter<S>),
+// common prefix of two slices.
+fn common_prefix<'a, T: Eq>(a: &'a [T], b: &'a [T]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
 }
 
-impl<S: BlobStore> TreeNodeIter<'static, S> {
-    fn from_arc(arc: Arc<Vec<TreeNode<S>>>) -> Self {
-        Self::Owned(OwnedTreeNodeIter::new_owned(arc))
-    }
+#[cfg_attr(feature = "custom-store", visibility::make(pub))]
+/// Converter that converts nodes from one kind of store to another
+trait NodeConverter<A, B: BlobStore> {
+    fn convert_node(&self, node: &TreeNodeRef<A>, store: &A) -> Result<TreeNode<B>, A::Error>
+    where
+        A: BlobStore;
+    fn convert_node_shortened(
+        &self,
+        node: &TreeNodeRef<A>,
+        store: &A,
+        n: usize,
+    ) -> Result<TreeNode<B>, A::Error>
+    where
+        A: BlobStore;
+    fn convert_value(&self, bv: &ValueRef<A>, store: &A) -> Result<Value<B>, A::Error>
+    where
+        A: BlobStore;
 }
 
-impl<'a, S: BlobStore> TreeNodeIter<'a, S> {
-    fn load(id: &[u8], store: &S) -> Result<Option<Self>, S::Error> {
-        Ok(BorrowedTreeNodeIter::load(id, store)?.map(Self::Borrowed))
-    }
+/// Converter that converts from a node with NoStore to any other store.
+///
+/// This is always possible and a noop.
+#[cfg_attr(feature = "custom-store", visibility::make(pub))]
+#[derive(Clone, Copy)]
+struct DowncastConverter;
 
-    fn from_slice(slice: &'a [TreeNode<S>]) -> Option<Self> {
-        if !slice.is_empty() {
-            Some(Self::Owned(OwnedTreeNodeIter::new(slice)))
-        } else {
-            None
-        }
+impl<B: BlobStore> NodeConverter<Detached, B> for DowncastConverter {
+    fn convert_node(
+        &self,
+        node: &TreeNodeRef<Detached>,
+        _: &Detached,
+    ) -> Result<TreeNode<B>, NoError> {
+        Ok(node.downcast())
     }
 
-    fn into_owned(self) -> Option<Arc<Vec<TreeNode<S>>>> {
-        match self {
-            Self::Owned(x) => x.into_owned(),
-            Self::Borrowed(x) => x.to_owned(),
-        }
+    fn convert_node_shortened(
+        &self,
+        node: &TreeNodeRef<Detached>,
+        store: &Detached,
+        n: usize,
+    ) -> Result<TreeNode<B>, NoError> {
+        node.clone_shortened(store, n).map(|x| x.downcast())
     }
 
-    fn detached(self, store: &S) -> Result<Option<Arc<Vec<TreeNode<Detached>>>>, S::Error> {
-        match self {
-            Self::Owned(x) => x.detached(store),
-            Self::Borrowed(x) => x.detached(store),
-        }
+    fn convert_value(&self, bv: &ValueRef, _: &Detached) -> Result<Value<B>, NoError> {
+        Ok(bv.downcast::<B>().to_owned())
     }
+}
 
-    fn find(&self, prefix: u8) -> Option<TreeNodeRef<S>> {
-        match self {
-            Self::Owned(x) => x.find(prefix).map(|x| TreeNodeRef::owned(x)),
-            Self::Borrowed(x) => x.find(prefix).map(|x| TreeNodeRef::borrowed(x)),
-        }
+/// Identity node converter to simplify plumbing
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "custom-store", visibility::make(pub))]
+struct IdentityConverter;
+
+impl<A: BlobStore> NodeConverter<A, A> for IdentityConverter {
+    fn convert_node(&self, node: &TreeNodeRef<A>, _store: &A) -> Result<TreeNode<A>, A::Error> {
+        Ok(node.to_owned())
     }
 
-    fn first_prefix_byte_opt(&mut self) -> Option<Option<u8>> {
-        match self {
-            Self::Owned(x) => x.first_prefix_byte_opt(),
-            Self::Borrowed(x) => x.first_prefix_byte_opt(),
-        }
+    fn convert_node_shortened(
+        &self,
+        node: &TreeNodeRef<A>,
+        store: &A,
+        n: usize,
+    ) -> Result<TreeNode<A>, A::Error> {
+        node.clone_shortened(store, n)
     }
 
-    fn next(&mut self) -> Option<TreeNodeRef<'_, S>> {
-        match self {
-            Self::Owned(x) => x.next().map(|x| TreeNodeRef::owned(x)),
-            Self::Borrowed(x) => x.next().map(|x| TreeNodeRef::borrowed(x)),
-        }
+    fn convert_value(&self, bv: &ValueRef<A>, _store: &A) -> Result<Value<A>, A::Error> {
+        Ok(bv.to_owned())
     }
+}
 
-    fn last(&mut self) -> Option<TreeNodeRef<'_, S>> {
-        match self {
-            Self::Owned(x) => x.last().map(|x| TreeNodeRef::owned(x)),
-            Self::Borrowed(x) => x.last().map(|x| TreeNodeRef::borrowed(x)),
-        }
+/// A converter that converts from one store to another store by just completely detaching it
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "custom-store", visibility::make(pub))]
+struct DetachConverter;
+
+impl<A: BlobStore, B: BlobStore> NodeConverter<A, B> for DetachConverter {
+    fn convert_node(&self, node: &TreeNodeRef<A>, store: &A) -> Result<TreeNode<B>, A::Error> {
+        Ok(node.detached(store)?.downcast())
+    }
+    fn convert_value(&self, value: &ValueRef<A>, store: &A) -> Result<Value<B>, A::Error> {
+        Ok(value.detached(store)?.downcast())
+    }
+    fn convert_node_shortened(
+        &self,
+        node: &TreeNodeRef<A>,
+        store: &A,
+        n: usize,
+    ) -> Result<TreeNode<B>, A::Error> {
+        let node = node.clone_shortened(store, n)?;
+        Ok(self.convert_node(&node.as_ref(), store)?.downcast())
     }
 }
 
-fn scan_prefix<S: BlobStore + Clone>(
-    store: S,
-    tree: &TreeNodeRef<S>,
-    prefix: &[u8],
-) -> Result<KeyValueIter<S>, S::Error> {
-    let store1 = store.clone();
-    find(&store, tree, prefix, |r| {
-        Ok(match r {
-            FindResult::Found(tree) => {
-                let prefix = IterKey::new(prefix);
-                let tree: TreeNode<S> = tree.to_owned();
-                KeyValueIter::new(TreeNodeIter::from_arc(Arc::new(vec![tree])), store1, prefix)
-            }
-            FindResult::Prefix { tree, matching } => {
-                let prefix = IterKey::new(&prefix[..prefix.len() - matching]);
-                let tree: TreeNode<S> = tree.to_owned();
-                KeyValueIter::new(TreeNodeIter::from_arc(Arc::new(vec![tree])), store1, prefix)
-            }
-            FindResult::NotFound => KeyValueIter::empty(store1),
-        })
-    })
+struct OuterJoin<'a, A: BlobStore, B: BlobStore, E> {
+    a: TreeNodeIter<'a, A>,
+    b: TreeNodeIter<'a, B>,
+    p: PhantomData<E>,
 }
 
-/// Outer combine two trees with a function f
-fn outer_combine<A, B, E, F>(
-    a: &TreeNodeRef<A>,
-    ab: A,
-    b: &TreeNodeRef<B>,
-    bb: B,
-    f: F,
-) -> Result<TreeNode<Detached>, E>
+impl<'a, A, B, E> OuterJoin<'a, A, B, E>
 where
-    A: BlobStore + Clone,
-    B: BlobStore + Clone,
+    A: BlobStore,
+    B: BlobStore,
     E: From<A::Error> + From<B::Error>,
-    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<Option<Value>, E> + Copy,
 {
-    let ap = a.load_prefix(&ab)?;
-    let bp = b.load_prefix(&bb)?;
-    let n = common_prefix(ap.as_ref(), bp.as_ref());
-    let value;
-    let children;
-    if n == ap.len() && n == bp.len() {
-        // prefixes are identical
-        value = if let Some(bv) = b.value_opt() {
-            if let Some(av) = a.value_opt() {
-                f(&av, &bv)?
-            } else {
-                Some(bv.detached(&bb)?)
-            }
+    pub fn new(a: TreeNodeIter<'a, A>, b: TreeNodeIter<'a, B>) -> Self {
+        Self {
+            a,
+            b,
+            p: PhantomData,
+        }
+    }
+
+    // get the next a, assuming we know already that it is not an Err
+    #[allow(clippy::type_complexity)]
+    fn next_a(&mut self) -> Option<(Option<TreeNodeRef<'_, A>>, Option<TreeNodeRef<'_, B>>)> {
+        let a = self.a.next().unwrap();
+        Some((Some(a), None))
+    }
+
+    // get the next b, assuming we know already that it is not an Err
+    #[allow(clippy::type_complexity)]
+    fn next_b(&mut self) -> Option<(Option<TreeNodeRef<'_, A>>, Option<TreeNodeRef<'_, B>>)> {
+        let b = self.b.next().unwrap();
+        Some((None, Some(b)))
+    }
+
+    // get the next a and b, assuming we know already that neither is an Err
+    #[allow(clippy::type_complexity)]
+    fn next_ab(&mut self) -> Option<(Option<TreeNodeRef<'_, A>>, Option<TreeNodeRef<'_, B>>)> {
+        let a = self.a.next();
+        let b = self.b.next();
+        Some((a, b))
+    }
+
+    fn next0(
+        &mut self,
+    ) -> Result<Option<(Option<TreeNodeRef<'_, A>>, Option<TreeNodeRef<'_, B>>)>, E> {
+        Ok(
+            #[allow(clippy::manual_map)]
+            if let (Some(ak), Some(bk)) = (
+                self.a.first_prefix_byte_opt(),
+                self.b.first_prefix_byte_opt(),
+            ) {
+                match ak.cmp(&bk) {
+                    Ordering::Less => self.next_a(),
+                    Ordering::Greater => self.next_b(),
+                    Ordering::Equal => self.next_ab(),
+                }
+            } else if let Some(a) = self.a.next() {
+                Some((Some(a), None))
+            } else if let Some(b) = self.b.next() {
+                Some((None, Some(b)))
+            } else {
+                None
+            },
+        )
+    }
+
+    fn next(
+        &mut self,
+    ) -> Option<Result<(Option<TreeNodeRef<'_, A>>, Option<TreeNodeRef<'_, B>>), E>> {
+        self.next0().transpose()
+    }
+}
+
+fn cmp<A: BlobStore, B: BlobStore>(
+    a: &InPlaceVecBuilder<'_, TreeNode<A>>,
+    b: &mut TreeNodeIter<'_, B>,
+) -> Option<Ordering> {
+    let ap = a.source_slice().first().map(|x| x.first_prefix_byte());
+    let bp = b.first_prefix_byte_opt();
+    match (ap, bp) {
+        (Some(a), Some(b)) => Some(a.cmp(&b)),
+        (Some(_), None) => Some(Ordering::Less),
+        (None, Some(_)) => Some(Ordering::Greater),
+        (None, None) => None,
+    }
+}
+
+unsafe fn extend_lifetime<T>(value: &T) -> &'static T {
+    std::mem::transmute(value)
+}
+
+struct OwnedTreeNodeIter<'a, S>(Option<Arc<Vec<TreeNode<S>>>>, slice::Iter<'a, TreeNode<S>>);
+
+impl<S: BlobStore> OwnedTreeNodeIter<'static, S> {
+    fn new_owned(owner: Arc<Vec<TreeNode<S>>>) -> Self {
+        let iter = unsafe { extend_lifetime(owner.as_ref()) }.iter();
+        Self(Some(owner.clone()), iter)
+    }
+}
+
+fn is_no_store<S: 'static>() -> bool {
+    TypeId::of::<S>() == TypeId::of::<Detached>()
+}
+
+impl<'a, S: BlobStore> OwnedTreeNodeIter<'a, S> {
+    fn new(slice: &'a [TreeNode<S>]) -> Self {
+        Self(None, slice.iter())
+    }
+
+    fn into_owned(mut self) -> Option<Arc<Vec<TreeNode<S>>>> {
+        if let Some(arc) = self.0.as_ref() {
+            Some(arc.clone())
+        } else if self.is_empty() {
+            None
+        } else {
+            let mut res = Vec::with_capacity(self.1.len());
+            while let Some(x) = self.next() {
+                res.push(x.to_owned());
+            }
+            Some(Arc::new(res))
+        }
+    }
+
+    fn detached(mut self, store: &S) -> Result<Option<Arc<Vec<TreeNode<Detached>>>>, S::Error> {
+        Ok(if self.is_empty() {
+            None
+        } else if self.0.is_some() && is_no_store::<S>() {
+            unsafe {
+                std::mem::transmute::<
+                    Option<Arc<Vec<TreeNode<S>>>>,
+                    Option<Arc<Vec<TreeNode<Detached>>>>,
+                >(self.0.clone())
+            }
+        } else {
+            let mut res = Vec::with_capacity(self.1.len());
+            while let Some(x) = self.next() {
+                res.push(x.detached(store)?);
+            }
+            Some(Arc::new(res))
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.1.as_slice().is_empty()
+    }
+
+    fn find(&self, prefix: u8) -> Option<&TreeNode<S>> {
+        let slice = self.1.as_slice();
+        if slice.len() == 256 {
+            Some(&slice[prefix as usize])
+        } else {
+            slice
+                .binary_search_by_key(&Some(prefix), |x| x.first_prefix_byte())
+                .ok()
+                .map(|i| &slice[i])
+        }
+    }
+
+    fn first_prefix_byte_opt(&mut self) -> Option<Option<u8>> {
+        self.1.as_slice().first().map(|x| x.first_prefix_byte())
+    }
+
+    fn next(&mut self) -> Option<&TreeNode<S>> {
+        self.1.next()
+    }
+
+    fn last(&mut self) -> Option<&TreeNode<S>> {
+        self.1.as_slice().last()
+    }
+
+    fn remaining(&self) -> usize {
+        self.1.len()
+    }
+
+    fn get(&self, i: usize) -> Option<&TreeNode<S>> {
+        self.1.as_slice().get(i)
+    }
+}
+
+struct BorrowedTreeNodeIter<S> {
+    data: OwnedBlob,
+    offset: usize,
+    record_size: u8,
+    p: PhantomData<S>,
+}
+
+impl<S: BlobStore> BorrowedTreeNodeIter<S> {
+    fn load(id: &[u8], store: &S) -> Result<Option<Self>, S::Error> {
+        Ok(if id.is_empty() {
+            None
+        } else {
+            let (record_size, data) = (id[0], store.read(&id[1..])?);
+            let res = Self {
+                data,
+                offset: 0,
+                record_size,
+                p: PhantomData,
+            };
+            // a hardened build checks every children blob, so corrupt data is an error instead of a
+            // truncated or unsorted list of children
+            #[cfg(feature = "no-panic-paths")]
+            if let Err(message) = res.validate() {
+                return Err(store.invalid_data(&format!(
+                    "children {}: {}",
+                    Hex::new(&id[1..]),
+                    message
+                )));
+            }
+            Some(res)
+        })
+    }
+
+    #[allow(clippy::wrong_self_convention)]
+    fn to_owned(mut self) -> Option<Arc<Vec<TreeNode<S>>>> {
+        if self.is_empty() {
+            None
+        } else {
+            let mut res = Vec::new();
+            while let Some(x) = self.next() {
+                res.push(x.to_owned());
+            }
+            Some(Arc::new(res))
+        }
+    }
+
+    fn detached(mut self, store: &S) -> Result<Option<Arc<Vec<TreeNode<Detached>>>>, S::Error> {
+        Ok(if self.is_empty() {
+            None
+        } else {
+            let mut res = Vec::new();
+            while let Some(x) = self.next() {
+                res.push(x.detached(store)?);
+            }
+            Some(Arc::new(res))
+        })
+    }
+
+    /// check that the data is a non empty sequence of nodes, sorted by their distinct first prefix bytes,
+    /// and that the record size matches the nodes
+    fn validate(&self) -> Result<(), String> {
+        let mut rest = &self.data[self.offset..];
+        let mut prev = None;
+        if rest.is_empty() {
+            return Err("empty children".into());
+        }
+        while !rest.is_empty() {
+            let Some((node, next)) = BorrowedTreeNode::<S>::read_one(rest) else {
+                return Err(format!(
+                    "undecodable node at {}",
+                    self.data.len() - rest.len()
+                ));
+            };
+            let Some(first) = node.first_prefix_byte() else {
+                return Err("child with empty prefix".into());
+            };
+            if prev.is_some_and(|prev| prev >= first) {
+                return Err("children not sorted".into());
+            }
+            if self.record_size != 0 && node.bytes_len() != self.record_size as usize {
+                return Err("record size mismatch".into());
+            }
+            prev = Some(first);
+            rest = next;
+        }
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.offset == self.data.len()
+    }
+
+    /// number of remaining records, if all records are known to have the same size
+    // is_multiple_of is too recent for the rust versions we support
+    #[allow(clippy::manual_is_multiple_of)]
+    fn fixed_records(&self) -> Option<usize> {
+        let record_size = self.record_size as usize;
+        let rest = self.data.len() - self.offset;
+        if record_size != 0 && rest % record_size == 0 {
+            Some(rest / record_size)
+        } else {
+            None
+        }
+    }
+
+    /// the i-th remaining record, assuming that all records have the same size
+    fn record(&self, i: usize) -> Option<BorrowedTreeNode<'_, S>> {
+        BorrowedTreeNode::read(&self.data[self.offset + i * (self.record_size as usize)..])
+    }
+
+    fn find(&self, prefix: u8) -> Option<BorrowedTreeNode<'_, S>> {
+        if let Some(n) = self.fixed_records() {
+            // records have the same size, so we can do a binary search
+            let (mut lo, mut hi) = (0, n);
+            while lo < hi {
+                let mid = (lo + hi) / 2;
+                let node = self.record(mid)?;
+                match node.first_prefix_byte().cmp(&Some(prefix)) {
+                    Ordering::Less => lo = mid + 1,
+                    Ordering::Equal => return Some(node),
+                    Ordering::Greater => hi = mid,
+                }
+            }
+            return None;
+        }
+        let mut offset = self.offset;
+        while let Some(node) = BorrowedTreeNode::<S>::read(&self.data[offset..]) {
+            match node.first_prefix_byte().cmp(&Some(prefix)) {
+                Ordering::Less => offset += node.bytes_len(),
+                Ordering::Equal => return Some(node),
+                Ordering::Greater => return None,
+            }
+        }
+        None
+    }
+
+    fn first_prefix_byte_opt(&mut self) -> Option<Option<u8>> {
+        BorrowedTreeNode::<S>::read(&self.data[self.offset..]).map(|x| x.first_prefix_byte())
+    }
+
+    fn next(&mut self) -> Option<BorrowedTreeNode<'_, S>> {
+        if let Some(node) = BorrowedTreeNode::read(&self.data[self.offset..]) {
+            self.offset += node.bytes_len();
+            Some(node)
+        } else {
+            None
+        }
+    }
+
+    fn last(&mut self) -> Option<BorrowedTreeNode<'_, S>> {
+        if let Some(n) = self.fixed_records() {
+            // records have the same size, so the last record is at a known offset
+            return n.checked_sub(1).and_then(|i| self.record(i));
+        }
+        let mut offset = self.offset;
+        let mut last = None;
+        while offset < self.data.len() {
+            if let Some(x) = BorrowedTreeNode::<S>::read(&self.data[offset..]) {
+                last = Some(x);
+                offset += x.bytes_len();
+            } else {
+                break;
+            }
+        }
+        last
+    }
+
+    /// number of remaining records
+    fn remaining(&self) -> usize {
+        if let Some(n) = self.fixed_records() {
+            return n;
+        }
+        let mut offset = self.offset;
+        let mut n = 0;
+        while let Some(x) = BorrowedTreeNode::<S>::read(&self.data[offset..]) {
+            offset += x.bytes_len();
+            n += 1;
+        }
+        n
+    }
+
+    /// the i-th remaining record, without advancing
+    fn get(&self, i: usize) -> Option<BorrowedTreeNode<'_, S>> {
+        if let Some(n) = self.fixed_records() {
+            return if i < n { self.record(i) } else { None };
+        }
+        let mut offset = self.offset;
+        for _ in 0..i {
+            offset += BorrowedTreeNode::<S>::read(&self.data[offset..])?.bytes_len();
+        }
+        BorrowedTreeNode::read(&self.data[offset..])
+    }
+}
+
+enum TreeNodeIter<'a, S> {
+    Owned(OwnedTreeNodeIter<'a, S>),
+    Borrowed(BorrowedTreeNodeIter<S>),
+}
+
+impl<S: BlobStore> TreeNodeIter<'static, S> {
+    fn from_arc(arc: Arc<Vec<TreeNode<S>>>) -> Self {
+        Self::Owned(OwnedTreeNodeIter::new_owned(arc))
+    }
+}
+
+impl<'a, S: BlobStore> TreeNodeIter<'a, S> {
+    fn load(id: &[u8], store: &S) -> Result<Option<Self>, S::Error> {
+        Ok(BorrowedTreeNodeIter::load(id, store)?.map(Self::Borrowed))
+    }
+
+    fn from_slice(slice: &'a [TreeNode<S>]) -> Option<Self> {
+        if !slice.is_empty() {
+            Some(Self::Owned(OwnedTreeNodeIter::new(slice)))
+        } else {
+            None
+        }
+    }
+
+    fn into_owned(self) -> Option<Arc<Vec<TreeNode<S>>>> {
+        match self {
+            Self::Owned(x) => x.into_owned(),
+            Self::Borrowed(x) => x.to_owned(),
+        }
+    }
+
+    fn detached(self, store: &S) -> Result<Option<Arc<Vec<TreeNode<Detached>>>>, S::Error> {
+        match self {
+            Self::Owned(x) => x.detached(store),
+            Self::Borrowed(x) => x.detached(store),
+        }
+    }
+
+    fn find(&self, prefix: u8) -> Option<TreeNodeRef<'_, S>> {
+        match self {
+            Self::Owned(x) => x.find(prefix).map(|x| TreeNodeRef::owned(x)),
+            Self::Borrowed(x) => x.find(prefix).map(|x| TreeNodeRef::borrowed(x)),
+        }
+    }
+
+    fn first_prefix_byte_opt(&mut self) -> Option<Option<u8>> {
+        match self {
+            Self::Owned(x) => x.first_prefix_byte_opt(),
+            Self::Borrowed(x) => x.first_prefix_byte_opt(),
+        }
+    }
+
+    fn next(&mut self) -> Option<TreeNodeRef<'_, S>> {
+        match self {
+            Self::Owned(x) => x.next().map(|x| TreeNodeRef::owned(x)),
+            Self::Borrowed(x) => x.next().map(|x| TreeNodeRef::borrowed(x)),
+        }
+    }
+
+    fn last(&mut self) -> Option<TreeNodeRef<'_, S>> {
+        match self {
+            Self::Owned(x) => x.last().map(|x| TreeNodeRef::owned(x)),
+            Self::Borrowed(x) => x.last().map(|x| TreeNodeRef::borrowed(x)),
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        match self {
+            Self::Owned(x) => x.remaining(),
+            Self::Borrowed(x) => x.remaining(),
+        }
+    }
+
+    fn get(&self, i: usize) -> Option<TreeNodeRef<'_, S>> {
+        match self {
+            Self::Owned(x) => x.get(i).map(|x| TreeNodeRef::owned(x)),
+            Self::Borrowed(x) => x.get(i).map(|x| TreeNodeRef::borrowed(x)),
+        }
+    }
+}
+
+fn scan_prefix<S: BlobStore + Clone>(
+    store: S,
+    tree: &TreeNodeRef<S>,
+    prefix: &[u8],
+) -> Result<KeyValueIter<S>, S::Error> {
+    let store1 = store.clone();
+    find(&store, tree, prefix, |r| {
+        Ok(match r {
+            FindResult::Found(tree) => {
+                // the found node prefix is the tail of the prefix, and will be added by the iterator
+                let matching = tree.load_prefix(&store)?.len();
+                let prefix = IterKey::new(&prefix[..prefix.len() - matching]);
+                let tree: TreeNode<S> = tree.to_owned();
+                KeyValueIter::new(TreeNodeIter::from_arc(Arc::new(vec![tree])), store1, prefix)
+            }
+            FindResult::Prefix { tree, matching } => {
+                let prefix = IterKey::new(&prefix[..prefix.len() - matching]);
+                let tree: TreeNode<S> = tree.to_owned();
+                KeyValueIter::new(TreeNodeIter::from_arc(Arc::new(vec![tree])), store1, prefix)
+            }
+            FindResult::NotFound => KeyValueIter::empty(store1),
+        })
+    })
+}
+
+fn scan_prefix_relative<S: BlobStore + Clone>(
+    store: S,
+    tree: &TreeNodeRef<S>,
+    prefix: &[u8],
+) -> Result<KeyValueIter<S>, S::Error> {
+    let store1 = store.clone();
+    find(&store, tree, prefix, |r| {
+        Ok(match r {
+            FindResult::Found(tree) => {
+                // the entire prefix of the found node is part of the searched prefix
+                let matching = tree.load_prefix(&store)?.len();
+                let tree = tree.clone_shortened(&store, matching)?;
+                KeyValueIter::new(
+                    TreeNodeIter::from_arc(Arc::new(vec![tree])),
+                    store1,
+                    IterKey::default(),
+                )
+            }
+            FindResult::Prefix { tree, matching } => {
+                let tree = tree.clone_shortened(&store, matching)?;
+                KeyValueIter::new(
+                    TreeNodeIter::from_arc(Arc::new(vec![tree])),
+                    store1,
+                    IterKey::default(),
+                )
+            }
+            FindResult::NotFound => KeyValueIter::empty(store1),
+        })
+    })
+}
+
+fn values_prefix<S: BlobStore + Clone>(
+    store: S,
+    tree: &TreeNodeRef<S>,
+    prefix: &[u8],
+) -> Result<ValueIter<S>, S::Error> {
+    let store1 = store.clone();
+    find(&store, tree, prefix, |r| {
+        Ok(match r {
+            FindResult::Found(tree) | FindResult::Prefix { tree, .. } => {
+                let tree: TreeNode<S> = tree.to_owned();
+                ValueIter::new(TreeNodeIter::from_arc(Arc::new(vec![tree])), store1)
+            }
+            FindResult::NotFound => ValueIter::empty(store1),
+        })
+    })
+}
+
+/// Outer combine two trees with a function f
+///
+/// The result uses the store `rb`. Nodes and values that are taken unchanged from either
+/// side are converted using `ca` and `cb`.
+fn outer_combine<A, B, R, E, CA, CB, F>(
+    a: &TreeNodeRef<A>,
+    ab: A,
+    b: &TreeNodeRef<B>,
+    bb: B,
+    rb: &R,
+    ca: CA,
+    cb: CB,
+    f: F,
+) -> Result<TreeNode<R>, E>
+where
+    A: BlobStore + Clone,
+    B: BlobStore + Clone,
+    R: BlobStore,
+    A::Error: From<R::Error>,
+    E: From<A::Error> + From<B::Error>,
+    CA: NodeConverter<A, R> + Copy,
+    CB: NodeConverter<B, R> + Copy,
+    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<Option<Value<R>>, E> + Copy,
+{
+    let ap = a.load_prefix(&ab)?;
+    let bp = b.load_prefix(&bb)?;
+    let n = common_prefix(ap.as_ref(), bp.as_ref());
+    let value;
+    let children;
+    if n == ap.len() && n == bp.len() {
+        // prefixes are identical
+        value = if let Some(bv) = b.value_opt() {
+            if let Some(av) = a.value_opt() {
+                f(&av, &bv)?
+            } else {
+                Some(cb.convert_value(&bv, &bb)?)
+            }
+        } else {
+            a.value_opt()
+                .map(|x| ca.convert_value(&x, &ab))
+                .transpose()?
+        };
+        let ac = a.load_children(&ab)?;
+        let bc = b.load_children(&bb)?;
+        children = outer_combine_children(ac, ab, bc, bb, rb, ca, cb, f)?;
+    } else if n == ap.len() {
+        // a is a prefix of b
+        // value is value of a
+        value = a
+            .value_opt()
+            .map(|x| ca.convert_value(&x, &ab))
+            .transpose()?;
+        let ac = a.load_children(&ab)?;
+        let bc = [b.clone_shortened(&bb, n)?];
+        children =
+            outer_combine_children(ac, ab, TreeNodeIter::from_slice(&bc), bb, rb, ca, cb, f)?;
+    } else if n == bp.len() {
+        // b is a prefix of a
+        // value is value of b
+        value = b
+            .value_opt()
+            .map(|x| cb.convert_value(&x, &bb))
+            .transpose()?;
+        let ac = [a.clone_shortened(&ab, n)?];
+        let bc = b.load_children(&bb)?;
+        children =
+            outer_combine_children(TreeNodeIter::from_slice(&ac), ab, bc, bb, rb, ca, cb, f)?;
+    } else {
+        // the two nodes are disjoint
+        // value is none
+        value = None;
+        // children is just the shortened children a and b in the right order
+        let a = ca.convert_node_shortened(a, &ab, n)?;
+        let b = cb.convert_node_shortened(b, &bb, n)?;
+        children = if ap[n] > bp[n] {
+            vec![b, a]
+        } else {
+            vec![a, b]
+        };
+    }
+    Ok(assemble(&ap[..n], value, children, rb).map_err(A::Error::from)?)
+}
+
+fn outer_combine_children<'a, A, B, R, E, CA, CB, F>(
+    ac: Option<TreeNodeIter<'a, A>>,
+    ab: A,
+    bc: Option<TreeNodeIter<'a, B>>,
+    bb: B,
+    rb: &R,
+    ca: CA,
+    cb: CB,
+    f: F,
+) -> Result<Vec<TreeNode<R>>, E>
+where
+    A: BlobStore + Clone,
+    B: BlobStore + Clone,
+    R: BlobStore,
+    A::Error: From<R::Error>,
+    E: From<A::Error> + From<B::Error>,
+    CA: NodeConverter<A, R> + Copy,
+    CB: NodeConverter<B, R> + Copy,
+    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<Option<Value<R>>, E> + Copy,
+{
+    let mut res = Vec::new();
+    match (ac, bc) {
+        (Some(ac), Some(bc)) => {
+            let mut iter = OuterJoin::<A, B, E>::new(ac, bc);
+            while let Some(x) = iter.next() {
+                let r = match x? {
+                    (Some(a), Some(b)) => {
+                        outer_combine(&a, ab.clone(), &b, bb.clone(), rb, ca, cb, f)?
+                    }
+                    (Some(a), None) => ca.convert_node(&a, &ab)?,
+                    (None, Some(b)) => cb.convert_node(&b, &bb)?,
+                    (None, None) => panic!(),
+                };
+                if !r.is_empty() {
+                    res.push(r);
+                }
+            }
+        }
+        (None, Some(mut bc)) => {
+            while let Some(b) = bc.next() {
+                res.push(cb.convert_node(&b, &bb)?);
+            }
+        }
+        (Some(mut ac), None) => {
+            while let Some(a) = ac.next() {
+                res.push(ca.convert_node(&a, &ab)?);
+            }
+        }
+        (None, None) => {}
+    }
+    Ok(res)
+}
+
+/// Outer combine two trees with a function f
+fn outer_combine_with<A, B, C, F>(
+    a: &mut TreeNode<A>,
+    ab: A,
+    b: &TreeNodeRef<B>,
+    bb: B,
+    c: C,
+    f: F,
+) -> Result<(), A::Error>
+where
+    A: BlobStore + Clone,
+    B: BlobStore + Clone,
+    C: NodeConverter<B, A> + Clone,
+    A::Error: From<B::Error>,
+    F: Fn(&mut Value<A>, &ValueRef<B>) -> Result<(), A::Error> + Copy,
+{
+    let ap = a.load_prefix(&ab)?;
+    let bp = b.load_prefix(&bb)?;
+    let n = common_prefix(ap.as_ref(), bp.as_ref());
+    if n == bp.len() {
+        // ensure that prefixes are identical even if ap.len() > n
+        if n != ap.len() {
+            a.split(&ab, n)?;
+        }
+        // prefixes are now identical
+        if let Some(bv) = b.value_opt() {
+            if let Some(mut av) = a.take_value_opt() {
+                f(&mut av, &bv)?;
+                a.set_value_owned(av.as_value_ref().0);
+            } else {
+                let av = c.convert_value(&bv, &bb)?;
+                a.set_value_owned(av.as_value_ref().0);
+            }
+        }
+        let ac = a.load_children_mut(&ab)?;
+        let bc = b.load_children(&bb)?;
+        outer_combine_children_with(ac, ab, bc, bb, c, f)?;
+    } else if n == ap.len() {
+        // a is a prefix of b
+        // value is value of a
+        let ac = a.load_children_mut(&ab)?;
+        let bc = [b.clone_shortened(&bb, n)?];
+        outer_combine_children_with(ac, ab, TreeNodeIter::from_slice(&bc), bb, c, f)?;
+    } else {
+        // the two nodes are disjoint
+        a.split(&ab, n)?;
+        let ac = a.load_children_mut(&ab)?;
+        ac.push(c.convert_node_shortened(b, &bb, n)?);
+        ac.sort_by_key(|x| x.first_prefix_byte());
+    }
+    a.canonicalize();
+    Ok(())
+}
+
+fn outer_combine_children_with<'a, A, B, C, F>(
+    ac: &'a mut Vec<TreeNode<A>>,
+    ab: A,
+    bc: Option<TreeNodeIter<'a, B>>,
+    bb: B,
+    c: C,
+    f: F,
+) -> Result<(), A::Error>
+where
+    A: BlobStore + Clone,
+    B: BlobStore + Clone,
+    C: NodeConverter<B, A> + Clone,
+    F: Fn(&mut Value<A>, &ValueRef<B>) -> Result<(), A::Error> + Copy,
+    A::Error: From<B::Error>,
+{
+    if let Some(mut bc) = bc {
+        if ac.is_empty() {
+            while let Some(bc) = bc.next() {
+                ac.push(c.convert_node(&bc, &bb)?);
+            }
+            return Ok(());
+        }
+        let mut acb = InPlaceVecBuilder::from(ac);
+        let mut bci = bc;
+        while let Some(ordering) = cmp(&acb, &mut bci) {
+            match ordering {
+                Ordering::Less => {
+                    acb.consume(1, true);
+                }
+                Ordering::Equal => {
+                    // the .unwrap() are safe because cmp guarantees that there is a value on both sides
+                    let ac = acb.source_slice_mut().get_mut(0).unwrap();
+                    let bc = bci.next().unwrap();
+                    outer_combine_with(ac, ab.clone(), &bc, bb.clone(), c.clone(), f)?;
+                    // only move if the child is non-empty
+                    let non_empty = !ac.is_empty();
+                    acb.consume(1, non_empty);
+                }
+                Ordering::Greater => {
+                    // the .unwrap() is safe because cmp guarantees that there is a value
+                    let b = bci.next().unwrap();
+                    let a = c.convert_node(&b, &bb)?;
+                    acb.push(a);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Outer combine two trees with a function f
+fn inner_combine<A, B, E, F>(
+    a: &TreeNodeRef<A>,
+    ab: A,
+    b: &TreeNodeRef<B>,
+    bb: B,
+    f: F,
+) -> Result<TreeNode<Detached>, E>
+where
+    A: BlobStore + Clone,
+    B: BlobStore + Clone,
+    E: From<A::Error> + From<B::Error>,
+    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<Option<Value>, E> + Copy,
+{
+    let ap = a.load_prefix(&ab)?;
+    let bp = b.load_prefix(&bb)?;
+    let n = common_prefix(ap.as_ref(), bp.as_ref());
+    let value;
+    let children;
+    if n == ap.len() && n == bp.len() {
+        // prefixes are identical
+        value = if let Some(bv) = b.value_opt() {
+            if let Some(av) = a.value_opt() {
+                f(&av, &bv)?
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        let ac = a.load_children(&ab)?;
+        let bc = b.load_children(&bb)?;
+        children = inner_combine_children(ac, ab, bc, bb, f)?;
+    } else if n == ap.len() {
+        // a is a prefix of b
+        // value is value of a
+        value = None;
+        let ac = a.load_children(&ab)?;
+        let bc = [b.clone_shortened(&bb, n)?];
+        children = inner_combine_children(ac, ab, TreeNodeIter::from_slice(&bc), bb, f)?;
+    } else if n == bp.len() {
+        // b is a prefix of a
+        // value is value of b
+        value = None;
+        let ac = [a.clone_shortened(&ab, n)?];
+        let bc = b.load_children(&bb)?;
+        children = inner_combine_children(TreeNodeIter::from_slice(&ac), ab, bc, bb, f)?;
+    } else {
+        // the two nodes are disjoint
+        // value is none
+        value = None;
+        // children is none
+        children = None;
+    }
+    let mut res = TreeNode::EMPTY;
+    res.set_prefix_slice(&ap[..n]);
+    res.set_value(value);
+    res.set_children_arc_opt(children);
+    res.canonicalize();
+    Ok(res)
+}
+
+fn inner_combine_children<'a, A, B, E, F>(
+    ac: Option<TreeNodeIter<'a, A>>,
+    ab: A,
+    bc: Option<TreeNodeIter<'a, B>>,
+    bb: B,
+    f: F,
+) -> Result<Option<Arc<Vec<TreeNode<Detached>>>>, E>
+where
+    A: BlobStore + Clone,
+    B: BlobStore + Clone,
+    E: From<A::Error> + From<B::Error>,
+    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<Option<Value>, E> + Copy,
+{
+    Ok(match (ac, bc) {
+        (Some(ac), Some(bc)) => {
+            let mut res = Vec::new();
+            let mut iter = OuterJoin::<A, B, E>::new(ac, bc);
+            while let Some(x) = iter.next() {
+                if let (Some(a), Some(b)) = x? {
+                    let r = inner_combine(&a, ab.clone(), &b, bb.clone(), f)?;
+                    if !r.is_empty() {
+                        res.push(r);
+                    }
+                }
+            }
+            if res.is_empty() {
+                None
+            } else {
+                Some(Arc::new(res))
+            }
+        }
+        _ => None,
+    })
+}
+
+/// Inner combine two trees with a predicate f
+fn inner_combine_pred<A, B, E, F>(
+    a: &TreeNodeRef<A>,
+    ab: A,
+    b: &TreeNodeRef<B>,
+    bb: B,
+    f: F,
+) -> Result<bool, E>
+where
+    A: BlobStore + Clone,
+    B: BlobStore + Clone,
+    E: From<A::Error> + From<B::Error>,
+    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<bool, E> + Copy,
+{
+    let ap = a.load_prefix(&ab)?;
+    let bp = b.load_prefix(&bb)?;
+    let n = common_prefix(ap.as_ref(), bp.as_ref());
+    if n == ap.len() && n == bp.len() {
+        if let (Some(av), Some(bv)) = (a.value_opt(), b.value_opt()) {
+            if f(&av, &bv)? {
+                return Ok(true);
+            }
+        }
+        let ac = a.load_children(&ab)?;
+        let bc = b.load_children(&bb)?;
+        return inner_combine_children_pred(ac, ab, bc, bb, f);
+    } else if n == ap.len() {
+        let bc = b.clone_shortened(&bb, n)?;
+        let bc = TreeNodeRef::owned(&bc);
+        if let Some(mut ac) = a.load_children(&ab)? {
+            while let Some(ac) = ac.next() {
+                if inner_combine_pred(&ac, ab.clone(), &bc, bb.clone(), f)? {
+                    return Ok(true);
+                }
+            }
+        }
+    } else if n == bp.len() {
+        let ac = a.clone_shortened(&ab, n)?;
+        let ac = TreeNodeRef::owned(&ac);
+        if let Some(mut bc) = b.load_children(&bb)? {
+            while let Some(bc) = bc.next() {
+                if inner_combine_pred(&ac, ab.clone(), &bc, bb.clone(), f)? {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+    Ok(false)
+}
+
+fn inner_combine_children_pred<A, B, E, F>(
+    ac: Option<TreeNodeIter<A>>,
+    ab: A,
+    bc: Option<TreeNodeIter<B>>,
+    bb: B,
+    f: F,
+) -> Result<bool, E>
+where
+    A: BlobStore + Clone,
+    B: BlobStore + Clone,
+    E: From<A::Error> + From<B::Error>,
+    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<bool, E> + Copy,
+{
+    if let (Some(ac), Some(bc)) = (ac, bc) {
+        let mut iter = OuterJoin::<A, B, E>::new(ac, bc);
+        while let Some(x) = iter.next() {
+            if let (Some(a), Some(b)) = x? {
+                if inner_combine_pred(&a, ab.clone(), &b, bb.clone(), f)? {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Outer combine two trees with a function f
+fn inner_combine_with<A, B, C, F>(
+    a: &mut TreeNode<A>,
+    ab: A,
+    b: &TreeNodeRef<B>,
+    bb: B,
+    c: C,
+    f: F,
+) -> Result<(), A::Error>
+where
+    A: BlobStore + Clone,
+    B: BlobStore + Clone,
+    C: NodeConverter<B, A> + Clone,
+    A::Error: From<B::Error>,
+    F: Fn(&mut Value<A>, &ValueRef<B>) -> Result<(), A::Error> + Copy,
+{
+    let ap = a.load_prefix(&ab)?;
+    let bp = b.load_prefix(&bb)?;
+    let n = common_prefix(ap.as_ref(), bp.as_ref());
+    if n == ap.len() && n == bp.len() {
+        // prefixes are now identical
+        if let (Some(mut av), Some(bv)) = (a.take_value_opt(), b.value_opt()) {
+            f(&mut av, &bv)?;
+            a.set_value_owned(av.as_value_ref().0);
+        }
+        let ac = a.load_children_mut(&ab)?;
+        let bc = b.load_children(&bb)?;
+        inner_combine_children_with(ac, ab, bc, bb, c, f)?;
+    } else if n == ap.len() {
+        // a is a prefix of b
+        // value is none
+        a.set_value_slice(None);
+        let ac = a.load_children_mut(&ab)?;
+        let bc = [b.clone_shortened(&bb, n)?];
+        inner_combine_children_with(ac, ab, TreeNodeIter::from_slice(&bc), bb, c, f)?;
+    } else if n == bp.len() {
+        // b is a prefix of b
+        a.split(&ab, n)?;
+        let ac = a.load_children_mut(&ab)?;
+        let bc = b.load_children(&bb)?;
+        inner_combine_children_with(ac, ab, bc, bb, c, f)?;
+    } else {
+        // the two nodes are disjoint
+        a.set_value_slice(None);
+        a.set_children_arc_opt(None);
+    }
+    a.canonicalize();
+    Ok(())
+}
+
+fn inner_combine_children_with<'a, A, B, C, F>(
+    ac: &'a mut Vec<TreeNode<A>>,
+    ab: A,
+    bc: Option<TreeNodeIter<'a, B>>,
+    bb: B,
+    c: C,
+    f: F,
+) -> Result<(), A::Error>
+where
+    A: BlobStore + Clone,
+    B: BlobStore + Clone,
+    C: NodeConverter<B, A> + Clone,
+    F: Fn(&mut Value<A>, &ValueRef<B>) -> Result<(), A::Error> + Copy,
+    A::Error: From<B::Error>,
+{
+    if let Some(bc) = bc {
+        if ac.is_empty() {
+            return Ok(());
+        }
+        let mut acb = InPlaceVecBuilder::from(ac);
+        let mut bci = bc;
+        while let Some(ordering) = cmp(&acb, &mut bci) {
+            match ordering {
+                Ordering::Less => {
+                    acb.consume(1, false);
+                }
+                Ordering::Equal => {
+                    // the .unwrap() are safe because cmp guarantees that there is a value on both sides
+                    let ac = acb.source_slice_mut().get_mut(0).unwrap();
+                    let bc = bci.next().unwrap();
+                    inner_combine_with(ac, ab.clone(), &bc, bb.clone(), c.clone(), f)?;
+                    // only move if the child is non-empty
+                    let non_empty = !ac.is_empty();
+                    acb.consume(1, non_empty);
+                }
+                Ordering::Greater => {
+                    // the .unwrap() is safe because cmp guarantees that there is a value
+                    let _ = bci.next().unwrap();
+                }
+            }
+        }
+    } else {
+        ac.clear();
+    }
+    Ok(())
+}
+
+/// Outer combine two trees with a function f
+fn left_combine<A, B, E, F>(
+    a: &TreeNodeRef<A>,
+    ab: A,
+    b: &TreeNodeRef<B>,
+    bb: B,
+    f: F,
+) -> Result<TreeNode<Detached>, E>
+where
+    A: BlobStore + Clone,
+    B: BlobStore + Clone,
+    E: From<A::Error> + From<B::Error>,
+    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<Option<Value>, E> + Copy,
+{
+    let ap = a.load_prefix(&ab)?;
+    let bp = b.load_prefix(&bb)?;
+    let n = common_prefix(ap.as_ref(), bp.as_ref());
+    let value;
+    let children;
+    let mut res = TreeNode::EMPTY;
+    if n == ap.len() && n == bp.len() {
+        // prefixes are identical
+        value = if let Some(av) = a.value_opt() {
+            if let Some(bv) = b.value_opt() {
+                f(&av, &bv)?
+            } else {
+                Some(av.detached(&ab)?)
+            }
         } else {
-            a.value_opt().map(|x| x.detached(&ab)).transpose()?
+            None
         };
         let ac = a.load_children(&ab)?;
         let bc = b.load_children(&bb)?;
-        children = outer_combine_children(ac, ab, bc, bb, f)?;
+        children = left_combine_children(ac, ab, bc, bb, f)?;
     } else if n == ap.len() {
         // a is a prefix of b
         // value is value of a
         value = a.value_opt().map(|x| x.detached(&ab)).transpose()?;
         let ac = a.load_children(&ab)?;
         let bc = [b.clone_shortened(&bb, n)?];
-        children = outer_combine_children(ac, ab, TreeNodeIter::from_slice(&bc), bb, f)?;
+        children = left_combine_children(ac, ab, TreeNodeIter::from_slice(&bc), bb, f)?;
     } else if n == bp.len() {
         // b is a prefix of a
         // value is value of b
-        value = b.value_opt().map(|x| x.detached(&bb)).transpose()?;
+        value = None;
         let ac = [a.clone_shortened(&ab, n)?];
         let bc = b.load_children(&bb)?;
-        children = outer_combine_children(TreeNodeIter::from_slice(&ac), ab, bc, bb, f)?;
+        children = left_combine_children(TreeNodeIter::from_slice(&ac), ab, bc, bb, f)?;
     } else {
-        // the two nodes are disjoint
-        // value is none
-        value = None;
-        // children is just the shortened children a and b in the right order
-        let a = DetachConverter.convert_node_shortened(a, &ab, n)?;
-        let b = DetachConverter.convert_node_shortened(b, &bb, n)?;
-        let vec = if ap[n] > bp[n] {
-            vec![b, a]
-        } else {
-            vec![a, b]
-        };
-        children = Some(Arc::new(vec));
+        return Ok(a.detached(&ab)?);
     }
-    let mut res = TreeNode::EMPTY;
     res.set_prefix_slice(&ap[..n]);
     res.set_value(value);
     res.set_children_arc_opt(children);
@@ -2077,7 +3930,7 @@ where
     Ok(res)
 }
 
-fn outer_combine_children<'a, A, B, E, F>(
+fn left_combine_children<'a, A, B, E, F>(
     ac: Option<TreeNodeIter<'a, A>>,
     ab: A,
     bc: Option<TreeNodeIter<'a, B>>,
@@ -2095,15 +3948,18 @@ where
             let mut res = Vec::new();
             let mut iter = OuterJoin::<A, B, E>::new(ac, bc);
             while let Some(x) = iter.next() {
-                let r = match x? {
-                    (Some(a), Some(b)) => outer_combine(&a, ab.clone(), &b, bb.clone(), f)?,
-                    (Some(a), None) => a.detached(&ab)?,
-                    (None, Some(b)) => b.detached(&bb)?,
-                    (None, None) => panic!(),
+                match x? {
+                    (Some(a), Some(b)) => {
+                        let r = left_combine(&a, ab.clone(), &b, bb.clone(), f)?;
+                        if !r.is_empty() {
+                            res.push(r);
+                        }
+                    }
+                    (Some(a), None) => {
+                        res.push(a.detached(&ab)?);
+                    }
+                    _ => {}
                 };
-                if !r.is_empty() {
-                    res.push(r);
-                }
             }
             if res.is_empty() {
                 None
@@ -2111,14 +3967,89 @@ where
                 Some(Arc::new(res))
             }
         }
-        (None, Some(bc)) => bc.detached(&bb)?,
         (Some(ac), None) => ac.detached(&ab)?,
-        (None, None) => None,
+        _ => None,
     })
 }
 
+/// Inner combine two trees with a predicate f
+fn left_combine_pred<A, B, E, F>(
+    a: &TreeNodeRef<A>,
+    ab: A,
+    b: &TreeNodeRef<B>,
+    bb: B,
+    f: F,
+) -> Result<bool, E>
+where
+    A: BlobStore + Clone,
+    B: BlobStore + Clone,
+    E: From<A::Error> + From<B::Error>,
+    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<bool, E> + Copy,
+{
+    let ap = a.load_prefix(&ab)?;
+    let bp = b.load_prefix(&bb)?;
+    let n = common_prefix(ap.as_ref(), bp.as_ref());
+    if n == ap.len() && n == bp.len() {
+        match (a.value_opt(), b.value_opt()) {
+            (Some(av), Some(bv)) if f(&av, &bv)? => {
+                return Ok(true);
+            }
+            (Some(_), None) => return Ok(true),
+            _ => {}
+        }
+        let ac = a.load_children(&ab)?;
+        let bc = b.load_children(&bb)?;
+        left_combine_children_pred(ac, ab, bc, bb, f)
+    } else if n == ap.len() {
+        if a.value_opt().is_some() {
+            return Ok(true);
+        };
+        let ac = a.load_children(&ab)?;
+        let bc = [b.clone_shortened(&bb, n)?];
+        left_combine_children_pred(ac, ab, TreeNodeIter::from_slice(&bc), bb, f)
+    } else if n == bp.len() {
+        let ac = [a.clone_shortened(&ab, n)?];
+        let bc = b.load_children(&bb)?;
+        left_combine_children_pred(TreeNodeIter::from_slice(&ac), ab, bc, bb, f)
+    } else {
+        Ok(true)
+    }
+}
+
+fn left_combine_children_pred<A, B, E, F>(
+    ac: Option<TreeNodeIter<A>>,
+    ab: A,
+    bc: Option<TreeNodeIter<B>>,
+    bb: B,
+    f: F,
+) -> Result<bool, E>
+where
+    A: BlobStore + Clone,
+    B: BlobStore + Clone,
+    E: From<A::Error> + From<B::Error>,
+    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<bool, E> + Copy,
+{
+    match (ac, bc) {
+        (Some(ac), Some(bc)) => {
+            let mut iter = OuterJoin::<A, B, E>::new(ac, bc);
+            while let Some(x) = iter.next() {
+                match x? {
+                    (Some(a), Some(b)) if left_combine_pred(&a, ab.clone(), &b, bb.clone(), f)? => {
+                        return Ok(true);
+                    }
+                    (Some(_), None) => return Ok(true),
+                    _ => {}
+                }
+            }
+        }
+        (Some(_), None) => return Ok(true),
+        _ => {}
+    }
+    Ok(false)
+}
+
 /// Outer combine two trees with a function f
-fn outer_combine_with<A, B, C, F>(
+fn left_combine_with<A, B, C, F>(
     a: &mut TreeNode<A>,
     ab: A,
     b: &TreeNodeRef<B>,
@@ -2136,42 +4067,37 @@ where
     let ap = a.load_prefix(&ab)?;
     let bp = b.load_prefix(&bb)?;
     let n = common_prefix(ap.as_ref(), bp.as_ref());
-    if n == bp.len() {
-        // ensure that prefixes are identical even if ap.len() > n
-        if n != ap.len() {
-            a.split(&ab, n)?;
-        }
+    if n == ap.len() && n == bp.len() {
         // prefixes are now identical
         if let Some(bv) = b.value_opt() {
             if let Some(mut av) = a.take_value_opt() {
                 f(&mut av, &bv)?;
                 a.set_value_owned(av.as_value_ref().0);
-            } else {
-                let av = c.convert_value(&bv, &bb)?;
-                a.set_value_owned(av.as_value_ref().0);
             }
         }
         let ac = a.load_children_mut(&ab)?;
         let bc = b.load_children(&bb)?;
-        outer_combine_children_with(ac, ab, bc, bb, c, f)?;
+        left_combine_children_with(ac, ab.clone(), bc, bb, c, f)?;
     } else if n == ap.len() {
         // a is a prefix of b
-        // value is value of a
         let ac = a.load_children_mut(&ab)?;
         let bc = [b.clone_shortened(&bb, n)?];
-        outer_combine_children_with(ac, ab, TreeNodeIter::from_slice(&bc), bb, c, f)?;
-    } else {
-        // the two nodes are disjoint
+        left_combine_children_with(ac, ab.clone(), TreeNodeIter::from_slice(&bc), bb, c, f)?;
+    } else if n == bp.len() {
+        // b is a prefix of a
         a.split(&ab, n)?;
         let ac = a.load_children_mut(&ab)?;
-        ac.push(c.convert_node_shortened(b, &bb, n)?);
-        ac.sort_by_key(|x| x.first_prefix_byte());
+        let bc = b.load_children(&bb)?;
+        left_combine_children_with(ac, ab.clone(), bc, bb, c, f)?;
+    } else {
+        // the two nodes are disjoint, so a is unchanged and might not even be loaded
+        return Ok(());
     }
-    a.canonicalize();
+    a.canonicalize_loaded(&ab)?;
     Ok(())
 }
 
-fn outer_combine_children_with<'a, A, B, C, F>(
+fn left_combine_children_with<'a, A, B, C, F>(
     ac: &'a mut Vec<TreeNode<A>>,
     ab: A,
     bc: Option<TreeNodeIter<'a, B>>,
@@ -2186,11 +4112,8 @@ where
     F: Fn(&mut Value<A>, &ValueRef<B>) -> Result<(), A::Error> + Copy,
     A::Error: From<B::Error>,
 {
-    if let Some(mut bc) = bc {
+    if let Some(bc) = bc {
         if ac.is_empty() {
-            while let Some(bc) = bc.next() {
-                ac.push(c.convert_node(&bc, &bb)?);
-            }
             return Ok(());
         }
         let mut acb = InPlaceVecBuilder::from(ac);
@@ -2204,16 +4127,14 @@ where
                     // the .unwrap() are safe because cmp guarantees that there is a value on both sides
                     let ac = acb.source_slice_mut().get_mut(0).unwrap();
                     let bc = bci.next().unwrap();
-                    outer_combine_with(ac, ab.clone(), &bc, bb.clone(), c.clone(), f)?;
+                    left_combine_with(ac, ab.clone(), &bc, bb.clone(), c.clone(), f)?;
                     // only move if the child is non-empty
                     let non_empty = !ac.is_empty();
                     acb.consume(1, non_empty);
                 }
                 Ordering::Greater => {
                     // the .unwrap() is safe because cmp guarantees that there is a value
-                    let b = bci.next().unwrap();
-                    let a = c.convert_node(&b, &bb)?;
-                    acb.push(a);
+                    let _ = bci.next().unwrap();
                 }
             }
         }
@@ -2221,242 +4142,392 @@ where
     Ok(())
 }
 
-/// Outer combine two trees with a function f
-fn inner_combine<A, B, E, F>(
-    a: &TreeNodeRef<A>,
+/// Outer or left combine a tree with a function f, returning None if the tree is unchanged
+///
+/// Unlike [outer_combine_with] and [left_combine_with], `a` is not modified. Nodes are only copied when
+/// a value in them or below them changes, so unchanged subtrees stay shared with `a`.
+fn combine_with_shared<A, B, C, F>(
+    a: &TreeNode<A>,
     ab: A,
     b: &TreeNodeRef<B>,
     bb: B,
+    c: C,
+    outer: bool,
     f: F,
-) -> Result<TreeNode<Detached>, E>
+) -> Result<Option<TreeNode<A>>, A::Error>
 where
     A: BlobStore + Clone,
     B: BlobStore + Clone,
-    E: From<A::Error> + From<B::Error>,
-    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<Option<Value>, E> + Copy,
+    C: NodeConverter<B, A> + Clone,
+    A::Error: From<B::Error>,
+    F: Fn(&mut Value<A>, &ValueRef<B>) -> Result<(), A::Error> + Copy,
 {
     let ap = a.load_prefix(&ab)?;
     let bp = b.load_prefix(&bb)?;
     let n = common_prefix(ap.as_ref(), bp.as_ref());
-    let value;
-    let children;
-    if n == ap.len() && n == bp.len() {
+    let (value, children) = if n == ap.len() && n == bp.len() {
         // prefixes are identical
-        value = if let Some(bv) = b.value_opt() {
+        let mut value = None;
+        if let Some(bv) = b.value_opt() {
             if let Some(av) = a.value_opt() {
-                f(&av, &bv)?
-            } else {
-                None
+                let mut v = av.to_owned();
+                f(&mut v, &bv)?;
+                if !v.is_same(av.0) {
+                    value = Some(v);
+                }
+            } else if outer {
+                value = Some(c.convert_value(&bv, &bb)?);
             }
-        } else {
-            None
-        };
-        let ac = a.load_children(&ab)?;
+        }
         let bc = b.load_children(&bb)?;
-        children = inner_combine_children(ac, ab, bc, bb, f)?;
+        let children = combine_children_with_shared(a, ab.clone(), bc, bb, c, outer, f)?;
+        (value, children)
     } else if n == ap.len() {
         // a is a prefix of b
-        // value is value of a
-        value = None;
-        let ac = a.load_children(&ab)?;
         let bc = [b.clone_shortened(&bb, n)?];
-        children = inner_combine_children(ac, ab, TreeNodeIter::from_slice(&bc), bb, f)?;
+        let bc = TreeNodeIter::from_slice(&bc);
+        let children = combine_children_with_shared(a, ab.clone(), bc, bb, c, outer, f)?;
+        (None, children)
+    } else if outer {
+        // b is a prefix of a, or the two nodes are disjoint. Either way b adds entries to a
+        let mut res = a.clone();
+        outer_combine_with(&mut res, ab, b, bb, c, f)?;
+        return Ok(Some(res));
     } else if n == bp.len() {
-        // b is a prefix of a
-        // value is value of b
-        value = None;
-        let ac = [a.clone_shortened(&ab, n)?];
-        let bc = b.load_children(&bb)?;
-        children = inner_combine_children(TreeNodeIter::from_slice(&ac), ab, bc, bb, f)?;
+        // b is a prefix of a, so only the child of b that continues the prefix of a matters
+        let child = match b.load_children(&bb)? {
+            Some(children) => children.find(ap[n]).map(|child| child.to_owned()),
+            None => None,
+        };
+        let child = match child {
+            Some(child) => child,
+            None => return Ok(None),
+        };
+        let shortened = a.clone_shortened(&ab, n)?;
+        let child = TreeNodeRef::owned(&child);
+        let res = combine_with_shared(&shortened, ab.clone(), &child, bb, c, outer, f)?;
+        return res
+            .map(|mut res| {
+                if !res.is_empty() {
+                    let mut prefix = ap[..n].to_vec();
+                    prefix.extend_from_slice(&res.load_prefix(&ab)?);
+                    res.set_prefix_slice(&prefix);
+                }
+                Ok(res)
+            })
+            .transpose();
     } else {
-        // the two nodes are disjoint
-        // value is none
-        value = None;
-        // children is none
-        children = None;
+        // the two nodes are disjoint, so a is unchanged
+        return Ok(None);
+    };
+    if value.is_none() && children.is_none() {
+        return Ok(None);
+    }
+    let mut res = a.clone();
+    if let Some(value) = value {
+        res.set_value_owned(value.as_value_ref().0);
+    }
+    match children {
+        Some(children) => res.set_children_arc(Arc::new(children)),
+        // a node with a value is canonical no matter what the children are
+        None if res.has_value() => return Ok(Some(res)),
+        // the value was removed, so the children are needed to canonicalize
+        None => {
+            res.load_children_mut(&ab)?;
+        }
     }
-    let mut res = TreeNode::EMPTY;
-    res.set_prefix_slice(&ap[..n]);
-    res.set_value(value);
-    res.set_children_arc_opt(children);
-    res.canonicalize();
-    Ok(res)
+    res.canonicalize_loaded(&ab)?;
+    Ok(Some(res))
 }
 
-fn inner_combine_children<'a, A, B, E, F>(
-    ac: Option<TreeNodeIter<'a, A>>,
+/// Combine the children of `a` with `bc`, returning None if none of them changed
+fn combine_children_with_shared<'a, A, B, C, F>(
+    a: &'a TreeNode<A>,
     ab: A,
     bc: Option<TreeNodeIter<'a, B>>,
     bb: B,
+    c: C,
+    outer: bool,
     f: F,
-) -> Result<Option<Arc<Vec<TreeNode<Detached>>>>, E>
+) -> Result<Option<Vec<TreeNode<A>>>, A::Error>
 where
     A: BlobStore + Clone,
     B: BlobStore + Clone,
-    E: From<A::Error> + From<B::Error>,
-    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<Option<Value>, E> + Copy,
+    C: NodeConverter<B, A> + Clone,
+    A::Error: From<B::Error>,
+    F: Fn(&mut Value<A>, &ValueRef<B>) -> Result<(), A::Error> + Copy,
 {
-    Ok(match (ac, bc) {
-        (Some(ac), Some(bc)) => {
-            let mut res = Vec::new();
-            let mut iter = OuterJoin::<A, B, E>::new(ac, bc);
+    let mut bc = match bc {
+        Some(bc) => bc,
+        None => return Ok(None),
+    };
+    let mut res = Vec::new();
+    let mut changed = false;
+    match a.load_children(&ab)? {
+        Some(ac) => {
+            let mut iter = OuterJoin::<A, B, A::Error>::new(ac, bc);
             while let Some(x) = iter.next() {
-                if let (Some(a), Some(b)) = x? {
-                    let r = inner_combine(&a, ab.clone(), &b, bb.clone(), f)?;
-                    if !r.is_empty() {
-                        res.push(r);
+                match x? {
+                    (Some(a), Some(b)) => {
+                        let a = a.to_owned();
+                        match combine_with_shared(
+                            &a,
+                            ab.clone(),
+                            &b,
+                            bb.clone(),
+                            c.clone(),
+                            outer,
+                            f,
+                        )? {
+                            Some(r) => {
+                                changed = true;
+                                if !r.is_empty() {
+                                    res.push(r);
+                                }
+                            }
+                            None => res.push(a),
+                        }
+                    }
+                    (Some(a), None) => res.push(a.to_owned()),
+                    (None, Some(b)) => {
+                        if outer {
+                            changed = true;
+                            res.push(c.convert_node(&b, &bb)?);
+                        }
                     }
+                    (None, None) => panic!(),
                 }
             }
-            if res.is_empty() {
-                None
-            } else {
-                Some(Arc::new(res))
+        }
+        None => {
+            if outer {
+                while let Some(b) = bc.next() {
+                    changed = true;
+                    res.push(c.convert_node(&b, &bb)?);
+                }
             }
         }
-        _ => None,
-    })
+    }
+    Ok(Some(res).filter(|_| changed))
 }
 
-/// Inner combine two trees with a predicate f
-fn inner_combine_pred<A, B, E, F>(
-    a: &TreeNodeRef<A>,
+/// Retain all entries for which the predicate `f` returns true, returning None if all entries are kept.
+///
+/// `key` is the key up to but excluding the prefix of `node`, and is restored before returning.
+/// Only the nodes on the path to a removed entry are copied, all other subtrees stay as they are.
+/// Emptied subtrees are removed and the copied nodes canonicalized.
+fn retain_entries<S, F>(
+    node: &TreeNodeRef<S>,
+    store: &S,
+    key: &mut Vec<u8>,
+    f: &mut F,
+) -> Result<Option<TreeNode<S>>, S::Error>
+where
+    S: BlobStore,
+    F: FnMut(&[u8], &ValueRef<S>) -> Result<bool, S::Error>,
+{
+    let n = key.len();
+    key.extend_from_slice(&node.load_prefix(store)?);
+    let keep = match node.value_opt() {
+        Some(value) => f(key, &value)?,
+        None => true,
+    };
+    let mut children = Vec::new();
+    let mut changed = false;
+    if let Some(mut iter) = node.load_children(store)? {
+        while let Some(child) = iter.next() {
+            match retain_entries(&child, store, key, f)? {
+                Some(child) => {
+                    changed = true;
+                    if !child.is_empty() {
+                        children.push(child);
+                    }
+                }
+                None => children.push(child.to_owned()),
+            }
+        }
+    }
+    key.truncate(n);
+    if keep && !changed {
+        return Ok(None);
+    }
+    let mut res = node.to_owned();
+    if !keep {
+        res.set_value_slice(None);
+    }
+    // canonicalizing needs the children of the copy loaded
+    res.set_children_arc_opt(Some(Arc::new(children)).filter(|x| !x.is_empty()));
+    res.canonicalize_loaded(store)?;
+    Ok(Some(res))
+}
+
+/// Retain all parts of the tree for which that contains a prefix.
+///
+/// The predicate `f` is used to filter the tree `that` before applying it.
+/// If the predicate returns always false, this will result in the empty tree.
+fn retain_prefix_with<A, B, F>(
+    a: &mut TreeNode<A>,
     ab: A,
     b: &TreeNodeRef<B>,
     bb: B,
     f: F,
-) -> Result<bool, E>
+) -> Result<(), A::Error>
 where
     A: BlobStore + Clone,
     B: BlobStore + Clone,
-    E: From<A::Error> + From<B::Error>,
-    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<bool, E> + Copy,
+    F: Fn(&ValueRef<B>) -> Result<bool, A::Error> + Copy,
+    A::Error: From<B::Error>,
 {
     let ap = a.load_prefix(&ab)?;
     let bp = b.load_prefix(&bb)?;
     let n = common_prefix(ap.as_ref(), bp.as_ref());
     if n == ap.len() && n == bp.len() {
-        if let (Some(av), Some(bv)) = (a.value_opt(), b.value_opt()) {
-            if f(&av, &bv)? {
-                return Ok(true);
-            }
-        }
-        let ac = a.load_children(&ab)?;
-        let bc = b.load_children(&bb)?;
-        return inner_combine_children_pred(ac, ab, bc, bb, f);
-    } else if n == ap.len() {
-        let bc = b.clone_shortened(&bb, n)?;
-        let bc = TreeNodeRef::owned(&bc);
-        if let Some(mut ac) = a.load_children(&ab)? {
-            while let Some(ac) = ac.next() {
-                if inner_combine_pred(&ac, ab.clone(), &bc, bb.clone(), f)? {
-                    return Ok(true);
-                }
-            }
+        // prefixes are identical
+        if b.value_opt().is_none() || !f(&b.value_opt().unwrap())? {
+            a.set_value_slice(None);
+            let ac = a.load_children_mut(&ab)?;
+            let bc = b.load_children(&bb)?;
+            retain_prefix_children_with(ac, ab, bc, bb, f)?;
         }
     } else if n == bp.len() {
-        let ac = a.clone_shortened(&ab, n)?;
-        let ac = TreeNodeRef::owned(&ac);
-        if let Some(mut bc) = b.load_children(&bb)? {
-            while let Some(bc) = bc.next() {
-                if inner_combine_pred(&ac, ab.clone(), &bc, bb.clone(), f)? {
-                    return Ok(true);
-                }
-            }
+        // that is a prefix of self
+        if b.value_opt().is_none() {
+            a.split(&ab, n)?;
+            let ac = a.load_children_mut(&ab)?;
+            let bc = b.load_children(&bb)?;
+            retain_prefix_children_with(ac, ab, bc, bb, f)?;
+        } else if !f(&b.value_opt().unwrap())? {
+            a.set_value_slice(None);
+            a.set_children_arc_opt(None);
         }
+    } else if n == ap.len() {
+        // self is a prefix of that
+        a.set_value_slice(None);
+        let ac = a.load_children_mut(&ab)?;
+        let bc = [b.clone_shortened(&bb, n)?];
+        retain_prefix_children_with(ac, ab, TreeNodeIter::from_slice(&bc), bb, f)?;
+    } else {
+        // disjoint, nuke it
+        a.set_value_slice(None);
+        a.set_children_arc_opt(None);
     }
-    Ok(false)
+    a.canonicalize();
+    Ok(())
 }
 
-fn inner_combine_children_pred<A, B, E, F>(
-    ac: Option<TreeNodeIter<A>>,
+fn retain_prefix_children_with<'a, A, B, F>(
+    ac: &'a mut Vec<TreeNode<A>>,
     ab: A,
-    bc: Option<TreeNodeIter<B>>,
+    bc: Option<TreeNodeIter<'a, B>>,
     bb: B,
     f: F,
-) -> Result<bool, E>
+) -> Result<(), A::Error>
 where
     A: BlobStore + Clone,
     B: BlobStore + Clone,
-    E: From<A::Error> + From<B::Error>,
-    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<bool, E> + Copy,
+    F: Fn(&ValueRef<B>) -> Result<bool, A::Error> + Copy,
+    A::Error: From<B::Error>,
 {
-    if let (Some(ac), Some(bc)) = (ac, bc) {
-        let mut iter = OuterJoin::<A, B, E>::new(ac, bc);
-        while let Some(x) = iter.next() {
-            if let (Some(a), Some(b)) = x? {
-                if inner_combine_pred(&a, ab.clone(), &b, bb.clone(), f)? {
-                    return Ok(true);
+    if let Some(bc) = bc {
+        if ac.is_empty() {
+            return Ok(());
+        }
+        let mut acb = InPlaceVecBuilder::from(ac);
+        let mut bci = bc;
+        while let Some(ordering) = cmp(&acb, &mut bci) {
+            match ordering {
+                Ordering::Less => {
+                    acb.consume(1, false);
+                }
+                Ordering::Equal => {
+                    // the .unwrap() are safe because cmp guarantees that there is a value on both sides
+                    let ac = acb.source_slice_mut().get_mut(0).unwrap();
+                    let bc = bci.next().unwrap();
+                    retain_prefix_with(ac, ab.clone(), &bc, bb.clone(), f)?;
+                    // only move if the child is non-empty
+                    let non_empty = !ac.is_empty();
+                    acb.consume(1, non_empty);
+                }
+                Ordering::Greater => {
+                    // the .unwrap() is safe because cmp guarantees that there is a value
+                    let _ = bci.next().unwrap();
                 }
             }
         }
+    } else {
+        ac.clear();
     }
-    Ok(false)
+    Ok(())
 }
 
-/// Outer combine two trees with a function f
-fn inner_combine_with<A, B, C, F>(
+/// Retain all parts of the tree for which that contains a prefix.
+///
+/// The predicate `f` is used to filter the tree `that` before applying it.
+/// If the predicate returns always false, this will result in the empty tree.
+fn remove_prefix_with<A, B, F>(
     a: &mut TreeNode<A>,
     ab: A,
     b: &TreeNodeRef<B>,
     bb: B,
-    c: C,
     f: F,
 ) -> Result<(), A::Error>
 where
     A: BlobStore + Clone,
     B: BlobStore + Clone,
-    C: NodeConverter<B, A> + Clone,
+    F: Fn(&ValueRef<B>) -> Result<bool, A::Error> + Copy,
     A::Error: From<B::Error>,
-    F: Fn(&mut Value<A>, &ValueRef<B>) -> Result<(), A::Error> + Copy,
 {
     let ap = a.load_prefix(&ab)?;
     let bp = b.load_prefix(&bb)?;
     let n = common_prefix(ap.as_ref(), bp.as_ref());
     if n == ap.len() && n == bp.len() {
-        // prefixes are now identical
-        if let (Some(mut av), Some(bv)) = (a.take_value_opt(), b.value_opt()) {
-            f(&mut av, &bv)?;
-            a.set_value_owned(av.as_value_ref().0);
+        // prefixes are identical
+        if b.value_opt().is_some() && f(&b.value_opt().unwrap())? {
+            // nuke it
+            a.set_value_slice(None);
+            a.set_children_arc_opt(None);
+        } else {
+            // recurse
+            let ac = a.load_children_mut(&ab)?;
+            let bc = b.load_children(&bb)?;
+            remove_prefix_children_with(ac, ab, bc, bb, f)?;
         }
-        let ac = a.load_children_mut(&ab)?;
-        let bc = b.load_children(&bb)?;
-        inner_combine_children_with(ac, ab, bc, bb, c, f)?;
-    } else if n == ap.len() {
-        // a is a prefix of b
-        // value is none
-        a.set_value_slice(None);
-        let ac = a.load_children_mut(&ab)?;
-        let bc = [b.clone_shortened(&bb, n)?];
-        inner_combine_children_with(ac, ab, TreeNodeIter::from_slice(&bc), bb, c, f)?;
     } else if n == bp.len() {
-        // b is a prefix of b
-        a.split(&ab, n)?;
+        // that is a prefix of self
+        if b.value_opt().is_some() && f(&b.value_opt().unwrap())? {
+            // nuke it
+            a.set_value_slice(None);
+            a.set_children_arc_opt(None);
+        } else {
+            // recurse
+            a.split(&ab, n)?;
+            let ac = a.load_children_mut(&ab)?;
+            let bc = b.load_children(&bb)?;
+            remove_prefix_children_with(ac, ab, bc, bb, f)?;
+        }
+    } else if n == ap.len() {
+        // self is a prefix of that
         let ac = a.load_children_mut(&ab)?;
-        let bc = b.load_children(&bb)?;
-        inner_combine_children_with(ac, ab, bc, bb, c, f)?;
+        let bc = [b.clone_shortened(&bb, n)?];
+        remove_prefix_children_with(ac, ab, TreeNodeIter::from_slice(&bc), bb, f)?;
     } else {
-        // the two nodes are disjoint
-        a.set_value_slice(None);
-        a.set_children_arc_opt(None);
+        // disjoint, nothing to do
     }
     a.canonicalize();
     Ok(())
 }
 
-fn inner_combine_children_with<'a, A, B, C, F>(
+fn remove_prefix_children_with<'a, A, B, F>(
     ac: &'a mut Vec<TreeNode<A>>,
     ab: A,
     bc: Option<TreeNodeIter<'a, B>>,
     bb: B,
-    c: C,
     f: F,
 ) -> Result<(), A::Error>
 where
     A: BlobStore + Clone,
     B: BlobStore + Clone,
-    C: NodeConverter<B, A> + Clone,
-    F: Fn(&mut Value<A>, &ValueRef<B>) -> Result<(), A::Error> + Copy,
+    F: Fn(&ValueRef<B>) -> Result<bool, A::Error> + Copy,
     A::Error: From<B::Error>,
 {
     if let Some(bc) = bc {
@@ -2468,13 +4539,13 @@ where
         while let Some(ordering) = cmp(&acb, &mut bci) {
             match ordering {
                 Ordering::Less => {
-                    acb.consume(1, false);
+                    acb.consume(1, true);
                 }
                 Ordering::Equal => {
                     // the .unwrap() are safe because cmp guarantees that there is a value on both sides
                     let ac = acb.source_slice_mut().get_mut(0).unwrap();
                     let bc = bci.next().unwrap();
-                    inner_combine_with(ac, ab.clone(), &bc, bb.clone(), c.clone(), f)?;
+                    remove_prefix_with(ac, ab.clone(), &bc, bb.clone(), f)?;
                     // only move if the child is non-empty
                     let non_empty = !ac.is_empty();
                     acb.consume(1, non_empty);
@@ -2485,637 +4556,807 @@ where
                 }
             }
         }
-    } else {
-        ac.clear();
     }
     Ok(())
 }
 
-/// Outer combine two trees with a function f
-fn left_combine<A, B, E, F>(
-    a: &TreeNodeRef<A>,
-    ab: A,
-    b: &TreeNodeRef<B>,
-    bb: B,
-    f: F,
-) -> Result<TreeNode<Detached>, E>
-where
-    A: BlobStore + Clone,
-    B: BlobStore + Clone,
-    E: From<A::Error> + From<B::Error>,
-    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<Option<Value>, E> + Copy,
-{
-    let ap = a.load_prefix(&ab)?;
-    let bp = b.load_prefix(&bb)?;
-    let n = common_prefix(ap.as_ref(), bp.as_ref());
-    let value;
-    let children;
-    let mut res = TreeNode::EMPTY;
-    if n == ap.len() && n == bp.len() {
-        // prefixes are identical
-        value = if let Some(av) = a.value_opt() {
-            if let Some(bv) = b.value_opt() {
-                f(&av, &bv)?
-            } else {
-                Some(av.detached(&ab)?)
-            }
-        } else {
-            None
-        };
-        let ac = a.load_children(&ab)?;
-        let bc = b.load_children(&bb)?;
-        children = left_combine_children(ac, ab, bc, bb, f)?;
-    } else if n == ap.len() {
-        // a is a prefix of b
-        // value is value of a
-        value = a.value_opt().map(|x| x.detached(&ab)).transpose()?;
-        let ac = a.load_children(&ab)?;
-        let bc = [b.clone_shortened(&bb, n)?];
-        children = left_combine_children(ac, ab, TreeNodeIter::from_slice(&bc), bb, f)?;
-    } else if n == bp.len() {
-        // b is a prefix of a
-        // value is value of b
-        value = None;
-        let ac = [a.clone_shortened(&ab, n)?];
-        let bc = b.load_children(&bb)?;
-        children = left_combine_children(TreeNodeIter::from_slice(&ac), ab, bc, bb, f)?;
-    } else {
-        return Ok(a.detached(&ab)?);
+/// Key for iteration of radixtree prefixes
+///
+/// This uses copy on write to allow iterating over all prefixes without allocations, provided that the keys are not stored somewhere.
+#[derive(Debug, Clone, Default)]
+pub struct IterKey(Arc<Vec<u8>>);
+
+impl IterKey {
+    fn new(root: &[u8]) -> Self {
+        Self(Arc::new(root.to_vec()))
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        // for typical iterator use, a reference is not kept for a long time, so this will be very cheap
+        //
+        // in the case a reference is kept, this will make a copy.
+        let elems = Arc::make_mut(&mut self.0);
+        elems.extend_from_slice(data);
+    }
+
+    fn pop(&mut self, n: usize) {
+        let elems = Arc::make_mut(&mut self.0);
+        elems.truncate(elems.len().saturating_sub(n));
+    }
+
+    pub fn to_owned_blob(&self) -> OwnedBlob {
+        self.0.clone().into()
     }
-    res.set_prefix_slice(&ap[..n]);
-    res.set_value(value);
-    res.set_children_arc_opt(children);
-    res.canonicalize();
-    Ok(res)
 }
 
-fn left_combine_children<'a, A, B, E, F>(
-    ac: Option<TreeNodeIter<'a, A>>,
-    ab: A,
-    bc: Option<TreeNodeIter<'a, B>>,
-    bb: B,
-    f: F,
-) -> Result<Option<Arc<Vec<TreeNode<Detached>>>>, E>
-where
-    A: BlobStore + Clone,
-    B: BlobStore + Clone,
-    E: From<A::Error> + From<B::Error>,
-    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<Option<Value>, E> + Copy,
-{
-    Ok(match (ac, bc) {
-        (Some(ac), Some(bc)) => {
-            let mut res = Vec::new();
-            let mut iter = OuterJoin::<A, B, E>::new(ac, bc);
-            while let Some(x) = iter.next() {
-                match x? {
-                    (Some(a), Some(b)) => {
-                        let r = left_combine(&a, ab.clone(), &b, bb.clone(), f)?;
-                        if !r.is_empty() {
-                            res.push(r);
-                        }
-                    }
-                    (Some(a), None) => {
-                        res.push(a.detached(&ab)?);
-                    }
-                    _ => {}
-                };
-            }
-            if res.is_empty() {
-                None
+impl AsRef<[u8]> for IterKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl Borrow<[u8]> for IterKey {
+    fn borrow(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl Deref for IterKey {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+/// Iterator over all tree values
+///
+/// This is more efficient than the key value pair iterator since it does not have to keep track of the keys.
+pub struct ValueIter<S: BlobStore = Detached> {
+    stack: Vec<TreeNodeIter<'static, S>>,
+    store: S,
+}
+
+impl<S: BlobStore> ValueIter<S> {
+    fn empty(store: S) -> Self {
+        Self {
+            stack: Vec::new(),
+            store,
+        }
+    }
+
+    fn new(iter: TreeNodeIter<'static, S>, store: S) -> Self {
+        Self {
+            stack: vec![iter],
+            store,
+        }
+    }
+
+    fn next0(&mut self) -> Result<Option<Value<S>>, S::Error> {
+        while !self.stack.is_empty() {
+            if let Some(node) = self.stack.last_mut().unwrap().next() {
+                let value = node.value_opt().map(|x| x.to_owned());
+                if let Some(children) = node.load_children_owned(&self.store)? {
+                    self.stack.push(children);
+                }
+                if let Some(value) = value {
+                    return Ok(Some(value));
+                }
             } else {
-                Some(Arc::new(res))
+                self.stack.pop();
             }
         }
-        (Some(ac), None) => ac.detached(&ab)?,
-        _ => None,
-    })
+        Ok(None)
+    }
 }
 
-/// Inner combine two trees with a predicate f
-fn left_combine_pred<A, B, E, F>(
-    a: &TreeNodeRef<A>,
-    ab: A,
-    b: &TreeNodeRef<B>,
-    bb: B,
-    f: F,
-) -> Result<bool, E>
-where
-    A: BlobStore + Clone,
-    B: BlobStore + Clone,
-    E: From<A::Error> + From<B::Error>,
-    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<bool, E> + Copy,
-{
-    let ap = a.load_prefix(&ab)?;
-    let bp = b.load_prefix(&bb)?;
-    let n = common_prefix(ap.as_ref(), bp.as_ref());
-    if n == ap.len() && n == bp.len() {
-        match (a.value_opt(), b.value_opt()) {
-            (Some(av), Some(bv)) => {
-                if f(&av, &bv)? {
-                    return Ok(true);
-                }
+impl<S: BlobStore> Iterator for ValueIter<S> {
+    type Item = Result<Value<S>, S::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next0() {
+            Ok(Some(x)) => Some(Ok(x)),
+            Ok(None) => None,
+            Err(cause) => {
+                // ensure that the next call to next will terminate
+                self.stack.clear();
+                Some(Err(cause))
             }
-            (Some(_), None) => return Ok(true),
-            _ => {}
         }
-        let ac = a.load_children(&ab)?;
-        let bc = b.load_children(&bb)?;
-        left_combine_children_pred(ac, ab, bc, bb, f)
-    } else if n == ap.len() {
-        if a.value_opt().is_some() {
-            return Ok(true);
-        };
-        let ac = a.load_children(&ab)?;
-        let bc = [b.clone_shortened(&bb, n)?];
-        left_combine_children_pred(ac, ab, TreeNodeIter::from_slice(&bc), bb, f)
-    } else if n == bp.len() {
-        let ac = [a.clone_shortened(&ab, n)?];
-        let bc = b.load_children(&bb)?;
-        left_combine_children_pred(TreeNodeIter::from_slice(&ac), ab, bc, bb, f)
-    } else {
-        Ok(true)
     }
-}
+}
+
+/// Iterator over keys and values of a [RadixTree]
+///
+/// The values are constructed as the tree is traversed. Therefore iteration is slightly more expensive than
+/// iterating over just values using [ValueIter]
+pub struct KeyValueIter<S: BlobStore = Detached> {
+    path: IterKey,
+    stack: Vec<(usize, Option<TreeNodeIter<'static, S>>)>,
+    store: S,
+}
+
+impl<S: BlobStore> KeyValueIter<S> {
+    fn empty(store: S) -> Self {
+        Self {
+            stack: Vec::new(),
+            path: IterKey::default(),
+            store,
+        }
+    }
+
+    fn new(iter: TreeNodeIter<'static, S>, store: S, prefix: IterKey) -> Self {
+        Self {
+            stack: vec![(0, Some(iter))],
+            path: prefix,
+            store,
+        }
+    }
 
-fn left_combine_children_pred<A, B, E, F>(
-    ac: Option<TreeNodeIter<A>>,
-    ab: A,
-    bc: Option<TreeNodeIter<B>>,
-    bb: B,
-    f: F,
-) -> Result<bool, E>
-where
-    A: BlobStore + Clone,
-    B: BlobStore + Clone,
-    E: From<A::Error> + From<B::Error>,
-    F: Fn(&ValueRef<A>, &ValueRef<B>) -> Result<bool, E> + Copy,
-{
-    match (ac, bc) {
-        (Some(ac), Some(bc)) => {
-            let mut iter = OuterJoin::<A, B, E>::new(ac, bc);
-            while let Some(x) = iter.next() {
-                match x? {
-                    (Some(a), Some(b)) => {
-                        if left_combine_pred(&a, ab.clone(), &b, bb.clone(), f)? {
-                            return Ok(true);
-                        }
+    fn next0(&mut self) -> Result<Option<(IterKey, Value<S>)>, S::Error> {
+        while !self.stack.is_empty() {
+            let (last_prefix_len, iter_opt) = &mut self.stack.last_mut().unwrap();
+            let last_prefix_len = *last_prefix_len;
+            if let Some(iter) = iter_opt {
+                if let Some(node) = iter.next() {
+                    let value = node.value_opt().map(|x| x.to_owned());
+                    let prefix = node.load_prefix(&self.store)?;
+                    let prefix_len = prefix.len();
+                    let children = node.load_children_owned(&self.store)?;
+                    self.path.append(prefix.as_ref());
+                    self.stack.push((prefix_len, children));
+                    if let Some(value) = value {
+                        return Ok(Some((self.path.clone(), value)));
                     }
-                    (Some(_), None) => return Ok(true),
-                    _ => {}
+                } else {
+                    *iter_opt = None;
                 }
+            } else {
+                self.path.pop(last_prefix_len);
+                self.stack.pop();
             }
         }
-        (Some(_), None) => return Ok(true),
-        _ => {}
+        Ok(None)
     }
-    Ok(false)
 }
 
-/// Outer combine two trees with a function f
-fn left_combine_with<A, B, C, F>(
-    a: &mut TreeNode<A>,
-    ab: A,
-    b: &TreeNodeRef<B>,
-    bb: B,
-    c: C,
-    f: F,
-) -> Result<(), A::Error>
-where
-    A: BlobStore + Clone,
-    B: BlobStore + Clone,
-    C: NodeConverter<B, A> + Clone,
-    A::Error: From<B::Error>,
-    F: Fn(&mut Value<A>, &ValueRef<B>) -> Result<(), A::Error> + Copy,
-{
-    let ap = a.load_prefix(&ab)?;
-    let bp = b.load_prefix(&bb)?;
-    let n = common_prefix(ap.as_ref(), bp.as_ref());
-    if n == ap.len() && n == bp.len() {
-        // prefixes are now identical
-        if let Some(bv) = b.value_opt() {
-            if let Some(mut av) = a.take_value_opt() {
-                f(&mut av, &bv)?;
-                a.set_value_owned(av.as_value_ref().0);
+impl<S: BlobStore> Iterator for KeyValueIter<S> {
+    type Item = Result<(IterKey, Value<S>), S::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next0() {
+            Ok(Some(x)) => Some(Ok(x)),
+            Ok(None) => None,
+            Err(cause) => {
+                // ensure that the next call to next will terminate
+                self.stack.clear();
+                Some(Err(cause))
             }
         }
-        let ac = a.load_children_mut(&ab)?;
-        let bc = b.load_children(&bb)?;
-        left_combine_children_with(ac, ab, bc, bb, c, f)?;
-    } else if n == ap.len() {
-        // a is a prefix of b
-        let ac = a.load_children_mut(&ab)?;
-        let bc = [b.clone_shortened(&bb, n)?];
-        left_combine_children_with(ac, ab, TreeNodeIter::from_slice(&bc), bb, c, f)?;
-    } else if n == bp.len() {
-        // b is a prefix of a
-        a.split(&ab, n)?;
-        let ac = a.load_children_mut(&ab)?;
-        let bc = b.load_children(&bb)?;
-        left_combine_children_with(ac, ab, bc, bb, c, f)?;
-    } else {
-        // the two nodes are disjoint
     }
-    a.canonicalize();
-    Ok(())
 }
 
-fn left_combine_children_with<'a, A, B, C, F>(
-    ac: &'a mut Vec<TreeNode<A>>,
-    ab: A,
-    bc: Option<TreeNodeIter<'a, B>>,
-    bb: B,
-    c: C,
-    f: F,
-) -> Result<(), A::Error>
-where
-    A: BlobStore + Clone,
-    B: BlobStore + Clone,
-    C: NodeConverter<B, A> + Clone,
-    F: Fn(&mut Value<A>, &ValueRef<B>) -> Result<(), A::Error> + Copy,
-    A::Error: From<B::Error>,
-{
-    if let Some(bc) = bc {
-        if ac.is_empty() {
-            return Ok(());
+/// Children of a node on the stack of an [UnpinnedIter]
+struct UnpinnedLevel<S: BlobStore> {
+    /// length of the prefix of the node, to restore the path when leaving it
+    prefix_len: usize,
+    /// id of the children blob, or empty if the children are in memory
+    id: Vec<u8>,
+    /// the children, None if the blob was dropped and has to be read again
+    iter: Option<TreeNodeIter<'static, S>>,
+    /// offset of the next child in the blob, to resume after reading it again
+    offset: usize,
+}
+
+impl<S: BlobStore> UnpinnedLevel<S> {
+    /// the children of a node, without reading them yet if they are in the store
+    fn new(node: &TreeNodeRef<S>, prefix_len: usize) -> Self {
+        let (id, iter) = match node.dispatch() {
+            Ok(owned) => match owned.get_children() {
+                Ok(children) => (Vec::new(), Some(TreeNodeIter::from_arc(children.clone()))),
+                Err(id) => (id.to_vec(), None),
+            },
+            Err(borrowed) if borrowed.children_hdr.is_none() => (Vec::new(), None),
+            Err(borrowed) => (borrowed.children_ref().slice().to_vec(), None),
+        };
+        Self {
+            prefix_len,
+            id,
+            iter,
+            offset: 0,
         }
-        let mut acb = InPlaceVecBuilder::from(ac);
-        let mut bci = bc;
-        while let Some(ordering) = cmp(&acb, &mut bci) {
-            match ordering {
-                Ordering::Less => {
-                    acb.consume(1, true);
-                }
-                Ordering::Equal => {
-                    // the .unwrap() are safe because cmp guarantees that there is a value on both sides
-                    let ac = acb.source_slice_mut().get_mut(0).unwrap();
-                    let bc = bci.next().unwrap();
-                    left_combine_with(ac, ab.clone(), &bc, bb.clone(), c.clone(), f)?;
-                    // only move if the child is non-empty
-                    let non_empty = !ac.is_empty();
-                    acb.consume(1, non_empty);
-                }
-                Ordering::Greater => {
-                    // the .unwrap() is safe because cmp guarantees that there is a value
-                    let _ = bci.next().unwrap();
-                }
+    }
+
+    /// drop the children blob, remembering where to continue
+    fn unpin(&mut self) {
+        if let Some(TreeNodeIter::Borrowed(iter)) = &self.iter {
+            self.offset = iter.offset;
+            self.iter = None;
+        }
+    }
+
+    /// the children, reading the blob again if it was dropped
+    fn load(&mut self, store: &S) -> Result<Option<&mut TreeNodeIter<'static, S>>, S::Error> {
+        if self.iter.is_none() {
+            if let Some(mut iter) = BorrowedTreeNodeIter::load(&self.id, store)? {
+                iter.offset = self.offset;
+                self.iter = Some(TreeNodeIter::Borrowed(iter));
             }
         }
+        Ok(self.iter.as_mut())
     }
-    Ok(())
 }
 
-/// Retain all parts of the tree for which that contains a prefix.
+/// Iterator over keys and values of an attached [RadixTree] with bounded memory use
 ///
-/// The predicate `f` is used to filter the tree `that` before applying it.
-/// If the predicate returns always false, this will result in the empty tree.
-fn retain_prefix_with<A, B, F>(
-    a: &mut TreeNode<A>,
-    ab: A,
-    b: &TreeNodeRef<B>,
-    bb: B,
-    f: F,
-) -> Result<(), A::Error>
-where
-    A: BlobStore + Clone,
-    B: BlobStore + Clone,
-    F: Fn(&ValueRef<B>) -> Result<bool, A::Error> + Copy,
-    A::Error: From<B::Error>,
-{
-    let ap = a.load_prefix(&ab)?;
-    let bp = b.load_prefix(&bb)?;
-    let n = common_prefix(ap.as_ref(), bp.as_ref());
-    if n == ap.len() && n == bp.len() {
-        // prefixes are identical
-        if b.value_opt().is_none() || !f(&b.value_opt().unwrap())? {
-            a.set_value_slice(None);
-            let ac = a.load_children_mut(&ab)?;
-            let bc = b.load_children(&bb)?;
-            retain_prefix_children_with(ac, ab, bc, bb, f)?;
+/// [KeyValueIter] keeps the children blob of every node on the current path alive. This iterator
+/// drops the children blob of a node when descending into one of its children, and reads it again
+/// when coming back, so only a single blob is kept alive at any time. This trades additional reads
+/// for memory, which matters for full scans of very large trees in stores where every read copies.
+pub struct UnpinnedIter<S: BlobStore = Detached> {
+    path: IterKey,
+    stack: Vec<UnpinnedLevel<S>>,
+    store: S,
+}
+
+impl<S: BlobStore> UnpinnedIter<S> {
+    fn new(node: &TreeNode<S>, store: S) -> Self {
+        let root = UnpinnedLevel {
+            prefix_len: 0,
+            id: Vec::new(),
+            iter: Some(TreeNodeIter::from_arc(Arc::new(vec![node.clone()]))),
+            offset: 0,
+        };
+        Self {
+            path: IterKey::default(),
+            stack: vec![root],
+            store,
         }
-    } else if n == bp.len() {
-        // that is a prefix of self
-        if b.value_opt().is_none() {
-            a.split(&ab, n)?;
-            let ac = a.load_children_mut(&ab)?;
-            let bc = b.load_children(&bb)?;
-            retain_prefix_children_with(ac, ab, bc, bb, f)?;
-        } else if !f(&b.value_opt().unwrap())? {
-            a.set_value_slice(None);
-            a.set_children_arc_opt(None);
+    }
+
+    fn next0(&mut self) -> Result<Option<(IterKey, Value<S>)>, S::Error> {
+        while let Some(level) = self.stack.last_mut() {
+            let node = match level.load(&self.store)? {
+                Some(iter) => iter.next(),
+                None => None,
+            };
+            if let Some(node) = node {
+                let value = node.value_opt().map(|x| x.to_owned());
+                let prefix = node.load_prefix(&self.store)?;
+                self.path.append(prefix.as_ref());
+                let child = UnpinnedLevel::new(&node, prefix.len());
+                level.unpin();
+                self.stack.push(child);
+                if let Some(value) = value {
+                    return Ok(Some((self.path.clone(), value)));
+                }
+            } else {
+                self.path.pop(level.prefix_len);
+                self.stack.pop();
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<S: BlobStore> Iterator for UnpinnedIter<S> {
+    type Item = Result<(IterKey, Value<S>), S::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next0() {
+            Ok(Some(x)) => Some(Ok(x)),
+            Ok(None) => None,
+            Err(cause) => {
+                // ensure that the next call to next will terminate
+                self.stack.clear();
+                Some(Err(cause))
+            }
+        }
+    }
+}
+
+/// Iterator over the keys and values of a [RadixTree] that skips subtrees that can not be read
+///
+/// Unlike [KeyValueIter], a store error does not terminate the iteration. The error is recorded
+/// together with the key at which it happened, and iteration continues with the next readable
+/// subtree. Use [LossyIter::errors] after iterating, e.g. via [Iterator::by_ref], to see what
+/// was skipped.
+pub struct LossyIter<S: BlobStore = Detached> {
+    path: IterKey,
+    stack: Vec<(usize, Option<TreeNodeIter<'static, S>>)>,
+    store: S,
+    errors: Vec<(Vec<u8>, S::Error)>,
+}
+
+impl<S: BlobStore> LossyIter<S> {
+    fn new(iter: TreeNodeIter<'static, S>, store: S) -> Self {
+        Self {
+            stack: vec![(0, Some(iter))],
+            path: IterKey::default(),
+            store,
+            errors: Vec::new(),
         }
-    } else if n == ap.len() {
-        // self is a prefix of that
-        a.set_value_slice(None);
-        let ac = a.load_children_mut(&ab)?;
-        let bc = [b.clone_shortened(&bb, n)?];
-        retain_prefix_children_with(ac, ab, TreeNodeIter::from_slice(&bc), bb, f)?;
-    } else {
-        // disjoint, nuke it
-        a.set_value_slice(None);
-        a.set_children_arc_opt(None);
     }
-    a.canonicalize();
-    Ok(())
+
+    /// Errors encountered so far, with the key of the skipped subtree
+    ///
+    /// If the prefix of a node could not be read, the key is the key of its parent.
+    pub fn errors(&self) -> &[(Vec<u8>, S::Error)] {
+        &self.errors
+    }
+
+    pub fn into_errors(self) -> Vec<(Vec<u8>, S::Error)> {
+        self.errors
+    }
 }
 
-fn retain_prefix_children_with<'a, A, B, F>(
-    ac: &'a mut Vec<TreeNode<A>>,
-    ab: A,
-    bc: Option<TreeNodeIter<'a, B>>,
-    bb: B,
-    f: F,
-) -> Result<(), A::Error>
-where
-    A: BlobStore + Clone,
-    B: BlobStore + Clone,
-    F: Fn(&ValueRef<B>) -> Result<bool, A::Error> + Copy,
-    A::Error: From<B::Error>,
-{
-    if let Some(bc) = bc {
-        if ac.is_empty() {
-            return Ok(());
-        }
-        let mut acb = InPlaceVecBuilder::from(ac);
-        let mut bci = bc;
-        while let Some(ordering) = cmp(&acb, &mut bci) {
-            match ordering {
-                Ordering::Less => {
-                    acb.consume(1, false);
-                }
-                Ordering::Equal => {
-                    // the .unwrap() are safe because cmp guarantees that there is a value on both sides
-                    let ac = acb.source_slice_mut().get_mut(0).unwrap();
-                    let bc = bci.next().unwrap();
-                    retain_prefix_with(ac, ab.clone(), &bc, bb.clone(), f)?;
-                    // only move if the child is non-empty
-                    let non_empty = !ac.is_empty();
-                    acb.consume(1, non_empty);
-                }
-                Ordering::Greater => {
-                    // the .unwrap() is safe because cmp guarantees that there is a value
-                    let _ = bci.next().unwrap();
+impl<S: BlobStore> Iterator for LossyIter<S> {
+    type Item = (IterKey, Value<S>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.stack.is_empty() {
+            let (last_prefix_len, iter_opt) = &mut self.stack.last_mut().unwrap();
+            let last_prefix_len = *last_prefix_len;
+            if let Some(iter) = iter_opt {
+                if let Some(node) = iter.next() {
+                    let prefix = match node.load_prefix(&self.store) {
+                        Ok(prefix) => prefix,
+                        Err(cause) => {
+                            // without the prefix, neither the value nor the children can be used
+                            self.errors.push((self.path.to_vec(), cause));
+                            continue;
+                        }
+                    };
+                    let value = node.value_opt().map(|x| x.to_owned());
+                    let prefix_len = prefix.len();
+                    self.path.append(prefix.as_ref());
+                    let children = match node.load_children_owned(&self.store) {
+                        Ok(children) => children,
+                        Err(cause) => {
+                            // the value of this node is still valid
+                            self.errors.push((self.path.to_vec(), cause));
+                            None
+                        }
+                    };
+                    self.stack.push((prefix_len, children));
+                    if let Some(value) = value {
+                        return Some((self.path.clone(), value));
+                    }
+                } else {
+                    *iter_opt = None;
                 }
+            } else {
+                self.path.pop(last_prefix_len);
+                self.stack.pop();
             }
         }
-    } else {
-        ac.clear();
+        None
     }
-    Ok(())
 }
 
-/// Retain all parts of the tree for which that contains a prefix.
+/// Iterator over the keys and values of a [RadixTree] within a key range
 ///
-/// The predicate `f` is used to filter the tree `that` before applying it.
-/// If the predicate returns always false, this will result in the empty tree.
-fn remove_prefix_with<A, B, F>(
-    a: &mut TreeNode<A>,
-    ab: A,
-    b: &TreeNodeRef<B>,
-    bb: B,
-    f: F,
-) -> Result<(), A::Error>
-where
-    A: BlobStore + Clone,
-    B: BlobStore + Clone,
-    F: Fn(&ValueRef<B>) -> Result<bool, A::Error> + Copy,
-    A::Error: From<B::Error>,
-{
-    let ap = a.load_prefix(&ab)?;
-    let bp = b.load_prefix(&bb)?;
-    let n = common_prefix(ap.as_ref(), bp.as_ref());
-    if n == ap.len() && n == bp.len() {
-        // prefixes are identical
-        if b.value_opt().is_some() && f(&b.value_opt().unwrap())? {
-            // nuke it
-            a.set_value_slice(None);
-            a.set_children_arc_opt(None);
-        } else {
-            // recurse
-            let ac = a.load_children_mut(&ab)?;
-            let bc = b.load_children(&bb)?;
-            remove_prefix_children_with(ac, ab, bc, bb, f)?;
+/// Only descends into nodes whose keys can intersect the range, and stops as soon as the end of the range is passed.
+pub struct RangeIter<S: BlobStore = Detached> {
+    path: IterKey,
+    stack: Vec<(usize, Option<TreeNodeIter<'static, S>>)>,
+    store: S,
+    range: KeyRange,
+}
+
+/// owned bounds of a key range
+struct KeyRange {
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
+
+impl KeyRange {
+    fn new<K: AsRef<[u8]>>(range: impl RangeBounds<K>) -> Self {
+        fn owned<K: AsRef<[u8]>>(bound: Bound<&K>) -> Bound<Vec<u8>> {
+            match bound {
+                Bound::Included(x) => Bound::Included(x.as_ref().to_vec()),
+                Bound::Excluded(x) => Bound::Excluded(x.as_ref().to_vec()),
+                Bound::Unbounded => Bound::Unbounded,
+            }
         }
-    } else if n == bp.len() {
-        // that is a prefix of self
-        if b.value_opt().is_some() && f(&b.value_opt().unwrap())? {
-            // nuke it
-            a.set_value_slice(None);
-            a.set_children_arc_opt(None);
-        } else {
-            // recurse
-            a.split(&ab, n)?;
-            let ac = a.load_children_mut(&ab)?;
-            let bc = b.load_children(&bb)?;
-            remove_prefix_children_with(ac, ab, bc, bb, f)?;
+        Self {
+            start: owned(range.start_bound()),
+            end: owned(range.end_bound()),
+        }
+    }
+
+    /// the smallest key in the range and the smallest key after the range, if bounded
+    fn split_keys(self) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+        // the smallest key that is larger than x is x followed by a zero byte
+        let successor = |mut x: Vec<u8>| {
+            x.push(0);
+            x
+        };
+        let start = match self.start {
+            Bound::Included(start) => Some(start),
+            Bound::Excluded(start) => Some(successor(start)),
+            Bound::Unbounded => None,
+        };
+        let end = match self.end {
+            Bound::Included(end) => Some(successor(end)),
+            Bound::Excluded(end) => Some(end),
+            Bound::Unbounded => None,
+        };
+        (start, end)
+    }
+
+    /// true if the node for key and all nodes after it are after the end of the range
+    fn past_end(&self, key: &[u8]) -> bool {
+        match &self.end {
+            Bound::Included(end) => key > end.as_slice(),
+            Bound::Excluded(end) => key >= end.as_slice(),
+            Bound::Unbounded => false,
+        }
+    }
+
+    /// true if all keys starting with prefix are before the start of the range
+    fn before_start(&self, prefix: &[u8]) -> bool {
+        match &self.start {
+            Bound::Included(start) | Bound::Excluded(start) => {
+                prefix < start.as_slice() && !start.starts_with(prefix)
+            }
+            Bound::Unbounded => false,
+        }
+    }
+
+    fn after_start(&self, key: &[u8]) -> bool {
+        match &self.start {
+            Bound::Included(start) => key >= start.as_slice(),
+            Bound::Excluded(start) => key > start.as_slice(),
+            Bound::Unbounded => true,
         }
-    } else if n == ap.len() {
-        // self is a prefix of that
-        let ac = a.load_children_mut(&ab)?;
-        let bc = [b.clone_shortened(&bb, n)?];
-        remove_prefix_children_with(ac, ab, TreeNodeIter::from_slice(&bc), bb, f)?;
-    } else {
-        // disjoint, nothing to do
     }
-    a.canonicalize();
-    Ok(())
 }
 
-fn remove_prefix_children_with<'a, A, B, F>(
-    ac: &'a mut Vec<TreeNode<A>>,
-    ab: A,
-    bc: Option<TreeNodeIter<'a, B>>,
-    bb: B,
-    f: F,
-) -> Result<(), A::Error>
-where
-    A: BlobStore + Clone,
-    B: BlobStore + Clone,
-    F: Fn(&ValueRef<B>) -> Result<bool, A::Error> + Copy,
-    A::Error: From<B::Error>,
-{
-    if let Some(bc) = bc {
-        if ac.is_empty() {
-            return Ok(());
+impl<S: BlobStore> RangeIter<S> {
+    fn new(iter: TreeNodeIter<'static, S>, store: S, range: KeyRange) -> Self {
+        Self {
+            path: IterKey::default(),
+            stack: vec![(0, Some(iter))],
+            store,
+            range,
         }
-        let mut acb = InPlaceVecBuilder::from(ac);
-        let mut bci = bc;
-        while let Some(ordering) = cmp(&acb, &mut bci) {
-            match ordering {
-                Ordering::Less => {
-                    acb.consume(1, true);
-                }
-                Ordering::Equal => {
-                    // the .unwrap() are safe because cmp guarantees that there is a value on both sides
-                    let ac = acb.source_slice_mut().get_mut(0).unwrap();
-                    let bc = bci.next().unwrap();
-                    remove_prefix_with(ac, ab.clone(), &bc, bb.clone(), f)?;
-                    // only move if the child is non-empty
-                    let non_empty = !ac.is_empty();
-                    acb.consume(1, non_empty);
-                }
-                Ordering::Greater => {
-                    // the .unwrap() is safe because cmp guarantees that there is a value
-                    let _ = bci.next().unwrap();
+    }
+
+    fn next0(&mut self) -> Result<Option<(IterKey, Value<S>)>, S::Error> {
+        while !self.stack.is_empty() {
+            let (last_prefix_len, iter_opt) = &mut self.stack.last_mut().unwrap();
+            let last_prefix_len = *last_prefix_len;
+            if let Some(iter) = iter_opt {
+                if let Some(node) = iter.next() {
+                    let prefix = node.load_prefix(&self.store)?;
+                    let prefix_len = prefix.len();
+                    self.path.append(prefix.as_ref());
+                    drop(prefix);
+                    if self.range.past_end(&self.path) {
+                        self.stack.clear();
+                        break;
+                    }
+                    if self.range.before_start(&self.path) {
+                        self.path.pop(prefix_len);
+                        continue;
+                    }
+                    let value = node.value_opt().map(|x| x.to_owned());
+                    let children = node.load_children_owned(&self.store)?;
+                    self.stack.push((prefix_len, children));
+                    if let Some(value) = value {
+                        if self.range.after_start(&self.path) {
+                            return Ok(Some((self.path.clone(), value)));
+                        }
+                    }
+                } else {
+                    *iter_opt = None;
                 }
+            } else {
+                self.path.pop(last_prefix_len);
+                self.stack.pop();
             }
         }
+        Ok(None)
     }
-    Ok(())
 }
 
-/// Key for iteration of radixtree prefixes
+impl<S: BlobStore> Iterator for RangeIter<S> {
+    type Item = Result<(IterKey, Value<S>), S::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next0() {
+            Ok(Some(x)) => Some(Ok(x)),
+            Ok(None) => None,
+            Err(cause) => {
+                // ensure that the next call to next will terminate
+                self.stack.clear();
+                Some(Err(cause))
+            }
+        }
+    }
+}
+
+/// A position in a [RadixTree] that can be moved forward and backward
 ///
-/// This uses copy on write to allow iterating over all prefixes without allocations, provided that the keys are not stored somewhere.
-#[derive(Debug, Clone, Default)]
-pub struct IterKey(Arc<Vec<u8>>);
+/// Created by [RadixTree::cursor]. A new cursor is not positioned at any entry. Use seek, first or last to
+/// position it. Once it moves past either end, it is again not positioned.
+pub struct Cursor<'a, S: BlobStore = Detached> {
+    tree: &'a RadixTree<S>,
+    /// key of the current node
+    path: Vec<u8>,
+    /// path of nodes from the root to the current node
+    stack: Vec<CursorLevel<S>>,
+}
 
-impl IterKey {
-    fn new(root: &[u8]) -> Self {
-        Self(Arc::new(root.to_vec()))
+struct CursorLevel<S: BlobStore> {
+    nodes: Arc<Vec<TreeNode<S>>>,
+    index: usize,
+    prefix_len: usize,
+}
+
+impl<'a, S: BlobStore + Clone> Cursor<'a, S> {
+    fn new(tree: &'a RadixTree<S>) -> Self {
+        Self {
+            tree,
+            path: Vec::new(),
+            stack: Vec::new(),
+        }
     }
 
-    fn append(&mut self, data: &[u8]) {
-        // for typical iterator use, a reference is not kept for a long time, so this will be very cheap
-        //
-        // in the case a reference is kept, this will make a copy.
-        let elems = Arc::make_mut(&mut self.0);
-        elems.extend_from_slice(data);
+    /// The key of the current entry, or None if the cursor is not positioned
+    pub fn key(&self) -> Option<&[u8]> {
+        self.current().map(|_| self.path.as_slice())
+    }
+
+    /// The value of the current entry, or None if the cursor is not positioned
+    pub fn value(&self) -> Option<ValueRef<'_, S>> {
+        self.current()?
+            .value_opt()
+            .map(|x| ValueRef(Ok(x.0), PhantomData))
+    }
+
+    fn current(&self) -> Option<&TreeNode<S>> {
+        self.stack.last().map(|level| &level.nodes[level.index])
+    }
+
+    fn load_children(&self, node: &TreeNode<S>) -> Result<Option<Arc<Vec<TreeNode<S>>>>, S::Error> {
+        Ok(match node.get_children() {
+            Ok(children) => Some(children.clone()).filter(|x| !x.is_empty()),
+            Err(id) => TreeNodeIter::load(id, &self.tree.store)?.map(|mut iter| {
+                let mut children = Vec::new();
+                while let Some(child) = iter.next() {
+                    children.push(child.to_owned());
+                }
+                Arc::new(children)
+            }),
+        })
+    }
+
+    fn reset(&mut self) {
+        self.stack.clear();
+        self.path.clear();
+    }
+
+    /// descend into node `index` of `nodes`
+    fn push(&mut self, nodes: Arc<Vec<TreeNode<S>>>, index: usize) -> Result<(), S::Error> {
+        let prefix = nodes[index].load_prefix(&self.tree.store)?;
+        let prefix_len = prefix.len();
+        self.path.extend_from_slice(&prefix);
+        drop(prefix);
+        self.stack.push(CursorLevel {
+            nodes,
+            index,
+            prefix_len,
+        });
+        Ok(())
     }
 
-    fn pop(&mut self, n: usize) {
-        let elems = Arc::make_mut(&mut self.0);
-        elems.truncate(elems.len().saturating_sub(n));
+    fn pop(&mut self) {
+        if let Some(level) = self.stack.pop() {
+            self.path.truncate(self.path.len() - level.prefix_len);
+        }
     }
 
-    pub fn to_owned_blob(&self) -> OwnedBlob {
-        self.0.clone().into()
+    /// move to sibling `index` of the current node
+    fn select(&mut self, index: usize) -> Result<(), S::Error> {
+        let level = self.stack.pop().unwrap();
+        self.path.truncate(self.path.len() - level.prefix_len);
+        self.push(level.nodes, index)
     }
-}
 
-impl AsRef<[u8]> for IterKey {
-    fn as_ref(&self) -> &[u8] {
-        self.0.as_ref()
+    /// move to the first entry in the subtree of the current node
+    fn descend_first(&mut self) -> Result<bool, S::Error> {
+        while let Some(node) = self.current() {
+            if node.has_value() {
+                return Ok(true);
+            }
+            match self.load_children(node)? {
+                Some(children) => self.push(children, 0)?,
+                // only possible for an empty tree
+                None => self.reset(),
+            }
+        }
+        Ok(false)
     }
-}
 
-impl Borrow<[u8]> for IterKey {
-    fn borrow(&self) -> &[u8] {
-        self.0.as_ref()
+    /// move to the last entry in the subtree of the current node
+    fn descend_last(&mut self) -> Result<bool, S::Error> {
+        while let Some(node) = self.current() {
+            match self.load_children(node)? {
+                Some(children) => {
+                    let last = children.len() - 1;
+                    self.push(children, last)?
+                }
+                None if node.has_value() => return Ok(true),
+                // only possible for an empty tree
+                None => self.reset(),
+            }
+        }
+        Ok(false)
     }
-}
 
-impl Deref for IterKey {
-    type Target = [u8];
+    /// move to the first entry after the subtree of the current node
+    fn skip_subtree(&mut self) -> Result<bool, S::Error> {
+        while let Some(level) = self.stack.last() {
+            if level.index + 1 < level.nodes.len() {
+                let index = level.index + 1;
+                self.select(index)?;
+                return self.descend_first();
+            }
+            self.pop();
+        }
+        Ok(false)
+    }
 
-    fn deref(&self) -> &Self::Target {
-        self.0.as_ref()
+    fn start(&mut self) -> Result<(), S::Error> {
+        self.reset();
+        self.push(Arc::new(vec![self.tree.node.clone()]), 0)
     }
-}
 
-/// Iterator over all tree values
-///
-/// This is more efficient than the key value pair iterator since it does not have to keep track of the keys.
-pub struct ValueIter<S: BlobStore = Detached> {
-    stack: Vec<TreeNodeIter<'static, S>>,
-    store: S,
-}
+    /// Move to the first entry. Returns false if the tree is empty.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_first(&mut self) -> Result<bool, S::Error> {
+        self.start()?;
+        self.descend_first()
+    }
 
-impl<S: BlobStore> ValueIter<S> {
-    fn empty(store: S) -> Self {
-        Self {
-            stack: Vec::new(),
-            store,
-        }
+    /// Move to the last entry. Returns false if the tree is empty.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_last(&mut self) -> Result<bool, S::Error> {
+        self.start()?;
+        self.descend_last()
     }
 
-    fn new(iter: TreeNodeIter<'static, S>, store: S) -> Self {
-        Self {
-            stack: vec![iter],
-            store,
+    /// Move to the first entry with a key greater than or equal to `key`. Returns false if there is none.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_seek(&mut self, key: impl AsRef<[u8]>) -> Result<bool, S::Error> {
+        let key = key.as_ref();
+        self.start()?;
+        loop {
+            let n = common_prefix(&self.path, key);
+            if n < self.path.len() {
+                // the keys of the current subtree are either all before or all after key
+                return if n == key.len() || self.path[n] > key[n] {
+                    self.descend_first()
+                } else {
+                    self.skip_subtree()
+                };
+            }
+            if n == key.len() {
+                return self.descend_first();
+            }
+            // the current key is a proper prefix of key, so continue with the first child that can contain it
+            let node = self.current().unwrap();
+            let c = Some(key[n]);
+            match self.load_children(node)? {
+                Some(children) => match children.iter().position(|x| x.first_prefix_byte() >= c) {
+                    Some(i) => self.push(children, i)?,
+                    None => return self.skip_subtree(),
+                },
+                None => return self.skip_subtree(),
+            }
         }
     }
 
-    fn next0(&mut self) -> Result<Option<Value<S>>, S::Error> {
-        while !self.stack.is_empty() {
-            if let Some(node) = self.stack.last_mut().unwrap().next() {
-                let value = node.value_opt().map(|x| x.to_owned());
-                if let Some(children) = node.load_children_owned(&self.store)? {
-                    self.stack.push(children);
-                }
-                if let Some(value) = value {
-                    return Ok(Some(value));
+    /// Move to the next entry. Returns false if there is none, or if the cursor is not positioned.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_next(&mut self) -> Result<bool, S::Error> {
+        match self.current() {
+            Some(node) => match self.load_children(node)? {
+                Some(children) => {
+                    self.push(children, 0)?;
+                    self.descend_first()
                 }
-            } else {
-                self.stack.pop();
+                None => self.skip_subtree(),
+            },
+            None => Ok(false),
+        }
+    }
+
+    /// Move to the previous entry. Returns false if there is none, or if the cursor is not positioned.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_prev(&mut self) -> Result<bool, S::Error> {
+        while let Some(level) = self.stack.last() {
+            if level.index > 0 {
+                let index = level.index - 1;
+                self.select(index)?;
+                return self.descend_last();
+            }
+            // the parent comes before all its children
+            self.pop();
+            if self.current().map(|x| x.has_value()).unwrap_or_default() {
+                return Ok(true);
             }
         }
-        Ok(None)
+        Ok(false)
     }
 }
 
-impl<S: BlobStore> Iterator for ValueIter<S> {
-    type Item = Result<Value<S>, S::Error>;
+impl<'a> Cursor<'a> {
+    pub fn first(&mut self) -> bool {
+        self.try_first().unwrap_safe()
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.next0() {
-            Ok(Some(x)) => Some(Ok(x)),
-            Ok(None) => None,
-            Err(cause) => {
-                // ensure that the next call to next will terminate
-                self.stack.clear();
-                Some(Err(cause))
-            }
-        }
+    pub fn last(&mut self) -> bool {
+        self.try_last().unwrap_safe()
+    }
+
+    pub fn seek(&mut self, key: impl AsRef<[u8]>) -> bool {
+        self.try_seek(key).unwrap_safe()
+    }
+
+    // a cursor is not an iterator, it can also move backwards
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> bool {
+        self.try_next().unwrap_safe()
+    }
+
+    pub fn prev(&mut self) -> bool {
+        self.try_prev().unwrap_safe()
     }
 }
 
-/// Iterator over keys and values of a [RadixTree]
+/// Iterator over keys and values of a [RadixTree] in a deterministic pseudo random order
 ///
-/// The values are constructed as the tree is traversed. Therefore iteration is slightly more expensive than
-/// iterating over just values using [ValueIter]
-pub struct KeyValueIter<S: BlobStore = Detached> {
+/// The children of each node are visited in a permutation determined by the seed.
+pub struct ShuffledIter<S: BlobStore = Detached> {
     path: IterKey,
-    stack: Vec<(usize, Option<TreeNodeIter<'static, S>>)>,
+    stack: Vec<(usize, Vec<TreeNode<S>>)>,
     store: S,
+    rng: SplitMix64,
 }
 
-impl<S: BlobStore> KeyValueIter<S> {
-    fn empty(store: S) -> Self {
+impl<S: BlobStore> ShuffledIter<S> {
+    fn new(node: TreeNode<S>, store: S, seed: u64) -> Self {
         Self {
-            stack: Vec::new(),
             path: IterKey::default(),
+            stack: vec![(0, vec![node])],
             store,
-        }
-    }
-
-    fn new(iter: TreeNodeIter<'static, S>, store: S, prefix: IterKey) -> Self {
-        Self {
-            stack: vec![(0, Some(iter))],
-            path: prefix,
-            store,
+            rng: SplitMix64::new(seed),
         }
     }
 
     fn next0(&mut self) -> Result<Option<(IterKey, Value<S>)>, S::Error> {
-        while !self.stack.is_empty() {
-            let (last_prefix_len, iter_opt) = &mut self.stack.last_mut().unwrap();
-            let last_prefix_len = *last_prefix_len;
-            if let Some(iter) = iter_opt {
-                if let Some(node) = iter.next() {
-                    let value = node.value_opt().map(|x| x.to_owned());
-                    let prefix = node.load_prefix(&self.store)?;
-                    let prefix_len = prefix.len();
-                    let children = node.load_children_owned(&self.store)?;
-                    self.path.append(prefix.as_ref());
-                    self.stack.push((prefix_len, children));
-                    if let Some(value) = value {
-                        return Ok(Some((self.path.clone(), value)));
+        while let Some((last_prefix_len, nodes)) = self.stack.last_mut() {
+            if let Some(node) = nodes.pop() {
+                let prefix = node.load_prefix(&self.store)?;
+                let prefix_len = prefix.len();
+                self.path.append(prefix.as_ref());
+                let mut children = Vec::new();
+                if let Some(mut iter) = node.load_children(&self.store)? {
+                    while let Some(child) = iter.next() {
+                        children.push(child.to_owned());
                     }
-                } else {
-                    *iter_opt = None;
+                }
+                // nodes are taken from the end, so the order of a shuffled vec is just as random
+                self.rng.shuffle(&mut children);
+                self.stack.push((prefix_len, children));
+                if let Some(value) = node.value_opt() {
+                    return Ok(Some((self.path.clone(), value.to_owned())));
                 }
             } else {
-                self.path.pop(last_prefix_len);
+                self.path.pop(*last_prefix_len);
                 self.stack.pop();
             }
         }
@@ -3123,7 +5364,7 @@ impl<S: BlobStore> KeyValueIter<S> {
     }
 }
 
-impl<S: BlobStore> Iterator for KeyValueIter<S> {
+impl<S: BlobStore> Iterator for ShuffledIter<S> {
     type Item = Result<(IterKey, Value<S>), S::Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -3219,6 +5460,70 @@ impl<S: BlobStore, F: Fn(&[u8], &TreeNodeRef<S>) -> Result<bool, S::Error>> Iter
     }
 }
 
+/// Cached number of entries of a tree
+///
+/// Kept up to date by single entry inserts and removes, and invalidated by combine operations.
+/// Computing the count requires a full traversal, so it is done lazily on the first call to
+/// [RadixTree::try_len] after invalidation.
+pub(crate) struct LenCache(AtomicU64);
+
+impl LenCache {
+    const UNKNOWN: u64 = u64::MAX;
+
+    pub(crate) fn known(n: u64) -> Self {
+        Self(AtomicU64::new(n))
+    }
+
+    fn get(&self) -> Option<u64> {
+        let n = self.0.load(atomic::Ordering::Relaxed);
+        if n == Self::UNKNOWN {
+            None
+        } else {
+            Some(n)
+        }
+    }
+
+    fn set(&self, n: u64) {
+        self.0.store(n, atomic::Ordering::Relaxed)
+    }
+
+    fn invalidate(&mut self) {
+        *self.0.get_mut() = Self::UNKNOWN;
+    }
+
+    fn increment(&mut self) {
+        let n = self.0.get_mut();
+        if *n != Self::UNKNOWN {
+            *n += 1;
+        }
+    }
+
+    fn decrement(&mut self) {
+        let n = self.0.get_mut();
+        if *n != Self::UNKNOWN {
+            *n -= 1;
+        }
+    }
+}
+
+impl Default for LenCache {
+    fn default() -> Self {
+        Self(AtomicU64::new(Self::UNKNOWN))
+    }
+}
+
+impl Clone for LenCache {
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.0.load(atomic::Ordering::Relaxed)))
+    }
+}
+
+impl Debug for LenCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
 impl<S: BlobStore + Default> Default for RadixTree<S> {
     fn default() -> Self {
         Self::empty(S::default())
@@ -3230,15 +5535,68 @@ impl RadixTree {
         self.try_get(key).unwrap_safe()
     }
 
+    pub fn get_many(&self, keys: impl IntoIterator<Item = impl AsRef<[u8]>>) -> Vec<Option<Value>> {
+        self.try_get_many(keys).unwrap_safe()
+    }
+
+    pub fn longest_prefix_match(&self, key: impl AsRef<[u8]>) -> Option<(Vec<u8>, Value)> {
+        self.try_longest_prefix_match(key).unwrap_safe()
+    }
+
+    pub fn get_traced(&self, key: impl AsRef<[u8]>) -> (Option<Value>, Trace) {
+        self.try_get_traced(key).unwrap_safe()
+    }
+
+    /// Get the entry for a key, for in place insertion or modification
+    ///
+    /// Modifying an occupied entry does not need another lookup.
+    pub fn entry(&mut self, key: impl AsRef<[u8]>) -> Entry<'_> {
+        let key = key.as_ref().to_vec();
+        match self.node.value_path(&key) {
+            Some(path) => Entry::Occupied(OccupiedEntry {
+                node: self.node.node_at_path_mut(&path),
+                key,
+            }),
+            None => Entry::Vacant(VacantEntry { key, tree: self }),
+        }
+    }
+
+    /// Get the value for a key, or an empty value if the key is not present
+    pub fn get_or_default(&self, key: impl AsRef<[u8]>) -> &[u8] {
+        self.node.get_slice(key.as_ref()).unwrap_or_default()
+    }
+
+    /// Borrow the value for a key
+    pub(crate) fn get_slice(&self, key: impl AsRef<[u8]>) -> Option<&[u8]> {
+        self.node.get_slice(key.as_ref())
+    }
+
     pub fn contains_key(&self, key: impl AsRef<[u8]>) -> bool {
         self.try_contains_key(key).unwrap_safe()
     }
 
+    pub fn contains_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Vec<bool> {
+        self.try_contains_many(keys).unwrap_safe()
+    }
+
+    /// Number of entries in the tree
+    pub fn len(&self) -> u64 {
+        self.try_len().unwrap_safe()
+    }
+
+    pub fn count_range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> u64 {
+        self.try_count_range(range).unwrap_safe()
+    }
+
+    pub fn count_prefix(&self, prefix: impl AsRef<[u8]>) -> u64 {
+        self.try_count_prefix(prefix).unwrap_safe()
+    }
+
     pub fn has_prefix(&self, prefix: impl AsRef<[u8]>) -> bool {
         self.try_has_prefix(prefix).unwrap_safe()
     }
 
-    pub fn remove_prefix(&mut self, prefix: impl AsRef<[u8]>) {
+    pub fn remove_prefix(&mut self, prefix: impl AsRef<[u8]>) -> RadixTree {
         self.try_remove_prefix(prefix).unwrap_safe()
     }
 
@@ -3246,16 +5604,24 @@ impl RadixTree {
         self.try_insert(key, value).unwrap_safe()
     }
 
-    pub fn remove(&mut self, key: impl AsRef<[u8]>) {
+    pub fn insert_owned(&mut self, key: impl AsRef<[u8]>, value: impl Into<Value>) {
+        self.try_insert_owned(key, value).unwrap_safe()
+    }
+
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Option<Value> {
         self.try_remove(key).unwrap_safe()
     }
 
     pub fn iter(&self) -> impl Iterator<Item = (IterKey, Value)> {
-        self.try_iter().map(|x| x.unwrap_safe())
+        self.try_iter().unwrap_safe_iter()
     }
 
     pub fn values(&self) -> impl Iterator<Item = Value> {
-        self.try_values().map(|x| x.unwrap_safe())
+        self.try_values().unwrap_safe_iter()
+    }
+
+    pub fn iter_shuffled(&self, seed: u64) -> impl Iterator<Item = (IterKey, Value)> {
+        self.try_iter_shuffled(seed).unwrap_safe_iter()
     }
 
     pub fn scan_prefix(
@@ -3264,7 +5630,37 @@ impl RadixTree {
     ) -> impl Iterator<Item = (IterKey, Value)> + '_ {
         self.try_scan_prefix(prefix)
             .unwrap_safe()
-            .map(|x| x.unwrap_safe())
+            .unwrap_safe_iter()
+    }
+
+    pub fn scan_limit(
+        &self,
+        start: impl AsRef<[u8]>,
+        limit: usize,
+    ) -> (Vec<(IterKey, Value)>, Option<IterKey>) {
+        self.try_scan_limit(start, limit).unwrap_safe()
+    }
+
+    pub fn range<K: AsRef<[u8]>>(
+        &self,
+        range: impl RangeBounds<K>,
+    ) -> impl Iterator<Item = (IterKey, Value)> {
+        self.try_range(range).unwrap_safe_iter()
+    }
+
+    pub fn scan_prefix_relative(
+        &self,
+        prefix: impl AsRef<[u8]>,
+    ) -> impl Iterator<Item = (IterKey, Value)> + '_ {
+        self.try_scan_prefix_relative(prefix)
+            .unwrap_safe()
+            .unwrap_safe_iter()
+    }
+
+    pub fn values_prefix(&self, prefix: impl AsRef<[u8]>) -> impl Iterator<Item = Value> + '_ {
+        self.try_values_prefix(prefix)
+            .unwrap_safe()
+            .unwrap_safe_iter()
     }
 
     pub fn group_by<'a>(
@@ -3306,6 +5702,27 @@ impl RadixTree {
             .unwrap_safe()
     }
 
+    pub fn intersects(&self, that: &RadixTree) -> bool {
+        self.try_intersects(that).unwrap_safe()
+    }
+
+    pub fn is_disjoint_from(&self, that: &RadixTree) -> bool {
+        self.try_is_disjoint_from(that).unwrap_safe()
+    }
+
+    pub fn intersection_count_estimate(&self, that: &RadixTree, max_exact: u64) -> u64 {
+        self.try_intersection_count_estimate(that, max_exact)
+            .unwrap_safe()
+    }
+
+    pub fn is_subset_of(&self, that: &RadixTree) -> bool {
+        self.try_is_subset_of(that).unwrap_safe()
+    }
+
+    pub fn is_superset_of(&self, that: &RadixTree) -> bool {
+        self.try_is_superset_of(that).unwrap_safe()
+    }
+
     pub fn left_combine(
         &self,
         that: &RadixTree,
@@ -3315,6 +5732,10 @@ impl RadixTree {
             .unwrap_safe()
     }
 
+    pub fn difference(&self, that: &RadixTree) -> RadixTree {
+        self.try_difference(that).unwrap_safe()
+    }
+
     pub fn left_combine_pred(
         &self,
         that: &RadixTree,
@@ -3332,22 +5753,110 @@ impl RadixTree {
         self.try_last_value().unwrap_safe()
     }
 
-    pub fn first_entry(&self, prefix: Vec<u8>) -> Option<(Vec<u8>, Value)> {
-        self.try_first_entry(prefix).unwrap_safe()
+    pub fn as_single(&self) -> Option<(Vec<u8>, Value)> {
+        self.try_as_single().unwrap_safe()
+    }
+
+    pub fn pop_first(&mut self) -> Option<(Vec<u8>, Value)> {
+        self.try_pop_first().unwrap_safe()
+    }
+
+    pub fn pop_last(&mut self) -> Option<(Vec<u8>, Value)> {
+        self.try_pop_last().unwrap_safe()
+    }
+
+    pub fn first_entry(&self) -> Option<(Vec<u8>, Value)> {
+        self.try_first_entry().unwrap_safe()
+    }
+
+    pub fn last_entry(&self) -> Option<(Vec<u8>, Value)> {
+        self.try_last_entry().unwrap_safe()
+    }
+
+    pub fn project_keys(&self, width: usize) -> RadixTree {
+        self.try_project_keys(width).unwrap_safe()
+    }
+
+    pub fn depth_profile(&self) -> Vec<LevelStats> {
+        self.try_depth_profile().unwrap_safe()
+    }
+
+    /// The `k` key prefixes of length `depth` with the most entries, heaviest first.
+    ///
+    /// See [RadixTree::try_heaviest_prefixes] for the order and the cost.
+    pub fn heaviest_prefixes(&self, depth: usize, k: usize) -> Vec<(Vec<u8>, PrefixStats)> {
+        self.try_heaviest_prefixes(depth, k).unwrap_safe()
+    }
+
+    pub fn nth(&self, n: u64) -> Option<(Vec<u8>, Value)> {
+        self.try_nth(n).unwrap_safe()
+    }
+
+    pub fn rank(&self, key: impl AsRef<[u8]>) -> u64 {
+        self.try_rank(key).unwrap_safe()
+    }
+
+    pub fn split_even(&self, n: usize) -> Vec<RadixTree> {
+        self.try_split_even(n).unwrap_safe()
     }
 
-    pub fn last_entry(&self, prefix: Vec<u8>) -> Option<(Vec<u8>, Value)> {
-        self.try_last_entry(prefix).unwrap_safe()
+    pub fn retain_range<K: AsRef<[u8]>>(&mut self, range: impl RangeBounds<K>) {
+        self.try_retain_range(range).unwrap_safe()
+    }
+
+    pub fn split_off(&mut self, key: impl AsRef<[u8]>) -> RadixTree {
+        self.try_split_off(key).unwrap_safe()
+    }
+
+    #[cfg(feature = "fst")]
+    pub fn to_fst_set(&self) -> anyhow::Result<fst::Set<Vec<u8>>> {
+        self.try_to_fst_set()
+    }
+
+    #[cfg(feature = "fst")]
+    pub fn to_fst_map(&self, f: impl FnMut(&[u8]) -> u64) -> anyhow::Result<fst::Map<Vec<u8>>> {
+        self.try_to_fst_map(f)
+    }
+}
+
+impl RadixTree {
+    pub fn leaf(value: impl AsRef<[u8]>) -> Self {
+        let res = Self::new(TreeNode::leaf(value.as_ref()), Detached);
+        res.len.set(1);
+        res
+    }
+
+    pub fn single(key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Self {
+        let res = Self::new(TreeNode::single(key.as_ref(), value.as_ref()), Detached);
+        res.len.set(1);
+        res
     }
-}
 
-impl RadixTree {
-    pub fn leaf(value: impl AsRef<[u8]>) -> Self {
-        Self::new(TreeNode::leaf(value.as_ref()), Detached)
+    /// Build a set-like tree with empty values from the keys of an [fst::Set]
+    #[cfg(feature = "fst")]
+    pub fn from_fst_set<D: AsRef<[u8]>>(set: &fst::Set<D>) -> RadixTree {
+        use fst::Streamer;
+        let mut res = RadixTree::default();
+        let mut stream = set.stream();
+        while let Some(key) = stream.next() {
+            res.insert(key, []);
+        }
+        res
     }
 
-    pub fn single(key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Self {
-        Self::new(TreeNode::single(key.as_ref(), value.as_ref()), Detached)
+    /// Build a tree from the entries of an [fst::Map], converting each u64 to a value using `f`
+    #[cfg(feature = "fst")]
+    pub fn from_fst_map<D: AsRef<[u8]>, V: AsRef<[u8]>>(
+        map: &fst::Map<D>,
+        mut f: impl FnMut(u64) -> V,
+    ) -> RadixTree {
+        use fst::Streamer;
+        let mut res = RadixTree::default();
+        let mut stream = map.stream();
+        while let Some((key, value)) = stream.next() {
+            res.insert(key, f(value));
+        }
+        res
     }
 
     pub fn outer_combine_with<S2: BlobStore<Error = NoError> + Clone>(
@@ -3377,6 +5886,24 @@ impl RadixTree {
             .unwrap_safe()
     }
 
+    pub fn outer_combine_with_shared<S2: BlobStore<Error = NoError> + Clone>(
+        &mut self,
+        that: &RadixTree<S2>,
+        f: impl Fn(&mut Value, &ValueRef<S2>) + Copy,
+    ) {
+        self.try_outer_combine_with_shared(that, DetachConverter, |a, b| Ok(f(a, b)))
+            .unwrap_safe()
+    }
+
+    pub fn left_combine_with_shared<S2: BlobStore<Error = NoError> + Clone>(
+        &mut self,
+        that: &RadixTree<S2>,
+        f: impl Fn(&mut Value, &ValueRef<S2>) + Copy,
+    ) {
+        self.try_left_combine_with_shared(that, DetachConverter, |a, b| Ok(f(a, b)))
+            .unwrap_safe()
+    }
+
     pub fn filter_prefix(
         &self,
         prefix: impl AsRef<[u8]>,
@@ -3385,6 +5912,10 @@ impl RadixTree {
         self.try_filter_prefix(prefix, substitution).unwrap_safe()
     }
 
+    pub fn retain(&mut self, mut f: impl FnMut(&[u8], &ValueRef) -> bool) {
+        self.try_retain(|k, v| Ok(f(k, v))).unwrap_safe()
+    }
+
     pub fn retain_prefix_with<S2: BlobStore<Error = NoError> + Clone>(
         &mut self,
         that: &RadixTree<S2>,
@@ -3404,17 +5935,80 @@ impl RadixTree {
     }
 }
 
+/// Iteration without results for trees using any store that can never fail
+///
+/// Detached trees have inherent methods with the same names, so this is only needed for other stores.
+pub trait InfallibleTreeExt<S: InfallibleStore> {
+    fn iter(&self) -> UnwrapSafeIter<KeyValueIter<S>>;
+
+    fn values(&self) -> UnwrapSafeIter<ValueIter<S>>;
+
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> UnwrapSafeIter<KeyValueIter<S>>;
+
+    fn scan_prefix_relative(&self, prefix: impl AsRef<[u8]>) -> UnwrapSafeIter<KeyValueIter<S>>;
+
+    fn values_prefix(&self, prefix: impl AsRef<[u8]>) -> UnwrapSafeIter<ValueIter<S>>;
+
+    fn keys_front_coded(&self) -> UnwrapSafeIter<FrontCodedKeyIter<S>>;
+
+    fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> UnwrapSafeIter<RangeIter<S>>;
+}
+
+impl<S: InfallibleStore + Clone> InfallibleTreeExt<S> for RadixTree<S> {
+    fn iter(&self) -> UnwrapSafeIter<KeyValueIter<S>> {
+        self.try_iter().unwrap_safe_iter()
+    }
+
+    fn values(&self) -> UnwrapSafeIter<ValueIter<S>> {
+        self.try_values().unwrap_safe_iter()
+    }
+
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> UnwrapSafeIter<KeyValueIter<S>> {
+        self.try_scan_prefix(prefix)
+            .unwrap_safe()
+            .unwrap_safe_iter()
+    }
+
+    fn scan_prefix_relative(&self, prefix: impl AsRef<[u8]>) -> UnwrapSafeIter<KeyValueIter<S>> {
+        self.try_scan_prefix_relative(prefix)
+            .unwrap_safe()
+            .unwrap_safe_iter()
+    }
+
+    fn values_prefix(&self, prefix: impl AsRef<[u8]>) -> UnwrapSafeIter<ValueIter<S>> {
+        self.try_values_prefix(prefix)
+            .unwrap_safe()
+            .unwrap_safe_iter()
+    }
+
+    fn keys_front_coded(&self) -> UnwrapSafeIter<FrontCodedKeyIter<S>> {
+        self.try_keys_front_coded().unwrap_safe_iter()
+    }
+
+    fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> UnwrapSafeIter<RangeIter<S>> {
+        self.try_range(range).unwrap_safe_iter()
+    }
+}
+
 impl RadixTree {
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
     fn try_attached<S: BlobStore>(&self, store: S) -> Result<RadixTree<S>, S::Error> {
         let node = self.node.try_attached(&store)?;
-        Ok(RadixTree { node, store })
+        Ok(RadixTree {
+            node,
+            store,
+            len: self.len.clone(),
+        })
     }
 }
 
 impl<S: BlobStore> RadixTree<S> {
     pub fn empty(store: S) -> Self {
-        Self::new(TreeNode::<S>::EMPTY, store)
+        Self {
+            node: TreeNode::<S>::EMPTY,
+            store,
+            len: LenCache::known(0),
+        }
     }
 
     pub fn try_load(store: S, id: Option<impl AsRef<[u8]>>) -> Result<Self, S::Error> {
@@ -3427,23 +6021,64 @@ impl<S: BlobStore> RadixTree<S> {
         })
     }
 
+    /// Copy the trees with the given root ids from `store` to `target`, returning their new root ids
+    ///
+    /// Only blobs that are reachable from one of the roots are copied. Subtrees shared between the roots
+    /// are copied once and stay shared. Once the new ids have been recorded, `store` can be discarded,
+    /// which reclaims the space of all blobs that were no longer reachable.
+    pub fn try_compact<S2: BlobStore>(
+        store: &S,
+        roots: &[impl AsRef<[u8]>],
+        target: &S2,
+    ) -> anyhow::Result<Vec<Vec<u8>>>
+    where
+        anyhow::Error: From<S::Error> + From<S2::Error>,
+    {
+        let mut copied = BTreeMap::new();
+        roots
+            .iter()
+            .map(|id| {
+                let data = store.read(id.as_ref())?;
+                let node = TreeNode::<S>::deserialize(&data)?;
+                let node = compact_node(&node, store, target, &mut copied)?;
+                let mut data = Vec::new();
+                node.serialize(&mut data, target)?;
+                Ok(target.write(&data)?)
+            })
+            .collect()
+    }
+
     fn new(node: TreeNode<S>, store: S) -> Self {
-        Self { node, store }
+        Self {
+            node,
+            store,
+            len: LenCache::default(),
+        }
     }
 
     pub fn store(this: &Self) -> &S {
         &this.store
     }
 
+    /// True if the tree has no entries
+    ///
+    /// This only inspects the root node, so it is cheap even if the length is not known.
     pub fn is_empty(&self) -> bool {
         self.node.is_empty()
     }
 
+    /// True if the tree has exactly one entry
+    ///
+    /// Like [RadixTree::is_empty], this only inspects the root node.
+    pub fn is_single_entry(&self) -> bool {
+        self.node.is_leaf() && self.node.has_value()
+    }
+
     pub fn is_leaf(&self) -> bool {
         self.node.is_leaf()
     }
 
-    pub fn value(&self) -> Option<ValueRef<S>> {
+    pub fn value(&self) -> Option<ValueRef<'_, S>> {
         let r = self.node.value_ref();
         if !r.is_none() {
             Some(ValueRef(Ok(r), PhantomData))
@@ -3452,7 +6087,7 @@ impl<S: BlobStore> RadixTree<S> {
         }
     }
 
-    pub fn prefix(&self) -> ValueRef<S> {
+    pub fn prefix(&self) -> ValueRef<'_, S> {
         let r = self.node.prefix_ref();
         ValueRef(Ok(r), PhantomData)
     }
@@ -3466,6 +6101,161 @@ impl PartialEq for RadixTree {
 
 impl Eq for RadixTree {}
 
+/// Lexicographic order of the entries, like for a [std::collections::BTreeMap]
+impl PartialOrd for RadixTree {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RadixTree {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // cheap for clones, since equality stops at shared subtrees
+        if self == other {
+            return Ordering::Equal;
+        }
+        let mut a = self.iter();
+        let mut b = other.iter();
+        loop {
+            return match (a.next(), b.next()) {
+                (Some((ak, av)), Some((bk, bv))) => {
+                    match ak
+                        .as_ref()
+                        .cmp(bk.as_ref())
+                        .then_with(|| av.as_ref().cmp(bv.as_ref()))
+                    {
+                        Ordering::Equal => continue,
+                        ordering => ordering,
+                    }
+                }
+                (Some(_), None) => Ordering::Greater,
+                (None, Some(_)) => Ordering::Less,
+                (None, None) => Ordering::Equal,
+            };
+        }
+    }
+}
+
+/// Hash of the entries, consistent with equality since trees are always canonical
+impl Hash for RadixTree {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for (k, v) in self.iter() {
+            k.as_ref().hash(state);
+            v.as_ref().hash(state);
+        }
+    }
+}
+
+/// A view into a single entry of a [RadixTree], which may either be vacant or occupied
+///
+/// Created by [RadixTree::entry].
+pub enum Entry<'a> {
+    Occupied(OccupiedEntry<'a>),
+    Vacant(VacantEntry<'a>),
+}
+
+/// An entry of a [RadixTree] that has a value
+pub struct OccupiedEntry<'a> {
+    key: Vec<u8>,
+    node: &'a mut TreeNode<Detached>,
+}
+
+/// An entry of a [RadixTree] that does not have a value
+pub struct VacantEntry<'a> {
+    key: Vec<u8>,
+    tree: &'a mut RadixTree,
+}
+
+impl<'a> Entry<'a> {
+    /// The key of this entry
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Self::Occupied(entry) => entry.key(),
+            Self::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// Insert `default` if the entry is vacant, and return the occupied entry
+    pub fn or_insert(self, default: impl AsRef<[u8]>) -> OccupiedEntry<'a> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Insert the result of `default` if the entry is vacant, and return the occupied entry
+    pub fn or_insert_with<V: AsRef<[u8]>>(self, default: impl FnOnce() -> V) -> OccupiedEntry<'a> {
+        match self {
+            Self::Occupied(entry) => entry,
+            Self::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Insert an empty value if the entry is vacant, and return the occupied entry
+    pub fn or_default(self) -> OccupiedEntry<'a> {
+        self.or_insert([])
+    }
+
+    /// Modify the value in place if the entry is occupied
+    pub fn and_modify(self, f: impl FnOnce(&mut Vec<u8>)) -> Self {
+        match self {
+            Self::Occupied(mut entry) => {
+                let mut value = entry.get().to_vec();
+                f(&mut value);
+                entry.insert(value);
+                Self::Occupied(entry)
+            }
+            Self::Vacant(entry) => Self::Vacant(entry),
+        }
+    }
+}
+
+impl<'a> OccupiedEntry<'a> {
+    /// The key of this entry
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// The value of this entry
+    pub fn get(&self) -> &[u8] {
+        self.node.value_ref().slice()
+    }
+
+    /// Convert into a reference to the value that lives as long as the borrow of the tree
+    pub fn into_ref(self) -> &'a [u8] {
+        self.node.value_ref().slice()
+    }
+
+    /// Replace the value of this entry, returning the previous value
+    pub fn insert(&mut self, value: impl AsRef<[u8]>) -> Vec<u8> {
+        let old = self.get().to_vec();
+        self.node.set_value_slice(Some(value.as_ref()));
+        old
+    }
+}
+
+impl<'a> VacantEntry<'a> {
+    /// The key of this entry
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Insert a value for this entry, and return the now occupied entry
+    pub fn insert(self, value: impl AsRef<[u8]>) -> OccupiedEntry<'a> {
+        let Self { key, tree } = self;
+        // the key is not in the tree, so the entry is always added
+        tree.len.increment();
+        let node = tree.node.insert_node(&key, value.as_ref());
+        OccupiedEntry { key, node }
+    }
+}
+
+/// Panics if the key is not present, like the std maps
+impl<K: AsRef<[u8]> + ?Sized> Index<&K> for RadixTree {
+    type Output = [u8];
+
+    fn index(&self, key: &K) -> &[u8] {
+        self.node.get_slice(key.as_ref()).expect("key not found")
+    }
+}
+
 impl<K: AsRef<[u8]>, V: AsRef<[u8]>> FromIterator<(K, V)> for RadixTree {
     fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
         let mut tree = RadixTree::default();
@@ -3488,30 +6278,184 @@ impl<S: BlobStore + Clone> RadixTree<S> {
         Ok(RadixTree {
             node,
             store: Detached,
+            len: self.len.clone(),
         })
     }
 
+    /// Run `op` on this tree and `that`, sharing reads from the store between both trees
+    ///
+    /// Both trees must be attached to the same store. Within `op`, each blob is read from the store at
+    /// most once, no matter how often and from which side it is loaded. This helps for combining trees
+    /// that share subtrees, e.g. two versions of the same tree. The cache is dropped when `op` returns.
+    #[cfg(feature = "custom-store")]
+    pub fn try_with_shared_reads<R>(
+        &self,
+        that: &RadixTree<S>,
+        op: impl FnOnce(&RadixTree<ReadCache<S>>, &RadixTree<ReadCache<S>>) -> R,
+    ) -> R {
+        fn cached<S: BlobStore + Clone>(
+            tree: &RadixTree<S>,
+            store: &ReadCache<S>,
+        ) -> RadixTree<ReadCache<S>> {
+            const {
+                assert!(
+                    std::mem::size_of::<TreeNode<S>>()
+                        == std::mem::size_of::<TreeNode<ReadCache<S>>>()
+                );
+                assert!(
+                    std::mem::align_of::<TreeNode<S>>()
+                        == std::mem::align_of::<TreeNode<ReadCache<S>>>()
+                );
+            }
+            // SAFETY: a node never stores a value of its store type. The store only appears as the
+            // PhantomData marker in ChildrenRef and as the parameter of nested TreeNodes, so the layout
+            // of TreeNode<S> does not depend on S, which the assertions above check. A ReadCache passes
+            // ids through unchanged, so the ids in the nodes are valid for both stores.
+            let node =
+                unsafe { &*(&tree.node as *const TreeNode<S> as *const TreeNode<ReadCache<S>>) };
+            RadixTree {
+                node: node.clone(),
+                store: store.clone(),
+                len: tree.len.clone(),
+            }
+        }
+        let store = ReadCache::new(self.store.clone());
+        op(&cached(self, &store), &cached(that, &store))
+    }
+
     /// Get the value for a given key
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
     fn try_get(&self, key: impl AsRef<[u8]>) -> Result<Option<Value<S>>, S::Error> {
         self.node.get(key.as_ref(), &self.store)
     }
 
+    /// Get the values for several keys, in the order of the keys
+    ///
+    /// A tree is a value, so all results come from the same version of the tree. To do a sequence of
+    /// related reads while another thread keeps modifying a tree, clone it once and read from the clone.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_get_many(
+        &self,
+        keys: impl IntoIterator<Item = impl AsRef<[u8]>>,
+    ) -> Result<Vec<Option<Value<S>>>, S::Error> {
+        keys.into_iter().map(|key| self.try_get(key)).collect()
+    }
+
+    /// The entry with the longest key that is a prefix of `key`, including `key` itself
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_longest_prefix_match(
+        &self,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Option<(Vec<u8>, Value<S>)>, S::Error> {
+        let key = key.as_ref();
+        let res = longest_prefix_match(&TreeNodeRef::owned(&self.node), &self.store, key, 0)?;
+        Ok(res.map(|(n, value)| (key[..n].to_vec(), value)))
+    }
+
+    /// Get the value for a key, together with a [Trace] of the work done by the lookup
+    ///
+    /// Useful to understand how the key design affects the number of blobs that have to be read.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_get_traced(&self, key: impl AsRef<[u8]>) -> Result<(Option<Value<S>>, Trace), S::Error> {
+        let mut trace = Trace::default();
+        let value = get_traced(
+            &TreeNodeRef::owned(&self.node),
+            &self.store,
+            key.as_ref(),
+            &mut trace,
+        )?;
+        Ok((value, trace))
+    }
+
     /// True if key is contained in this set
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
     fn try_contains_key(&self, key: impl AsRef<[u8]>) -> Result<bool, S::Error> {
         self.node.contains_key(key.as_ref(), &self.store)
     }
 
+    /// Number of entries in the tree
+    ///
+    /// The count is cached, so this only traverses the tree after a combine operation or when
+    /// the tree was just loaded.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_len(&self) -> Result<u64, S::Error> {
+        if let Some(n) = self.len.get() {
+            return Ok(n);
+        }
+        let n = count_entries(&TreeNodeRef::owned(&self.node), &self.store)?;
+        self.len.set(n);
+        Ok(n)
+    }
+
+    /// Number of entries with keys in `range`
+    ///
+    /// Only prefixes and children are read, values are never loaded from the store.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_count_range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Result<u64, S::Error> {
+        let node = TreeNodeRef::owned(&self.node);
+        let (start, end) = KeyRange::new(range).split_keys();
+        let start = match start {
+            Some(start) => rank(&node, &self.store, &start)?,
+            None => 0,
+        };
+        let end = match end {
+            Some(end) => rank(&node, &self.store, &end)?,
+            None => self.try_len()?,
+        };
+        Ok(end.saturating_sub(start))
+    }
+
+    /// Number of entries with keys starting with `prefix`
+    ///
+    /// Only prefixes and children are read, values are never loaded from the store.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_count_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<u64, S::Error> {
+        find(
+            &self.store,
+            &TreeNodeRef::owned(&self.node),
+            prefix.as_ref(),
+            |x| match x {
+                FindResult::Found(tree) | FindResult::Prefix { tree, .. } => {
+                    count_entries(tree, &self.store)
+                }
+                FindResult::NotFound => Ok(0),
+            },
+        )
+    }
+
+    /// For each key, whether it is contained in the tree
+    ///
+    /// All keys are looked up in a single descent, sharing the work for common prefixes. This is
+    /// most efficient if the keys are sorted.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_contains_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<bool>, S::Error> {
+        let mut res = vec![false; keys.len()];
+        contains_many(
+            &TreeNodeRef::owned(&self.node),
+            &self.store,
+            keys,
+            0,
+            &mut res,
+        )?;
+        Ok(res)
+    }
+
     /// True if there are keys at or below the given prefix
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
     fn try_has_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<bool, S::Error> {
         self.node.has_prefix(prefix.as_ref(), &self.store)
     }
 
+    /// Remove all entries starting with `prefix` and return them as a new tree
+    ///
+    /// The subtree is detached in a single traversal, without visiting the removed entries.
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
-    fn try_remove_prefix(&mut self, prefix: impl AsRef<[u8]>) -> Result<(), S::Error> {
-        self.try_remove_prefix_with(&RadixTree::single(prefix, &[]), |_| Ok(true))
+    fn try_remove_prefix(&mut self, prefix: impl AsRef<[u8]>) -> Result<RadixTree<S>, S::Error> {
+        let removed = self.node.take_prefix(prefix.as_ref(), &self.store)?;
+        if !removed.is_empty() {
+            self.len.invalidate();
+        }
+        Ok(RadixTree::new(removed, self.store.clone()))
     }
 
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
@@ -3520,22 +6464,34 @@ impl<S: BlobStore + Clone> RadixTree<S> {
         key: impl AsRef<[u8]>,
         value: impl AsRef<[u8]>,
     ) -> Result<(), S::Error> {
-        self.try_outer_combine_with(
-            &RadixTree::single(key, value),
-            DowncastConverter,
-            |value, replacement| {
-                value.set(Some(replacement.downcast()));
-                Ok(())
-            },
-        )
+        self.try_insert_owned(key, Value::copy_from_slice(value.as_ref()))
     }
 
+    /// Insert or replace an entry with a value that is already owned, e.g. a `Vec<u8>` or an `Arc<Vec<u8>>`
+    ///
+    /// Unlike [RadixTree::try_insert], the data of large values is moved into the tree without copying.
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
-    fn try_remove(&mut self, key: impl AsRef<[u8]>) -> Result<(), S::Error> {
-        self.try_left_combine_with(&RadixTree::single(key, &[]), DowncastConverter, |l, _| {
-            l.set(None);
-            Ok(())
-        })
+    fn try_insert_owned(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl Into<Value<S>>,
+    ) -> Result<(), S::Error> {
+        if self.node.insert(key.as_ref(), value.into(), &self.store)? {
+            self.len.increment();
+        }
+        Ok(())
+    }
+
+    /// Remove an entry, returning its value if the key was in the tree
+    ///
+    /// The tree is traversed once, and nothing is loaded into the tree or copied if the key is not there.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_remove(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Value<S>>, S::Error> {
+        let res = self.node.remove(key.as_ref(), &self.store)?;
+        if res.is_some() {
+            self.len.decrement();
+        }
+        Ok(res)
     }
 
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
@@ -3547,6 +6503,12 @@ impl<S: BlobStore + Clone> RadixTree<S> {
         )
     }
 
+    /// Like [RadixTree::try_iter], but keeps at most one blob from the store in memory, see [UnpinnedIter]
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_iter_unpinned(&self) -> UnpinnedIter<S> {
+        UnpinnedIter::new(&self.node, self.store.clone())
+    }
+
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
     fn try_values(&self) -> ValueIter<S> {
         ValueIter::new(
@@ -3555,6 +6517,60 @@ impl<S: BlobStore + Clone> RadixTree<S> {
         )
     }
 
+    /// Iterate over all entries that can be read, skipping subtrees that produce store errors
+    ///
+    /// Useful to salvage as much data as possible from a damaged store. See [LossyIter].
+    pub fn lossy_iter(&self) -> LossyIter<S> {
+        LossyIter::new(
+            TreeNodeIter::from_arc(Arc::new(vec![self.node.clone()])),
+            self.store.clone(),
+        )
+    }
+
+    /// A cursor for moving through the entries in both directions
+    pub fn cursor(&self) -> Cursor<'_, S> {
+        Cursor::new(self)
+    }
+
+    /// Iterate over all entries with keys in `range`, in key order.
+    ///
+    /// Subtrees that are entirely outside of the range are skipped without loading them.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> RangeIter<S> {
+        RangeIter::new(
+            TreeNodeIter::from_arc(Arc::new(vec![self.node.clone()])),
+            self.store.clone(),
+            KeyRange::new(range),
+        )
+    }
+
+    /// Up to `limit` entries with keys at or after `start`, and the key to resume from, if there are more.
+    ///
+    /// Passing the resume key as `start` of the next call continues the scan, without keeping
+    /// any iterator state between calls.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_scan_limit(
+        &self,
+        start: impl AsRef<[u8]>,
+        limit: usize,
+    ) -> Result<(Vec<(IterKey, Value<S>)>, Option<IterKey>), S::Error> {
+        let mut iter = self.try_range(start.as_ref()..);
+        let mut res = Vec::with_capacity(limit.min(1024));
+        for item in iter.by_ref().take(limit) {
+            res.push(item?);
+        }
+        let next = iter.next().transpose()?.map(|(key, _)| key);
+        Ok((res, next))
+    }
+
+    /// Iterate over all entries in a deterministic pseudo random order given by `seed`.
+    ///
+    /// Useful for load testing and for sampling the key space without bias towards the start.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_iter_shuffled(&self, seed: u64) -> ShuffledIter<S> {
+        ShuffledIter::new(self.node.clone(), self.store.clone(), seed)
+    }
+
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
     fn try_scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<KeyValueIter<S>, S::Error> {
         scan_prefix(
@@ -3564,6 +6580,33 @@ impl<S: BlobStore + Clone> RadixTree<S> {
         )
     }
 
+    /// Iterate over all entries whose key starts with `prefix`, with keys relative to the prefix.
+    ///
+    /// Unlike [RadixTree::try_filter_prefix], this does not build a new tree.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_scan_prefix_relative(
+        &self,
+        prefix: impl AsRef<[u8]>,
+    ) -> Result<KeyValueIter<S>, S::Error> {
+        scan_prefix_relative(
+            self.store.clone(),
+            &TreeNodeRef::owned(&self.node),
+            prefix.as_ref(),
+        )
+    }
+
+    /// Iterate over the values of all entries whose key starts with `prefix`, in key order.
+    ///
+    /// Like [RadixTree::try_scan_prefix], but without reconstructing the keys.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_values_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<ValueIter<S>, S::Error> {
+        values_prefix(
+            self.store.clone(),
+            &TreeNodeRef::owned(&self.node),
+            prefix.as_ref(),
+        )
+    }
+
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
     fn try_group_by<'a, F: Fn(&[u8], &TreeNodeRef<S>) -> Result<bool, S::Error> + 'a>(
         &'a self,
@@ -3575,31 +6618,54 @@ impl<S: BlobStore + Clone> RadixTree<S> {
             IterKey::default(),
             descend,
         )
-        .map(|r| {
-            r.map(|node| RadixTree {
-                node,
-                store: self.store.clone(),
-            })
-        })
+        .map(|r| r.map(|node| RadixTree::new(node, self.store.clone())))
+    }
+
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_outer_combine<S2, E, F>(&self, that: &RadixTree<S2>, f: F) -> Result<RadixTree, E>
+    where
+        S2: BlobStore + Clone,
+        E: From<S2::Error> + From<S::Error>,
+        F: Fn(&ValueRef<S>, &ValueRef<S2>) -> Result<Option<Value>, E> + Copy,
+    {
+        self.try_outer_combine_into(that, Detached, DetachConverter, DetachConverter, f)
     }
 
+    /// Outer combine with another tree, producing a tree that uses `store`
+    ///
+    /// Subtrees and values that are taken unchanged from `self` or `that` are converted using `ca` and `cb`.
+    /// Using [IdentityConverter] for a side that is already attached to `store` keeps referring to the stored
+    /// nodes by id instead of loading them into memory. Use [RadixTree::try_reattach] on the result to write
+    /// the new nodes to the store.
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
-    fn try_outer_combine<S2, E, F>(&self, that: &RadixTree<S2>, f: F) -> Result<RadixTree, E>
+    fn try_outer_combine_into<S2, S3, CA, CB, E, F>(
+        &self,
+        that: &RadixTree<S2>,
+        store: S3,
+        ca: CA,
+        cb: CB,
+        f: F,
+    ) -> Result<RadixTree<S3>, E>
     where
         S2: BlobStore + Clone,
-        E: From<S2::Error> + From<S::Error>,
-        F: Fn(&ValueRef<S>, &ValueRef<S2>) -> Result<Option<Value>, E> + Copy,
+        S3: BlobStore,
+        CA: NodeConverter<S, S3> + Copy,
+        CB: NodeConverter<S2, S3> + Copy,
+        S::Error: From<S3::Error>,
+        E: From<S::Error> + From<S2::Error>,
+        F: Fn(&ValueRef<S>, &ValueRef<S2>) -> Result<Option<Value<S3>>, E> + Copy,
     {
-        Ok(RadixTree {
-            node: outer_combine(
-                &TreeNodeRef::owned(&self.node),
-                self.store.clone(),
-                &TreeNodeRef::owned(&that.node),
-                that.store.clone(),
-                f,
-            )?,
-            store: Detached,
-        })
+        let node = outer_combine(
+            &TreeNodeRef::owned(&self.node),
+            self.store.clone(),
+            &TreeNodeRef::owned(&that.node),
+            that.store.clone(),
+            &store,
+            ca,
+            cb,
+            f,
+        )?;
+        Ok(RadixTree::new(node, store))
     }
 
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
@@ -3613,8 +6679,9 @@ impl<S: BlobStore + Clone> RadixTree<S> {
         S2: BlobStore + Clone,
         C: NodeConverter<S2, S> + Clone,
         F: Fn(&mut Value<S>, &ValueRef<S2>) -> Result<(), S::Error> + Copy,
-        S::Error: From<S2::Error> + From<NoError>,
+        S::Error: From<S2::Error>,
     {
+        self.len.invalidate();
         outer_combine_with(
             &mut self.node,
             self.store.clone(),
@@ -3641,6 +6708,7 @@ impl<S: BlobStore + Clone> RadixTree<S> {
                 f,
             )?,
             store: Detached,
+            len: LenCache::default(),
         })
     }
 
@@ -3655,8 +6723,9 @@ impl<S: BlobStore + Clone> RadixTree<S> {
         S2: BlobStore + Clone,
         C: NodeConverter<S2, S> + Clone,
         F: Fn(&mut Value<S>, &ValueRef<S2>) -> Result<(), S::Error> + Copy,
-        S::Error: From<S2::Error> + From<NoError>,
+        S::Error: From<S2::Error>,
     {
+        self.len.invalidate();
         inner_combine_with(
             &mut self.node,
             self.store.clone(),
@@ -3683,6 +6752,146 @@ impl<S: BlobStore + Clone> RadixTree<S> {
         )
     }
 
+    /// True if the two trees have at least one key in common
+    ///
+    /// Stops as soon as a common key is found, without building a combined tree.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_intersects<S2, E>(&self, that: &RadixTree<S2>) -> Result<bool, E>
+    where
+        S2: BlobStore + Clone,
+        E: From<S::Error> + From<S2::Error>,
+    {
+        self.try_inner_combine_pred(that, |_, _| Ok(true))
+    }
+
+    /// True if the two trees have no keys in common
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_is_disjoint_from<S2, E>(&self, that: &RadixTree<S2>) -> Result<bool, E>
+    where
+        S2: BlobStore + Clone,
+        E: From<S::Error> + From<S2::Error>,
+    {
+        Ok(!self.try_intersects::<S2, E>(that)?)
+    }
+
+    /// The number of keys the two trees have in common, exact if one of them has at most `max_exact` entries
+    ///
+    /// Otherwise, `max_exact` random walks go from the root of one tree down to one of its entries, choosing
+    /// uniformly between the value and the children of each node. The estimate is the mean of the inverse
+    /// probabilities of the walks that end at a key contained in the other tree, which is unbiased. Each walk
+    /// only loads the nodes along its path, so the cost is bounded by `max_exact` times the depth of the
+    /// trees, and the intersection is never built. The walks are seeded from the first and last keys of
+    /// both trees, so the estimate for the same trees is always the same.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_intersection_count_estimate<S2, E>(
+        &self,
+        that: &RadixTree<S2>,
+        max_exact: u64,
+    ) -> Result<u64, E>
+    where
+        S2: BlobStore + Clone,
+        E: From<S::Error> + From<S2::Error>,
+    {
+        /// the keys of a tree, if it has at most `max` entries, or else just the first one
+        fn small_keys<S: BlobStore + Clone>(
+            tree: &RadixTree<S>,
+            max: u64,
+        ) -> Result<(Option<Vec<IterKey>>, Option<IterKey>), S::Error> {
+            let mut keys = Vec::new();
+            for item in tree.try_iter().take(max.saturating_add(1) as usize) {
+                keys.push(item?.0);
+            }
+            Ok(if keys.len() as u64 <= max {
+                let first = keys.first().cloned();
+                (Some(keys), first)
+            } else {
+                (None, keys.into_iter().next())
+            })
+        }
+        /// the estimated number of keys of `small` that are in `large`
+        fn walk<A, B, E>(
+            small: &RadixTree<A>,
+            large: &RadixTree<B>,
+            samples: u64,
+            rng: &mut SplitMix64,
+        ) -> Result<u64, E>
+        where
+            A: BlobStore + Clone,
+            B: BlobStore + Clone,
+            E: From<A::Error> + From<B::Error>,
+        {
+            let node = TreeNodeRef::owned(&small.node);
+            let mut sum = 0.0;
+            for _ in 0..samples {
+                if let Some((key, p)) = random_entry(Vec::new(), &node, &small.store, rng)? {
+                    if large.try_contains_key(&key)? {
+                        sum += 1.0 / p;
+                    }
+                }
+            }
+            Ok((sum / samples as f64).round() as u64)
+        }
+        let (a, a_first) = small_keys(self, max_exact)?;
+        if let Some(keys) = a {
+            let mut res = 0;
+            for key in keys {
+                if that.try_contains_key(&key)? {
+                    res += 1;
+                }
+            }
+            return Ok(res);
+        }
+        let (b, b_first) = small_keys(that, max_exact)?;
+        if let Some(keys) = b {
+            let mut res = 0;
+            for key in keys {
+                if self.try_contains_key(&key)? {
+                    res += 1;
+                }
+            }
+            return Ok(res);
+        }
+        let mut seed = Vec::new();
+        for key in [a_first, b_first].into_iter().flatten() {
+            seed.extend_from_slice(&key);
+        }
+        if let Some((key, _)) = self.try_last_entry()? {
+            seed.extend_from_slice(&key);
+        }
+        if let Some((key, _)) = that.try_last_entry()? {
+            seed.extend_from_slice(&key);
+        }
+        let mut rng = SplitMix64::new(fnv1a(&seed));
+        let samples = max_exact.max(1);
+        // walks in the smaller tree hit more often, so prefer it if the sizes are already known
+        match (self.len.get(), that.len.get()) {
+            (Some(n), Some(m)) if m < n => walk::<S2, S, E>(that, self, samples, &mut rng),
+            _ => walk::<S, S2, E>(self, that, samples, &mut rng),
+        }
+    }
+
+    /// True if all keys of this tree are contained in `that`
+    ///
+    /// Stops as soon as a key that is not contained in `that` is found.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_is_subset_of<S2, E>(&self, that: &RadixTree<S2>) -> Result<bool, E>
+    where
+        S2: BlobStore + Clone,
+        E: From<S::Error> + From<S2::Error>,
+    {
+        Ok(!self.try_left_combine_pred::<S2, E, _>(that, |_, _| Ok(false))?)
+    }
+
+    /// True if all keys of `that` are contained in this tree
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_is_superset_of<S2, E>(&self, that: &RadixTree<S2>) -> Result<bool, E>
+    where
+        S2: BlobStore + Clone,
+        E: From<S::Error> + From<S2::Error>,
+    {
+        that.try_is_subset_of::<S, E>(self)
+    }
+
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
     fn try_left_combine<S2, E, F>(&self, that: &RadixTree<S2>, f: F) -> Result<RadixTree, E>
     where
@@ -3699,9 +6908,20 @@ impl<S: BlobStore + Clone> RadixTree<S> {
                 f,
             )?,
             store: Detached,
+            len: LenCache::default(),
         })
     }
 
+    /// All entries of this tree whose keys are not contained in `that`
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_difference<S2, E>(&self, that: &RadixTree<S2>) -> Result<RadixTree, E>
+    where
+        S2: BlobStore + Clone,
+        E: From<S2::Error> + From<S::Error>,
+    {
+        self.try_left_combine(that, |_, _| Ok(None))
+    }
+
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
     fn try_left_combine_pred<S2, E, F>(&self, that: &RadixTree<S2>, f: F) -> Result<bool, E>
     where
@@ -3729,8 +6949,9 @@ impl<S: BlobStore + Clone> RadixTree<S> {
         S2: BlobStore + Clone,
         C: NodeConverter<S2, S> + Clone,
         F: Fn(&mut Value<S>, &ValueRef<S2>) -> Result<(), S::Error> + Copy,
-        S::Error: From<S2::Error> + From<NoError>,
+        S::Error: From<S2::Error>,
     {
+        self.len.invalidate();
         left_combine_with(
             &mut self.node,
             self.store.clone(),
@@ -3741,13 +6962,96 @@ impl<S: BlobStore + Clone> RadixTree<S> {
         )
     }
 
+    /// Like [RadixTree::try_outer_combine_with], but keeps nodes untouched when `f` leaves their value unchanged
+    ///
+    /// Nodes are only copied on the path to a value that actually changes, so applying the same batch
+    /// twice does not destroy the sharing with clones of the tree taken in between.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_outer_combine_with_shared<S2, C, F>(
+        &mut self,
+        that: &RadixTree<S2>,
+        c: C,
+        f: F,
+    ) -> Result<(), S::Error>
+    where
+        S2: BlobStore + Clone,
+        C: NodeConverter<S2, S> + Clone,
+        F: Fn(&mut Value<S>, &ValueRef<S2>) -> Result<(), S::Error> + Copy,
+        S::Error: From<S2::Error>,
+    {
+        self.try_combine_with_shared(that, c, true, f)
+    }
+
+    /// Like [RadixTree::try_left_combine_with], but keeps nodes untouched when `f` leaves their value unchanged
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_left_combine_with_shared<S2, C, F>(
+        &mut self,
+        that: &RadixTree<S2>,
+        c: C,
+        f: F,
+    ) -> Result<(), S::Error>
+    where
+        S2: BlobStore + Clone,
+        C: NodeConverter<S2, S> + Clone,
+        F: Fn(&mut Value<S>, &ValueRef<S2>) -> Result<(), S::Error> + Copy,
+        S::Error: From<S2::Error>,
+    {
+        self.try_combine_with_shared(that, c, false, f)
+    }
+
+    fn try_combine_with_shared<S2, C, F>(
+        &mut self,
+        that: &RadixTree<S2>,
+        c: C,
+        outer: bool,
+        f: F,
+    ) -> Result<(), S::Error>
+    where
+        S2: BlobStore + Clone,
+        C: NodeConverter<S2, S> + Clone,
+        F: Fn(&mut Value<S>, &ValueRef<S2>) -> Result<(), S::Error> + Copy,
+        S::Error: From<S2::Error>,
+    {
+        let res = combine_with_shared(
+            &self.node,
+            self.store.clone(),
+            &TreeNodeRef::owned(&that.node),
+            that.store.clone(),
+            c,
+            outer,
+            f,
+        )?;
+        if let Some(node) = res {
+            self.node = node;
+            self.len.invalidate();
+        }
+        Ok(())
+    }
+
+    /// Retain only the entries for which the predicate returns true
+    ///
+    /// Emptied subtrees are pruned, but the rest of the tree is modified in place.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_retain(
+        &mut self,
+        mut f: impl FnMut(&[u8], &ValueRef<S>) -> Result<bool, S::Error>,
+    ) -> Result<(), S::Error> {
+        let node = TreeNodeRef::owned(&self.node);
+        if let Some(node) = retain_entries(&node, &self.store, &mut Vec::new(), &mut f)? {
+            self.len.invalidate();
+            self.node = node;
+        }
+        Ok(())
+    }
+
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
     fn try_retain_prefix_with<S2, F>(&mut self, that: &RadixTree<S2>, f: F) -> Result<(), S::Error>
     where
         S2: BlobStore + Clone,
         F: Fn(&ValueRef<S2>) -> Result<bool, S::Error> + Copy,
-        S::Error: From<S2::Error> + From<NoError>,
+        S::Error: From<S2::Error>,
     {
+        self.len.invalidate();
         retain_prefix_with(
             &mut self.node,
             self.store.clone(),
@@ -3762,8 +7066,9 @@ impl<S: BlobStore + Clone> RadixTree<S> {
     where
         S2: BlobStore + Clone,
         F: Fn(&ValueRef<S2>) -> Result<bool, S::Error> + Copy,
-        S::Error: From<S2::Error> + From<NoError>,
+        S::Error: From<S2::Error>,
     {
+        self.len.invalidate();
         remove_prefix_with(
             &mut self.node,
             self.store.clone(),
@@ -3773,6 +7078,10 @@ impl<S: BlobStore + Clone> RadixTree<S> {
         )
     }
 
+    /// The entries with keys starting with `prefix` as a standalone tree, with `prefix` replaced by `substitution`
+    ///
+    /// Use the same value for both to keep the keys, or an empty substitution to get keys relative to the prefix.
+    /// The subtree is shared with this tree, not copied.
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
     fn try_filter_prefix(
         &self,
@@ -3798,14 +7107,240 @@ impl<S: BlobStore + Clone> RadixTree<S> {
         last_value(&TreeNodeRef::owned(&self.node), &self.store)
     }
 
+    /// The only entry of the tree, or None if the tree is empty or has more than one entry
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_as_single(&self) -> Result<Option<(Vec<u8>, Value<S>)>, S::Error> {
+        Ok(if self.is_single_entry() {
+            let key = self.node.load_prefix(&self.store)?.to_vec();
+            self.node.value_opt().map(|value| (key, value.to_owned()))
+        } else {
+            None
+        })
+    }
+
+    /// Remove the entry with the smallest key and return it
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_pop_first(&mut self) -> Result<Option<(Vec<u8>, Value<S>)>, S::Error> {
+        self.try_pop(false)
+    }
+
+    /// Remove the entry with the largest key and return it
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_pop_last(&mut self) -> Result<Option<(Vec<u8>, Value<S>)>, S::Error> {
+        self.try_pop(true)
+    }
+
+    fn try_pop(&mut self, last: bool) -> Result<Option<(Vec<u8>, Value<S>)>, S::Error> {
+        let mut key = Vec::new();
+        let res = self.node.pop(last, &mut key, &self.store)?;
+        if res.is_some() {
+            self.len.decrement();
+        }
+        Ok(res.map(|value| (key, value)))
+    }
+
+    /// The entry with the smallest key, with the key assembled from the prefixes along the path
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_first_entry(&self) -> Result<Option<(Vec<u8>, Value<S>)>, S::Error> {
+        first_entry(Vec::new(), &TreeNodeRef::owned(&self.node), &self.store)
+    }
+
+    /// The entry with the largest key, with the key assembled from the prefixes along the path
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_last_entry(&self) -> Result<Option<(Vec<u8>, Value<S>)>, S::Error> {
+        last_entry(Vec::new(), &TreeNodeRef::owned(&self.node), &self.store)
+    }
+
+    /// Distinct key prefixes of length `width` as a tree with empty values
+    ///
+    /// Useful for fixed width composite keys, e.g. to get all series ids of `(series, timestamp)`
+    /// keys. Keys shorter than `width` are included completely. Only the nodes up to depth `width`
+    /// are visited.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_project_keys(&self, width: usize) -> Result<RadixTree, S::Error> {
+        if self.node.is_empty() {
+            return Ok(RadixTree::default());
+        }
+        let node = project_keys(&TreeNodeRef::owned(&self.node), &self.store, width)?;
+        Ok(RadixTree::new(node, Detached))
+    }
+
+    /// Node and entry counts per level, starting with the root at index 0
+    ///
+    /// Useful for monitoring how deep the key space is, e.g. to catch key designs where each new
+    /// key adds a level. Values are not loaded. The profile of an empty tree is empty.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_depth_profile(&self) -> Result<Vec<LevelStats>, S::Error> {
+        let mut res = Vec::new();
+        if !self.node.is_empty() {
+            depth_profile(&TreeNodeRef::owned(&self.node), &self.store, 0, &mut res)?;
+        }
+        Ok(res)
+    }
+
+    /// The `k` key prefixes of length `depth` with the most entries, heaviest first.
+    ///
+    /// Ties are broken by total size in bytes, then by key order. Keys shorter than
+    /// `depth` are reported under the complete key. Useful to pick split points when sharding.
+    ///
+    /// Nodes do not store entry counts, so this visits every node of the tree on each call. Values
+    /// stored as ids are not read, only their length is queried with [BlobStore::blob_len].
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_heaviest_prefixes(
+        &self,
+        depth: usize,
+        k: usize,
+    ) -> Result<Vec<(Vec<u8>, PrefixStats)>, S::Error> {
+        let mut res = Vec::new();
+        prefix_stats(
+            &TreeNodeRef::owned(&self.node),
+            &self.store,
+            &mut Vec::new(),
+            depth,
+            &mut res,
+        )?;
+        res.retain(|(_, stats)| stats.entries > 0);
+        res.sort_by_key(|(_, stats)| Reverse((stats.entries, stats.bytes)));
+        res.truncate(k);
+        Ok(res)
+    }
+
+    /// Copy this tree to another store, rewriting all ids
+    ///
+    /// Unlike detaching and attaching, this streams the blobs from one store to the other without loading the
+    /// entire tree into memory, so it can be used to move large trees between store implementations. Shared
+    /// subtrees are copied once, like for [RadixTree::try_compact].
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_migrate<S2: BlobStore>(&self, target: S2) -> anyhow::Result<RadixTree<S2>>
+    where
+        anyhow::Error: From<S::Error> + From<S2::Error>,
+    {
+        let node = compact_node(&self.node, &self.store, &target, &mut BTreeMap::new())?;
+        Ok(RadixTree {
+            node,
+            store: target,
+            len: self.len.clone(),
+        })
+    }
+
+    /// The entry with index `n` in key order
+    ///
+    /// This is a traversal that counts the entries of all subtrees before the entry, so it is linear in `n`.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_nth(&self, n: u64) -> Result<Option<(Vec<u8>, Value<S>)>, S::Error> {
+        nth_entry(Vec::new(), &TreeNodeRef::owned(&self.node), &self.store, n)
+    }
+
+    /// The number of keys that are smaller than `key`
+    ///
+    /// This is the index of `key` in key order if it is in the tree, otherwise the index where it would be inserted.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_rank(&self, key: impl AsRef<[u8]>) -> Result<u64, S::Error> {
+        rank(&TreeNodeRef::owned(&self.node), &self.store, key.as_ref())
+    }
+
+    /// Split the tree into `n` trees in key order, with roughly equal numbers of entries.
+    ///
+    /// The parts use the same store and share all subtrees that are not cut by a split point.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_split_even(&self, n: usize) -> Result<Vec<RadixTree<S>>, S::Error> {
+        let total = self.try_len()?;
+        // find the keys at all split points in a single pass over the entries
+        let mut indices = (1..n as u64).map(|i| i * total / n as u64).peekable();
+        let mut keys = Vec::with_capacity(n.saturating_sub(1));
+        let mut iter = self.try_iter();
+        let mut index = 0;
+        while indices.peek().is_some() {
+            let key = match iter.next() {
+                Some(entry) => entry?.0,
+                None => break,
+            };
+            while indices.next_if_eq(&index).is_some() {
+                keys.push(key.to_vec());
+            }
+            index += 1;
+        }
+        let mut res = Vec::with_capacity(n);
+        let mut rest = self.node.clone();
+        for i in 0..n.saturating_sub(1) {
+            if let Some(key) = keys.get(i) {
+                let (left, right) = split_at_key(&TreeNodeRef::owned(&rest), &self.store, key)?;
+                res.push(RadixTree::new(left, self.store.clone()));
+                rest = right;
+            } else {
+                res.push(RadixTree::empty(self.store.clone()));
+            }
+        }
+        if n > 0 {
+            res.push(RadixTree::new(rest, self.store.clone()));
+        }
+        Ok(res)
+    }
+
+    /// Retain only the entries with keys in `range`.
+    ///
+    /// Subtrees are only cut at the two boundaries of the range, everything in between is shared.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_retain_range<K: AsRef<[u8]>>(
+        &mut self,
+        range: impl RangeBounds<K>,
+    ) -> Result<(), S::Error> {
+        let (start, end) = KeyRange::new(range).split_keys();
+        if let Some(start) = start {
+            let (_, right) = split_at_key(&TreeNodeRef::owned(&self.node), &self.store, &start)?;
+            self.node = right;
+        }
+        if let Some(end) = end {
+            let (left, _) = split_at_key(&TreeNodeRef::owned(&self.node), &self.store, &end)?;
+            self.node = left;
+        }
+        self.len.invalidate();
+        Ok(())
+    }
+
+    /// Split the tree at `key`, keeping the keys before `key` and returning the keys at or after it.
+    ///
+    /// Both trees use the same store and share all subtrees that are not cut by the split point.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_split_off(&mut self, key: impl AsRef<[u8]>) -> Result<RadixTree<S>, S::Error> {
+        let (left, right) =
+            split_at_key(&TreeNodeRef::owned(&self.node), &self.store, key.as_ref())?;
+        self.node = left;
+        self.len.invalidate();
+        Ok(RadixTree::new(right, self.store.clone()))
+    }
+
+    /// Build an [fst::Set] containing all keys of the tree
+    ///
+    /// Values are not loaded, so this only reads the nodes of the tree.
+    #[cfg(feature = "fst")]
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
-    fn try_first_entry(&self, prefix: Vec<u8>) -> Result<Option<(Vec<u8>, Value<S>)>, S::Error> {
-        first_entry(prefix, &TreeNodeRef::owned(&self.node), &self.store)
+    fn try_to_fst_set(&self) -> anyhow::Result<fst::Set<Vec<u8>>>
+    where
+        anyhow::Error: From<S::Error>,
+    {
+        let mut builder = fst::SetBuilder::memory();
+        for entry in self.try_iter() {
+            let (key, _) = entry?;
+            builder.insert(&key)?;
+        }
+        Ok(builder.into_set())
     }
 
+    /// Build an [fst::Map] containing all keys of the tree, mapping each value to an u64 using `f`
+    #[cfg(feature = "fst")]
     #[cfg_attr(feature = "custom-store", visibility::make(pub))]
-    fn try_last_entry(&self, prefix: Vec<u8>) -> Result<Option<(Vec<u8>, Value<S>)>, S::Error> {
-        last_entry(prefix, &TreeNodeRef::owned(&self.node), &self.store)
+    fn try_to_fst_map(&self, mut f: impl FnMut(&[u8]) -> u64) -> anyhow::Result<fst::Map<Vec<u8>>>
+    where
+        anyhow::Error: From<S::Error>,
+    {
+        let mut builder = fst::MapBuilder::memory();
+        for entry in self.try_iter() {
+            let (key, value) = entry?;
+            let value = value.load(&self.store)?;
+            builder.insert(&key, f(&value))?;
+        }
+        Ok(builder.into_map())
     }
 
     /// Writes the entire tree to the store
@@ -3817,4 +7352,67 @@ impl<S: BlobStore + Clone> RadixTree<S> {
         self.node = TreeNode::deserialize(&data)?;
         Ok(id)
     }
+
+    /// Check that all blobs of the tree can be read from the store and decoded
+    ///
+    /// `check` is called with the id and the content of every blob, and can be used to verify
+    /// checksums or content hashes, e.g. [crate::store::ContentStore::check]. Unlike all other
+    /// operations, errors are not returned but collected in the report, so a single pass finds all damage
+    /// below the root node.
+    ///
+    /// Reading from a corrupt store returns errors instead of panicking. The exception is a corrupt id
+    /// that points back to a node above it, which makes the tree infinite, so traversing it never ends.
+    /// This is reported as an invalid blob, so a store should be verified before traversing it if it
+    /// might be damaged.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn verify(&self, check: impl Fn(&[u8], &[u8]) -> bool) -> VerifyReport {
+        let mut res = VerifyReport::default();
+        verify_node(
+            &self.node.as_ref(),
+            &self.store,
+            &check,
+            &mut Vec::new(),
+            &mut res,
+        );
+        res
+    }
+
+    /// Store ids of all blobs reachable from this tree
+    ///
+    /// This is the mark phase of a garbage collection, see [crate::roots::Roots::try_garbage]. The blob
+    /// containing the root node, as returned by [RadixTree::try_reattach], is not part of the tree.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_reachable_blobs(&self) -> Result<BTreeSet<Vec<u8>>, S::Error> {
+        let mut res = BTreeSet::new();
+        self.try_extend_reachable_blobs(&mut res)?;
+        Ok(res)
+    }
+
+    /// Add ids of all blobs reachable from this tree to `res`
+    ///
+    /// Subtrees whose id is already in `res` are not visited again, so marking several trees that
+    /// share subtrees visits each shared subtree once.
+    pub(crate) fn try_extend_reachable_blobs(
+        &self,
+        res: &mut BTreeSet<Vec<u8>>,
+    ) -> Result<(), S::Error> {
+        reachable_blobs(&self.node.as_ref(), &self.store, res)
+    }
+
+    /// Blobs that were reachable from `previous`, but are no longer reachable from this tree
+    ///
+    /// After removing or replacing entries of an attached tree and reattaching it, this gives the
+    /// blobs of the removed subtrees, so they can be freed right away instead of being leaked until
+    /// a full garbage collection of the store. Both trees must use the same store.
+    ///
+    /// The blob containing the root node of `previous`, as returned by [RadixTree::try_reattach],
+    /// is not part of the tree and must be freed separately.
+    #[cfg_attr(feature = "custom-store", visibility::make(pub))]
+    fn try_pending_free(&self, previous: &RadixTree<S>) -> Result<PendingFree, S::Error> {
+        let mut keep = BTreeSet::new();
+        reachable_blobs(&self.node.as_ref(), &self.store, &mut keep)?;
+        let mut res = PendingFree::default();
+        unreachable_blobs(&previous.node.as_ref(), &previous.store, &keep, &mut res)?;
+        Ok(res)
+    }
 }
@@ -1,13 +1,20 @@
-#![allow(clippy::redundant_clone)]
+#![allow(
+    clippy::redundant_clone,
+    clippy::almost_complete_range,
+    clippy::manual_is_multiple_of
+)]
 use crate::store::MemStore;
 use obey::{binary_element_test, binary_property_test, TestSamples};
 use proptest::prelude::*;
 use std::{
     collections::{BTreeMap, BTreeSet},
-    time::Instant,
+    io::{self, Write},
 };
 
-use super::*;
+use super::{
+    export::{MAX_TREE_BYTES_DEPTH, TREE_BYTES_HEADER},
+    *,
+};
 
 fn arb_prefix() -> impl Strategy<Value = Vec<u8>> {
     proptest::strategy::Union::new_weighted(vec![
@@ -41,6 +48,198 @@ fn to_btree_map(t: &RadixTree) -> BTreeMap<Vec<u8>, Vec<u8>> {
     t.iter().map(|(k, v)| (k.to_vec(), v.to_vec())).collect()
 }
 
+/// A sink for parallel export that collects each part into a shared buffer
+struct PartSink(Arc<std::sync::Mutex<BTreeMap<usize, Vec<u8>>>>, usize);
+
+impl Write for PartSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut parts = self.0.lock().unwrap();
+        parts.entry(self.1).or_default().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Parse entries in export format
+fn parse_export(mut data: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    fn take(data: &mut &[u8]) -> Vec<u8> {
+        let len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+        let res = data[4..4 + len].to_vec();
+        *data = &data[4 + len..];
+        res
+    }
+    let mut res = Vec::new();
+    while !data.is_empty() {
+        let key = take(&mut data);
+        let value = take(&mut data);
+        res.push((key, value));
+    }
+    res
+}
+
+/// A store that can never fail, other than the detached store
+#[derive(Debug, Clone, Default)]
+struct InfallibleMemStore(MemStore);
+
+impl BlobStore for InfallibleMemStore {
+    type Error = NoError;
+
+    fn read(&self, id: &[u8]) -> Result<OwnedBlob, NoError> {
+        Ok(self.0.read(id).unwrap())
+    }
+
+    fn write(&self, data: &[u8]) -> Result<Vec<u8>, NoError> {
+        Ok(self.0.write(data).unwrap())
+    }
+
+    fn sync(&self) -> Result<(), NoError> {
+        Ok(self.0.sync().unwrap())
+    }
+}
+
+/// A store that counts reads
+#[derive(Debug, Clone, Default)]
+struct CountingStore(MemStore, Arc<AtomicU64>);
+
+impl BlobStore for CountingStore {
+    type Error = anyhow::Error;
+
+    fn read(&self, id: &[u8]) -> anyhow::Result<OwnedBlob> {
+        self.1.fetch_add(1, atomic::Ordering::Relaxed);
+        self.0.read(id)
+    }
+
+    fn write(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.0.write(data)
+    }
+
+    fn sync(&self) -> anyhow::Result<()> {
+        self.0.sync()
+    }
+}
+
+/// A store that corrupts every blob it reads by xoring one byte, to check that corrupt stores never panic
+#[derive(Debug, Clone)]
+struct CorruptStore(MemStore, usize, u8);
+
+impl BlobStore for CorruptStore {
+    type Error = anyhow::Error;
+
+    fn read(&self, id: &[u8]) -> anyhow::Result<OwnedBlob> {
+        let mut data = self.0.read(id)?.to_vec();
+        if !data.is_empty() {
+            let i = self.1 % data.len();
+            data[i] ^= self.2;
+        }
+        Ok(Blob::from_arc_vec(Arc::new(data)))
+    }
+
+    fn write(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.0.write(data)
+    }
+
+    fn sync(&self) -> anyhow::Result<()> {
+        self.0.sync()
+    }
+
+    fn invalid_data(&self, message: &str) -> anyhow::Error {
+        self.0.invalid_data(message)
+    }
+}
+
+/// A store that returns ids that are too long to be stored in a node
+#[derive(Debug, Clone)]
+struct LongIdStore;
+
+impl BlobStore for LongIdStore {
+    type Error = NoError;
+
+    fn read(&self, _id: &[u8]) -> Result<OwnedBlob, NoError> {
+        unreachable!()
+    }
+
+    fn write(&self, _data: &[u8]) -> Result<Vec<u8>, NoError> {
+        Ok(vec![0; crate::store::MAX_ID_LEN + 1])
+    }
+
+    fn sync(&self) -> Result<(), NoError> {
+        Ok(())
+    }
+}
+
+#[test]
+#[should_panic(expected = "ids must be between 1 and 126 bytes")]
+fn long_id_rejected() {
+    let t = RadixTree::single("a", [0u8; 200]);
+    let _ = t.try_attached(LongIdStore);
+}
+
+/// Like [LongIdStore], but with an error type that can describe the problem
+#[derive(Debug, Clone)]
+struct LongIdErrorStore(MemStore);
+
+impl BlobStore for LongIdErrorStore {
+    type Error = anyhow::Error;
+
+    fn read(&self, id: &[u8]) -> anyhow::Result<OwnedBlob> {
+        self.0.read(id)
+    }
+
+    fn write(&self, _data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(vec![0; crate::store::MAX_ID_LEN + 1])
+    }
+
+    fn sync(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn invalid_data(&self, message: &str) -> anyhow::Error {
+        self.0.invalid_data(message)
+    }
+}
+
+#[test]
+fn long_id_error() {
+    let t = RadixTree::single("a", [0u8; 200]);
+    let err = t
+        .try_attached(LongIdErrorStore(MemStore::default()))
+        .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("ids must be between 1 and 126 bytes"));
+}
+
+#[cfg(feature = "no-panic-paths")]
+#[test]
+fn invalid_children_error() {
+    let store = MemStore::default();
+    // children that can not be decoded, and empty children
+    for data in [&[0xffu8, 0xff][..], &[]] {
+        let junk = store.write(data).unwrap();
+        let mut node = TreeNode::<MemStore>::EMPTY;
+        node.set_prefix_slice(b"a");
+        node.set_children_id(&[&[0u8][..], &junk].concat());
+        let tree = RadixTree::new(node, store.clone());
+        assert!(tree.try_first_value().is_err());
+        assert!(tree.try_iter().any(|r| r.is_err()));
+    }
+}
+
+#[test]
+fn read_invalid_nodes() {
+    // prefix id without a store id
+    assert!(BorrowedTreeNode::<Detached>::read(&[0x81, b'a', 0x80, 0x80]).is_none());
+    // children stored as data
+    assert!(BorrowedTreeNode::<Detached>::read(&[0x00, 0x80, 0x01, 0x00]).is_none());
+    // children with a record size but without a store id
+    assert!(BorrowedTreeNode::<Detached>::read(&[0x00, 0x80, 0x81, 0x00]).is_none());
+    // empty node
+    assert!(BorrowedTreeNode::<Detached>::read(&[0x00, 0x80, 0x80]).is_some());
+}
+
 #[test]
 fn sizes2() {
     assert_eq!(
@@ -52,10 +251,8 @@ fn sizes2() {
         4 * std::mem::size_of::<usize>()
     );
     // todo: get this to 4xusize with some union magic?
-    assert_eq!(
-        std::mem::size_of::<TreeNodeRef<Detached>>(),
-        5 * std::mem::size_of::<usize>()
-    );
+    // newer compilers already get there using the niche in the discriminator of BorrowedTreeNode
+    assert!(std::mem::size_of::<TreeNodeRef<Detached>>() <= 5 * std::mem::size_of::<usize>());
     println!("{}", std::mem::size_of::<TreeNode<Detached>>());
     println!("{}", std::mem::size_of::<BorrowedTreeNode<Detached>>());
     println!("{}", std::mem::size_of::<TreeNodeRef<Detached>>());
@@ -113,125 +310,6 @@ fn sizes2() {
 //     assert!(tree.contains_key("romane"));
 // }
 
-#[test]
-fn new_build_bench() {
-    let elems = (0..2_000_000u64)
-        .map(|i| {
-            if i % 100000 == 0 {
-                println!("{}", i);
-            }
-            (
-                i.to_string().as_bytes().to_vec(),
-                i.to_string().as_bytes().to_vec(),
-            )
-        })
-        .collect::<Vec<_>>();
-    let elems2 = elems.clone();
-    let elems3 = elems.clone();
-    let elems_bt = elems.iter().cloned().collect::<BTreeMap<_, _>>();
-    let mut t = TreeNode::<Detached>::EMPTY;
-    let t0 = Instant::now();
-    for (k, v) in elems.clone() {
-        let b = TreeNode::<Detached>::single(&k, &v);
-        outer_combine_with(
-            &mut t,
-            Detached,
-            &b.as_ref(),
-            Detached,
-            DowncastConverter,
-            |_, _| Ok(()),
-        )
-        .unwrap();
-    }
-    println!("build {}", t0.elapsed().as_secs_f64());
-
-    let t0 = Instant::now();
-    let _r: BTreeMap<_, _> = elems2.into_iter().collect();
-    println!("build ref {:#?}", t0.elapsed().as_secs_f64());
-
-    let t0 = Instant::now();
-    for (key, _value) in &elems3 {
-        assert!(t.contains_key(key, &Detached).unwrap());
-    }
-    println!("validate contains_key {}", t0.elapsed().as_secs_f64());
-
-    let t0 = Instant::now();
-    for (key, _value) in &elems3 {
-        assert!(elems_bt.contains_key(key));
-    }
-    println!("validate contains_key ref {}", t0.elapsed().as_secs_f64());
-
-    let t0 = Instant::now();
-    for (key, value) in &elems3 {
-        let v: Value = t.get(key, &Detached).unwrap().unwrap();
-        assert_eq!(v.read().unwrap(), &value[..]);
-    }
-    println!("validate get {}", t0.elapsed().as_secs_f64());
-
-    let t0 = Instant::now();
-    for (key, value) in &elems3 {
-        let v = elems_bt.get(key).unwrap();
-        assert_eq!(v, value);
-    }
-    println!("validate get ref {}", t0.elapsed().as_secs_f64());
-
-    let store = MemStore::default();
-    let mut target = Vec::new();
-    let t0 = Instant::now();
-    // t.dump(0, &NoStore).unwrap();
-    t.serialize(&mut target, &store).unwrap();
-    println!("{} {}", Hex::new(&target), t0.elapsed().as_secs_f64());
-    let d = TreeNode::<MemStore>::deserialize(&target).unwrap();
-    println!("{:?}", d);
-    // d.dump(0, &store).unwrap();
-
-    let t0 = Instant::now();
-    for (key, value) in &elems3 {
-        let v: Value<MemStore> = d.get(key, &store).unwrap().unwrap();
-        assert_eq!(v.read().unwrap(), &value[..]);
-    }
-    println!("validate get attached {}", t0.elapsed().as_secs_f64());
-}
-
-#[test]
-fn new_smoke() {
-    {
-        let a = TreeNode::single(b"a", b"1");
-        let b = TreeNode::single(b"b", b"2");
-        println!("a={:?}", a);
-        println!("b={:?}", b);
-        let mut r = a;
-        outer_combine_with(
-            &mut r,
-            Detached,
-            &b.as_ref(),
-            Detached,
-            DowncastConverter,
-            |_, _| Ok(()),
-        )
-        .unwrap();
-        println!("r={:?}", r);
-    }
-
-    {
-        let a = TreeNode::<Detached>::single(b"aa", b"1");
-        let b = TreeNode::<Detached>::single(b"ab", b"2");
-        println!("a={:?}", a);
-        println!("b={:?}", b);
-        let mut r = a;
-        outer_combine_with(
-            &mut r,
-            Detached,
-            &b.as_ref(),
-            Detached,
-            DowncastConverter,
-            |_, _| Ok(()),
-        )
-        .unwrap();
-        println!("r={:?}", r);
-    }
-}
-
 impl TestSamples<Vec<u8>, Option<Vec<u8>>> for RadixTree {
     fn samples(&self, res: &mut BTreeSet<Vec<u8>>) {
         res.insert(vec![]);
@@ -268,7 +346,7 @@ proptest! {
         let tree = mk_owned_tree(&reference);
         let store = MemStore::default();
         let tree = tree.try_attached(store.clone()).unwrap();
-        let actual = tree.try_iter().map(|e| {
+        let actual: BTreeMap<_, _> = tree.try_iter().map(|e| {
             let (k, v) = e.unwrap();
             (k.to_vec(), v.load(&store).unwrap().to_vec())
         }).collect();
@@ -279,6 +357,368 @@ proptest! {
         prop_assert_eq!(reference, actual);
     }
 
+    #[test]
+    fn insert_remove(a in arb_tree_contents(), b in arb_tree_contents()) {
+        // canonical tree built without insert
+        let combined = |x: &BTreeMap<Vec<u8>, Vec<u8>>| x.iter().fold(RadixTree::default(), |t, (k, v)| {
+            t.outer_combine(&RadixTree::single(k, v), |_, b| Some(b.to_owned()))
+        });
+        let mut reference = a.clone();
+        let mut tree = mk_owned_tree(&a);
+        let store = MemStore::default();
+        let mut attached = tree.try_attached(store.clone()).unwrap();
+        attached.try_reattach().unwrap();
+        for (k, v) in &b {
+            reference.insert(k.clone(), v.clone());
+            tree.insert(k, v);
+            attached.try_insert(k, v).unwrap();
+        }
+        prop_assert_eq!(&tree, &combined(&reference));
+        prop_assert_eq!(&attached.try_detached().unwrap(), &tree);
+        for k in a.keys().step_by(2).chain(b.keys().skip(1).step_by(2)) {
+            let expected = reference.remove(k);
+            prop_assert_eq!(tree.remove(k).map(|v| v.to_vec()), expected.clone());
+            let removed = attached.try_remove(k).unwrap();
+            prop_assert_eq!(removed.map(|v| v.load(&store).unwrap().to_vec()), expected);
+        }
+        prop_assert_eq!(&tree, &combined(&reference));
+        prop_assert_eq!(&attached.try_detached().unwrap(), &tree);
+        // removing keys that are not there neither loads nor copies anything
+        attached.try_reattach().unwrap();
+        let children = attached.node.get_children().map_err(|id| id.to_vec()).err();
+        let shared = tree.clone();
+        for k in a.keys().chain(b.keys()).filter(|k| !reference.contains_key(*k)) {
+            prop_assert!(tree.remove(k).is_none());
+            prop_assert!(attached.try_remove(k).unwrap().is_none());
+        }
+        prop_assert_eq!(attached.node.get_children().map_err(|id| id.to_vec()).err(), children);
+        if let (Ok(a), Ok(b)) = (tree.node.get_children(), shared.node.get_children()) {
+            prop_assert!(Arc::ptr_eq(a, b));
+        }
+    }
+
+    #[test]
+    fn insert_owned(a in arb_tree_contents(), b in arb_tree_contents()) {
+        let mut tree = mk_owned_tree(&a);
+        let mut owned = mk_owned_tree(&a);
+        for (i, (k, v)) in b.iter().enumerate() {
+            tree.insert(k, v);
+            if i % 2 == 0 {
+                owned.insert_owned(k, v.clone());
+            } else {
+                owned.insert_owned(k, Arc::new(v.clone()));
+            }
+        }
+        prop_assert_eq!(&owned, &tree);
+        prop_assert_eq!(owned.len(), tree.len());
+    }
+
+    #[test]
+    fn iter_unpinned(a in arb_tree_contents(), b in arb_tree_contents()) {
+        let store = MemStore::default();
+        let mut attached = mk_owned_tree(&a).try_attached(store.clone()).unwrap();
+        attached.try_reattach().unwrap();
+        // mix of children in memory and in the store
+        for (k, v) in &b {
+            attached.try_insert(k, v).unwrap();
+        }
+        let expected = attached.try_iter().map(|r| {
+            let (k, v) = r.unwrap();
+            (k.to_vec(), v.load(&store).unwrap().to_vec())
+        }).collect::<Vec<_>>();
+        let actual = attached.try_iter_unpinned().map(|r| {
+            let (k, v) = r.unwrap();
+            (k.to_vec(), v.load(&store).unwrap().to_vec())
+        }).collect::<Vec<_>>();
+        prop_assert_eq!(actual, expected);
+        prop_assert_eq!(mk_owned_tree(&a).try_iter_unpinned().count(), a.len());
+    }
+
+    #[test]
+    fn to_from_bytes(a in arb_tree_contents()) {
+        let tree = mk_owned_tree(&a);
+        let data = tree.to_bytes();
+        let decoded = RadixTree::from_bytes(&data).unwrap();
+        prop_assert_eq!(&decoded, &tree);
+        prop_assert_eq!(decoded.len(), a.len() as u64);
+        // the encoding does not depend on insertion order or on the store
+        let reversed = a.iter().rev().collect::<RadixTree>();
+        prop_assert_eq!(&reversed.to_bytes(), &data);
+        let store = MemStore::default();
+        let mut attached = tree.try_attached(store).unwrap();
+        attached.try_reattach().unwrap();
+        prop_assert_eq!(&attached.try_to_bytes().unwrap(), &data);
+        // streaming gives the same bytes, and reads only the tree
+        let mut streamed = Vec::new();
+        let bytes = attached.try_write_to(&mut streamed).unwrap();
+        prop_assert_eq!(bytes, data.len() as u64);
+        prop_assert_eq!(&streamed, &data);
+        streamed.extend_from_slice(b"rest");
+        let mut source = &streamed[..];
+        prop_assert_eq!(&RadixTree::read_from(&mut source).unwrap(), &tree);
+        prop_assert_eq!(source, b"rest");
+    }
+
+    #[test]
+    fn with_shared_reads(a in arb_tree_contents(), b in arb_tree_contents()) {
+        let store = MemStore::default();
+        let mut at = mk_owned_tree(&a).try_attached(store.clone()).unwrap();
+        at.try_reattach().unwrap();
+        let mut bt = mk_owned_tree(&b).try_attached(store.clone()).unwrap();
+        bt.try_reattach().unwrap();
+        let expected = mk_owned_tree(&a).inner_combine(&mk_owned_tree(&b), |a, _| Some(a.to_owned()));
+        let actual = at.try_with_shared_reads(&bt, |a, b| {
+            let store = RadixTree::store(a).clone();
+            a.try_inner_combine(b, |a, _| Ok::<_, anyhow::Error>(Some(a.detached(&store)?)))
+        }).unwrap();
+        prop_assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn export_import_chunked(a in arb_tree_contents(), chunk_size in 1usize..50) {
+        let tree = mk_owned_tree(&a);
+        let mut chunks = Vec::new();
+        let manifest = tree.export_chunked(|i, chunk| {
+            assert_eq!(i, chunks.len());
+            chunks.push(chunk.to_vec());
+            Ok(())
+        }, chunk_size).unwrap();
+        prop_assert_eq!(chunks.concat(), tree.to_bytes());
+        prop_assert_eq!(manifest.bytes, tree.to_bytes().len() as u64);
+        prop_assert!(chunks.iter().rev().skip(1).all(|c| c.len() == chunk_size));
+        let mut import = RadixTree::import_chunked(manifest);
+        for (i, chunk) in chunks.iter().enumerate() {
+            import.add(i, chunk).unwrap();
+        }
+        prop_assert_eq!(import.finish().unwrap(), tree);
+    }
+
+    #[test]
+    fn diff_summary(a in arb_tree_contents(), b in arb_tree_contents(), depth in 0usize..4) {
+        let mut expected = BTreeMap::<Vec<u8>, DiffStats>::new();
+        let mut add = |k: &Vec<u8>, v: &Vec<u8>, side: fn(&mut DiffStats) -> &mut PrefixStats| {
+            let group = k[..k.len().min(depth)].to_vec();
+            side(expected.entry(group).or_default()).add(PrefixStats { entries: 1, bytes: (k.len() + v.len()) as u64 });
+        };
+        for (k, v) in &a {
+            match b.get(k) {
+                None => add(k, v, |x| &mut x.removed),
+                Some(bv) if bv != v => add(k, bv, |x| &mut x.changed),
+                Some(_) => {}
+            }
+        }
+        for (k, v) in &b {
+            if !a.contains_key(k) {
+                add(k, v, |x| &mut x.added);
+            }
+        }
+        let expected = expected.into_iter().collect::<Vec<_>>();
+        let at = mk_owned_tree(&a);
+        let bt = mk_owned_tree(&b);
+        prop_assert_eq!(at.diff_summary(&bt, depth), expected.clone());
+        let mut entries = Vec::new();
+        at.diff(&bt, |k, av, bv| {
+            entries.push((k.to_vec(), av.map(|x| x.to_owned().to_vec()), bv.map(|x| x.to_owned().to_vec())))
+        });
+        let expected_entries = a.keys().chain(b.keys()).collect::<BTreeSet<_>>().into_iter()
+            .map(|k| (k.clone(), a.get(k).cloned(), b.get(k).cloned()))
+            .filter(|(_, av, bv)| av != bv)
+            .collect::<Vec<_>>();
+        prop_assert_eq!(entries, expected_entries);
+        // attached, with b as a modified version of a so subtrees are shared
+        let store = MemStore::default();
+        let mut attached = at.try_attached(store.clone()).unwrap();
+        attached.try_reattach().unwrap();
+        let mut modified = attached.clone();
+        for k in a.keys() {
+            if !b.contains_key(k) {
+                modified.try_remove(k).unwrap();
+            }
+        }
+        for (k, v) in &b {
+            modified.try_insert(k, v).unwrap();
+        }
+        prop_assert_eq!(attached.try_diff_summary(&modified, depth).unwrap(), expected);
+    }
+
+    #[test]
+    fn intersection_count_exact(a in arb_tree_contents(), b in arb_tree_contents()) {
+        let at = mk_owned_tree(&a);
+        let bt = mk_owned_tree(&b);
+        let expected = a.keys().filter(|k| b.contains_key(*k)).count() as u64;
+        let max_exact = a.len().min(b.len()) as u64;
+        prop_assert_eq!(at.intersection_count_estimate(&bt, max_exact), expected);
+        prop_assert_eq!(bt.intersection_count_estimate(&at, max_exact), expected);
+    }
+
+    #[test]
+    fn changes(a in arb_tree_contents(), b in arb_tree_contents()) {
+        let at = mk_owned_tree(&a);
+        let mut bt = at.clone();
+        for k in a.keys().filter(|k| !b.contains_key(*k)) {
+            bt.remove(k);
+        }
+        for (k, v) in &b {
+            bt.insert(k, v);
+        }
+        let mut expected = Vec::new();
+        for k in a.keys().chain(b.keys()).collect::<BTreeSet<_>>() {
+            match (a.get(k), b.get(k)) {
+                (Some(x), Some(y)) if x != y => expected.push(Change::Modified(k.clone(), x.clone(), y.clone())),
+                (Some(x), None) => expected.push(Change::Removed(k.clone(), x.clone())),
+                (None, Some(y)) => expected.push(Change::Added(k.clone(), y.clone())),
+                _ => {}
+            }
+        }
+        prop_assert_eq!(at.changes(&bt), expected);
+    }
+
+    #[test]
+    fn corrupt_store_no_panic(a in arb_tree_contents(), i in any::<usize>(), x in 1..=255u8) {
+        check_corrupt_store(&a, i, x);
+    }
+
+    #[test]
+    fn corrupt_store_large_no_panic(
+        a in proptest::collection::btree_map(
+            proptest::collection::vec(b'a'..=b'c', 0..300),
+            proptest::collection::vec(any::<u8>(), 0..2000),
+            0..20,
+        ),
+        depth in 0..300usize,
+        i in any::<usize>(),
+        x in 1..=255u8,
+    ) {
+        // long keys and values are stored as separate blobs, and a chain of keys gives a deep tree
+        let mut a = a;
+        for n in 0..depth {
+            a.insert(vec![b'c'; n], vec![0; n]);
+        }
+        check_corrupt_store(&a, i, x);
+    }
+
+    #[test]
+    fn from_bytes_no_panic(data in proptest::collection::vec(any::<u8>(), 0..10000)) {
+        let _ = RadixTree::from_bytes(&data);
+        let mut data = data;
+        data.splice(0..0, TREE_BYTES_HEADER.iter().cloned());
+        let _ = RadixTree::from_bytes(&data);
+        let _ = RadixTree::read_from(&data[..]);
+        let _ = TreeNode::<MemStore>::deserialize(&data);
+    }
+
+    #[test]
+    fn from_bytes_nested_no_panic(
+        depth in 0..100_000usize,
+        edits in proptest::collection::vec((any::<usize>(), any::<u8>()), 0..8),
+    ) {
+        let mut data = nested_tree_bytes(depth);
+        for (i, x) in edits {
+            let i = i % data.len();
+            data[i] = x;
+        }
+        let _ = RadixTree::from_bytes(&data);
+        let _ = RadixTree::read_from(&data[..]);
+    }
+
+    #[test]
+    #[cfg(feature = "merkle")]
+    fn merkle_proofs(a in arb_tree_contents(), b in arb_tree_contents()) {
+        let at = mk_owned_tree(&a);
+        let root = at.root_hash();
+        for (k, v) in &a {
+            let proof = at.prove(k).unwrap();
+            prop_assert!(proof.verify(&root, k, v));
+            prop_assert!(!proof.verify(&root, k, &[v.as_slice(), b"x"].concat()));
+            prop_assert!(!proof.verify(&[0; 32], k, v));
+        }
+        for k in b.keys().filter(|k| !a.contains_key(*k)) {
+            prop_assert!(at.prove(k).is_none());
+        }
+        // the hash only depends on the entries
+        let mut ab = mk_owned_tree(&b);
+        for k in b.keys() {
+            ab.remove(k);
+        }
+        for (k, v) in a.iter().rev() {
+            ab.insert(k, v);
+        }
+        prop_assert_eq!(ab.root_hash(), root);
+        let attached = at.try_attached(MemStore::default()).unwrap();
+        prop_assert_eq!(attached.try_root_hash().unwrap(), root);
+    }
+
+    #[test]
+    fn migrate(a in arb_tree_contents()) {
+        use crate::store::ContentStore;
+        let at = mk_owned_tree(&a);
+        let mem = MemStore::default();
+        let mut attached = at.try_attached(mem.clone()).unwrap();
+        let id = attached.try_reattach().unwrap();
+        let loaded = RadixTree::try_load(mem, Some(&id)).unwrap();
+        let target = ContentStore::default();
+        let migrated = loaded.try_migrate(target.clone()).unwrap();
+        prop_assert_eq!(migrated.try_detached().unwrap(), at.clone());
+        let report = migrated.verify(ContentStore::check);
+        prop_assert!(report.is_ok());
+        prop_assert!(report.unreachable(target.ids()).is_empty());
+        let direct = at.try_migrate(ContentStore::default()).unwrap();
+        prop_assert_eq!(direct.try_detached().unwrap(), at);
+    }
+
+    #[test]
+    fn len(a in arb_tree_contents(), b in arb_tree_contents()) {
+        let mut reference = a.clone();
+        let mut tree = RadixTree::default();
+        for (k, v) in &a {
+            tree.insert(k, v);
+        }
+        prop_assert_eq!(tree.len(), reference.len() as u64);
+        let store = MemStore::default();
+        let mut attached = tree.try_attached(store.clone()).unwrap();
+        let id = attached.try_reattach().unwrap();
+        let mut loaded = RadixTree::try_load(store, Some(id)).unwrap();
+        for (k, v) in &b {
+            reference.insert(k.clone(), v.clone());
+            tree.insert(k, v);
+            attached.try_insert(k, v).unwrap();
+            loaded.try_insert(k, v).unwrap();
+        }
+        for k in a.keys().step_by(2) {
+            reference.remove(k);
+            tree.remove(k);
+            attached.try_remove(k).unwrap();
+            loaded.try_remove(k).unwrap();
+        }
+        let n = reference.len() as u64;
+        prop_assert_eq!(tree.len(), n);
+        prop_assert_eq!(tree.is_empty(), n == 0);
+        prop_assert_eq!(attached.try_len().unwrap(), n);
+        prop_assert_eq!(loaded.try_len().unwrap(), n);
+        prop_assert_eq!(attached.try_detached().unwrap().len(), n);
+        let b_tree = mk_owned_tree(&b);
+        tree.outer_combine_with(&b_tree, |_, _| {});
+        reference.extend(b);
+        prop_assert_eq!(tree.len(), reference.len() as u64);
+    }
+
+    #[test]
+    fn entry(a in arb_tree_contents(), b in arb_tree_contents()) {
+        let mut reference = a.clone();
+        let mut tree = mk_owned_tree(&a);
+        let original = tree.clone();
+        for (k, v) in a.keys().step_by(2).map(|k| (k, &b"x"[..])).chain(b.iter().map(|(k, v)| (k, v.as_slice()))) {
+            reference.entry(k.clone()).and_modify(|x| x.push(0)).or_insert_with(|| v.to_vec());
+            let entry = tree.entry(k).and_modify(|x| x.push(0)).or_insert(v);
+            prop_assert_eq!(entry.key(), k.as_slice());
+            prop_assert_eq!(entry.get(), reference[k].as_slice());
+        }
+        prop_assert_eq!(&tree, &mk_owned_tree(&reference));
+        prop_assert_eq!(tree.len(), reference.len() as u64);
+        // modifying does not affect clones
+        prop_assert_eq!(to_btree_map(&original), a);
+    }
+
     #[test]
     fn get_contains(x in arb_tree_contents()) {
         let reference = x;
@@ -302,9 +742,264 @@ proptest! {
         }
     }
 
+    #[test]
+    fn scan_prefix_relative(x in arb_tree_contents(), prefix in arb_prefix()) {
+        let tree = mk_owned_tree(&x);
+        let actual = tree.scan_prefix_relative(&prefix).map(|(k, v)| (k.to_vec(), v.to_vec())).collect::<Vec<_>>();
+        let expected = x.iter()
+            .filter_map(|(k, v)| Some((k.strip_prefix(prefix.as_slice())?.to_vec(), v.clone())))
+            .collect::<Vec<_>>();
+        prop_assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn values_prefix(x in arb_tree_contents(), prefix in any::<Vec<u8>>()) {
+        let tree = mk_owned_tree(&x);
+        let expected = tree.scan_prefix(&prefix).map(|(_, v)| v.to_vec()).collect::<Vec<_>>();
+        let actual = tree.values_prefix(&prefix).map(|v| v.to_vec()).collect::<Vec<_>>();
+        prop_assert_eq!(actual, expected);
+    }
 
     #[test]
-    fn filter_prefix(x in arb_tree_contents(), prefix in any::<Vec<u8>>()) {
+    fn keys_front_coded(x in arb_tree_contents()) {
+        let tree = mk_owned_tree(&x);
+        let mut prev = Vec::new();
+        let mut keys = Vec::new();
+        for (shared, suffix) in tree.keys_front_coded() {
+            prop_assert!(shared <= prev.len());
+            // shared is the entire common prefix with the previous key
+            prop_assert!(keys.is_empty() || suffix.first() != prev.get(shared));
+            prev.truncate(shared);
+            prev.extend_from_slice(&suffix);
+            keys.push(prev.clone());
+        }
+        prop_assert_eq!(keys, x.keys().cloned().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn export_keys_front_coded(x in arb_tree_contents()) {
+        let tree = mk_owned_tree(&x);
+        let mut data = Vec::new();
+        let bytes = tree.export_keys_front_coded(&mut data).unwrap();
+        prop_assert_eq!(bytes, data.len() as u64);
+        let keys = FrontCodedKeyReader::new(data.as_slice()).collect::<io::Result<Vec<_>>>().unwrap();
+        prop_assert_eq!(keys, x.keys().cloned().collect::<Vec<_>>());
+        // every proper prefix of the data is either a shorter list or an error
+        for n in 0..data.len() {
+            let keys = FrontCodedKeyReader::new(&data[..n]).collect::<io::Result<Vec<_>>>();
+            if let Ok(keys) = keys {
+                prop_assert!(keys.len() < x.len());
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "fst")]
+    fn fst_roundtrip(x in arb_tree_contents()) {
+        let tree = mk_owned_tree(&x);
+        let set = tree.to_fst_set().unwrap();
+        prop_assert_eq!(set.stream().into_bytes(), x.keys().cloned().collect::<Vec<_>>());
+        let map = tree.to_fst_map(|v| v.len() as u64).unwrap();
+        let expected = x.iter().map(|(k, v)| (k.clone(), v.len() as u64)).collect::<Vec<_>>();
+        prop_assert_eq!(map.stream().into_byte_vec(), expected);
+        let tree = RadixTree::from_fst_map(&map, |n| vec![0u8; n as usize]);
+        let expected = x.iter().map(|(k, v)| (k.clone(), vec![0u8; v.len()])).collect::<BTreeMap<_, _>>();
+        prop_assert_eq!(to_btree_map(&tree), expected);
+        let set = RadixTree::from_fst_set(&tree.to_fst_set().unwrap());
+        prop_assert_eq!(set.iter().map(|(k, _)| k.to_vec()).collect::<Vec<_>>(), x.keys().cloned().collect::<Vec<_>>());
+        let store = MemStore::default();
+        let mut attached = mk_owned_tree(&x).try_attached(store).unwrap();
+        attached.try_reattach().unwrap();
+        let map = attached.try_to_fst_map(|v| v.len() as u64).unwrap();
+        prop_assert_eq!(map.stream().into_byte_vec().len(), x.len());
+    }
+
+    #[test]
+    #[cfg(feature = "spill")]
+    fn from_unsorted_iter_with_spill(entries in prop::collection::vec((arb_prefix(), arb_prefix()), 0..100), budget in 0usize..200) {
+        // duplicate keys, to check that later entries win
+        let entries = entries.iter().chain(entries.iter().rev()).collect::<Vec<_>>();
+        let expected = entries.iter().map(|(k, v)| (k, v)).collect::<RadixTree>();
+        let actual = RadixTree::from_unsorted_iter_with_spill(entries.iter().map(|(k, v)| (k, v)), budget, Detached).unwrap();
+        prop_assert_eq!(actual.len(), expected.len());
+        prop_assert_eq!(&actual, &expected);
+        let store = MemStore::default();
+        let attached = RadixTree::from_unsorted_iter_with_spill(entries.iter().map(|(k, v)| (k, v)), budget, store).unwrap();
+        prop_assert_eq!(attached.try_len().unwrap(), expected.len());
+        // all nodes are in the store, only the root is in memory
+        prop_assert!(attached.node.get_children().map(|c| c.is_empty()).unwrap_or(true));
+        prop_assert_eq!(attached.try_detached().unwrap(), expected);
+    }
+
+    #[test]
+    fn range(x in arb_tree_contents(), start in arb_prefix(), end in arb_prefix(), kinds in (0..3u8, 0..3u8)) {
+        let bound = |x: &Vec<u8>, kind| match kind {
+            0 => Bound::Included(x.clone()),
+            1 => Bound::Excluded(x.clone()),
+            _ => Bound::Unbounded,
+        };
+        let range = (bound(&start, kinds.0), bound(&end, kinds.1));
+        let tree = mk_owned_tree(&x);
+        let actual = tree.range(range.clone()).map(|(k, v)| (k.to_vec(), v.to_vec())).collect::<Vec<_>>();
+        let expected = x.into_iter().filter(|(k, _)| range.contains(k)).collect::<Vec<_>>();
+        prop_assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn ord_hash(a in arb_tree_contents(), b in arb_tree_contents()) {
+        let hash = |t: &RadixTree| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            t.hash(&mut hasher);
+            hasher.finish()
+        };
+        let at = mk_owned_tree(&a);
+        let bt = mk_owned_tree(&b);
+        prop_assert_eq!(at.cmp(&bt), a.cmp(&b));
+        prop_assert_eq!(at == bt, a == b);
+        prop_assert_eq!(at.cmp(&at.clone()), Ordering::Equal);
+        // trees with the same content built in a different way have the same hash
+        let mut rebuilt = RadixTree::default();
+        for (k, v) in a.iter().rev() {
+            rebuilt.insert(k, v);
+        }
+        prop_assert_eq!(hash(&at), hash(&rebuilt));
+    }
+
+    #[test]
+    fn scan_limit(x in arb_tree_contents(), limit in 1usize..5) {
+        let tree = mk_owned_tree(&x);
+        let mut all = Vec::new();
+        let mut start = Vec::new();
+        loop {
+            let (page, next) = tree.scan_limit(&start, limit);
+            prop_assert!(page.len() <= limit);
+            all.extend(page.into_iter().map(|(k, v)| (k.to_vec(), v.to_vec())));
+            match next {
+                Some(key) => {
+                    prop_assert_eq!(all.len() % limit, 0);
+                    start = key.to_vec();
+                }
+                None => break,
+            }
+        }
+        prop_assert_eq!(all, x.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn contains_many(x in arb_tree_contents(), mut keys in prop::collection::vec(arb_prefix(), 0..20)) {
+        let tree = mk_owned_tree(&x);
+        let store = MemStore::default();
+        let mut attached = tree.try_attached(store).unwrap();
+        attached.try_reattach().unwrap();
+        // unsorted with duplicates, then sorted including all keys of the tree
+        for _ in 0..2 {
+            let expected = keys.iter().map(|k| x.contains_key(k)).collect::<Vec<_>>();
+            prop_assert_eq!(&tree.contains_many(&keys), &expected);
+            prop_assert_eq!(&attached.try_contains_many(&keys).unwrap(), &expected);
+            keys.extend(x.keys().cloned());
+            keys.sort();
+        }
+    }
+
+    #[test]
+    fn longest_prefix_match(x in arb_tree_contents(), key in arb_prefix()) {
+        let expected = x
+            .iter()
+            .filter(|(k, _)| key.starts_with(k))
+            .max_by_key(|(k, _)| k.len())
+            .map(|(k, v)| (k.clone(), v.clone()));
+        let tree = mk_owned_tree(&x);
+        let actual = tree.longest_prefix_match(&key).map(|(k, v)| (k, v.to_vec()));
+        prop_assert_eq!(actual, expected.clone());
+        let store = MemStore::default();
+        let mut attached = tree.try_attached(store.clone()).unwrap();
+        attached.try_reattach().unwrap();
+        let actual = attached
+            .try_longest_prefix_match(&key)
+            .unwrap()
+            .map(|(k, v)| (k, v.load(&store).unwrap().to_vec()));
+        prop_assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn retain_range(x in arb_tree_contents(), start in arb_prefix(), end in arb_prefix(), kinds in (0..3u8, 0..3u8)) {
+        let bound = |x: &Vec<u8>, kind| match kind {
+            0 => Bound::Included(x.clone()),
+            1 => Bound::Excluded(x.clone()),
+            _ => Bound::Unbounded,
+        };
+        let range = (bound(&start, kinds.0), bound(&end, kinds.1));
+        let expected = x.iter().filter(|(k, _)| range.contains(*k)).map(|(k, v)| (k.clone(), v.clone())).collect();
+        let mut tree = mk_owned_tree(&x);
+        tree.retain_range(range.clone());
+        prop_assert_eq!(&tree, &mk_owned_tree(&expected));
+        let store = MemStore::default();
+        let mut attached = mk_owned_tree(&x).try_attached(store).unwrap();
+        attached.try_reattach().unwrap();
+        attached.try_retain_range(range).unwrap();
+        prop_assert_eq!(&attached.try_detached().unwrap(), &tree);
+    }
+
+    #[test]
+    fn count_range_prefix(x in arb_tree_contents(), start in arb_prefix(), end in arb_prefix(), kinds in (0..3u8, 0..3u8)) {
+        let bound = |x: &Vec<u8>, kind| match kind {
+            0 => Bound::Included(x.clone()),
+            1 => Bound::Excluded(x.clone()),
+            _ => Bound::Unbounded,
+        };
+        let range = (bound(&start, kinds.0), bound(&end, kinds.1));
+        let expected_range = x.keys().filter(|k| range.contains(*k)).count() as u64;
+        let expected_prefix = x.keys().filter(|k| k.starts_with(&start)).count() as u64;
+        let tree = mk_owned_tree(&x);
+        prop_assert_eq!(tree.count_range(range.clone()), expected_range);
+        prop_assert_eq!(tree.count_prefix(&start), expected_prefix);
+        let store = MemStore::default();
+        let mut attached = tree.try_attached(store).unwrap();
+        attached.try_reattach().unwrap();
+        prop_assert_eq!(attached.try_count_range(range).unwrap(), expected_range);
+        prop_assert_eq!(attached.try_count_prefix(&start).unwrap(), expected_prefix);
+    }
+
+    #[test]
+    fn cursor(x in arb_tree_contents(), key in arb_prefix()) {
+        let store = MemStore::default();
+        let mut tree = mk_owned_tree(&x).try_attached(store.clone()).unwrap();
+        tree.try_reattach().unwrap();
+        let entry = |c: &Cursor<MemStore>| c.key().map(|k| {
+            (k.to_vec(), c.value().unwrap().to_owned().load(&store).unwrap().to_vec())
+        });
+        let mut c = tree.cursor();
+        prop_assert_eq!(entry(&c), None);
+        // forward from the seek position
+        let mut found = c.try_seek(&key).unwrap();
+        for expected in x.range(key.clone()..) {
+            prop_assert!(found);
+            prop_assert_eq!(entry(&c), Some((expected.0.clone(), expected.1.clone())));
+            found = c.try_next().unwrap();
+        }
+        prop_assert!(!found);
+        prop_assert_eq!(entry(&c), None);
+        // backward from the seek position
+        found = if c.try_seek(&key).unwrap() {
+            c.try_prev().unwrap()
+        } else {
+            c.try_last().unwrap()
+        };
+        for expected in x.range(..key.clone()).rev() {
+            prop_assert!(found);
+            prop_assert_eq!(entry(&c), Some((expected.0.clone(), expected.1.clone())));
+            found = c.try_prev().unwrap();
+        }
+        prop_assert!(!found);
+        // first and last
+        prop_assert_eq!(c.try_first().unwrap(), !x.is_empty());
+        prop_assert_eq!(entry(&c), x.iter().next().map(|(k, v)| (k.clone(), v.clone())));
+        prop_assert_eq!(c.try_last().unwrap(), !x.is_empty());
+        prop_assert_eq!(entry(&c), x.iter().next_back().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    #[test]
+    fn filter_prefix(x in arb_tree_contents(), prefix in any::<Vec<u8>>(), substitution in arb_prefix()) {
         let reference = x;
         let tree = mk_owned_tree(&reference);
         let filtered = tree.filter_prefix(&prefix, &prefix);
@@ -313,6 +1008,20 @@ proptest! {
             let t = reference.get(k.as_ref()).unwrap();
             prop_assert_eq!(v.as_ref(), t);
         }
+        // re-rooting on a different prefix gives a canonical tree with all entries under the prefix
+        let expected = reference
+            .iter()
+            .filter_map(|(k, v)| Some((k.strip_prefix(prefix.as_slice())?, v)))
+            .map(|(k, v)| ([&substitution[..], k].concat(), v.clone()))
+            .collect::<BTreeMap<_, _>>();
+        let filtered = tree.filter_prefix(&prefix, &substitution);
+        prop_assert_eq!(&filtered, &mk_owned_tree(&expected));
+        prop_assert_eq!(filtered.len(), expected.len() as u64);
+        let store = MemStore::default();
+        let mut attached = tree.try_attached(store).unwrap();
+        attached.try_reattach().unwrap();
+        let filtered = attached.try_filter_prefix(&prefix, &substitution).unwrap();
+        prop_assert_eq!(filtered.try_detached().unwrap(), mk_owned_tree(&expected));
     }
 
     #[test]
@@ -337,6 +1046,73 @@ proptest! {
         prop_assert_eq!(lbu, lbu_reference);
     }
 
+    #[test]
+    fn union_attached(a in arb_tree_contents(), b in arb_tree_contents()) {
+        let store = MemStore::default();
+        let at = mk_owned_tree(&a).try_attached(store.clone()).unwrap();
+        let bt = mk_owned_tree(&b).try_attached(store.clone()).unwrap();
+        let rbut = at.try_outer_combine::<MemStore, anyhow::Error, _>(&bt, |_, b| Ok(Some(b.detached(&store)?))).unwrap();
+        let mut rbu_reference = a.clone();
+        rbu_reference.extend(b.clone());
+        prop_assert_eq!(to_btree_map(&rbut), rbu_reference);
+    }
+
+    #[test]
+    fn union_into_store(a in arb_tree_contents(), b in arb_tree_contents()) {
+        let store = MemStore::default();
+        let at = mk_owned_tree(&a).try_attached(store.clone()).unwrap();
+        let bt = mk_owned_tree(&b).try_attached(store.clone()).unwrap();
+        let count = store.count();
+        let mut rbut = at
+            .try_outer_combine_into::<_, _, _, _, anyhow::Error, _>(
+                &bt,
+                store.clone(),
+                IdentityConverter,
+                IdentityConverter,
+                |_, b| Ok(Some(b.to_owned())),
+            )
+            .unwrap();
+        // combining does not write anything
+        prop_assert_eq!(store.count(), count);
+        let mut rbu_reference = a.clone();
+        rbu_reference.extend(b.clone());
+        let load = |t: &RadixTree<MemStore>| t.try_iter().map(|e| {
+            let (k, v) = e.unwrap();
+            (k.to_vec(), v.load(&store).unwrap().to_vec())
+        }).collect::<BTreeMap<_, _>>();
+        prop_assert_eq!(load(&rbut), rbu_reference.clone());
+        rbut.try_reattach().unwrap();
+        prop_assert_eq!(load(&rbut), rbu_reference);
+    }
+
+    #[test]
+    fn pending_free(a in arb_tree_contents(), b in arb_tree_contents()) {
+        let store = MemStore::default();
+        let mut t = mk_owned_tree(&a).try_attached(store.clone()).unwrap();
+        let root = t.try_reattach().unwrap();
+        let previous = t.clone();
+        let mut reference = a.clone();
+        for k in a.keys().step_by(2).chain(b.keys()) {
+            t.try_remove(k).unwrap();
+            reference.remove(k);
+        }
+        let root2 = t.try_reattach().unwrap();
+        let pending = t.try_pending_free(&previous).unwrap();
+        for id in pending.ids.iter().chain(Some(&root)) {
+            prop_assert!(store.remove(id));
+        }
+        // everything that is left is reachable from the new tree
+        let mut reachable = BTreeSet::new();
+        reachable_blobs(&t.node.as_ref(), &store, &mut reachable).unwrap();
+        reachable.insert(root2);
+        prop_assert_eq!(store.count(), reachable.len());
+        let load = |t: &RadixTree<MemStore>| t.try_iter().map(|e| {
+            let (k, v) = e.unwrap();
+            (k.to_vec(), v.load(&store).unwrap().to_vec())
+        }).collect::<BTreeMap<_, _>>();
+        prop_assert_eq!(load(&t), reference);
+    }
+
     #[test]
     fn union_sample(a in arb_owned_tree(), b in arb_owned_tree()) {
         let r = a.outer_combine(&b, |a, _| Some(a.to_owned()));
@@ -420,7 +1196,13 @@ proptest! {
         let lbu = to_btree_map(&lbut);
         let mut lbu_reference = a.clone();
         lbu_reference.retain(|k, _| !b.contains_key(k));
-        prop_assert_eq!(lbu, lbu_reference);
+        prop_assert_eq!(&lbu, &lbu_reference);
+        prop_assert_eq!(&at.difference(&bt), &lbut);
+        let store = MemStore::default();
+        let aa = at.try_attached(store.clone()).unwrap();
+        let ba = bt.try_attached(store).unwrap();
+        let res: RadixTree = aa.try_difference::<_, anyhow::Error>(&ba).unwrap();
+        prop_assert_eq!(&res, &lbut);
     }
 
     #[test]
@@ -436,118 +1218,1324 @@ proptest! {
     }
 
     #[test]
-    fn difference_with(a in arb_owned_tree(), b in arb_owned_tree()) {
-        let r1 = a.left_combine(&b, |a, _| Some(a.to_owned()));
-        let mut r2 = a.clone();
-        r2.left_combine_with(&b, |_, _| {});
-        prop_assert_eq!(to_btree_map(&r1), to_btree_map(&r2));
-
-        let r1 = a.left_combine(&b, |_, b| Some(b.to_owned()));
-        let mut r2 = a.clone();
-        r2.left_combine_with(&b, |a, b| a.set(Some(b)));
-        prop_assert_eq!(to_btree_map(&r1), to_btree_map(&r2));
+    fn difference_with(a in arb_owned_tree(), b in arb_owned_tree()) {
+        let r1 = a.left_combine(&b, |a, _| Some(a.to_owned()));
+        let mut r2 = a.clone();
+        r2.left_combine_with(&b, |_, _| {});
+        prop_assert_eq!(to_btree_map(&r1), to_btree_map(&r2));
+
+        let r1 = a.left_combine(&b, |_, b| Some(b.to_owned()));
+        let mut r2 = a.clone();
+        r2.left_combine_with(&b, |a, b| a.set(Some(b)));
+        prop_assert_eq!(to_btree_map(&r1), to_btree_map(&r2));
+    }
+
+    #[test]
+    fn combine_with_shared(a in arb_owned_tree(), b in arb_owned_tree()) {
+        // values with an odd first byte are removed, all others replaced
+        let f = |a: &mut Value, b: &ValueRef| {
+            if b.data().unwrap().first().map(|x| x % 2 == 1).unwrap_or_default() {
+                a.set(None)
+            } else {
+                a.set(Some(b))
+            }
+        };
+        let mut r1 = a.clone();
+        r1.outer_combine_with(&b, f);
+        let mut r2 = a.clone();
+        r2.outer_combine_with_shared(&b, f);
+        prop_assert_eq!(&r1, &r2);
+        prop_assert_eq!(r1.len(), r2.len());
+        let mut r1 = a.clone();
+        r1.left_combine_with(&b, f);
+        let mut r2 = a.clone();
+        r2.left_combine_with_shared(&b, f);
+        prop_assert_eq!(&r1, &r2);
+        prop_assert_eq!(r1.len(), r2.len());
+        let store = MemStore::default();
+        let mut attached = a.try_attached(store).unwrap();
+        attached.try_reattach().unwrap();
+        attached.try_outer_combine_with_shared(&b, DowncastConverter, |a, b| {
+            if b.data().unwrap().first().map(|x| x % 2 == 1).unwrap_or_default() {
+                a.set(None)
+            } else {
+                a.set(Some(b.downcast()))
+            }
+            Ok(())
+        }).unwrap();
+        let mut r1 = a.clone();
+        r1.outer_combine_with(&b, f);
+        prop_assert_eq!(attached.try_detached().unwrap(), r1);
+    }
+
+    #[test]
+    fn intersects(a in arb_tree_contents(), b in arb_tree_contents()) {
+        let at = mk_owned_tree(&a);
+        let bt = mk_owned_tree(&b);
+        let keys_intersect = at.inner_combine_pred(&bt, |_, _| true);
+        let keys_intersect_ref = a.keys().any(|ak| b.contains_key(ak));
+        prop_assert_eq!(keys_intersect, keys_intersect_ref);
+        prop_assert_eq!(at.intersects(&bt), keys_intersect_ref);
+        prop_assert_eq!(at.is_disjoint_from(&bt), !keys_intersect_ref);
+        let store = MemStore::default();
+        let aa = at.try_attached(store.clone()).unwrap();
+        let ba = bt.try_attached(store).unwrap();
+        prop_assert_eq!(aa.try_intersects::<_, anyhow::Error>(&ba).unwrap(), keys_intersect_ref);
+    }
+
+    #[test]
+    fn intersects_sample(a in arb_owned_tree(), b in arb_owned_tree()) {
+        let keys_intersect = a.inner_combine_pred(&b, |_, _| true);
+        prop_assert!(binary_property_test(&a, &b, !keys_intersect, |a, b| !(a.is_some() & b.is_some())));
+    }
+
+    #[test]
+    fn is_subset(a in arb_tree_contents(), b in arb_tree_contents()) {
+        let at = mk_owned_tree(&a);
+        let bt = mk_owned_tree(&b);
+        let at_subset_bt = !at.left_combine_pred(&bt, |_, _| false);
+        let at_subset_bt_ref = a.keys().all(|ak| b.contains_key(ak));
+        prop_assert_eq!(at_subset_bt, at_subset_bt_ref);
+        prop_assert_eq!(at.is_subset_of(&bt), at_subset_bt_ref);
+        prop_assert_eq!(bt.is_superset_of(&at), at_subset_bt_ref);
+        let store = MemStore::default();
+        let aa = at.try_attached(store.clone()).unwrap();
+        let ba = bt.try_attached(store).unwrap();
+        prop_assert_eq!(aa.try_is_subset_of::<_, anyhow::Error>(&ba).unwrap(), at_subset_bt_ref);
+    }
+
+    #[test]
+    fn is_subset_sample(a in arb_owned_tree(), b in arb_owned_tree()) {
+        let is_not_subset = a.left_combine_pred(&b, |_, _| false);
+        prop_assert!(binary_property_test(&a, &b, !is_not_subset, |a, b| a.is_none() | b.is_some()))
+    }
+
+    #[test]
+    fn retain_prefix_with(a in arb_tree_contents(), b in arb_tree_contents()) {
+        let mut at = mk_owned_tree(&a);
+        let bt = mk_owned_tree(&b);
+        at.retain_prefix_with(&bt, |_| true);
+        let mut expected = a;
+        expected.retain(|k, _| b.keys().any(|bk| k.starts_with(bk)));
+        let actual = to_btree_map(&at);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn remove_prefix_with(a in arb_tree_contents(), b in arb_tree_contents()) {
+        let mut at = mk_owned_tree(&a);
+        let bt = mk_owned_tree(&b);
+        at.remove_prefix_with(&bt, |_| true);
+        let mut expected = a;
+        expected.retain(|k, _| !b.keys().any(|bk| k.starts_with(bk)));
+        let actual = to_btree_map(&at);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn group_by_true(a in arb_tree_contents()) {
+        let at = mk_owned_tree(&a);
+        let trees: Vec<RadixTree> = at.group_by(|_, _| true).collect::<Vec<_>>();
+        prop_assert_eq!(a.len(), trees.len());
+        for ((k0, v0), tree) in a.iter().zip(trees) {
+            let k0: &[u8] = k0;
+            let v0: &[u8] = v0;
+            let k1 = tree.node.load_prefix(&Detached).unwrap().to_vec();
+            let v1 = tree.node.value_opt().map(|x| x.to_vec());
+            prop_assert!(tree.node.is_leaf());
+            prop_assert_eq!(k0, k1);
+            prop_assert_eq!(Some(v0.to_vec()), v1);
+        }
+    }
+
+    #[test]
+    fn group_by_fixed(a in arb_tree_contents(), n in 0usize..8) {
+        let at = mk_owned_tree(&a);
+        let trees: Vec<RadixTree> = at.group_by(|x, _| x.len() <= n).collect::<Vec<_>>();
+        prop_assert!(trees.len() <= a.len());
+        let mut leafs = BTreeMap::new();
+        for tree in &trees {
+            let prefix = tree.node.load_prefix(&Detached).unwrap().to_vec();
+            if prefix.len() <= n {
+                prop_assert!(tree.node.is_leaf());
+                prop_assert!(tree.node.value_opt().is_some());
+                let value = tree.node.value_opt().unwrap().to_vec();
+                let prev = leafs.insert(prefix, value);
+                prop_assert!(prev.is_none());
+            } else {
+                for (k, v) in tree.iter() {
+                    let prev = leafs.insert(k.to_vec(), v.to_vec());
+                    prop_assert!(prev.is_none());
+                }
+            }
+        }
+        prop_assert_eq!(a, leafs);
+    }
+
+    #[test]
+    fn heaviest_prefixes(a in arb_tree_contents(), depth in 0usize..4, k in 0usize..8) {
+        let at = mk_owned_tree(&a);
+        let mut expected = BTreeMap::<Vec<u8>, PrefixStats>::new();
+        for (key, value) in &a {
+            let stats = expected.entry(key[..key.len().min(depth)].to_vec()).or_default();
+            stats.entries += 1;
+            stats.bytes += (key.len() + value.len()) as u64;
+        }
+        let mut expected = expected.into_iter().collect::<Vec<_>>();
+        expected.sort_by_key(|(_, stats)| Reverse((stats.entries, stats.bytes)));
+        expected.truncate(k);
+        prop_assert_eq!(at.heaviest_prefixes(depth, k), expected);
+    }
+
+    #[test]
+    fn depth_profile(a in arb_tree_contents()) {
+        let at = mk_owned_tree(&a);
+        let profile = at.depth_profile();
+        prop_assert_eq!(profile.iter().map(|x| x.entries).sum::<u64>(), a.len() as u64);
+        prop_assert_eq!(profile.first().map(|x| x.nodes), Some(1).filter(|_| !a.is_empty()));
+        prop_assert!(profile.iter().all(|x| x.nodes > 0));
+        let store = MemStore::default();
+        let attached = at.try_attached(store).unwrap();
+        prop_assert_eq!(attached.try_depth_profile().unwrap(), profile);
+    }
+
+    #[test]
+    fn nth_rank(a in arb_tree_contents(), key in arb_prefix()) {
+        let at = mk_owned_tree(&a);
+        for (i, (k, v)) in a.iter().enumerate() {
+            let (key, value) = at.nth(i as u64).unwrap();
+            prop_assert_eq!(&key, k);
+            prop_assert_eq!(value.to_vec(), v.clone());
+            prop_assert_eq!(at.rank(k), i as u64);
+        }
+        prop_assert!(at.nth(a.len() as u64).is_none());
+        prop_assert_eq!(at.rank(&key), a.range(..key.clone()).count() as u64);
+        let store = MemStore::default();
+        let mut attached = at.try_attached(store).unwrap();
+        attached.try_reattach().unwrap();
+        prop_assert_eq!(attached.try_rank(&key).unwrap(), a.range(..key).count() as u64);
+    }
+
+    #[test]
+    fn split_even(a in arb_tree_contents(), n in 0usize..5) {
+        let at = mk_owned_tree(&a);
+        let parts = at.split_even(n);
+        prop_assert_eq!(parts.len(), n);
+        let mut all = Vec::new();
+        for part in &parts {
+            let entries = part.iter().map(|(k, v)| (k.to_vec(), v.to_vec())).collect::<Vec<_>>();
+            prop_assert!(entries.len() >= a.len() / n);
+            prop_assert!(entries.len() <= a.len().div_ceil(n));
+            all.extend(entries);
+        }
+        if n > 0 {
+            prop_assert_eq!(all, a.into_iter().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn pop_first_last(a in arb_tree_contents()) {
+        let mut tree = mk_owned_tree(&a);
+        let store = MemStore::default();
+        let mut attached = tree.try_attached(store.clone()).unwrap();
+        attached.try_reattach().unwrap();
+        let mut reference = a.clone();
+        for i in 0..a.len() + 1 {
+            let expected = if i % 2 == 0 { reference.pop_first() } else { reference.pop_last() };
+            let (actual, actual_attached) = if i % 2 == 0 {
+                (tree.pop_first(), attached.try_pop_first().unwrap())
+            } else {
+                (tree.pop_last(), attached.try_pop_last().unwrap())
+            };
+            prop_assert_eq!(actual.map(|(k, v)| (k, v.to_vec())), expected.clone());
+            let actual_attached = actual_attached.map(|(k, v)| (k, v.load(&store).unwrap().to_vec()));
+            prop_assert_eq!(actual_attached, expected);
+            prop_assert_eq!(&tree, &mk_owned_tree(&reference));
+            prop_assert_eq!(&attached.try_detached().unwrap(), &tree);
+            prop_assert_eq!(attached.try_len().unwrap(), reference.len() as u64);
+        }
+    }
+
+    #[test]
+    fn retain(a in arb_tree_contents(), m in 1usize..4) {
+        let keep = |k: &[u8], v: &[u8]| (k.len() + v.len()) % m == 0;
+        let mut reference = a.clone();
+        reference.retain(|k, v| keep(k, v));
+        let mut tree = mk_owned_tree(&a);
+        tree.retain(|k, v| keep(k, &v.to_owned()));
+        prop_assert_eq!(&tree, &mk_owned_tree(&reference));
+        let store = MemStore::default();
+        let mut attached = mk_owned_tree(&a).try_attached(store.clone()).unwrap();
+        attached.try_reattach().unwrap();
+        attached.try_retain(|k, v| Ok(keep(k, &v.load(&store)?))).unwrap();
+        prop_assert_eq!(&attached.try_detached().unwrap(), &tree);
+        prop_assert_eq!(attached.try_len().unwrap(), reference.len() as u64);
+        // keeping every entry leaves an attached tree as it is
+        attached.try_reattach().unwrap();
+        let children = attached.node.get_children().unwrap_err().to_vec();
+        attached.try_retain(|_, _| Ok(true)).unwrap();
+        prop_assert_eq!(attached.node.get_children().unwrap_err(), &children[..]);
+    }
+
+    #[test]
+    fn value_load(a in arb_tree_contents()) {
+        let store = MemStore::default();
+        let mut attached = mk_owned_tree(&a).try_attached(store.clone()).unwrap();
+        attached.try_reattach().unwrap();
+        let mut values = Vec::new();
+        attached.try_retain(|_, v| {
+            values.push((v.data_inline_or_arc().map(|x| x.to_vec()), v.load(&store)?.to_vec()));
+            Ok(true)
+        }).unwrap();
+        prop_assert_eq!(values.len(), a.len());
+        for ((inline, loaded), expected) in values.into_iter().zip(a.values()) {
+            prop_assert_eq!(&loaded, expected);
+            // only small values are stored inline
+            prop_assert_eq!(inline.is_some(), expected.len() < 128);
+            if let Some(inline) = inline {
+                prop_assert_eq!(&inline, expected);
+            }
+        }
+        for entry in attached.try_iter() {
+            let (k, v) = entry.unwrap();
+            prop_assert_eq!(v.load(&store).unwrap().to_vec(), a[k.as_ref()].clone());
+            if let Some(data) = v.data_inline_or_arc() {
+                prop_assert_eq!(data, &a[k.as_ref()][..]);
+            }
+        }
+    }
+
+    #[test]
+    fn project_keys(a in arb_tree_contents(), width in 0usize..4) {
+        let expected = a
+            .keys()
+            .map(|k| (k[..k.len().min(width)].to_vec(), Vec::new()))
+            .collect::<BTreeMap<_, _>>();
+        let tree = mk_owned_tree(&a);
+        prop_assert_eq!(&tree.project_keys(width), &mk_owned_tree(&expected));
+        let store = MemStore::default();
+        let mut attached = tree.try_attached(store).unwrap();
+        attached.try_reattach().unwrap();
+        prop_assert_eq!(&attached.try_project_keys(width).unwrap(), &mk_owned_tree(&expected));
+    }
+
+    #[test]
+    fn remove_prefix(a in arb_tree_contents(), prefix in arb_prefix()) {
+        let (expected_removed, expected): (BTreeMap<_, _>, BTreeMap<_, _>) =
+            a.clone().into_iter().partition(|(k, _)| k.starts_with(&prefix));
+        let mut tree = mk_owned_tree(&a);
+        let removed = tree.remove_prefix(&prefix);
+        prop_assert_eq!(&tree, &mk_owned_tree(&expected));
+        prop_assert_eq!(&removed, &mk_owned_tree(&expected_removed));
+        let store = MemStore::default();
+        let mut attached = mk_owned_tree(&a).try_attached(store).unwrap();
+        attached.try_reattach().unwrap();
+        let children = attached.node.get_children().map_err(|id| id.to_vec()).err();
+        let removed = attached.try_remove_prefix(&prefix).unwrap();
+        if expected_removed.is_empty() {
+            // nothing is loaded or copied if there is nothing to remove
+            prop_assert_eq!(attached.node.get_children().map_err(|id| id.to_vec()).err(), children);
+        }
+        prop_assert_eq!(&attached.try_detached().unwrap(), &tree);
+        prop_assert_eq!(&removed.try_detached().unwrap(), &mk_owned_tree(&expected_removed));
+        prop_assert_eq!(attached.try_len().unwrap(), expected.len() as u64);
+    }
+
+    #[test]
+    fn as_single(a in arb_tree_contents()) {
+        let expected = if a.len() == 1 { a.iter().next().map(|(k, v)| (k.clone(), v.clone())) } else { None };
+        let tree = mk_owned_tree(&a);
+        prop_assert_eq!(tree.is_single_entry(), a.len() == 1);
+        prop_assert_eq!(tree.as_single().map(|(k, v)| (k, v.to_vec())), expected.clone());
+        let store = MemStore::default();
+        let mut attached = tree.try_attached(store.clone()).unwrap();
+        attached.try_reattach().unwrap();
+        prop_assert_eq!(attached.is_single_entry(), a.len() == 1);
+        let actual = attached.try_as_single().unwrap().map(|(k, v)| (k, v.load(&store).unwrap().to_vec()));
+        prop_assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn lossy_iter(a in arb_tree_contents(), index in any::<prop::sample::Index>()) {
+        let store = MemStore::default();
+        let mut tree = mk_owned_tree(&a).try_attached(store.clone()).unwrap();
+        tree.try_reattach().unwrap();
+        let all = tree.lossy_iter().map(|(k, _)| k.to_vec()).collect::<Vec<_>>();
+        prop_assert_eq!(&all, &a.keys().cloned().collect::<Vec<_>>());
+        // damage the store by removing a random blob
+        let mut ids = BTreeSet::new();
+        reachable_blobs(&TreeNodeRef::owned(&tree.node), &store, &mut ids).unwrap();
+        if let Some(id) = ids.iter().nth(index.index(ids.len().max(1))) {
+            store.remove(id);
+        }
+        let mut iter = tree.lossy_iter();
+        let keys = iter.by_ref().map(|(k, _)| k.to_vec()).collect::<Vec<_>>();
+        // all keys that could be read are in order and part of the original tree
+        prop_assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        prop_assert!(keys.iter().all(|k| a.contains_key(k)));
+        if iter.errors().is_empty() {
+            prop_assert_eq!(keys, all);
+        } else {
+            prop_assert!(keys.len() < all.len());
+            for (key, _) in iter.errors() {
+                prop_assert!(a.keys().any(|k| k.starts_with(key)));
+            }
+        }
+    }
+
+    #[test]
+    fn get_traced(a in arb_tree_contents(), key in arb_prefix()) {
+        let tree = mk_owned_tree(&a);
+        let store = MemStore::default();
+        let mut attached = tree.try_attached(store.clone()).unwrap();
+        attached.try_reattach().unwrap();
+        for key in a.keys().chain(Some(&key)) {
+            let (value, trace) = tree.get_traced(key);
+            prop_assert_eq!(value.map(|v| v.to_vec()), a.get(key).cloned());
+            prop_assert_eq!(trace.blobs_loaded, 0);
+            prop_assert!(trace.nodes_visited as usize <= key.len() + 1);
+            let (value, trace) = attached.try_get_traced(key).unwrap();
+            let value = value.map(|v| v.load(&store).unwrap().to_vec());
+            prop_assert_eq!(value.as_ref(), a.get(key));
+            prop_assert!(trace.nodes_visited as usize <= key.len() + 1);
+            prop_assert!(trace.blobs_loaded <= 2 * trace.nodes_visited);
+        }
+    }
+
+    #[test]
+    fn split_off(a in arb_tree_contents(), key in arb_prefix()) {
+        let mut left = mk_owned_tree(&a);
+        let right = left.split_off(&key);
+        let mut expected_left = a.clone();
+        let expected_right = expected_left.split_off(&key);
+        prop_assert_eq!(to_btree_map(&left), expected_left);
+        prop_assert_eq!(to_btree_map(&right), expected_right);
+        prop_assert_eq!(&left, &mk_owned_tree(&to_btree_map(&left)));
+        prop_assert_eq!(&right, &mk_owned_tree(&to_btree_map(&right)));
+        // attached, the parts detach to the same canonical trees
+        let store = MemStore::default();
+        let mut attached = mk_owned_tree(&a).try_attached(store).unwrap();
+        let attached_right = attached.try_split_off(&key).unwrap();
+        prop_assert_eq!(attached.try_detached().unwrap(), left);
+        prop_assert_eq!(attached_right.try_detached().unwrap(), right);
+    }
+
+    #[test]
+    fn split_even_attached(a in arb_tree_contents(), n in 1usize..5) {
+        let store = MemStore::default();
+        let at = mk_owned_tree(&a).try_attached(store.clone()).unwrap();
+        let mut all = BTreeMap::new();
+        for part in at.try_split_even(n).unwrap() {
+            for e in part.try_iter() {
+                let (k, v) = e.unwrap();
+                all.insert(k.to_vec(), v.load(&store).unwrap().to_vec());
+            }
+        }
+        prop_assert_eq!(all, a);
+    }
+
+    #[test]
+    fn par_export(a in arb_tree_contents(), n in 1usize..5) {
+        let store = MemStore::default();
+        let at = mk_owned_tree(&a).try_attached(store.clone()).unwrap();
+        let parts = Arc::new(std::sync::Mutex::new(BTreeMap::new()));
+        let index = at.try_par_export(n, |i| PartSink(parts.clone(), i)).unwrap();
+        prop_assert_eq!(index.len(), n);
+        let parts = parts.lock().unwrap();
+        let mut all = Vec::new();
+        for (i, part) in index.iter().enumerate() {
+            let data = parts.get(&i).cloned().unwrap_or_default();
+            let entries = parse_export(&data);
+            prop_assert_eq!(part.offset, all.len() as u64);
+            prop_assert_eq!(part.bytes, data.len() as u64);
+            prop_assert_eq!(part.entries, entries.len() as u64);
+            prop_assert_eq!(&part.first_key, &entries.first().map(|(k, _)| k.clone()));
+            all.extend_from_slice(&data);
+        }
+        let all = parse_export(&all);
+        prop_assert_eq!(all, a.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn par_import(a in arb_tree_contents(), b in arb_tree_contents(), n in 1usize..5) {
+        let parts = Arc::new(std::sync::Mutex::new(BTreeMap::new()));
+        mk_owned_tree(&a).par_export(n, |i| PartSink(parts.clone(), i)).unwrap();
+        let mut shards = parts.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        // an overlapping shard at the end overrides existing values
+        let bparts = Arc::new(std::sync::Mutex::new(BTreeMap::new()));
+        mk_owned_tree(&b).par_export(1, |i| PartSink(bparts.clone(), i)).unwrap();
+        shards.extend(bparts.lock().unwrap().values().cloned());
+        let readers = shards.iter().map(|x| &x[..]).collect::<Vec<_>>();
+        let imported = RadixTree::par_import(readers).unwrap();
+        let mut reference = a.clone();
+        reference.extend(b.clone());
+        prop_assert_eq!(to_btree_map(&imported), reference);
     }
 
     #[test]
-    fn intersects(a in arb_tree_contents(), b in arb_tree_contents()) {
-        let at = mk_owned_tree(&a);
-        let bt = mk_owned_tree(&b);
-        let keys_intersect = at.inner_combine_pred(&bt, |_, _| true);
-        let keys_intersect_ref = a.keys().any(|ak| b.contains_key(ak));
-        prop_assert_eq!(keys_intersect, keys_intersect_ref);
+    fn iter_shuffled(a in arb_tree_contents(), seed in any::<u64>()) {
+        let t = mk_owned_tree(&a);
+        let entries = t.iter_shuffled(seed).map(|(k, v)| (k.to_vec(), v.to_vec())).collect::<Vec<_>>();
+        prop_assert_eq!(entries.len(), a.len());
+        prop_assert_eq!(&entries.iter().cloned().collect::<BTreeMap<_, _>>(), &a);
+        // same seed gives the same order, also for an attached tree
+        let again = t.iter_shuffled(seed).map(|(k, v)| (k.to_vec(), v.to_vec())).collect::<Vec<_>>();
+        prop_assert_eq!(&again, &entries);
+        let store = MemStore::default();
+        let at = t.try_attached(store.clone()).unwrap();
+        let attached = at.try_iter_shuffled(seed).map(|e| {
+            let (k, v) = e.unwrap();
+            (k.to_vec(), v.load(&store).unwrap().to_vec())
+        }).collect::<Vec<_>>();
+        prop_assert_eq!(attached, entries);
     }
 
     #[test]
-    fn intersects_sample(a in arb_owned_tree(), b in arb_owned_tree()) {
-        let keys_intersect = a.inner_combine_pred(&b, |_, _| true);
-        prop_assert!(binary_property_test(&a, &b, !keys_intersect, |a, b| !(a.is_some() & b.is_some())));
+    fn first_last_value_entry(a in arb_tree_contents()) {
+        let at = mk_owned_tree(&a);
+        prop_assert_eq!(at.first_value().map(|x| x.to_vec()), a.values().next().map(|v| v.to_vec()));
+        prop_assert_eq!(at.first_entry().map(|(k, v)| (k.to_vec(), v.to_vec())), a.iter().next().map(|(k, v)| (k.to_vec(), v.to_vec())));
+        prop_assert_eq!(at.last_value().map(|x| x.to_vec()), a.values().last().map(|v| v.to_vec()));
+        prop_assert_eq!(at.last_entry().map(|(k, v)| (k.to_vec(), v.to_vec())), a.iter().last().map(|(k, v)| (k.to_vec(), v.to_vec())));
     }
 
     #[test]
-    fn is_subset(a in arb_tree_contents(), b in arb_tree_contents()) {
+    fn first_last_entry_btreemap(a in arb_tree_contents(), b in arb_tree_contents()) {
+        // union of two random trees to get more multi-level trees
+        let mut a = a;
+        a.extend(b);
         let at = mk_owned_tree(&a);
-        let bt = mk_owned_tree(&b);
-        let at_subset_bt = !at.left_combine_pred(&bt, |_, _| false);
-        let at_subset_bt_ref = a.keys().all(|ak| b.contains_key(ak));
-        prop_assert_eq!(at_subset_bt, at_subset_bt_ref);
+        let owned = |(k, v): (&Vec<u8>, &Vec<u8>)| (k.clone(), v.clone());
+        let first = at.first_entry().map(|(k, v)| (k, v.to_vec()));
+        prop_assert_eq!(first, a.first_key_value().map(owned));
+        let last = at.last_entry().map(|(k, v)| (k, v.to_vec()));
+        prop_assert_eq!(last, a.last_key_value().map(owned));
     }
 
     #[test]
-    fn is_subset_sample(a in arb_owned_tree(), b in arb_owned_tree()) {
-        let is_not_subset = a.left_combine_pred(&b, |_, _| false);
-        prop_assert!(binary_property_test(&a, &b, !is_not_subset, |a, b| a.is_none() | b.is_some()))
+    fn first_last_value_entry_attached(a in arb_tree_contents()) {
+        let store = MemStore::default();
+        let at = mk_owned_tree(&a).try_attached(store.clone()).unwrap();
+        let load = |v: Value<MemStore>| v.load(&store).unwrap().to_vec();
+        prop_assert_eq!(at.try_first_value().unwrap().map(load), a.values().next().cloned());
+        prop_assert_eq!(at.try_last_value().unwrap().map(load), a.values().last().cloned());
+        prop_assert_eq!(at.try_first_entry().unwrap().map(|(k, v)| (k, load(v))), a.iter().next().map(|(k, v)| (k.clone(), v.clone())));
+        prop_assert_eq!(at.try_last_entry().unwrap().map(|(k, v)| (k, load(v))), a.iter().last().map(|(k, v)| (k.clone(), v.clone())));
     }
 
     #[test]
-    fn retain_prefix_with(a in arb_tree_contents(), b in arb_tree_contents()) {
-        let mut at = mk_owned_tree(&a);
-        let bt = mk_owned_tree(&b);
-        at.retain_prefix_with(&bt, |_| true);
-        let mut expected = a;
-        expected.retain(|k, _| b.keys().any(|bk| k.starts_with(bk)));
-        let actual = to_btree_map(&at);
-        assert_eq!(expected, actual);
+    fn get_attached(a in arb_tree_contents(), key in arb_prefix()) {
+        let store = MemStore::default();
+        let at = mk_owned_tree(&a).try_attached(store.clone()).unwrap();
+        for (k, v) in &a {
+            let value = at.try_get(k).unwrap().map(|x| x.load(&store).unwrap().to_vec());
+            prop_assert_eq!(value.as_ref(), Some(v));
+        }
+        prop_assert_eq!(at.try_contains_key(&key).unwrap(), a.contains_key(&key));
     }
 
     #[test]
-    fn remove_prefix_with(a in arb_tree_contents(), b in arb_tree_contents()) {
-        let mut at = mk_owned_tree(&a);
-        let bt = mk_owned_tree(&b);
-        at.remove_prefix_with(&bt, |_| true);
-        let mut expected = a;
-        expected.retain(|k, _| !b.keys().any(|bk| k.starts_with(bk)));
-        let actual = to_btree_map(&at);
-        assert_eq!(expected, actual);
+    fn infallible_store_iter(a in arb_tree_contents(), prefix in arb_prefix()) {
+        let store = InfallibleMemStore::default();
+        let at = mk_owned_tree(&a).try_attached(store.clone()).unwrap_safe();
+        let load = |v: Value<InfallibleMemStore>| v.load(&store).unwrap_safe().to_vec();
+        let entries = at.iter().map(|(k, v)| (k.to_vec(), load(v))).collect::<BTreeMap<_, _>>();
+        prop_assert_eq!(&entries, &a);
+        let values = at.values().map(load).collect::<Vec<_>>();
+        prop_assert_eq!(values, a.values().cloned().collect::<Vec<_>>());
+        let scanned = at.scan_prefix(&prefix).map(|(k, v)| (k.to_vec(), load(v))).collect::<BTreeMap<_, _>>();
+        let expected = a.iter().filter(|(k, _)| k.starts_with(&prefix)).map(|(k, v)| (k.clone(), v.clone())).collect::<BTreeMap<_, _>>();
+        prop_assert_eq!(scanned, expected);
     }
 
     #[test]
-    fn group_by_true(a in arb_tree_contents()) {
+    fn index_get_or_default(a in arb_tree_contents(), key in arb_prefix()) {
         let at = mk_owned_tree(&a);
-        let trees: Vec<RadixTree> = at.group_by(|_, _| true).collect::<Vec<_>>();
-        prop_assert_eq!(a.len(), trees.len());
-        for ((k0, v0), tree) in a.iter().zip(trees) {
-            let k0: &[u8] = k0;
-            let v0: &[u8] = v0;
-            let k1 = tree.node.load_prefix(&Detached).unwrap().to_vec();
-            let v1 = tree.node.value_opt().map(|x| x.to_vec());
-            prop_assert!(tree.node.is_leaf());
-            prop_assert_eq!(k0, k1);
-            prop_assert_eq!(Some(v0.to_vec()), v1);
+        for (k, v) in &a {
+            prop_assert_eq!(&at[k], &v[..]);
+            prop_assert_eq!(at.get_or_default(k), &v[..]);
         }
+        let expected = a.get(&key).map(|v| &v[..]).unwrap_or_default();
+        prop_assert_eq!(at.get_or_default(&key), expected);
     }
+}
 
-    #[test]
-    fn group_by_fixed(a in arb_tree_contents(), n in 0usize..8) {
-        let at = mk_owned_tree(&a);
-        let trees: Vec<RadixTree> = at.group_by(|x, _| x.len() <= n).collect::<Vec<_>>();
-        prop_assert!(trees.len() <= a.len());
-        let mut leafs = BTreeMap::new();
-        for tree in &trees {
-            let prefix = tree.node.load_prefix(&Detached).unwrap().to_vec();
-            if prefix.len() <= n {
-                prop_assert!(tree.node.is_leaf());
-                prop_assert!(tree.node.value_opt().is_some());
-                let value = tree.node.value_opt().unwrap().to_vec();
-                let prev = leafs.insert(prefix, value);
-                prop_assert!(prev.is_none());
-            } else {
-                for (k, v) in tree.iter() {
-                    let prev = leafs.insert(k.to_vec(), v.to_vec());
-                    prop_assert!(prev.is_none());
+#[test]
+fn combine_with_shared_keeps_sharing() {
+    let a = (0..100u8)
+        .map(|i| ([i / 10, i % 10], [i]))
+        .collect::<RadixTree>();
+    let children = |t: &RadixTree| t.node.get_children().unwrap().clone();
+    // applying a subset of the tree again does not touch anything
+    let batch = (0..20u8)
+        .map(|i| ([i / 10, i % 10], [i]))
+        .collect::<RadixTree>();
+    let mut t = a.clone();
+    t.outer_combine_with_shared(&batch, |a, b| a.set(Some(b)));
+    assert!(Arc::ptr_eq(&children(&t), &children(&a)));
+    t.left_combine_with_shared(&batch, |a, b| a.set(Some(b)));
+    assert!(Arc::ptr_eq(&children(&t), &children(&a)));
+    // changing a value only copies the nodes on the path to it
+    t.outer_combine_with_shared(&RadixTree::single([0u8, 0], [42]), |a, b| a.set(Some(b)));
+    assert_eq!(t.get([0u8, 0]).unwrap().as_ref(), &[42]);
+    let (tc, ac) = (children(&t), children(&a));
+    assert!(!Arc::ptr_eq(&tc, &ac));
+    assert!(!Arc::ptr_eq(
+        tc[0].get_children().unwrap(),
+        ac[0].get_children().unwrap()
+    ));
+    assert!(Arc::ptr_eq(
+        tc[1].get_children().unwrap(),
+        ac[1].get_children().unwrap()
+    ));
+}
+
+#[test]
+fn depth_profile_levels() {
+    let t = crate::radixtree! { "a", "ab", "ac", "acd" };
+    let level = |nodes, entries, prefix_bytes| LevelStats {
+        nodes,
+        entries,
+        prefix_bytes,
+    };
+    assert_eq!(
+        t.depth_profile(),
+        vec![level(1, 1, 1), level(2, 2, 2), level(1, 1, 1)]
+    );
+    assert!(RadixTree::default().depth_profile().is_empty());
+}
+
+#[test]
+fn insert_owned_moves_data() {
+    let mut t = RadixTree::default();
+    let vec = b"a value that is too large to be inlined".to_vec();
+    let ptr = vec.as_ptr();
+    t.insert_owned("vec", vec);
+    assert_eq!(t.get("vec").unwrap().as_ptr(), ptr);
+    let arc = Arc::new(b"another value that is too large to be inlined".to_vec());
+    t.insert_owned("arc", arc.clone());
+    assert_eq!(t.get("arc").unwrap().as_ptr(), arc.as_ptr());
+    // small values are inlined no matter what
+    t.insert_owned("small", b"tiny".to_vec());
+    assert_eq!(t.get("small").unwrap().as_ref(), b"tiny");
+}
+
+#[test]
+fn shared_reads() {
+    let store = CountingStore::default();
+    let mut a = (0..100u8)
+        .map(|i| ([b'a', i % 10, i], [i; 20]))
+        .collect::<RadixTree>()
+        .try_attached(store.clone())
+        .unwrap();
+    a.try_reattach().unwrap();
+    let mut b = a.clone();
+    b.try_insert([b'b'], [0u8; 20]).unwrap();
+    b.try_reattach().unwrap();
+    let reads = || store.1.load(atomic::Ordering::Relaxed);
+    let before = reads();
+    let expected = a
+        .try_outer_combine(&b, |a, _| Ok::<_, anyhow::Error>(Some(a.detached(&store)?)))
+        .unwrap();
+    let unshared = reads() - before;
+    let before = reads();
+    let actual = a
+        .try_with_shared_reads(&b, |a, b| {
+            let store = RadixTree::store(a).clone();
+            a.try_outer_combine(b, |a, _| Ok::<_, anyhow::Error>(Some(a.detached(&store)?)))
+        })
+        .unwrap();
+    let shared = reads() - before;
+    assert_eq!(actual, expected);
+    // the subtree below "a" is shared between both trees, so it is only read once
+    assert!(shared < unshared);
+}
+
+#[test]
+fn remove_single_descent() {
+    let store = CountingStore::default();
+    let mut tree = (0..1000u32)
+        .map(|i| (i.to_string(), i.to_be_bytes()))
+        .collect::<RadixTree>()
+        .try_attached(store.clone())
+        .unwrap();
+    let id = tree.try_reattach().unwrap();
+    let reads = || store.1.load(atomic::Ordering::Relaxed);
+    for key in ["123", "999", "1234", "12a"] {
+        let tree = RadixTree::try_load(store.clone(), Some(&id)).unwrap();
+        let before = reads();
+        let found = tree.try_get(key).unwrap().is_some();
+        let get = reads() - before;
+        let mut tree = RadixTree::try_load(store.clone(), Some(&id)).unwrap();
+        let before = reads();
+        assert_eq!(tree.try_remove(key).unwrap().is_some(), found);
+        // removing reads the same blobs as a lookup, each of them once
+        assert_eq!(reads() - before, get);
+        let mut tree = RadixTree::try_load(store.clone(), Some(&id)).unwrap();
+        let before = reads();
+        assert_eq!(tree.try_remove_prefix(key).unwrap().is_empty(), !found);
+        assert_eq!(reads() - before, get);
+    }
+}
+
+#[test]
+fn memory_budget() {
+    use crate::store::{MemoryBudget, ReadCache};
+    let store = CountingStore::default();
+    let mut tree = (0..100u8)
+        .map(|i| ([i % 10, i], [i; 100]))
+        .collect::<RadixTree>()
+        .try_attached(store.clone())
+        .unwrap();
+    let id = tree.try_reattach().unwrap();
+    let budget = MemoryBudget::new(2000);
+    let a = ReadCache::with_budget(store.clone(), budget.clone());
+    let b = ReadCache::with_budget(store.clone(), budget.clone());
+    for cache in [&a, &b] {
+        let tree = RadixTree::try_load(cache.clone(), Some(&id)).unwrap();
+        let entries = tree.try_iter().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(entries.len(), 100);
+        assert!(budget.used() <= budget.limit());
+    }
+    assert!(a.count() > 0 || b.count() > 0);
+    // a blob larger than the entire budget is never cached
+    let large = store.write(&[0u8; 3000]).unwrap();
+    let before = budget.out_of_budget();
+    a.read(&large).unwrap();
+    assert_eq!(budget.out_of_budget(), before + 1);
+    assert!(budget.used() <= budget.limit());
+    drop((a, b));
+    assert_eq!(budget.used(), 0);
+}
+
+#[test]
+fn verify() {
+    use crate::store::ContentStore;
+    let store = ContentStore::default();
+    let mut tree = (0..100u32)
+        .map(|i| (i.to_string(), [i as u8; 100]))
+        .collect::<RadixTree>()
+        .try_attached(store.clone())
+        .unwrap();
+    let root = tree.try_reattach().unwrap();
+    let report = tree.verify(ContentStore::check);
+    assert!(report.is_ok());
+    assert_eq!(report.unreachable(store.ids()), vec![root]);
+    // a blob that fails the check, and blobs that are missing
+    let mem = MemStore::default();
+    let mut tree = tree
+        .try_detached()
+        .unwrap()
+        .try_attached(mem.clone())
+        .unwrap();
+    tree.try_reattach().unwrap();
+    let ids = tree.verify(|_, _| true).reachable;
+    let corrupt = ids.iter().next().unwrap().clone();
+    let report = tree.verify(|id, _| id != corrupt);
+    assert_eq!(
+        report.corrupt,
+        [corrupt].into_iter().collect::<BTreeSet<_>>()
+    );
+    assert!(mem.remove(ids.iter().last().unwrap()));
+    assert_eq!(tree.verify(|_, _| true).missing.len(), 1);
+    // children that can not be decoded
+    let junk = mem.write(&[0xff, 0xff]).unwrap();
+    let mut node = TreeNode::<MemStore>::EMPTY;
+    node.set_prefix_slice(b"a");
+    node.set_children_id(&[&[0u8][..], &junk].concat());
+    let report = RadixTree::new(node, mem).verify(|_, _| true);
+    assert!(report.invalid.contains_key(&junk));
+}
+
+#[test]
+fn get_many() {
+    let mut tree = (0..10u8).map(|i| ([i], [i])).collect::<RadixTree>();
+    let snapshot = tree.clone();
+    tree.remove([1u8]);
+    let values = snapshot.get_many([[1u8], [2], [10]]);
+    let values = values
+        .iter()
+        .map(|v| v.as_ref().map(|v| v.to_vec()))
+        .collect::<Vec<_>>();
+    assert_eq!(values, vec![Some(vec![1]), Some(vec![2]), None]);
+    assert!(tree.get_many([[1u8]])[0].is_none());
+}
+
+#[cfg(feature = "debug-checksums")]
+#[test]
+#[should_panic(expected = "was modified")]
+fn debug_checksums() {
+    let tree = crate::radixtree! { "a" => "a value that is too large to be inlined" };
+    let ptr = tree.get("a").unwrap().as_ptr();
+    // simulate memory corruption
+    unsafe { *(ptr as *mut u8) = b'x' };
+    tree.get("a").unwrap().to_vec();
+}
+
+#[test]
+fn intersection_count_estimate() {
+    let a = (0..10000u32)
+        .map(|i| (i.to_be_bytes(), []))
+        .collect::<RadixTree>();
+    let b = (0..20000u32)
+        .step_by(2)
+        .map(|i| (i.to_be_bytes(), []))
+        .collect::<RadixTree>();
+    assert_eq!(a.intersection_count_estimate(&b, 10000), 5000);
+    let estimate = a.intersection_count_estimate(&b, 1000);
+    assert!((4500..5500).contains(&estimate), "{}", estimate);
+    assert_eq!(a.intersection_count_estimate(&RadixTree::default(), 0), 0);
+    // the estimate only reads the nodes along the sampled paths, not the whole tree
+    let store = CountingStore::default();
+    let large = (0..100000u32)
+        .map(|i| (i.to_be_bytes(), []))
+        .collect::<RadixTree>();
+    let a = large.try_attached(store.clone()).unwrap();
+    let b = b.try_attached(store.clone()).unwrap();
+    store.1.store(0, atomic::Ordering::Relaxed);
+    assert_eq!(a.try_iter().count(), 100000);
+    let iter_reads = store.1.swap(0, atomic::Ordering::Relaxed);
+    a.try_intersection_count_estimate::<_, anyhow::Error>(&b, 20)
+        .unwrap();
+    let estimate_reads = store.1.load(atomic::Ordering::Relaxed);
+    assert!(
+        estimate_reads * 2 < iter_reads,
+        "{} {}",
+        estimate_reads,
+        iter_reads
+    );
+}
+
+#[test]
+#[cfg(feature = "blake3")]
+fn merkle_hashers() {
+    assert_eq!(
+        hex::encode(Sha256Hasher::digest(b"")),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        hex::encode(Blake3Hasher::digest(b"")),
+        "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+    );
+    let tree = (0..100u32)
+        .map(|i| (i.to_be_bytes(), i.to_string()))
+        .collect::<RadixTree>();
+    let root = tree.root_hash_with::<Blake3Hasher>();
+    assert_ne!(root, tree.root_hash());
+    let key = 42u32.to_be_bytes();
+    let proof = tree.prove_with::<Blake3Hasher>(key).unwrap();
+    assert!(proof.verify_with::<Blake3Hasher>(&root, &key, b"42"));
+    assert!(!proof.verify(&root, &key, b"42"));
+    assert!(!tree.prove(key).unwrap().verify(&root, &key, b"42"));
+}
+
+#[test]
+#[cfg(feature = "merkle")]
+fn repair_from() -> anyhow::Result<()> {
+    let peer = (0..1000u32)
+        .map(|i| (i.to_be_bytes(), i.to_string()))
+        .collect::<RadixTree>();
+    let root = peer.root_hash();
+    let mut damaged = peer.clone();
+    damaged.insert(500u32.to_be_bytes(), "x");
+    damaged.remove(7u32.to_be_bytes());
+    damaged.insert(2000u32.to_be_bytes(), "y");
+    let local = damaged.try_attached(MemStore::default())?;
+    let mut fetched = 0;
+    let repaired = local.try_repair_from(&root, |path| {
+        fetched += 1;
+        peer.merkle_node(path)
+            .ok_or_else(|| anyhow::anyhow!("no node at {}", hex::encode(path)))
+    })?;
+    assert_eq!(repaired.try_detached()?, peer);
+    assert_eq!(repaired.try_root_hash()?, root);
+    // only the nodes on the paths to the damaged entries are fetched
+    assert!(fetched < 10, "{}", fetched);
+    // nothing is fetched for an intact tree
+    let repaired = repaired.try_repair_from(&root, |_| anyhow::bail!("fetched"))?;
+    assert_eq!(repaired.try_detached()?, peer);
+    // a peer sending nodes that do not match the hash is detected
+    let other = RadixTree::single("a", "b");
+    assert!(local
+        .try_repair_from(&root, |path| Ok(other.merkle_node(path).unwrap()))
+        .is_err());
+    Ok(())
+}
+
+/// the hashes of the hand written SHA-256 that was used before, so switching implementations does not
+/// change the hashes of existing trees
+#[test]
+#[cfg(feature = "merkle")]
+fn root_hash_stable() {
+    let tree = (0..100u32)
+        .map(|i| (i.to_be_bytes(), i.to_string()))
+        .collect::<RadixTree>();
+    assert_eq!(
+        hex::encode(tree.root_hash()),
+        "9514b0f45e9de52d1c734ae161617b47267ba9f53448b76c981725286a01290b"
+    );
+}
+
+#[test]
+fn pending_free_inner_value() {
+    let store = MemStore::default();
+    let mut t = RadixTree::empty(store.clone());
+    t.try_insert("a", [1u8; 1000]).unwrap();
+    t.try_insert("ab", [2u8; 1000]).unwrap();
+    t.try_insert("ac", [3u8; 1000]).unwrap();
+    t.try_reattach().unwrap();
+    let previous = t.clone();
+    let mut ids = BTreeSet::new();
+    reachable_blobs(&previous.node.as_ref(), &store, &mut ids).unwrap();
+    let old_value = ids
+        .into_iter()
+        .find(|id| store.read(id).unwrap().as_ref() == [1u8; 1000])
+        .unwrap();
+    // replace the value of the inner node "a", keeping its children
+    t.try_insert("a", [4u8; 1000]).unwrap();
+    t.try_reattach().unwrap();
+    let pending = t.try_pending_free(&previous).unwrap();
+    assert!(pending.ids.contains(&old_value));
+}
+
+#[test]
+fn concurrent_clone_and_mutate() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<RadixTree>();
+    assert_send_sync::<RadixTree<MemStore>>();
+    let tree = (0..1000u32)
+        .map(|i| (i.to_be_bytes(), [1u8; 20]))
+        .collect::<RadixTree>();
+    let reference = to_btree_map(&tree);
+    std::thread::scope(|scope| {
+        for i in 0..4u8 {
+            let tree = &tree;
+            let reference = &reference;
+            scope.spawn(move || {
+                for _ in 0..10 {
+                    let mut writer = tree.clone();
+                    let reader = writer.clone();
+                    for key in reference.keys().step_by(7) {
+                        writer.insert(key, [i; 20]);
+                    }
+                    writer.remove_prefix([0, 0, 1]);
+                    // the writer only modifies its own copies of shared nodes
+                    assert_eq!(&to_btree_map(&reader), reference);
+                    assert!(writer.iter().all(|(k, v)| {
+                        v.as_ref() == [1u8; 20]
+                            || (v.as_ref() == [i; 20] && reference.contains_key(k.as_ref()))
+                    }));
+                }
+            });
+        }
+    });
+    assert_eq!(to_btree_map(&tree), reference);
+}
+
+#[test]
+fn content_store_dedup() {
+    let store = crate::store::ContentStore::default();
+    let a = (0..1000u32)
+        .map(|i| (format!("{:04}", i), [i as u8; 20]))
+        .collect::<RadixTree>();
+    let mut b = a.clone();
+    b.insert("0123", [0u8; 20]);
+    let mut at = a.try_attached(store.clone()).unwrap();
+    let ida = at.try_reattach().unwrap();
+    let count = store.count();
+    // writing the same tree again stores nothing new
+    let mut at2 = a.try_attached(store.clone()).unwrap();
+    assert_eq!(at2.try_reattach().unwrap(), ida);
+    assert_eq!(store.count(), count);
+    // a similar tree only stores the nodes on the path to the change
+    let mut bt = b.try_attached(store.clone()).unwrap();
+    bt.try_reattach().unwrap();
+    assert!(store.count() - count < count);
+    assert_eq!(at.try_detached().unwrap(), a);
+    assert_eq!(bt.try_detached().unwrap(), b);
+}
+
+#[test]
+fn import_chunked_resume() {
+    let tree = (0..100u32)
+        .map(|i| (format!("{:03}", i), [i as u8; 20]))
+        .collect::<RadixTree>();
+    let mut chunks = Vec::new();
+    let manifest = tree
+        .export_chunked(
+            |_, chunk| {
+                chunks.push(chunk.to_vec());
+                Ok(())
+            },
+            100,
+        )
+        .unwrap();
+    let mut import = RadixTree::import_chunked(manifest);
+    import.add(0, &chunks[0]).unwrap();
+    // out of order
+    assert!(import.add(2, &chunks[2]).is_err());
+    // corrupted, and does not change the state
+    let mut corrupted = chunks[1].clone();
+    corrupted[0] ^= 1;
+    assert!(import.add(1, &corrupted).is_err());
+    assert_eq!(import.next_chunk(), 1);
+    assert!(!import.is_complete());
+    assert!(import.clone().finish().is_err());
+    // resume with the next chunk
+    for (i, chunk) in chunks.iter().enumerate().skip(import.next_chunk()) {
+        import.add(i, chunk).unwrap();
+    }
+    assert!(import.is_complete());
+    assert!(import.add(chunks.len(), &[]).is_err());
+    assert_eq!(import.finish().unwrap(), tree);
+}
+
+#[test]
+fn compact() {
+    let store = MemStore::default();
+    let mut tree = (0..1000u32)
+        .map(|i| (format!("{:04}", i), vec![i as u8; 200]))
+        .collect::<RadixTree>()
+        .try_attached(store.clone())
+        .unwrap();
+    let mut roots = Vec::new();
+    let mut versions = Vec::new();
+    for i in 0..10u32 {
+        tree.try_insert(format!("{:04}", i * 97), [i as u8; 300])
+            .unwrap();
+        tree.try_remove(format!("{:04}", i * 13)).unwrap();
+        roots.push(tree.try_reattach().unwrap());
+        versions.push(tree.try_detached().unwrap());
+    }
+    // keep the last two versions
+    let target = MemStore::default();
+    let ids = RadixTree::try_compact(&store, &roots[8..], &target).unwrap();
+    let mut live = BTreeSet::new();
+    for id in &ids {
+        live.insert(id.clone());
+        let tree = RadixTree::try_load(target.clone(), Some(id)).unwrap();
+        reachable_blobs(&tree.node.as_ref(), &target, &mut live).unwrap();
+    }
+    // only reachable blobs were copied, and shared subtrees only once
+    assert_eq!(target.count(), live.len());
+    assert!(target.count() < store.count());
+    for (id, expected) in ids.iter().zip(&versions[8..]) {
+        let tree = RadixTree::try_load(target.clone(), Some(id)).unwrap();
+        assert_eq!(&tree.try_detached().unwrap(), expected);
+    }
+}
+
+#[test]
+fn diff_summary_skips_shared() {
+    let store = CountingStore::default();
+    let mut a = (0..1000u32)
+        .map(|i| (format!("{:04}", i), [i as u8; 20]))
+        .collect::<RadixTree>()
+        .try_attached(store.clone())
+        .unwrap();
+    a.try_reattach().unwrap();
+    let mut b = a.clone();
+    b.try_insert("0123", [0u8; 20]).unwrap();
+    b.try_remove("0456").unwrap();
+    b.try_insert("2000", []).unwrap();
+    b.try_reattach().unwrap();
+    let reads = || store.1.load(atomic::Ordering::Relaxed);
+    let before = reads();
+    let diff = a.try_diff_summary(&b, 1).unwrap();
+    let stats = |entries, bytes| PrefixStats { entries, bytes };
+    assert_eq!(
+        diff,
+        vec![
+            (
+                b"0".to_vec(),
+                DiffStats {
+                    added: PrefixStats::default(),
+                    removed: stats(1, 24),
+                    changed: stats(1, 24),
+                }
+            ),
+            (
+                b"2".to_vec(),
+                DiffStats {
+                    added: stats(1, 4),
+                    ..Default::default()
                 }
+            )
+        ]
+    );
+    // only the nodes along the changed paths are read
+    assert!(reads() - before < 20);
+}
+
+#[test]
+fn from_bytes_invalid() {
+    let data = crate::radixtree! { "dog" => "Hund", "dot" => "Punkt" }.to_bytes();
+    assert!(RadixTree::from_bytes(&data).is_ok());
+    for n in 0..data.len() {
+        assert!(RadixTree::from_bytes(&data[..n]).is_err());
+    }
+    let mut trailing = data.clone();
+    trailing.push(0);
+    assert!(RadixTree::from_bytes(&trailing).is_err());
+    let mut version = data;
+    version[4] = 2;
+    assert!(RadixTree::from_bytes(&version).is_err());
+    // root without value and a single child must be merged with the child
+    let mut single = b"rdxt\x01".to_vec();
+    single.extend_from_slice(&[0, 0, 1, 1, b'a', 1, 0]);
+    assert!(RadixTree::from_bytes(&single).is_err());
+}
+
+/// read a tree from a store that corrupts every blob, checking that this never panics
+fn check_corrupt_store(a: &BTreeMap<Vec<u8>, Vec<u8>>, i: usize, x: u8) {
+    let store = MemStore::default();
+    let mut tree = mk_owned_tree(a).try_attached(store.clone()).unwrap();
+    let id = tree.try_reattach().unwrap();
+    let store = CorruptStore(store, i, x);
+    // errors are fine, panics are not
+    if let Ok(tree) = RadixTree::try_load(store, Some(&id)) {
+        // lookups are bounded by the key length
+        for k in a.keys() {
+            let _ = tree.try_get(k);
+            let _ = tree.try_contains_key(k);
+        }
+        // a corrupt id that points back up the tree makes the tree infinite, so only traverse the
+        // tree if verify finds no cycle. Other corrupt children are errors in a hardened build.
+        let report = tree.verify(|_, _| true);
+        let traverse = if cfg!(feature = "no-panic-paths") {
+            !report.invalid.values().any(|m| m.contains("cycle"))
+        } else {
+            report.is_ok()
+        };
+        if traverse {
+            let _ = tree.try_iter().collect::<Vec<_>>();
+            let _ = tree.try_detached();
+            let _ = tree.try_len();
+            let _ = tree.try_nth(1);
+            let _ = tree.try_first_entry();
+            let _ = tree.try_last_entry();
+            for k in a.keys() {
+                let _ = tree.try_scan_prefix(k).map(|iter| iter.collect::<Vec<_>>());
             }
         }
-        prop_assert_eq!(a, leafs);
     }
+}
+
+/// bytes of a tree with keys "", "a", "aa", ..., with every key a child of the previous one
+fn nested_tree_bytes(depth: usize) -> Vec<u8> {
+    let mut data = TREE_BYTES_HEADER.to_vec();
+    data.extend_from_slice(&[0, 1, 1]);
+    for i in 1..=depth {
+        data.extend_from_slice(&[1, b'a', 1, (i < depth) as u8]);
+    }
+    data
+}
 
-    #[test]
-    fn first_last_value_entry(a in arb_tree_contents()) {
-        let at = mk_owned_tree(&a);
-        prop_assert_eq!(at.first_value().map(|x| x.to_vec()), a.values().next().map(|v| v.to_vec()));
-        prop_assert_eq!(at.first_entry(Vec::new()).map(|(k, v)| (k.to_vec(), v.to_vec())), a.iter().next().map(|(k, v)| (k.to_vec(), v.to_vec())));
-        prop_assert_eq!(at.last_value().map(|x| x.to_vec()), a.values().last().map(|v| v.to_vec()));
-        prop_assert_eq!(at.last_entry(Vec::new()).map(|(k, v)| (k.to_vec(), v.to_vec())), a.iter().last().map(|(k, v)| (k.to_vec(), v.to_vec())));
+#[test]
+fn from_bytes_deeply_nested() {
+    let data = nested_tree_bytes(MAX_TREE_BYTES_DEPTH);
+    let tree = RadixTree::from_bytes(&data).unwrap();
+    assert_eq!(tree.len(), MAX_TREE_BYTES_DEPTH as u64 + 1);
+    assert_eq!(tree.to_bytes(), data);
+    assert!(RadixTree::from_bytes(&nested_tree_bytes(MAX_TREE_BYTES_DEPTH + 1)).is_err());
+    // this would overflow the stack without a depth limit
+    let data = nested_tree_bytes(1_000_000);
+    assert!(RadixTree::from_bytes(&data).is_err());
+    assert!(RadixTree::read_from(&data[..]).is_err());
+    // a stream is read up to the end of the tree, with the same depth limit
+    let mut source = &nested_tree_bytes(MAX_TREE_BYTES_DEPTH)[..];
+    assert!(RadixTree::read_from(&mut source).is_ok());
+    assert!(source.is_empty());
+}
+
+#[test]
+fn par_import_truncated() {
+    let parts = Arc::new(std::sync::Mutex::new(BTreeMap::new()));
+    crate::radixtree! { "dog" => "Hund" }
+        .par_export(1, |i| PartSink(parts.clone(), i))
+        .unwrap();
+    let data = parts.lock().unwrap()[&0].clone();
+    assert!(RadixTree::par_import(vec![&data[..]]).is_ok());
+    for n in 1..data.len() {
+        assert!(RadixTree::par_import(vec![&data[..n]]).is_err());
+    }
+}
+
+#[test]
+fn iter_shuffled_seeds() {
+    let t = (0..1000u32)
+        .map(|i| (i.to_string(), ""))
+        .collect::<RadixTree>();
+    let keys = |seed| {
+        t.iter_shuffled(seed)
+            .map(|(k, _)| k.to_vec())
+            .collect::<Vec<_>>()
+    };
+    let sorted = t.iter().map(|(k, _)| k.to_vec()).collect::<Vec<_>>();
+    assert_ne!(keys(0), sorted);
+    assert_ne!(keys(0), keys(1));
+    assert_eq!(keys(1), keys(1));
+}
+
+#[test]
+fn entry_api() {
+    let mut t = crate::radixtree! { "dog" => "Hund" };
+    match t.entry("cat") {
+        Entry::Vacant(entry) => {
+            assert_eq!(entry.key(), b"cat");
+            assert_eq!(entry.insert("Katze").into_ref(), b"Katze");
+        }
+        Entry::Occupied(_) => panic!("cat should be vacant"),
+    }
+    match t.entry("dog") {
+        Entry::Occupied(mut entry) => assert_eq!(entry.insert("Hündin"), b"Hund"),
+        Entry::Vacant(_) => panic!("dog should be occupied"),
+    }
+    assert_eq!(t.entry("cow").or_default().get(), b"");
+    assert_eq!(&t["dog"], "Hündin".as_bytes());
+    assert_eq!(&t["cat"], b"Katze");
+    assert_eq!(&t["cow"], b"");
+}
+
+#[test]
+fn front_coded_key_reader_invalid() {
+    // shared prefix of the first key can not be longer than the empty previous key
+    let data = [1u8, 0];
+    let res = FrontCodedKeyReader::new(&data[..]).next().unwrap();
+    assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    let t = crate::radixtree! { "romane", "romanus", "romulus", "rubens" };
+    let mut data = Vec::new();
+    t.export_keys_front_coded(&mut data).unwrap();
+    assert_eq!(&data[..8], b"\x00\x06romane");
+    assert_eq!(&data[8..12], b"\x05\x02us");
+}
+
+#[test]
+fn cursor_str() {
+    let t = crate::radixtree! { "romane", "romanus", "romulus", "rubens" };
+    let mut c = t.cursor();
+    assert!(c.seek("romb"));
+    assert_eq!(c.key(), Some(&b"romulus"[..]));
+    assert!(c.prev());
+    assert_eq!(c.key(), Some(&b"romanus"[..]));
+    assert!(c.next() && c.next());
+    assert_eq!(c.key(), Some(&b"rubens"[..]));
+    assert!(!c.next());
+    assert_eq!(c.key(), None);
+    assert!(!c.seek("s"));
+}
+
+#[test]
+fn range_str() {
+    let t = crate::radixtree! { "romane", "romanus", "romulus", "rubens", "ruber" };
+    let keys = |iter: &mut dyn Iterator<Item = (IterKey, Value)>| {
+        iter.map(|(k, _)| String::from_utf8(k.to_vec()).unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        keys(&mut t.range("romanus".."rubens")),
+        ["romanus", "romulus"]
+    );
+    assert_eq!(keys(&mut t.range("rub"..)), ["rubens", "ruber"]);
+    assert_eq!(keys(&mut t.range(..="romane")), ["romane"]);
+}
+
+#[test]
+fn eq_empty_root_value() {
+    let empty = RadixTree::default();
+    let mut root = RadixTree::default();
+    // same prefix and children as the empty tree, but with an empty value
+    root.insert("", "");
+    assert_ne!(empty, root);
+    assert_ne!(empty.cmp(&root), Ordering::Equal);
+    root.remove("");
+    assert_eq!(empty, root);
+}
+
+#[test]
+fn index_str() {
+    let t = crate::radixtree! { "dog" => "Hund", "cat" => "Katze" };
+    assert_eq!(&t["dog"], b"Hund");
+    assert_eq!(&t[b"cat"], b"Katze");
+    assert_eq!(t.get_or_default("cow"), b"");
+}
+
+#[test]
+#[should_panic(expected = "key not found")]
+fn index_missing() {
+    let t = crate::radixtree! { "dog" => "Hund" };
+    let _ = &t["do"];
+}
+
+#[test]
+fn last_value_fixed_record_size() {
+    // all children have the same serialized size, so the record size is set
+    let a = btreemap! { b"a".to_vec() => b"1".to_vec(), b"b".to_vec() => b"2".to_vec(), b"c".to_vec() => b"3".to_vec() };
+    let store = MemStore::default();
+    let at = mk_owned_tree(&a).try_attached(store.clone()).unwrap();
+    let last = at.try_last_value().unwrap().unwrap();
+    assert_eq!(last.load(&store).unwrap().as_ref(), b"3");
+    for (k, v) in &a {
+        assert_eq!(
+            at.try_get(k)
+                .unwrap()
+                .unwrap()
+                .load(&store)
+                .unwrap()
+                .as_ref(),
+            &v[..]
+        );
+    }
+    assert!(!at.try_contains_key(b"d").unwrap());
+}
+
+#[test]
+fn polymorphic_keys() {
+    let mut t = RadixTree::default();
+    t.insert("str", "1");
+    t.insert(String::from("string"), String::from("2"));
+    t.insert(Vec::from(*b"bytes"), vec![3]);
+    t.insert(*b"array", [4]);
+    t.insert(&b"slice"[..], &[5][..]);
+    assert!(t.contains_key(String::from("str")));
+    assert!(t.contains_key(b"string"));
+    assert!(t.contains_key(Vec::from("bytes")));
+    assert!(t.contains_key(*b"array"));
+    assert_eq!(t.get("slice").unwrap().as_ref(), &[5]);
+    assert!(t.has_prefix(String::from("s")));
+    assert_eq!(t.first_entry().unwrap().0, b"array");
+    assert_eq!(t.last_entry().unwrap().0, b"string");
+    t.remove(String::from("str"));
+    t.remove_prefix(*b"s");
+    assert_eq!(
+        t.iter().map(|(k, _)| k.to_vec()).collect::<Vec<_>>(),
+        vec![b"array".to_vec(), b"bytes".to_vec()]
+    );
+}
+
+#[test]
+fn outer_combine_custom_error() {
+    // a user defined error that only knows about the store error
+    #[derive(Debug)]
+    struct MyError;
+
+    impl From<anyhow::Error> for MyError {
+        fn from(_: anyhow::Error) -> Self {
+            MyError
+        }
     }
+
+    let a = btreemap! { b"a".to_vec() => b"1".to_vec() };
+    let b = btreemap! { b"b".to_vec() => b"2".to_vec() };
+    let store = MemStore::default();
+    let at = mk_owned_tree(&a).try_attached(store.clone()).unwrap();
+    let bt = mk_owned_tree(&b).try_attached(store.clone()).unwrap();
+    let r = at
+        .try_outer_combine::<_, MyError, _>(&bt, |_, b| Ok(Some(b.detached(&store)?)))
+        .unwrap();
+    let mut reference = a.clone();
+    reference.extend(b);
+    assert_eq!(to_btree_map(&r), reference);
 }
 
 #[test]
@@ -878,3 +2866,28 @@ fn remove_prefix_with5() {
     let r = to_btree_map(&at);
     assert_eq!(r, btreemap! {});
 }
+
+#[cfg(feature = "alloc-count")]
+#[test]
+fn allocation_budget() {
+    use crate::alloc_count::count_allocations;
+    // make sure the counting allocator is active
+    let (_, n) = count_allocations(|| Vec::<u8>::with_capacity(1));
+    assert_eq!(n, 1);
+    let elems = (0..1000u64)
+        .map(|i| (i.to_string(), [b'x'; 100]))
+        .collect::<BTreeMap<_, _>>();
+    let t = elems.iter().collect::<RadixTree>();
+    for key in elems.keys() {
+        // lookups on a detached tree must not allocate
+        let (v, n) = count_allocations(|| t.get(key));
+        assert_eq!(n, 0);
+        assert!(v.is_some());
+        let (_, n) = count_allocations(|| t.contains_key(key));
+        assert_eq!(n, 0);
+        let (_, n) = count_allocations(|| t.get_or_default(key).len());
+        assert_eq!(n, 0);
+    }
+    let (_, n) = count_allocations(|| t.contains_key("nope"));
+    assert_eq!(n, 0);
+}
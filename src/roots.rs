@@ -0,0 +1,277 @@
+//! Named roots, for keeping several versions of a dataset in one store
+//!
+//! ## Example
+//!
+//! ```rust
+//! # use radixdb::{roots::Roots, store::MemStore, RadixTree};
+//! let store = MemStore::default();
+//! let mut roots = Roots::empty(store.clone());
+//! let mut tree = RadixTree::empty(store.clone());
+//! tree.try_insert("a", "1")?;
+//! roots.try_snapshot("v1", &mut tree)?;
+//! tree.try_insert("b", "2")?;
+//! roots.try_snapshot("v2", &mut tree)?;
+//! // the id of the table of roots, to be kept e.g. using PagedFileStore::commit_root
+//! let id = roots.try_commit()?;
+//! let roots = Roots::try_load(store, Some(&id))?;
+//! let v1 = roots.try_get_tree("v1")?.unwrap();
+//! assert!(!v1.try_contains_key("b")?);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use crate::{store::BlobStore, RadixTree};
+use std::collections::BTreeSet;
+
+/// A table of named root ids of trees in a store
+///
+/// The table is itself a tree in the same store, mapping names to root ids, so it can be persisted
+/// with a single id using [Roots::try_commit]. Snapshots of the same dataset share all unchanged
+/// nodes, since attaching a modified tree only writes the nodes that changed.
+///
+/// Setting or removing a root only changes the table in memory. Until the next [Roots::try_commit]
+/// the table is dirty, see [Roots::is_dirty], and the blobs of the new roots are not reachable from
+/// any persisted id.
+pub struct Roots<S: BlobStore> {
+    names: RadixTree<S>,
+    /// id of the table as of the last load or commit
+    committed: Option<Vec<u8>>,
+    /// true if roots were set or removed since the last load or commit
+    dirty: bool,
+}
+
+impl<S: BlobStore + Clone> Roots<S> {
+    /// A table without any roots
+    pub fn empty(store: S) -> Self {
+        Self {
+            names: RadixTree::empty(store),
+            committed: None,
+            dirty: false,
+        }
+    }
+
+    /// Load a table of roots from an id returned by [Roots::try_commit], or an empty table for None
+    pub fn try_load(store: S, id: Option<impl AsRef<[u8]>>) -> Result<Self, S::Error> {
+        let committed = id.as_ref().map(|id| id.as_ref().to_vec());
+        Ok(Self {
+            names: RadixTree::try_load(store, id)?,
+            committed,
+            dirty: false,
+        })
+    }
+
+    /// Record `id` as the root with the given name, replacing any previous root with that name
+    pub fn try_set_root(&mut self, name: impl AsRef<[u8]>, id: &[u8]) -> Result<(), S::Error> {
+        self.dirty = true;
+        self.names.try_insert(name, id)
+    }
+
+    /// The root id with the given name
+    pub fn try_get_root(&self, name: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, S::Error> {
+        match self.names.try_get(name)? {
+            Some(value) => Ok(Some(value.load(RadixTree::store(&self.names))?.to_vec())),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove the root with the given name
+    ///
+    /// The blobs of the tree stay in the store, since they might be shared with other roots.
+    pub fn try_remove_root(&mut self, name: impl AsRef<[u8]>) -> Result<(), S::Error> {
+        if self.names.try_remove(name)?.is_some() {
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// True if roots were set or removed since the last [Roots::try_load] or [Roots::try_commit]
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// All names in order
+    pub fn try_names(&self) -> impl Iterator<Item = Result<Vec<u8>, S::Error>> {
+        self.names.try_iter().map(|r| r.map(|(k, _)| k.to_vec()))
+    }
+
+    /// Attach `tree` and record its root under `name`, returning the root id
+    pub fn try_snapshot(
+        &mut self,
+        name: impl AsRef<[u8]>,
+        tree: &mut RadixTree<S>,
+    ) -> Result<Vec<u8>, S::Error> {
+        let id = tree.try_reattach()?;
+        self.try_set_root(name, &id)?;
+        Ok(id)
+    }
+
+    /// Load the tree with the given name
+    pub fn try_get_tree(&self, name: impl AsRef<[u8]>) -> Result<Option<RadixTree<S>>, S::Error> {
+        match self.try_get_root(name)? {
+            Some(id) => Ok(Some(RadixTree::try_load(
+                RadixTree::store(&self.names).clone(),
+                Some(id),
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Write the table to the store, returning its id
+    pub fn try_commit(&mut self) -> Result<Vec<u8>, S::Error> {
+        let id = self.names.try_reattach()?;
+        self.committed = Some(id.clone());
+        self.dirty = false;
+        Ok(id)
+    }
+
+    /// Store ids of all blobs reachable from the table or from one of the roots
+    ///
+    /// This includes the id of the table as of the last [Roots::try_load] or [Roots::try_commit]. Fails
+    /// if the table is dirty, since the committed table would then not match the roots in memory. Call
+    /// this right after a commit, since blobs that are only reachable from an earlier version of the
+    /// table are not included.
+    pub fn try_reachable(&self) -> Result<BTreeSet<Vec<u8>>, S::Error> {
+        if self.dirty {
+            return Err(anyhow::anyhow!("table of roots has uncommitted changes").into());
+        }
+        let mut res = self.names.try_reachable_blobs()?;
+        res.extend(self.committed.clone());
+        for name in self.try_names() {
+            let name = name?;
+            if let Some(id) = self.try_get_root(&name)? {
+                // roots with the same id are only visited once
+                if res.insert(id.clone()) {
+                    let tree =
+                        RadixTree::try_load(RadixTree::store(&self.names).clone(), Some(id))?;
+                    tree.try_extend_reachable_blobs(&mut res)?;
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Mark and sweep garbage collection: the ids in `ids` that are not reachable from the table or any root
+    ///
+    /// `ids` are all ids in the store, e.g. from [crate::store::MemStore::ids]. The returned blobs can be
+    /// removed from the store, see [Roots::try_reachable]. Fails if the table is dirty, since blobs
+    /// of roots that are not yet committed would otherwise be collected. Stores that can not remove single blobs can use
+    /// [Roots::try_compact] instead.
+    pub fn try_garbage(
+        &self,
+        ids: impl IntoIterator<Item = impl AsRef<[u8]>>,
+    ) -> Result<Vec<Vec<u8>>, S::Error> {
+        let reachable = self.try_reachable()?;
+        Ok(ids
+            .into_iter()
+            .filter(|id| !reachable.contains(id.as_ref()))
+            .map(|id| id.as_ref().to_vec())
+            .collect())
+    }
+
+    /// Copy forward garbage collection: copy the table and all roots to `target`
+    ///
+    /// Only reachable blobs are copied, and subtrees shared between roots stay shared, see
+    /// [RadixTree::try_compact]. Commit the returned table to get its id in `target`.
+    pub fn try_compact<S2: BlobStore + Clone>(&self, target: S2) -> anyhow::Result<Roots<S2>>
+    where
+        anyhow::Error: From<S::Error> + From<S2::Error>,
+    {
+        let mut names = Vec::new();
+        let mut ids = Vec::new();
+        for name in self.try_names() {
+            let name = name?;
+            if let Some(id) = self.try_get_root(&name)? {
+                names.push(name);
+                ids.push(id);
+            }
+        }
+        let ids = RadixTree::try_compact(RadixTree::store(&self.names), &ids, &target)?;
+        let mut res = Roots::empty(target);
+        for (name, id) in names.iter().zip(ids) {
+            res.try_set_root(name, &id)?;
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemStore;
+
+    #[test]
+    fn named_snapshots() -> anyhow::Result<()> {
+        let store = MemStore::default();
+        let mut roots = Roots::empty(store.clone());
+        let mut tree = (0..100u32)
+            .map(|i| (i.to_be_bytes(), [i as u8; 20]))
+            .collect::<RadixTree>()
+            .try_attached(store.clone())?;
+        assert!(!roots.is_dirty());
+        let v1 = roots.try_snapshot("v1", &mut tree)?;
+        assert!(roots.is_dirty());
+        let count = store.count();
+        tree.try_insert(1000u32.to_be_bytes(), [0u8; 20])?;
+        roots.try_snapshot("v2", &mut tree)?;
+        // only the changed path is written
+        assert!(store.count() - count < 10);
+        roots.try_set_root("old", &v1)?;
+        roots.try_remove_root("old")?;
+        let id = roots.try_commit()?;
+        assert!(!roots.is_dirty());
+        let roots = Roots::try_load(store, Some(&id))?;
+        let names = roots.try_names().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(names, vec![b"v1".to_vec(), b"v2".to_vec()]);
+        assert_eq!(roots.try_get_root("v1")?, Some(v1));
+        let v1 = roots.try_get_tree("v1")?.unwrap();
+        let v2 = roots.try_get_tree("v2")?.unwrap();
+        assert_eq!(v1.try_detached()?.len(), 100);
+        assert_eq!(v2.try_detached()?.len(), 101);
+        assert!(roots.try_get_tree("v3")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn garbage_collection() -> anyhow::Result<()> {
+        let store = MemStore::default();
+        let mut roots = Roots::empty(store.clone());
+        let mut tree = RadixTree::empty(store.clone());
+        for v in 0..10u32 {
+            for i in 0..100u32 {
+                tree.try_insert(i.to_be_bytes(), [v as u8; 20])?;
+            }
+            roots.try_snapshot(format!("v{}", v), &mut tree)?;
+        }
+        let expected = |roots: &Roots<MemStore>| -> anyhow::Result<()> {
+            let names = roots.try_names().collect::<Result<Vec<_>, _>>()?;
+            assert_eq!(names, vec![b"v8".to_vec(), b"v9".to_vec()]);
+            let v8 = roots.try_get_tree("v8")?.unwrap();
+            let value = v8.try_get(0u32.to_be_bytes())?.unwrap();
+            assert_eq!(value.load(RadixTree::store(&v8))?.as_ref(), [8u8; 20]);
+            Ok(())
+        };
+        for v in 0..8 {
+            roots.try_remove_root(format!("v{}", v))?;
+        }
+        // mark and sweep refuses to run with uncommitted roots
+        assert!(roots.try_garbage(store.ids()).is_err());
+        let id = roots.try_commit()?;
+        let count = store.count();
+        // mark and sweep
+        let garbage = roots.try_garbage(store.ids())?;
+        assert!(!garbage.is_empty());
+        assert!(!garbage.contains(&id));
+        for id in &garbage {
+            assert!(store.remove(id));
+        }
+        assert_eq!(store.count(), count - garbage.len());
+        let roots = Roots::try_load(store.clone(), Some(&id))?;
+        expected(&roots)?;
+        assert!(roots.try_garbage(store.ids())?.is_empty());
+        // copy forward
+        let target = MemStore::default();
+        let mut compacted = roots.try_compact(target.clone())?;
+        let id = compacted.try_commit()?;
+        assert!(target.count() <= store.count());
+        expected(&Roots::try_load(target, Some(&id))?)?;
+        Ok(())
+    }
+}
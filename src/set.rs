@@ -0,0 +1,254 @@
+//! A set of byte keys on top of the byte level [RadixTree]
+//!
+//! Keys are inserted with empty values. An empty value is stored inline in the node, so inserting a key never
+//! allocates for the value.
+//!
+//! ## Example
+//!
+//! ```rust
+//! # use radixdb::set::RadixSet;
+//! let a = ["cat", "dog", "mouse"].into_iter().collect::<RadixSet>();
+//! let b = ["dog", "rabbit"].into_iter().collect::<RadixSet>();
+//! assert!(a.contains("dog"));
+//! assert_eq!(a.intersection(&b).iter().collect::<Vec<_>>(), vec![b"dog".to_vec()]);
+//! assert_eq!(a.difference(&b).len(), 2);
+//! ```
+use std::fmt::Debug;
+
+use crate::{
+    store::{BlobStore, Detached},
+    RadixTree,
+};
+
+/// A set of byte keys, stored as a radix tree with empty values
+pub struct RadixSet<S: BlobStore = Detached> {
+    tree: RadixTree<S>,
+}
+
+impl<S: BlobStore + Clone> Clone for RadixSet<S> {
+    fn clone(&self) -> Self {
+        Self::new(self.tree.clone())
+    }
+}
+
+impl<S: BlobStore> Debug for RadixSet<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("RadixSet").field(&self.tree).finish()
+    }
+}
+
+impl<S: BlobStore + Default> Default for RadixSet<S> {
+    fn default() -> Self {
+        Self::new(RadixTree::default())
+    }
+}
+
+impl<S: BlobStore> From<RadixTree<S>> for RadixSet<S> {
+    fn from(tree: RadixTree<S>) -> Self {
+        Self::new(tree)
+    }
+}
+
+impl PartialEq for RadixSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.tree == other.tree
+    }
+}
+
+impl Eq for RadixSet {}
+
+impl<S: BlobStore> RadixSet<S> {
+    /// Wrap a byte level tree
+    ///
+    /// The tree should have empty values, like all sets built by this module. Values are kept as they
+    /// are, so two sets with the same keys but different values are not equal, and [RadixSet::union]
+    /// and [RadixSet::intersection] keep the values of `self` for keys in both sets.
+    pub fn new(tree: RadixTree<S>) -> Self {
+        Self { tree }
+    }
+
+    /// The underlying byte level tree
+    pub fn tree(&self) -> &RadixTree<S> {
+        &self.tree
+    }
+
+    /// Unwrap into the underlying byte level tree
+    pub fn into_inner(self) -> RadixTree<S> {
+        self.tree
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+}
+
+impl RadixSet {
+    pub fn len(&self) -> u64 {
+        self.tree.len()
+    }
+
+    pub fn contains(&self, key: impl AsRef<[u8]>) -> bool {
+        self.tree.contains_key(key)
+    }
+
+    pub fn insert(&mut self, key: impl AsRef<[u8]>) {
+        self.tree.insert(key, [])
+    }
+
+    /// Remove a key, returning true if it was in the set
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> bool {
+        self.tree.remove(key).is_some()
+    }
+
+    /// All keys in order
+    pub fn iter(&self) -> impl Iterator<Item = Vec<u8>> {
+        self.tree.iter().map(|(k, _)| k.to_vec())
+    }
+
+    /// All keys starting with `prefix` in order
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> impl Iterator<Item = Vec<u8>> + '_ {
+        self.tree.scan_prefix(prefix).map(|(k, _)| k.to_vec())
+    }
+
+    pub fn union(&self, that: &RadixSet) -> RadixSet {
+        Self::new(
+            self.tree
+                .outer_combine(&that.tree, |a, _| Some(a.to_owned())),
+        )
+    }
+
+    pub fn intersection(&self, that: &RadixSet) -> RadixSet {
+        Self::new(
+            self.tree
+                .inner_combine(&that.tree, |a, _| Some(a.to_owned())),
+        )
+    }
+
+    pub fn difference(&self, that: &RadixSet) -> RadixSet {
+        Self::new(self.tree.difference(&that.tree))
+    }
+
+    /// In place union, sharing the subtrees of `that` that are not in this set
+    pub fn union_with(&mut self, that: &RadixSet) {
+        self.tree.outer_combine_with(&that.tree, |_, _| {})
+    }
+
+    /// In place intersection
+    pub fn intersection_with(&mut self, that: &RadixSet) {
+        self.tree.inner_combine_with(&that.tree, |_, _| {})
+    }
+
+    /// In place difference
+    pub fn difference_with(&mut self, that: &RadixSet) {
+        self.tree.left_combine_with(&that.tree, |a, _| a.set(None))
+    }
+
+    pub fn is_subset_of(&self, that: &RadixSet) -> bool {
+        self.tree.is_subset_of(&that.tree)
+    }
+
+    pub fn is_disjoint_from(&self, that: &RadixSet) -> bool {
+        self.tree.is_disjoint_from(&that.tree)
+    }
+}
+
+impl<K: AsRef<[u8]>> FromIterator<K> for RadixSet {
+    fn from_iter<T: IntoIterator<Item = K>>(iter: T) -> Self {
+        let mut res = Self::default();
+        for key in iter {
+            res.insert(key);
+        }
+        res
+    }
+}
+
+#[cfg(feature = "custom-store")]
+impl<S: BlobStore + Clone> RadixSet<S> {
+    pub fn try_contains(&self, key: impl AsRef<[u8]>) -> Result<bool, S::Error> {
+        self.tree.try_contains_key(key)
+    }
+
+    pub fn try_insert(&mut self, key: impl AsRef<[u8]>) -> Result<(), S::Error> {
+        self.tree.try_insert(key, [])
+    }
+
+    pub fn try_remove(&mut self, key: impl AsRef<[u8]>) -> Result<bool, S::Error> {
+        Ok(self.tree.try_remove(key)?.is_some())
+    }
+
+    /// All keys in order. Values are never loaded from the store.
+    pub fn try_iter(&self) -> impl Iterator<Item = Result<Vec<u8>, S::Error>> {
+        self.tree.try_iter().map(|r| r.map(|(k, _)| k.to_vec()))
+    }
+
+    /// Write all changes to the store, see [RadixTree::try_reattach]
+    pub fn try_reattach(&mut self) -> Result<Vec<u8>, S::Error> {
+        self.tree.try_reattach()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemStore;
+    use proptest::prelude::*;
+    use std::collections::BTreeSet;
+
+    fn arb_keys() -> impl Strategy<Value = BTreeSet<Vec<u8>>> {
+        proptest::collection::btree_set(proptest::collection::vec(b'0'..=b'9', 0..9), 0..10)
+    }
+
+    proptest! {
+        #[test]
+        fn set_ops(a in arb_keys(), b in arb_keys()) {
+            let at = a.iter().collect::<RadixSet>();
+            let bt = b.iter().collect::<RadixSet>();
+            prop_assert_eq!(at.len(), a.len() as u64);
+            prop_assert_eq!(at.iter().collect::<BTreeSet<_>>(), a.clone());
+            for k in &b {
+                prop_assert_eq!(at.contains(k), a.contains(k));
+            }
+            let keys = |t: &RadixSet| t.iter().collect::<BTreeSet<_>>();
+            let mut union = at.clone();
+            union.union_with(&bt);
+            prop_assert_eq!(keys(&at.union(&bt)), a.union(&b).cloned().collect::<BTreeSet<_>>());
+            prop_assert_eq!(&union, &at.union(&bt));
+            let mut intersection = at.clone();
+            intersection.intersection_with(&bt);
+            prop_assert_eq!(keys(&at.intersection(&bt)), a.intersection(&b).cloned().collect::<BTreeSet<_>>());
+            prop_assert_eq!(&intersection, &at.intersection(&bt));
+            let mut difference = at.clone();
+            difference.difference_with(&bt);
+            prop_assert_eq!(keys(&at.difference(&bt)), a.difference(&b).cloned().collect::<BTreeSet<_>>());
+            prop_assert_eq!(&difference, &at.difference(&bt));
+            prop_assert_eq!(at.is_subset_of(&bt), a.is_subset(&b));
+            prop_assert_eq!(at.is_disjoint_from(&bt), a.is_disjoint(&b));
+        }
+
+        #[test]
+        fn set_attached(a in arb_keys()) {
+            let store = MemStore::default();
+            let t = a.iter().collect::<RadixSet>();
+            let mut t = RadixSet::new(t.into_inner().try_attached(store).unwrap());
+            for k in &a {
+                prop_assert!(t.try_contains(k).unwrap());
+            }
+            t.try_insert(b"x").unwrap();
+            t.try_reattach().unwrap();
+            let mut reference = a.clone();
+            reference.insert(b"x".to_vec());
+            let keys = t.try_iter().collect::<Result<BTreeSet<_>, _>>().unwrap();
+            prop_assert_eq!(keys, reference);
+        }
+    }
+
+    #[test]
+    fn set_values() {
+        let a = RadixSet::new(crate::radixtree! { "a" => "" });
+        let b = RadixSet::new(crate::radixtree! { "a" => "x" });
+        assert_eq!(a.iter().collect::<Vec<_>>(), b.iter().collect::<Vec<_>>());
+        assert_ne!(a, b);
+        assert_eq!(b.union(&a), b);
+        assert_eq!(a.intersection(&b), a);
+    }
+}
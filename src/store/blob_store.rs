@@ -2,15 +2,21 @@ use std::{
     any::Any,
     borrow::Borrow,
     cmp::Ordering,
+    convert::Infallible,
     fmt::Debug,
     hash::Hash,
     ops::{Bound, Deref, RangeBounds},
     sync::Arc,
 };
 
+/// The maximum size of an id returned by [BlobStore::write]
+///
+/// Ids are stored inline in tree nodes, together with a length header and one extra byte.
+pub const MAX_ID_LEN: usize = 126;
+
 /// A generic blob store with variable id size
 pub trait BlobStore: Debug + Send + Sync + 'static {
-    /// The error. Use NoError for a store that can never fail
+    /// The error. Use [NoError] for a store that can never fail
     type Error: From<NoError> + From<anyhow::Error> + Debug;
 
     /// Read a blob with the given id. Since ids can be of arbitrary size, passed as a slice
@@ -18,16 +24,34 @@ pub trait BlobStore: Debug + Send + Sync + 'static {
 
     /// Write a blob, returning an id into a target vec `tgt`.
     ///
+    /// Ids must be between 1 and [MAX_ID_LEN] bytes. Writing a tree to a store that returns other ids fails
+    /// with the error from [BlobStore::invalid_data].
+    ///
     /// If this returns an error, the tgt vec is guaranteed to be unmodified.
     fn write(&self, data: &[u8]) -> std::result::Result<Vec<u8>, Self::Error>;
 
     /// Ensure all data is persisted
     fn sync(&self) -> std::result::Result<(), Self::Error>;
 
+    /// The length of the blob with the given id
+    ///
+    /// The default reads the whole blob. Stores that keep the length next to the data override this.
+    fn blob_len(&self, id: &[u8]) -> std::result::Result<usize, Self::Error> {
+        Ok(self.read(id)?.len())
+    }
+
     /// True if the store needs deep detach. This is true for basically all stores except the special NoStore store
     fn needs_deep_detach(&self) -> bool {
         true
     }
+
+    /// The error for data of this store that can not be used, like an id returned by [BlobStore::write]
+    /// that is not between 1 and [MAX_ID_LEN] bytes, or a children blob that does not decode
+    ///
+    /// The default converts an [anyhow::Error] with the message.
+    fn invalid_data(&self, message: &str) -> Self::Error {
+        anyhow::anyhow!("{}", message).into()
+    }
 }
 
 /// A blob that can be cheaply sliced
@@ -116,7 +140,7 @@ impl<'a> Blob<'a> {
     pub fn to_owned(self) -> OwnedBlob {
         if self.owner.is_some() {
             OwnedBlob {
-                data: unsafe { std::mem::transmute(self.data) },
+                data: unsafe { std::mem::transmute::<&'a [u8], &'static [u8]>(self.data) },
                 owner: self.owner,
             }
         } else {
@@ -243,9 +267,17 @@ impl BlobStore for DynBlobStore {
         self.as_ref().sync()
     }
 
+    fn blob_len(&self, id: &[u8]) -> std::result::Result<usize, Self::Error> {
+        self.as_ref().blob_len(id)
+    }
+
     fn needs_deep_detach(&self) -> bool {
         self.as_ref().needs_deep_detach()
     }
+
+    fn invalid_data(&self, message: &str) -> Self::Error {
+        self.as_ref().invalid_data(message)
+    }
 }
 
 /// A special store that does nothing, to be used with detached trees that don't use a store
@@ -280,19 +312,24 @@ impl BlobStore for Detached {
 pub enum NoError {}
 
 impl From<NoError> for anyhow::Error {
-    fn from(_: NoError) -> Self {
-        panic!()
+    fn from(never: NoError) -> Self {
+        match never {}
     }
 }
 
 impl From<anyhow::Error> for NoError {
-    fn from(_: anyhow::Error) -> Self {
-        panic!()
+    fn from(error: anyhow::Error) -> Self {
+        panic!("{}", error)
     }
 }
 
-/// Extension trait that adds unwrap_safe for unwrapping results safely when the error type is uninhabited
+impl From<Infallible> for NoError {
+    fn from(never: Infallible) -> Self {
+        match never {}
+    }
+}
 
+/// Extension trait that adds unwrap_safe for unwrapping results safely when the error type is uninhabited
 pub trait UnwrapSafeExt<T> {
     /// Safe unwrap - guaranteed not to panic
     fn unwrap_safe(self) -> T;
@@ -300,10 +337,44 @@ pub trait UnwrapSafeExt<T> {
 
 impl<T> UnwrapSafeExt<T> for Result<T, NoError> {
     fn unwrap_safe(self) -> T {
-        self.unwrap()
+        match self {
+            Ok(value) => value,
+            Err(never) => match never {},
+        }
     }
 }
 
+/// Marker trait for stores that can never fail, such as [Detached]
+pub trait InfallibleStore: BlobStore<Error = NoError> {}
+
+impl<S: BlobStore<Error = NoError>> InfallibleStore for S {}
+
+/// Iterator adapter that safely unwraps the items of an iterator over results that can never fail
+#[derive(Debug, Clone)]
+pub struct UnwrapSafeIter<I>(I);
+
+impl<T, I: Iterator<Item = Result<T, NoError>>> Iterator for UnwrapSafeIter<I> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.0.next().map(UnwrapSafeExt::unwrap_safe)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+/// Extension trait that adds unwrap_safe_iter for iterators over results that can never fail
+pub trait UnwrapSafeIterExt<T>: Iterator<Item = Result<T, NoError>> + Sized {
+    /// Safe unwrap of all items - guaranteed not to panic
+    fn unwrap_safe_iter(self) -> UnwrapSafeIter<Self> {
+        UnwrapSafeIter(self)
+    }
+}
+
+impl<T, I: Iterator<Item = Result<T, NoError>>> UnwrapSafeIterExt<T> for I {}
+
 #[cfg(test)]
 mod tests {
     #![allow(dead_code)]
@@ -0,0 +1,446 @@
+use super::{blob_store::OwnedBlob, Blob, BlobStore};
+use std::sync::Arc;
+
+/// Codec byte for blobs that are stored as they are
+const RAW: u8 = 0;
+/// Codec byte for blobs compressed in the lz4 block format, followed by the uncompressed size as u32 le
+const LZ4: u8 = 1;
+/// Codec byte for zstd frames compressed with the dictionary of the store, followed by the uncompressed size
+/// as u32 le
+#[cfg(feature = "zstd")]
+const ZSTD_DICT: u8 = 2;
+
+/// A store wrapper that compresses blobs
+///
+/// Blobs of at least `threshold` bytes are compressed on write, and decompressed on read. Each blob gets
+/// a header byte indicating the codec, so blobs that do not get smaller are stored as they are, and the
+/// threshold can be changed for an existing store. Compression uses the lz4 block format, which is fast
+/// enough to not matter compared to reading from disk.
+///
+/// Small values have too little repetition to compress well on their own. With the `zstd` feature,
+/// a dictionary trained on typical values, see `CompressedStore::train_dictionary`, can be used instead.
+#[derive(Debug, Clone)]
+pub struct CompressedStore<S> {
+    inner: S,
+    threshold: usize,
+    #[cfg(feature = "zstd")]
+    dictionary: Option<Arc<Vec<u8>>>,
+}
+
+impl<S> CompressedStore<S> {
+    pub fn new(inner: S, threshold: usize) -> Self {
+        Self {
+            inner,
+            threshold,
+            #[cfg(feature = "zstd")]
+            dictionary: None,
+        }
+    }
+
+    /// The wrapped store, containing the compressed blobs
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<S: BlobStore> CompressedStore<S> {
+    /// Train a zstd dictionary of at most `max_size` bytes on samples of typical values
+    ///
+    /// Training needs a reasonable number of samples, a few hundred at least, and fails if there are too few.
+    pub fn train_dictionary(
+        samples: impl IntoIterator<Item = impl AsRef<[u8]>>,
+        max_size: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let samples = samples
+            .into_iter()
+            .map(|x| x.as_ref().to_vec())
+            .collect::<Vec<_>>();
+        Ok(zstd::dict::from_samples(&samples, max_size)?)
+    }
+
+    /// Compress blobs of at least `threshold` bytes with zstd and `dictionary` from now on
+    ///
+    /// Blobs written before remain readable, except for blobs written with a different dictionary,
+    /// so a store should keep using the same dictionary, see [CompressedStore::try_write_dictionary].
+    pub fn with_dictionary(self, dictionary: Vec<u8>) -> Self {
+        Self {
+            dictionary: Some(Arc::new(dictionary)),
+            ..self
+        }
+    }
+
+    /// Write the dictionary to the store itself, so it can be loaded with [CompressedStore::try_load_dictionary]
+    pub fn try_write_dictionary(&self) -> Result<Option<Vec<u8>>, S::Error> {
+        match &self.dictionary {
+            Some(dictionary) => Ok(Some(self.inner.write(&[&[RAW], &dictionary[..]].concat())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Use the dictionary stored with [CompressedStore::try_write_dictionary]
+    pub fn try_load_dictionary(self, id: &[u8]) -> Result<Self, S::Error> {
+        let dictionary = self.read(id)?.to_vec();
+        Ok(self.with_dictionary(dictionary))
+    }
+}
+
+impl<S: BlobStore> BlobStore for CompressedStore<S> {
+    type Error = S::Error;
+
+    fn read(&self, id: &[u8]) -> std::result::Result<OwnedBlob, Self::Error> {
+        let blob = self.inner.read(id)?;
+        match blob.first() {
+            Some(&RAW) => Ok(blob.slice(1..)),
+            Some(&LZ4) if blob.len() >= 5 => {
+                let len = u32::from_le_bytes(blob[1..5].try_into().unwrap()) as usize;
+                let data = lz4::decompress(&blob[5..], len)
+                    .ok_or_else(|| anyhow::anyhow!("corrupt compressed blob"))?;
+                Ok(Blob::from_arc_vec(Arc::new(data)))
+            }
+            #[cfg(feature = "zstd")]
+            Some(&ZSTD_DICT) if blob.len() >= 5 => {
+                let dictionary = self.dictionary.as_ref().ok_or_else(|| {
+                    anyhow::anyhow!("blob compressed with a dictionary, but the store has none")
+                })?;
+                let len = u32::from_le_bytes(blob[1..5].try_into().unwrap()) as usize;
+                let data = zstd::bulk::Decompressor::with_dictionary(dictionary)
+                    .and_then(|mut d| d.decompress(&blob[5..], len))
+                    .map_err(anyhow::Error::new)?;
+                if data.len() != len {
+                    return Err(anyhow::anyhow!("corrupt compressed blob").into());
+                }
+                Ok(Blob::from_arc_vec(Arc::new(data)))
+            }
+            _ => Err(anyhow::anyhow!("unknown blob codec").into()),
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> std::result::Result<Vec<u8>, Self::Error> {
+        #[cfg(feature = "zstd")]
+        if let Some(dictionary) = &self.dictionary {
+            if data.len() >= self.threshold && data.len() <= u32::MAX as usize {
+                // the length is in the header, and a blob is only ever read with the dictionary of the store
+                let compressed = zstd::bulk::Compressor::with_dictionary(0, dictionary)
+                    .and_then(|mut c| {
+                        c.include_contentsize(false)?;
+                        c.include_dictid(false)?;
+                        c.compress(data)
+                    })
+                    .map_err(anyhow::Error::new)?;
+                if compressed.len() + 5 < data.len() + 1 {
+                    let mut blob = Vec::with_capacity(compressed.len() + 5);
+                    blob.push(ZSTD_DICT);
+                    blob.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                    blob.extend_from_slice(&compressed);
+                    return self.inner.write(&blob);
+                }
+            }
+        }
+        if data.len() >= self.threshold && data.len() <= u32::MAX as usize {
+            let compressed = lz4::compress(data);
+            if compressed.len() + 5 < data.len() + 1 {
+                let mut blob = Vec::with_capacity(compressed.len() + 5);
+                blob.push(LZ4);
+                blob.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                blob.extend_from_slice(&compressed);
+                return self.inner.write(&blob);
+            }
+        }
+        let mut blob = Vec::with_capacity(data.len() + 1);
+        blob.push(RAW);
+        blob.extend_from_slice(data);
+        self.inner.write(&blob)
+    }
+
+    fn sync(&self) -> std::result::Result<(), Self::Error> {
+        self.inner.sync()
+    }
+
+    fn needs_deep_detach(&self) -> bool {
+        self.inner.needs_deep_detach()
+    }
+
+    fn invalid_data(&self, message: &str) -> Self::Error {
+        self.inner.invalid_data(message)
+    }
+}
+
+/// A minimal implementation of the lz4 block format
+mod lz4 {
+    const MIN_MATCH: usize = 4;
+    /// the last 5 bytes of a block are always literals
+    const LAST_LITERALS: usize = 5;
+    /// the last match must start at least 12 bytes before the end of the block
+    const MF_LIMIT: usize = 12;
+    const HASH_BITS: u32 = 12;
+
+    fn write_len(out: &mut Vec<u8>, mut len: usize) {
+        while len >= 255 {
+            out.push(255);
+            len -= 255;
+        }
+        out.push(len as u8);
+    }
+
+    fn write_sequence(out: &mut Vec<u8>, literals: &[u8], m: Option<(u16, usize)>) {
+        let lit = literals.len();
+        let ml = m.map(|(_, len)| len - MIN_MATCH).unwrap_or_default();
+        out.push(((lit.min(15) as u8) << 4) | ml.min(15) as u8);
+        if lit >= 15 {
+            write_len(out, lit - 15);
+        }
+        out.extend_from_slice(literals);
+        if let Some((offset, _)) = m {
+            out.extend_from_slice(&offset.to_le_bytes());
+            if ml >= 15 {
+                write_len(out, ml - 15);
+            }
+        }
+    }
+
+    /// greedy compression using a hash table of 4 byte sequences
+    pub fn compress(input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut table = vec![0usize; 1 << HASH_BITS];
+        let mut anchor = 0;
+        let mut i = 0;
+        while i + MF_LIMIT < input.len() {
+            let seq = u32::from_le_bytes(input[i..i + 4].try_into().unwrap());
+            let h = (seq.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize;
+            // positions are stored plus one, so 0 means empty
+            let candidate = std::mem::replace(&mut table[h], i + 1);
+            if let Some(c) = candidate.checked_sub(1) {
+                if i - c <= u16::MAX as usize && input[c..c + 4] == input[i..i + 4] {
+                    let max = input.len() - LAST_LITERALS - i;
+                    let mut len = MIN_MATCH;
+                    while len < max && input[c + len] == input[i + len] {
+                        len += 1;
+                    }
+                    write_sequence(&mut out, &input[anchor..i], Some(((i - c) as u16, len)));
+                    i += len;
+                    anchor = i;
+                    continue;
+                }
+            }
+            i += 1;
+        }
+        write_sequence(&mut out, &input[anchor..], None);
+        out
+    }
+
+    fn read_len(input: &[u8], i: &mut usize) -> Option<usize> {
+        let mut res = 0usize;
+        loop {
+            let b = *input.get(*i)?;
+            *i += 1;
+            res = res.checked_add(b as usize)?;
+            if b != 255 {
+                return Some(res);
+            }
+        }
+    }
+
+    /// decompress a block, returning None if it is corrupt or does not have the expected size
+    pub fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        loop {
+            let token = *input.get(i)?;
+            i += 1;
+            let mut lit = (token >> 4) as usize;
+            if lit == 15 {
+                lit += read_len(input, &mut i)?;
+            }
+            let literals = input.get(i..i.checked_add(lit)?)?;
+            if out.len() + lit > len {
+                return None;
+            }
+            out.extend_from_slice(literals);
+            i += lit;
+            if i == input.len() {
+                break;
+            }
+            let offset = u16::from_le_bytes(input.get(i..i + 2)?.try_into().unwrap()) as usize;
+            i += 2;
+            let mut ml = (token & 15) as usize;
+            if ml == 15 {
+                ml += read_len(input, &mut i)?;
+            }
+            ml += MIN_MATCH;
+            if offset == 0 || offset > out.len() || out.len() + ml > len {
+                return None;
+            }
+            // the match can overlap with the data it produces, so copy byte by byte
+            let start = out.len() - offset;
+            for k in 0..ml {
+                out.push(out[start + k]);
+            }
+        }
+        Some(out).filter(|out| out.len() == len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemStore;
+    use proptest::prelude::*;
+
+    fn arb_data() -> impl Strategy<Value = Vec<u8>> {
+        prop_oneof![
+            proptest::collection::vec(any::<u8>(), 0..300),
+            // few distinct bytes, so there are plenty of matches
+            proptest::collection::vec(0u8..3, 0..3000),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn lz4_roundtrip(data in arb_data()) {
+            let compressed = lz4::compress(&data);
+            prop_assert_eq!(lz4::decompress(&compressed, data.len()), Some(data.clone()));
+            if !data.is_empty() {
+                prop_assert_eq!(lz4::decompress(&compressed, data.len() - 1), None);
+            }
+        }
+
+        #[test]
+        fn compressed_store_roundtrip(data in arb_data(), threshold in 0usize..100) {
+            let store = CompressedStore::new(MemStore::default(), threshold);
+            let id = store.write(&data).unwrap();
+            let blob = store.read(&id).unwrap();
+            prop_assert_eq!(blob.as_ref(), &data[..]);
+        }
+    }
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// blocks written by the reference lz4 implementation, so the format is checked against
+    /// something other than our own compressor
+    fn reference_blocks() -> Vec<(Vec<u8>, Vec<u8>)> {
+        vec![
+            // a single literal only sequence
+            (b"hello".to_vec(), unhex("5068656c6c6f")),
+            // a match overlapping with its own output
+            (b"abc".repeat(16), unhex("3f616263030015506263616263")),
+            // extra length bytes for the match length
+            (
+                [&b"a".repeat(300)[..], b"0123456789"].concat(),
+                unhex("1f610100ff19a030313233343536373839"),
+            ),
+            // extra length bytes for the literal length
+            (
+                b"The quick brown fox jumps over the lazy dog. The quick brown fox jumps over the lazy dog.".to_vec(),
+                unhex("ff1e54686520717569636b2062726f776e20666f78206a756d7073206f76657220746865206c617a7920646f672e202d00145020646f672e"),
+            ),
+        ]
+    }
+
+    #[test]
+    fn lz4_reference_blocks() {
+        for (data, block) in reference_blocks() {
+            assert_eq!(lz4::decompress(&block, data.len()), Some(data.clone()));
+            let compressed = lz4::compress(&data);
+            assert_eq!(lz4::decompress(&compressed, data.len()), Some(data));
+        }
+    }
+
+    #[test]
+    fn lz4_malformed() {
+        for (data, block) in reference_blocks() {
+            // wrong length
+            assert_eq!(lz4::decompress(&block, data.len() - 1), None);
+            assert_eq!(lz4::decompress(&block, data.len() + 1), None);
+            // truncated
+            for n in 0..block.len() {
+                assert_eq!(lz4::decompress(&block[..n], data.len()), None);
+            }
+        }
+        // offset 0
+        assert_eq!(lz4::decompress(&unhex("1061000010"), 5), None);
+        // offset beyond the start of the output
+        assert_eq!(lz4::decompress(&unhex("1061020010"), 5), None);
+        // literal length continues past the end of the input
+        assert_eq!(lz4::decompress(&unhex("f0ffffff"), 1000), None);
+        // literal length longer than the output
+        assert_eq!(lz4::decompress(&unhex("f0ff0061"), 1), None);
+        // match length longer than the output
+        assert_eq!(lz4::decompress(&unhex("1f610100ff0010"), 10), None);
+    }
+
+    #[test]
+    fn compressed_store() {
+        let store = CompressedStore::new(MemStore::default(), 64);
+        let large = b"abcdefgh".repeat(100);
+        let id = store.write(&large).unwrap();
+        assert!(store.inner().read(&id).unwrap().len() < 100);
+        assert_eq!(store.read(&id).unwrap().as_ref(), &large[..]);
+        // below the threshold
+        let id = store.write(b"abcdabcdabcdabcdabcd").unwrap();
+        assert_eq!(store.inner().read(&id).unwrap()[0], RAW);
+        // corrupt blobs are rejected
+        let id = store
+            .inner()
+            .write(&[LZ4, 100, 0, 0, 0, 0x10, b'a'])
+            .unwrap();
+        assert!(store.read(&id).is_err());
+        let id = store.inner().write(&[7]).unwrap();
+        assert!(store.read(&id).is_err());
+        #[cfg(feature = "zstd")]
+        {
+            let id = store.inner().write(&[ZSTD_DICT, 1, 0, 0, 0, 0]).unwrap();
+            assert!(store.read(&id).is_err());
+        }
+        // a tree with large values
+        let tree = (0..100u32)
+            .map(|i| (i.to_be_bytes(), [i as u8; 1000]))
+            .collect::<crate::RadixTree>();
+        let mut attached = tree.try_attached(store.clone()).unwrap();
+        attached.try_reattach().unwrap();
+        assert_eq!(attached.try_detached().unwrap(), tree);
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn dictionary_compression() {
+        // small json like values, that are too short to compress on their own
+        let values = (0..1000u32)
+            .map(|i| {
+                format!(
+                    r#"{{"id":{},"name":"user{}","active":true,"roles":["reader"]}}"#,
+                    i,
+                    i * 7
+                )
+            })
+            .collect::<Vec<_>>();
+        let dictionary =
+            CompressedStore::<MemStore>::train_dictionary(values.iter().step_by(2), 4096).unwrap();
+        let inner = MemStore::default();
+        let plain = CompressedStore::new(inner.clone(), 16);
+        let store = plain.clone().with_dictionary(dictionary);
+        let stored = |store: &CompressedStore<MemStore>| {
+            values
+                .iter()
+                .map(|v| {
+                    let id = store.write(v.as_bytes()).unwrap();
+                    assert_eq!(store.read(&id).unwrap().as_ref(), v.as_bytes());
+                    store.inner().read(&id).unwrap().len()
+                })
+                .sum::<usize>()
+        };
+        assert!(stored(&store) * 2 < stored(&plain));
+        // the dictionary is kept in the store itself
+        let dictionary_id = store.try_write_dictionary().unwrap().unwrap();
+        let id = store.write(values[1].as_bytes()).unwrap();
+        assert_eq!(store.inner().read(&id).unwrap()[0], ZSTD_DICT);
+        assert!(plain.read(&id).is_err());
+        let loaded = plain.try_load_dictionary(&dictionary_id).unwrap();
+        assert_eq!(loaded.read(&id).unwrap().as_ref(), values[1].as_bytes());
+    }
+}
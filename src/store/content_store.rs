@@ -0,0 +1,88 @@
+use super::{blob_store::OwnedBlob, Blob, BlobStore};
+use crate::util::fnv1a;
+use anyhow::Context;
+use parking_lot::Mutex;
+use std::{collections::BTreeMap, fmt::Debug, sync::Arc};
+
+/// A content addressed in memory store
+///
+/// The id of a blob is a 64 bit hash of its content, so writing the same data again returns the
+/// existing id instead of storing a copy. Attaching several similar trees to the same store therefore
+/// stores their common subtrees and values only once.
+///
+/// The hash is not cryptographic. On a collision, the next free id is used, so ids of colliding blobs
+/// depend on the order in which they were written.
+#[derive(Default, Clone)]
+pub struct ContentStore {
+    data: Arc<Mutex<BTreeMap<u64, Arc<Vec<u8>>>>>,
+}
+
+impl ContentStore {
+    /// Number of distinct blobs
+    pub fn count(&self) -> usize {
+        self.data.lock().len()
+    }
+
+    /// Ids of all blobs
+    pub fn ids(&self) -> Vec<Vec<u8>> {
+        self.data
+            .lock()
+            .keys()
+            .map(|id| id.to_be_bytes().to_vec())
+            .collect()
+    }
+
+    /// True if `id` is the id `data` gets when there are no hash collisions
+    ///
+    /// Meant as the check for [crate::RadixTree::verify], to find blobs whose content was damaged.
+    pub fn check(id: &[u8], data: &[u8]) -> bool {
+        id == fnv1a(data).to_be_bytes()
+    }
+
+    /// Total size of all distinct blobs
+    pub fn bytes(&self) -> usize {
+        self.data.lock().values().map(|x| x.len()).sum()
+    }
+}
+
+impl Debug for ContentStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let data = self.data.lock();
+        f.debug_struct("ContentStore")
+            .field("count", &data.len())
+            .finish()
+    }
+}
+
+impl BlobStore for ContentStore {
+    type Error = anyhow::Error;
+
+    fn read(&self, id: &[u8]) -> std::result::Result<OwnedBlob, Self::Error> {
+        anyhow::ensure!(id.len() == 8);
+        let id = u64::from_be_bytes(id.try_into().unwrap());
+        let data = self.data.lock();
+        data.get(&id)
+            .map(|x| Blob::from_arc_vec(x.clone()))
+            .context("value not found")
+    }
+
+    fn write(&self, slice: &[u8]) -> std::result::Result<Vec<u8>, Self::Error> {
+        let mut data = self.data.lock();
+        let mut id = fnv1a(slice);
+        loop {
+            match data.get(&id) {
+                Some(existing) if existing.as_slice() == slice => break,
+                Some(_) => id = id.wrapping_add(1),
+                None => {
+                    data.insert(id, Arc::new(slice.to_vec()));
+                    break;
+                }
+            }
+        }
+        Ok(id.to_be_bytes().to_vec())
+    }
+
+    fn sync(&self) -> std::result::Result<(), Self::Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+use super::{blob_store::OwnedBlob, Blob, BlobStore};
+use crate::util::fnv1a;
+use std::{fmt::Debug, sync::Arc};
+
+/// The operations of an embedded key value database that are needed to store blobs, see [KvStore]
+///
+/// This is small enough to be implemented for the tree or column family of an existing database in a
+/// few lines, e.g. for sled:
+///
+/// ```ignore
+/// impl KvBackend for SledBackend {
+///     type Error = sled::Error;
+///     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+///         Ok(self.0.get(key)?.map(|x| x.to_vec()))
+///     }
+///     fn insert_if_absent(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+///         let res = self.0.compare_and_swap(key, None as Option<&[u8]>, Some(value))?;
+///         Ok(res.err().and_then(|e| e.current).map(|x| x.to_vec()))
+///     }
+///     fn flush(&self) -> Result<(), Self::Error> {
+///         self.0.flush().map(|_| ())
+///     }
+/// }
+/// ```
+pub trait KvBackend: Send + Sync + 'static {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Atomically insert `value` unless there already is a value for `key`
+    ///
+    /// Returns None if the value was inserted, or the existing value otherwise.
+    fn insert_if_absent(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Ensure all inserts are persisted
+    fn flush(&self) -> Result<(), Self::Error>;
+}
+
+/// A blob store on top of an existing key value database
+///
+/// Blobs are stored under the key prefix followed by a 64 bit hash of their content, like in
+/// [super::ContentStore], so writing the same blob twice stores it only once and no id counter has
+/// to be kept in the database. If the hash is taken by a different blob, the following ids are tried.
+/// The prefix allows sharing a database with other data.
+///
+/// Blobs are only written using [KvBackend::insert_if_absent], so the blob under an id is never replaced,
+/// even with concurrent writers. For S3 compatible object stores, see `S3Store` with the `s3` feature.
+pub struct KvStore<B> {
+    backend: Arc<B>,
+    prefix: Vec<u8>,
+}
+
+impl<B> Clone for KvStore<B> {
+    fn clone(&self) -> Self {
+        Self {
+            backend: self.backend.clone(),
+            prefix: self.prefix.clone(),
+        }
+    }
+}
+
+impl<B> Debug for KvStore<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KvStore")
+            .field("prefix", &hex::encode(&self.prefix))
+            .finish()
+    }
+}
+
+impl<B> KvStore<B> {
+    pub fn new(backend: B, prefix: impl AsRef<[u8]>) -> Self {
+        Self {
+            backend: Arc::new(backend),
+            prefix: prefix.as_ref().to_vec(),
+        }
+    }
+
+    /// The wrapped database
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    fn key(&self, id: u64) -> Vec<u8> {
+        let mut key = self.prefix.clone();
+        key.extend_from_slice(&id.to_be_bytes());
+        key
+    }
+}
+
+impl<B: KvBackend> BlobStore for KvStore<B> {
+    type Error = anyhow::Error;
+
+    fn read(&self, id: &[u8]) -> std::result::Result<OwnedBlob, Self::Error> {
+        anyhow::ensure!(id.len() == 8);
+        let id = u64::from_be_bytes(id.try_into().unwrap());
+        match self.backend.get(&self.key(id))? {
+            Some(data) => Ok(Blob::from_arc_vec(Arc::new(data))),
+            None => anyhow::bail!("value not found"),
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> std::result::Result<Vec<u8>, Self::Error> {
+        let mut id = fnv1a(data);
+        loop {
+            match self.backend.insert_if_absent(&self.key(id), data)? {
+                Some(existing) if existing != data => id = id.wrapping_add(1),
+                _ => break,
+            }
+        }
+        Ok(id.to_be_bytes().to_vec())
+    }
+
+    fn sync(&self) -> std::result::Result<(), Self::Error> {
+        Ok(self.backend.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RadixTree;
+    use std::{collections::BTreeMap, convert::Infallible, sync::Mutex};
+
+    #[derive(Default)]
+    struct MapBackend(Mutex<BTreeMap<Vec<u8>, Vec<u8>>>);
+
+    impl KvBackend for MapBackend {
+        type Error = Infallible;
+
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+            Ok(self.0.lock().unwrap().get(key).cloned())
+        }
+
+        fn insert_if_absent(
+            &self,
+            key: &[u8],
+            value: &[u8],
+        ) -> Result<Option<Vec<u8>>, Self::Error> {
+            let mut map = self.0.lock().unwrap();
+            match map.get(key) {
+                Some(existing) => Ok(Some(existing.clone())),
+                None => {
+                    map.insert(key.to_vec(), value.to_vec());
+                    Ok(None)
+                }
+            }
+        }
+
+        fn flush(&self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn kv_store() {
+        let store = KvStore::new(MapBackend::default(), b"tree/");
+        let tree = (0..100u32)
+            .map(|i| (i.to_string(), [i as u8; 100]))
+            .collect::<RadixTree>();
+        let mut attached = tree.try_attached(store.clone()).unwrap();
+        let id = attached.try_reattach().unwrap();
+        store.sync().unwrap();
+        let loaded = RadixTree::try_load(store.clone(), Some(&id)).unwrap();
+        assert_eq!(loaded.try_detached().unwrap(), tree);
+        let data = store.backend().0.lock().unwrap();
+        assert!(data.keys().all(|key| key.starts_with(b"tree/")));
+        drop(data);
+        // identical blobs are stored once
+        assert_eq!(store.write(b"abc").unwrap(), store.write(b"abc").unwrap());
+        assert!(store.read(&[0; 8]).is_err());
+    }
+
+    #[test]
+    fn kv_store_collision() {
+        let store = KvStore::new(MapBackend::default(), b"");
+        // another blob already has the hash of abc
+        let hash = fnv1a(b"abc");
+        store
+            .backend()
+            .insert_if_absent(&hash.to_be_bytes(), b"xyz")
+            .unwrap();
+        let id = store.write(b"abc").unwrap();
+        assert_eq!(id, hash.wrapping_add(1).to_be_bytes());
+        assert_eq!(store.write(b"abc").unwrap(), id);
+        assert_eq!(store.read(&id).unwrap().as_ref(), b"abc");
+        assert_eq!(store.read(&hash.to_be_bytes()).unwrap().as_ref(), b"xyz");
+    }
+}
@@ -12,6 +12,23 @@ impl MemStore {
     pub fn count(&self) -> usize {
         self.data.lock().len()
     }
+
+    /// Ids of all blobs
+    pub fn ids(&self) -> Vec<Vec<u8>> {
+        self.data
+            .lock()
+            .keys()
+            .map(|id| id.to_be_bytes().to_vec())
+            .collect()
+    }
+
+    /// Remove a blob, returning true if it was present
+    pub fn remove(&self, id: &[u8]) -> bool {
+        match <[u8; 8]>::try_from(id) {
+            Ok(id) => self.data.lock().remove(&u64::from_be_bytes(id)).is_some(),
+            Err(_) => false,
+        }
+    }
 }
 
 impl Debug for MemStore {
@@ -3,19 +3,43 @@
 //! You only have to interact with these types if you want to use RadixTrees as persistent databases.
 mod blob;
 pub(crate) mod blob_store;
+#[cfg(feature = "compressed-store")]
+mod compressed_store;
+#[cfg(feature = "mem-store")]
+mod content_store;
+#[cfg(feature = "custom-store")]
+mod kv_store;
 #[cfg(feature = "mem-store")]
 mod mem_store;
 #[cfg(all(not(target_arch = "wasm32"), feature = "custom-store"))]
 mod paged_file_store;
+#[cfg(feature = "custom-store")]
+mod read_cache;
+#[cfg(feature = "s3")]
+mod s3_store;
 
 #[cfg(feature = "custom-store")]
 pub use blob_store::DynBlobStore;
 #[cfg(feature = "custom-store")]
 pub use blob_store::UnwrapSafeExt;
-pub use blob_store::{Blob, BlobStore, Detached, NoError};
+pub use blob_store::{
+    Blob, BlobStore, Detached, InfallibleStore, NoError, UnwrapSafeIter, UnwrapSafeIterExt,
+    MAX_ID_LEN,
+};
 
+#[cfg(feature = "mem-store")]
+pub use content_store::ContentStore;
 #[cfg(feature = "mem-store")]
 pub use mem_store::MemStore;
 
+#[cfg(feature = "compressed-store")]
+pub use compressed_store::CompressedStore;
+#[cfg(feature = "custom-store")]
+pub use kv_store::{KvBackend, KvStore};
+#[cfg(feature = "custom-store")]
+pub use read_cache::{MemoryBudget, ReadCache};
+#[cfg(feature = "s3")]
+pub use s3_store::{ObjectClient, ObjectError, Retry, S3Client, S3Store};
+
 #[cfg(all(not(target_arch = "wasm32"), feature = "paged-file-store"))]
-pub use paged_file_store::PagedFileStore;
+pub use paged_file_store::{MmapStore, PagedFileStore, StoreFeatures, StoreHeader};
@@ -7,6 +7,7 @@ use std::{
     fs::File,
     io::{Read, Seek, SeekFrom, Write},
     sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use super::{blob_store::OwnedBlob, BlobStore};
@@ -29,14 +30,136 @@ impl Debug for PagedFileStore {
 
 struct Inner {
     file: File,
+    header: StoreHeader,
     page_size: u64,
     pages: FnvHashMap<u64, Page>,
     recent: FnvHashMap<u64, OwnedBlob>,
     last_id: u64,
+    root: u64,
 }
 
 const ALIGN: usize = 8;
 const HEADER_SIZE: u64 = 4096;
+/// Magic bytes at the start of every store file
+const MAGIC: [u8; 8] = *b"RADIXDB\0";
+/// The current format version
+const VERSION: u16 = 1;
+
+/// Optional format features of a store file
+///
+/// A store can only be opened if all features recorded in its header are supported by this
+/// version of the library.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StoreFeatures(u32);
+
+impl StoreFeatures {
+    /// No optional features
+    pub const NONE: Self = Self(0);
+    /// Nodes contain the number of entries below them
+    pub const COUNTS: Self = Self(1);
+    /// Nodes contain structural hashes of their subtrees
+    pub const HASHES: Self = Self(2);
+    /// Blobs are compressed
+    pub const COMPRESSION: Self = Self(4);
+    /// Blobs are compressed with a shared dictionary
+    pub const DICTIONARY: Self = Self(8);
+    /// Features that are supported by this version of the library
+    pub const SUPPORTED: Self = Self::NONE;
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Header at the start of a store file
+///
+/// Layout, all numbers big endian:
+/// - 0..8: magic bytes
+/// - 8..10: format version
+/// - 12..16: feature flags
+/// - 16..24: size of the data, excluding the header
+/// - 24..32: page size
+/// - 32..40: creation time in seconds since the unix epoch, or 0 if unknown
+/// - 40..48: id of the last committed root, or 0 if no root was committed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreHeader {
+    pub version: u16,
+    pub features: StoreFeatures,
+    pub page_size: u64,
+    pub created: u64,
+}
+
+impl StoreHeader {
+    fn new(page_size: u64, created: u64) -> Self {
+        Self {
+            version: VERSION,
+            features: StoreFeatures::NONE,
+            page_size,
+            created,
+        }
+    }
+
+    fn write(&self, file: &mut File) -> anyhow::Result<()> {
+        let mut buf = [0u8; 16];
+        buf[0..8].copy_from_slice(&MAGIC);
+        buf[8..10].copy_from_slice(&self.version.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.features.0.to_be_bytes());
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&buf)?;
+        file.seek(SeekFrom::Start(24))?;
+        file.write_all(&self.page_size.to_be_bytes())?;
+        file.write_all(&self.created.to_be_bytes())?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    /// Read the header, or None for a file written before headers existed
+    fn read(file: &mut File) -> anyhow::Result<Option<Self>> {
+        let mut buf = [0u8; 40];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut buf)?;
+        file.seek(SeekFrom::End(0))?;
+        if buf[0..8] == [0u8; 8] {
+            return Ok(None);
+        }
+        anyhow::ensure!(buf[0..8] == MAGIC, "not a radixdb store file");
+        let version = u16::from_be_bytes(buf[8..10].try_into().unwrap());
+        let features = u32::from_be_bytes(buf[12..16].try_into().unwrap());
+        let page_size = u64::from_be_bytes(buf[24..32].try_into().unwrap());
+        let created = u64::from_be_bytes(buf[32..40].try_into().unwrap());
+        Ok(Some(Self {
+            version,
+            features: StoreFeatures(features),
+            page_size,
+            created,
+        }))
+    }
+
+    fn validate(&self, page_size: u64) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.version == VERSION,
+            "unsupported store format version {}, expected {}",
+            self.version,
+            VERSION
+        );
+        anyhow::ensure!(
+            StoreFeatures::SUPPORTED.contains(self.features),
+            "store uses unsupported features {:#x}",
+            self.features.0 & !StoreFeatures::SUPPORTED.0
+        );
+        anyhow::ensure!(
+            self.page_size == page_size,
+            "store was created with page size {}, but opened with page size {}",
+            self.page_size,
+            page_size
+        );
+        Ok(())
+    }
+}
 
 struct PageInner {
     page_size: usize,
@@ -118,30 +241,87 @@ fn write_size(file: &mut File, size: u64) -> anyhow::Result<()> {
     Ok(())
 }
 
+fn read_root(file: &mut File) -> anyhow::Result<u64> {
+    let mut buf = [0u8; 8];
+    file.seek(SeekFrom::Start(40))?;
+    file.read_exact(&mut buf)?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn write_root(file: &mut File, root: u64) -> anyhow::Result<()> {
+    file.seek(SeekFrom::Start(40))?;
+    file.write_all(&root.to_be_bytes())?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(())
+}
+
 impl Inner {
     pub fn new(mut file: File, page_size: u64) -> anyhow::Result<Self> {
         anyhow::ensure!((page_size as usize) % ALIGN == 0);
         let end = file.seek(std::io::SeekFrom::End(0))?;
-        if end == 0 {
+        let header = if end == 0 {
             // write header
             file.set_len(HEADER_SIZE)?;
-            file.seek(std::io::SeekFrom::End(0))?;
+            let created = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            let header = StoreHeader::new(page_size, created);
+            header.write(&mut file)?;
+            header
         } else if end < HEADER_SIZE {
             // something went seriously wrong
             anyhow::bail!("Incomplete header!");
-        }
+        } else if let Some(header) = StoreHeader::read(&mut file)? {
+            header.validate(page_size)?;
+            header
+        } else {
+            // file from before headers were introduced, creation time is unknown
+            let header = StoreHeader::new(page_size, 0);
+            header.write(&mut file)?;
+            header
+        };
         let size = read_size(&mut file)?;
+        let root = read_root(&mut file)?;
+        anyhow::ensure!(root <= size, "committed root is beyond the end of the data");
         file.set_len(size + HEADER_SIZE)?;
         file.seek(std::io::SeekFrom::End(0))?;
         Ok(Self {
             file,
+            header,
             page_size,
             pages: Default::default(),
             recent: Default::default(),
             last_id: size,
+            root,
         })
     }
 
+    /// record `root` as the committed root, once everything written before it is durable
+    fn commit_root(&mut self, root: u64) -> anyhow::Result<()> {
+        anyhow::ensure!(root >= 4 && root <= self.last_id, "invalid root id");
+        self.file.sync_data()?;
+        write_root(&mut self.file, root)?;
+        self.file.sync_data()?;
+        self.root = root;
+        Ok(())
+    }
+
+    /// truncate the data to the end of the committed root
+    ///
+    /// must only be called before any pages are mapped, since mappings of truncated pages would become invalid
+    fn discard_uncommitted(&mut self) -> anyhow::Result<()> {
+        assert!(self.pages.is_empty());
+        write_size(&mut self.file, self.root)?;
+        self.file.set_len(self.root + HEADER_SIZE)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.sync_data()?;
+        self.recent.clear();
+        self.last_id = self.root;
+        Ok(())
+    }
+
     fn load_page(&mut self, page: u64) -> anyhow::Result<()> {
         let start = offset_of_page(page, self.page_size);
         let mmap = unsafe {
@@ -172,10 +352,26 @@ impl Inner {
         Ok(())
     }
 
+    /// length of the blob at `offset`, reading only the length for blobs that are not loaded
+    fn blob_len(&mut self, offset: u64) -> anyhow::Result<usize> {
+        anyhow::ensure!(offset >= 4 && offset <= self.last_id, "id out of range");
+        let last_page = page(self.last_id, self.page_size);
+        if page(offset - 1, self.page_size) < last_page || self.recent.contains_key(&offset) {
+            return Ok(self.bytes(offset)?.len());
+        }
+        self.file.seek(SeekFrom::Start(offset + HEADER_SIZE - 4))?;
+        let mut size = [0u8; 4];
+        self.file.read_exact(&mut size)?;
+        self.file.seek(SeekFrom::End(0))?;
+        let size = u32::from_be_bytes(size) as u64;
+        anyhow::ensure!(offset >= 4 + size);
+        Ok(size as usize)
+    }
+
     fn close_page(&mut self, current_page: u64) -> anyhow::Result<()> {
         // println!("close_page page={} offset={}", current_page, self.file.stream_position()?);
         let start = offset_of_page(current_page, self.page_size);
-        self.pad_to(start + (self.page_size as u64))?;
+        self.pad_to(start + self.page_size)?;
         self.commit()?;
         self.load_page(current_page)?;
         self.recent
@@ -198,6 +394,7 @@ impl Inner {
     }
 
     fn bytes(&mut self, offset: u64) -> anyhow::Result<OwnedBlob> {
+        anyhow::ensure!(offset >= 4 && offset <= self.last_id, "id out of range");
         let last_page = page(self.last_id, self.page_size);
         let page = page(offset - 1, self.page_size);
         let page_offset = offset_within_page(offset, self.page_size);
@@ -260,6 +457,21 @@ impl PagedFileStore {
         Ok(Self(Arc::new(Mutex::new(Inner::new(file, page_size)?))))
     }
 
+    /// The header of the underlying file
+    pub fn header(&self) -> StoreHeader {
+        self.0.lock().header.clone()
+    }
+
+    /// Open a store after a crash, discarding everything written after the last committed root
+    ///
+    /// Blobs written by an attach that did not get to [PagedFileStore::commit_root] are not reachable
+    /// from any committed root, so they are removed. If no root was ever committed, this removes all data.
+    pub fn recover(file: File, page_size: u64) -> anyhow::Result<Self> {
+        let mut inner = Inner::new(file, page_size)?;
+        inner.discard_uncommitted()?;
+        Ok(Self(Arc::new(Mutex::new(inner))))
+    }
+
     pub fn last_id(&self) -> Option<[u8; 8]> {
         let id = self.0.lock().last_id;
         if id == 0 {
@@ -268,16 +480,40 @@ impl PagedFileStore {
             Some(id.to_be_bytes())
         }
     }
+
+    /// Atomically make `id`, as returned by [crate::RadixTree::try_reattach], the committed root
+    ///
+    /// All data is synced to disk before the root is recorded in the header, so after a crash the
+    /// committed root is always complete, see [PagedFileStore::recover].
+    pub fn commit_root(&self, id: &[u8]) -> anyhow::Result<()> {
+        let id = u64::from_be_bytes(id.try_into()?);
+        self.0.lock().commit_root(id)
+    }
+
+    /// The last committed root, see [PagedFileStore::commit_root]
+    pub fn root(&self) -> Option<[u8; 8]> {
+        let id = self.0.lock().root;
+        if id == 0 {
+            None
+        } else {
+            Some(id.to_be_bytes())
+        }
+    }
 }
 
 impl BlobStore for PagedFileStore {
     type Error = anyhow::Error;
 
     fn read(&self, id: &[u8]) -> anyhow::Result<OwnedBlob> {
-        let offset = u64::from_be_bytes(id.try_into().unwrap());
+        let offset = u64::from_be_bytes(id.try_into()?);
         self.0.lock().bytes(offset)
     }
 
+    fn blob_len(&self, id: &[u8]) -> anyhow::Result<usize> {
+        let offset = u64::from_be_bytes(id.try_into()?);
+        self.0.lock().blob_len(offset)
+    }
+
     fn write(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
         let id = self.0.lock().append(data)?;
         Ok(id.to_be_bytes().to_vec())
@@ -288,6 +524,91 @@ impl BlobStore for PagedFileStore {
     }
 }
 
+/// A read only view of a store file written by [PagedFileStore] that maps the entire file at once
+///
+/// Reads return blobs pointing directly into the mapping, which keep the mapping alive. So reading
+/// never copies and never takes a lock. Only data that was committed when the store was opened is visible.
+#[derive(Clone)]
+pub struct MmapStore(Arc<MmapInner>);
+
+struct MmapInner {
+    header: StoreHeader,
+    /// mapping of the data after the header, None if there is no data
+    mmap: Option<Mmap>,
+}
+
+impl Debug for MmapStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MmapStore")
+            .field("page_size", &self.0.header.page_size)
+            .field("size", &self.0.data().len())
+            .finish()
+    }
+}
+
+impl MmapInner {
+    fn data(&self) -> &[u8] {
+        self.mmap.as_ref().map(|x| x.as_ref()).unwrap_or_default()
+    }
+}
+
+impl MmapStore {
+    pub fn new(mut file: File, page_size: u64) -> anyhow::Result<Self> {
+        let end = file.seek(SeekFrom::End(0))?;
+        anyhow::ensure!(end >= HEADER_SIZE, "Incomplete header!");
+        let header = match StoreHeader::read(&mut file)? {
+            Some(header) => {
+                header.validate(page_size)?;
+                header
+            }
+            // file from before headers were introduced, creation time is unknown
+            None => StoreHeader::new(page_size, 0),
+        };
+        let size = read_size(&mut file)?;
+        anyhow::ensure!(size + HEADER_SIZE <= end, "store file is truncated");
+        let mmap = if size > 0 {
+            Some(unsafe {
+                MmapOptions::new()
+                    .offset(HEADER_SIZE)
+                    .len(size as usize)
+                    .map(&file)?
+            })
+        } else {
+            None
+        };
+        Ok(Self(Arc::new(MmapInner { header, mmap })))
+    }
+
+    /// The header of the underlying file
+    pub fn header(&self) -> StoreHeader {
+        self.0.header.clone()
+    }
+}
+
+impl BlobStore for MmapStore {
+    type Error = anyhow::Error;
+
+    fn read(&self, id: &[u8]) -> anyhow::Result<OwnedBlob> {
+        let offset = u64::from_be_bytes(id.try_into()?) as usize;
+        let data = self.0.data();
+        anyhow::ensure!(offset >= 4 && offset <= data.len(), "id out of range");
+        let length = u32::from_be_bytes(data[offset - 4..offset].try_into().unwrap()) as usize;
+        anyhow::ensure!(offset >= length + 4, "invalid blob length");
+        let slice: &[u8] = &data[offset - 4 - length..offset - 4];
+        // the slice is kept alive by the owner
+        let slice: &'static [u8] = unsafe { std::mem::transmute(slice) };
+        Ok(OwnedBlob::owned_new(slice, Some(self.0.clone())))
+    }
+
+    fn write(&self, _data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        anyhow::bail!("MmapStore is read only")
+    }
+
+    fn sync(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{fs, time::Instant};
@@ -404,6 +725,7 @@ mod tests {
         let path = dir.path().join("large2.rdb");
         let file = fs::OpenOptions::new()
             .create(true)
+            .truncate(true)
             .read(true)
             .write(true)
             .open(&path)?;
@@ -420,6 +742,7 @@ mod tests {
         let path = dir.path().join("large.rdb");
         let file = fs::OpenOptions::new()
             .create(true)
+            .truncate(true)
             .read(true)
             .write(true)
             .open(&path)?;
@@ -464,6 +787,162 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn paged_file_store_header() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("header.rdb");
+        let open = || {
+            fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(&path)
+        };
+        let store = PagedFileStore::new(open()?, TEST_SIZE)?;
+        let id = store.write(b"abc")?;
+        let header = store.header();
+        assert_eq!(header.version, VERSION);
+        assert_eq!(header.features, StoreFeatures::NONE);
+        assert_eq!(header.page_size, TEST_SIZE);
+        assert!(header.created > 0);
+        drop(store);
+        // reopen with the same parameters
+        let store = PagedFileStore::new(open()?, TEST_SIZE)?;
+        assert_eq!(store.header(), header);
+        assert_eq!(store.read(&id)?.as_ref(), b"abc");
+        drop(store);
+        // reopen with a different page size
+        let err = PagedFileStore::new(open()?, TEST_SIZE * 2).unwrap_err();
+        assert!(err.to_string().contains("page size"));
+        // unsupported features
+        let mut file = open()?;
+        StoreHeader {
+            features: StoreFeatures::HASHES,
+            ..header.clone()
+        }
+        .write(&mut file)?;
+        let err = PagedFileStore::new(file, TEST_SIZE).unwrap_err();
+        assert!(err.to_string().contains("unsupported features"));
+        // unsupported version
+        let mut file = open()?;
+        StoreHeader {
+            version: VERSION + 1,
+            ..header.clone()
+        }
+        .write(&mut file)?;
+        let err = PagedFileStore::new(file, TEST_SIZE).unwrap_err();
+        assert!(err.to_string().contains("version"));
+        // not a store file
+        let mut file = open()?;
+        file.write_all(b"garbage!")?;
+        let err = PagedFileStore::new(file, TEST_SIZE).unwrap_err();
+        assert!(err.to_string().contains("not a radixdb store"));
+        Ok(())
+    }
+
+    #[test]
+    fn mmap_store() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("mmap.rdb");
+        let open = || {
+            fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(&path)
+        };
+        let store = PagedFileStore::new(open()?, TEST_SIZE)?;
+        // empty store
+        assert!(MmapStore::new(open()?, TEST_SIZE)?
+            .read(&8u64.to_be_bytes())
+            .is_err());
+        // enough blocks to span several pages
+        let blocks = (0..100u64)
+            .map(|i| {
+                (
+                    store.write(&mk_block::<100>(i)).unwrap(),
+                    mk_block::<100>(i),
+                )
+            })
+            .collect::<Vec<_>>();
+        let tree = (0..10u32)
+            .map(|i| (i.to_be_bytes(), [i as u8; 20]))
+            .collect::<crate::RadixTree>();
+        let mut attached = tree.try_attached(store.clone())?;
+        let id = attached.try_reattach()?;
+        drop(attached);
+        drop(store);
+        let mmap = MmapStore::new(open()?, TEST_SIZE)?;
+        for (id, block) in &blocks {
+            let a = mmap.read(id)?;
+            let b = mmap.read(id)?;
+            assert_eq!(a.as_ref(), block);
+            // both point into the same mapping
+            assert_eq!(a.as_ptr(), b.as_ptr());
+        }
+        let loaded = crate::RadixTree::try_load(mmap.clone(), Some(id))?;
+        assert_eq!(loaded.try_detached()?, tree);
+        assert!(mmap.write(b"abc").is_err());
+        assert!(MmapStore::new(open()?, TEST_SIZE * 2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn paged_file_store_recover() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("recover.rdb");
+        let open = || {
+            fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(&path)
+        };
+        let store = PagedFileStore::new(open()?, TEST_SIZE)?;
+        assert_eq!(store.root(), None);
+        let tree = (0..10u32)
+            .map(|i| (i.to_be_bytes(), [i as u8; 20]))
+            .collect::<crate::RadixTree>();
+        let mut attached = tree.try_attached(store.clone())?;
+        let root = attached.try_reattach()?;
+        store.commit_root(&root)?;
+        assert!(store.commit_root(&u64::MAX.to_be_bytes()).is_err());
+        // an attach that crashes before committing, spanning several pages
+        for i in 0..20 {
+            store.write(&mk_block::<100>(i))?;
+        }
+        drop(attached);
+        drop(store);
+        let store = PagedFileStore::new(open()?, TEST_SIZE)?;
+        assert_eq!(store.root().map(|x| x.to_vec()), Some(root.clone()));
+        assert_ne!(store.last_id(), store.root());
+        drop(store);
+        let store = PagedFileStore::recover(open()?, TEST_SIZE)?;
+        assert_eq!(store.last_id(), store.root());
+        let loaded = crate::RadixTree::try_load(store.clone(), store.root())?;
+        assert_eq!(loaded.try_detached()?, tree);
+        // writing continues after the root
+        let id = store.write(b"abc")?;
+        assert_eq!(store.read(&id)?.as_ref(), b"abc");
+        // invalid ids are errors
+        assert!(store.read(&[1, 2, 3]).is_err());
+        assert!(store.read(&0u64.to_be_bytes()).is_err());
+        assert!(store.read(&u64::MAX.to_be_bytes()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn paged_file_store_legacy_header() -> anyhow::Result<()> {
+        let file = tempfile::tempfile()?;
+        file.set_len(HEADER_SIZE)?;
+        let store = PagedFileStore::new(file, TEST_SIZE)?;
+        assert_eq!(store.header(), StoreHeader::new(TEST_SIZE, 0));
+        Ok(())
+    }
+
     proptest! {
 
         #[test]
@@ -476,6 +955,7 @@ mod tests {
                     .map(|block| store.append(block.as_ref())
                         .map(|offset| (offset, block))).collect::<anyhow::Result<Vec<_>>>().unwrap();
             for (offset, block) in res.iter() {
+                prop_assert_eq!(store.blob_len(*offset).unwrap(), block.len());
                 let actual = store.bytes(*offset).unwrap();
                 let expected: &[u8] = block;
                 prop_assert_eq!(actual.as_ref(), expected);
@@ -0,0 +1,301 @@
+use super::{blob_store::OwnedBlob, BlobStore};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// A memory limit in bytes that can be shared between several caches
+///
+/// Clones share the same limit. Caches reserve memory before keeping a blob and release it when
+/// the blob is evicted, so the sum of everything kept by all caches sharing a budget stays below the limit.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget(Arc<BudgetInner>);
+
+#[derive(Debug)]
+struct BudgetInner {
+    limit: u64,
+    used: AtomicU64,
+    out_of_budget: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Self {
+        Self(Arc::new(BudgetInner {
+            limit,
+            used: AtomicU64::new(0),
+            out_of_budget: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.0.limit
+    }
+
+    /// Bytes currently reserved by all users of this budget
+    pub fn used(&self) -> u64 {
+        self.0.used.load(Ordering::SeqCst)
+    }
+
+    /// Number of times something could not be cached because the budget was exhausted, even after eviction
+    pub fn out_of_budget(&self) -> u64 {
+        self.0.out_of_budget.load(Ordering::SeqCst)
+    }
+
+    /// Try to reserve `n` bytes, returning false if that would exceed the limit
+    pub fn try_reserve(&self, n: u64) -> bool {
+        self.0
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(n).filter(|x| *x <= self.0.limit)
+            })
+            .is_ok()
+    }
+
+    /// Release `n` previously reserved bytes
+    pub fn release(&self, n: u64) {
+        self.0.used.fetch_sub(n, Ordering::SeqCst);
+    }
+
+    fn record_out_of_budget(&self) {
+        self.0.out_of_budget.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// blobs with the tick of their last use
+    blobs: HashMap<Vec<u8>, (OwnedBlob, u64)>,
+    /// ids by the tick of their last use, for evicting the least recently used blobs first
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    budget: Option<MemoryBudget>,
+}
+
+impl CacheState {
+    fn size(id: &[u8], blob: &OwnedBlob) -> u64 {
+        (id.len() + blob.len()) as u64
+    }
+
+    /// get a blob and mark it as most recently used
+    fn get(&mut self, id: &[u8]) -> Option<OwnedBlob> {
+        let (blob, last_use) = self.blobs.get_mut(id)?;
+        if self.budget.is_some() {
+            // without a budget nothing is evicted, so the order does not matter
+            if let Some(id) = self.order.remove(last_use) {
+                self.tick += 1;
+                *last_use = self.tick;
+                self.order.insert(self.tick, id);
+            }
+        }
+        Some(blob.clone())
+    }
+
+    fn insert(&mut self, id: &[u8], blob: &OwnedBlob) {
+        if self.blobs.contains_key(id) {
+            return;
+        }
+        if let Some(budget) = &self.budget {
+            let size = Self::size(id, blob);
+            while !budget.try_reserve(size) {
+                let Some((_, oldest)) = self.order.pop_first() else {
+                    budget.record_out_of_budget();
+                    return;
+                };
+                if let Some((evicted, _)) = self.blobs.remove(&oldest) {
+                    budget.release(Self::size(&oldest, &evicted));
+                }
+            }
+        }
+        self.tick += 1;
+        self.blobs.insert(id.to_vec(), (blob.clone(), self.tick));
+        self.order.insert(self.tick, id.to_vec());
+    }
+}
+
+impl Drop for CacheState {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            let size = self
+                .blobs
+                .iter()
+                .map(|(id, (blob, _))| Self::size(id, blob))
+                .sum();
+            budget.release(size);
+        }
+    }
+}
+
+/// A store wrapper that reads every blob from the underlying store at most once, unless it was evicted
+///
+/// Clones share the cache. Blobs are kept alive until the last clone is dropped, so this is meant to be
+/// used for the duration of a single operation, see [crate::RadixTree::try_with_shared_reads], or be
+/// limited using [ReadCache::with_budget].
+///
+/// With [ReadCache::with_write_caching], written blobs are kept as well. Writes still go to the underlying
+/// store immediately, so it can be used on its own at any time.
+#[derive(Clone)]
+pub struct ReadCache<S> {
+    inner: S,
+    cache: Arc<Mutex<CacheState>>,
+    cache_writes: bool,
+}
+
+impl<S> ReadCache<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            cache: Default::default(),
+            cache_writes: false,
+        }
+    }
+
+    /// A cache that keeps blobs only as long as they fit in `budget`, evicting the least recently used blobs first
+    ///
+    /// Blobs that do not fit even after evicting everything else are returned but not cached.
+    pub fn with_budget(inner: S, budget: MemoryBudget) -> Self {
+        Self {
+            inner,
+            cache: Arc::new(Mutex::new(CacheState {
+                blobs: Default::default(),
+                order: Default::default(),
+                tick: 0,
+                budget: Some(budget),
+            })),
+            cache_writes: false,
+        }
+    }
+
+    /// Also cache written blobs, under the id assigned by the wrapped store
+    ///
+    /// Combined with [ReadCache::with_budget], this keeps the most recently written and read blobs
+    /// in memory in front of a persistent store.
+    pub fn with_write_caching(self) -> Self {
+        Self {
+            cache_writes: true,
+            ..self
+        }
+    }
+
+    /// The wrapped store
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Number of distinct blobs that are currently cached
+    pub fn count(&self) -> usize {
+        self.cache.lock().unwrap().blobs.len()
+    }
+}
+
+impl<S: Debug> Debug for ReadCache<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReadCache")
+            .field("inner", &self.inner)
+            .field("count", &self.count())
+            .finish()
+    }
+}
+
+impl<S: BlobStore> BlobStore for ReadCache<S> {
+    type Error = S::Error;
+
+    fn read(&self, id: &[u8]) -> std::result::Result<OwnedBlob, Self::Error> {
+        if let Some(blob) = self.cache.lock().unwrap().get(id) {
+            return Ok(blob);
+        }
+        // do not hold the lock while reading, a concurrent read of the same blob is harmless
+        let blob = self.inner.read(id)?;
+        self.cache.lock().unwrap().insert(id, &blob);
+        Ok(blob)
+    }
+
+    fn write(&self, data: &[u8]) -> std::result::Result<Vec<u8>, Self::Error> {
+        let id = self.inner.write(data)?;
+        if self.cache_writes {
+            self.cache
+                .lock()
+                .unwrap()
+                .insert(&id, &OwnedBlob::copy_from_slice(data));
+        }
+        Ok(id)
+    }
+
+    fn sync(&self) -> std::result::Result<(), Self::Error> {
+        self.inner.sync()
+    }
+
+    fn blob_len(&self, id: &[u8]) -> std::result::Result<usize, Self::Error> {
+        if let Some((blob, _)) = self.cache.lock().unwrap().blobs.get(id) {
+            return Ok(blob.len());
+        }
+        self.inner.blob_len(id)
+    }
+
+    fn needs_deep_detach(&self) -> bool {
+        self.inner.needs_deep_detach()
+    }
+
+    fn invalid_data(&self, message: &str) -> Self::Error {
+        self.inner.invalid_data(message)
+    }
+}
+
+#[cfg(all(test, feature = "mem-store"))]
+mod tests {
+    use super::*;
+    use crate::{store::MemStore, RadixTree};
+
+    #[test]
+    fn write_caching() -> anyhow::Result<()> {
+        let inner = MemStore::default();
+        let budget = MemoryBudget::new(4000);
+        let store = ReadCache::with_budget(inner.clone(), budget.clone()).with_write_caching();
+        let mut tree = RadixTree::empty(store.clone());
+        for i in 0..100u32 {
+            tree.try_insert(i.to_string(), [i as u8; 100])?;
+        }
+        let id = tree.try_reattach()?;
+        // all blobs are persistent, only the most recent ones are cached
+        assert!(store.count() > 0);
+        assert!(store.count() < inner.ids().len());
+        assert!(budget.used() <= 4000);
+        // evicted blobs are read from the wrapped store
+        for id in inner.ids() {
+            assert_eq!(store.read(&id)?, inner.read(&id)?);
+        }
+        assert!(budget.used() <= 4000);
+        let tree = RadixTree::try_load(inner, Some(&id))?;
+        assert_eq!(tree.try_detached()?.len(), 100);
+        // without write caching, only reads are cached
+        let store = ReadCache::new(MemStore::default());
+        let id = store.write(b"data")?;
+        assert_eq!(store.count(), 0);
+        store.read(&id)?;
+        assert_eq!(store.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn least_recently_used_eviction() -> anyhow::Result<()> {
+        let inner = MemStore::default();
+        let ids = (0..100u8)
+            .map(|i| inner.write(&[i; 100]))
+            .collect::<Result<Vec<_>, _>>()?;
+        let store = ReadCache::with_budget(inner, MemoryBudget::new(1000));
+        let cached = |id: &[u8]| store.cache.lock().unwrap().blobs.contains_key(id);
+        let hot = &ids[0];
+        for id in &ids[1..] {
+            store.read(hot)?;
+            store.read(id)?;
+        }
+        // the hot blob is read all the time, so it survives while the others are evicted
+        assert!(cached(hot));
+        assert!(!cached(&ids[1]));
+        assert!(cached(&ids[99]));
+        Ok(())
+    }
+}
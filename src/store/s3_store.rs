@@ -0,0 +1,628 @@
+use super::{blob_store::OwnedBlob, Blob, BlobStore};
+use crate::util::fnv1a;
+use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+use std::{fmt, fmt::Debug, io::Read, sync::Arc, thread, time::Duration};
+
+/// How long the signature of a request is valid
+const SIGNATURE_VALIDITY: Duration = Duration::from_secs(600);
+
+/// The maximum number of parts of a multipart upload
+const MAX_PARTS: usize = 10000;
+
+/// The error of a request of an [ObjectClient]
+#[derive(Debug)]
+pub enum ObjectError {
+    /// The request might succeed when retried, e.g. after a timeout or a 5xx response
+    Transient(anyhow::Error),
+    /// The request will fail again when retried, e.g. after a 403 response
+    Permanent(anyhow::Error),
+}
+
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transient(e) => write!(f, "transient error: {}", e),
+            Self::Permanent(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ObjectError {}
+
+/// The requests to an S3 compatible object store that are needed to store blobs, see [S3Store]
+///
+/// [S3Client] implements them using the S3 REST API. Other implementations can use the SDK of a cloud
+/// provider, or keep the objects in memory for tests.
+pub trait ObjectClient: Send + Sync + 'static {
+    /// Get an object, or None if there is no object with this name
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, ObjectError>;
+
+    /// Upload an object, unless there already is an object with this name
+    ///
+    /// Returns false if the object already existed.
+    fn put_if_absent(&self, name: &str, data: &[u8]) -> Result<bool, ObjectError>;
+
+    /// Start a multipart upload, returning the upload id
+    fn create_multipart(&self, name: &str) -> Result<String, ObjectError>;
+
+    /// Upload a part of a multipart upload, returning its etag. Parts are numbered from 1.
+    fn upload_part(
+        &self,
+        name: &str,
+        upload_id: &str,
+        part: u16,
+        data: &[u8],
+    ) -> Result<String, ObjectError>;
+
+    /// Complete a multipart upload with the etags of all parts, unless there already is an object with
+    /// this name
+    ///
+    /// Returns false if the object already existed.
+    fn complete_multipart_if_absent(
+        &self,
+        name: &str,
+        upload_id: &str,
+        etags: &[String],
+    ) -> Result<bool, ObjectError>;
+
+    /// Abort a multipart upload, deleting the parts uploaded so far
+    fn abort_multipart(&self, name: &str, upload_id: &str) -> Result<(), ObjectError>;
+}
+
+/// How often requests that failed with [ObjectError::Transient] are retried
+#[derive(Debug, Clone)]
+pub struct Retry {
+    /// The number of attempts, including the first one
+    pub attempts: u32,
+    /// The delay before the first retry, doubled for every following retry
+    pub backoff: Duration,
+    /// The maximum delay between two attempts
+    pub max_backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Retry {
+    /// No retries at all
+    pub fn none() -> Self {
+        Self {
+            attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    fn run<T>(&self, mut f: impl FnMut() -> Result<T, ObjectError>) -> anyhow::Result<T> {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Ok(res) => break Ok(res),
+                Err(ObjectError::Transient(_)) if attempt < self.attempts => {
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    attempt += 1;
+                }
+                Err(ObjectError::Transient(e)) | Err(ObjectError::Permanent(e)) => break Err(e),
+            }
+        }
+    }
+}
+
+/// A blob store on top of a bucket of an S3 compatible object store
+///
+/// Blobs are stored as objects named by the prefix followed by a 64 bit hash of their content in hex,
+/// with the same handling of hash collisions as [super::KvStore]. Objects are only written with
+/// conditional requests, so the object for an id is never replaced, even with concurrent writers.
+///
+/// Blobs larger than the part size are uploaded as multipart uploads, several parts at a time. Requests
+/// that fail with a transient error are retried, see [Retry].
+pub struct S3Store<C> {
+    client: Arc<C>,
+    prefix: String,
+    retry: Retry,
+    part_size: usize,
+    concurrency: usize,
+}
+
+impl<C> Clone for S3Store<C> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            prefix: self.prefix.clone(),
+            retry: self.retry.clone(),
+            part_size: self.part_size,
+            concurrency: self.concurrency,
+        }
+    }
+}
+
+impl<C> Debug for S3Store<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Store")
+            .field("prefix", &self.prefix)
+            .field("retry", &self.retry)
+            .field("part_size", &self.part_size)
+            .field("concurrency", &self.concurrency)
+            .finish()
+    }
+}
+
+impl<C> S3Store<C> {
+    /// A store using `client`, with object names starting with `prefix`, e.g. `"tree/"`
+    pub fn new(client: C, prefix: impl Into<String>) -> Self {
+        Self {
+            client: Arc::new(client),
+            prefix: prefix.into(),
+            retry: Retry::default(),
+            part_size: 16 << 20,
+            concurrency: 4,
+        }
+    }
+
+    pub fn with_retry(self, retry: Retry) -> Self {
+        Self { retry, ..self }
+    }
+
+    /// Upload blobs larger than `part_size` in parts of this size
+    ///
+    /// S3 requires at least 5 MiB for all parts but the last one, and allows at most 10000 parts.
+    pub fn with_part_size(self, part_size: usize) -> Self {
+        Self {
+            part_size: part_size.max(1),
+            ..self
+        }
+    }
+
+    /// Upload up to `concurrency` parts of a multipart upload at the same time
+    pub fn with_concurrency(self, concurrency: usize) -> Self {
+        Self {
+            concurrency: concurrency.max(1),
+            ..self
+        }
+    }
+
+    /// The wrapped client
+    pub fn client(&self) -> &C {
+        &self.client
+    }
+
+    fn name(&self, id: u64) -> String {
+        format!("{}{:016x}", self.prefix, id)
+    }
+}
+
+impl<C: ObjectClient> S3Store<C> {
+    fn put_if_absent(&self, name: &str, data: &[u8]) -> anyhow::Result<bool> {
+        if data.len() <= self.part_size {
+            return self.retry.run(|| self.client.put_if_absent(name, data));
+        }
+        let upload_id = self.retry.run(|| self.client.create_multipart(name))?;
+        let res = self.upload_parts(name, &upload_id, data).and_then(|etags| {
+            self.retry.run(|| {
+                self.client
+                    .complete_multipart_if_absent(name, &upload_id, &etags)
+            })
+        });
+        if !matches!(res, Ok(true)) {
+            // the parts of an upload that is not completed are kept until aborted. If aborting fails
+            // as well, they have to be removed by a lifecycle rule of the bucket.
+            let _ = self.client.abort_multipart(name, &upload_id);
+        }
+        res
+    }
+
+    fn upload_parts(
+        &self,
+        name: &str,
+        upload_id: &str,
+        data: &[u8],
+    ) -> anyhow::Result<Vec<String>> {
+        let parts = data.chunks(self.part_size).collect::<Vec<_>>();
+        anyhow::ensure!(parts.len() <= MAX_PARTS, "blob too large for the part size");
+        let mut etags = Vec::with_capacity(parts.len());
+        for (i, batch) in parts.chunks(self.concurrency).enumerate() {
+            let first = i * self.concurrency + 1;
+            let batch = thread::scope(|scope| {
+                let handles = batch
+                    .iter()
+                    .enumerate()
+                    .map(|(j, part)| {
+                        let number = (first + j) as u16;
+                        scope.spawn(move || {
+                            self.retry
+                                .run(|| self.client.upload_part(name, upload_id, number, part))
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("part upload panicked"))
+                    .collect::<anyhow::Result<Vec<_>>>()
+            })?;
+            etags.extend(batch);
+        }
+        Ok(etags)
+    }
+}
+
+impl<C: ObjectClient> BlobStore for S3Store<C> {
+    type Error = anyhow::Error;
+
+    fn read(&self, id: &[u8]) -> std::result::Result<OwnedBlob, Self::Error> {
+        anyhow::ensure!(id.len() == 8);
+        let name = self.name(u64::from_be_bytes(id.try_into().unwrap()));
+        match self.retry.run(|| self.client.get(&name))? {
+            Some(data) => Ok(Blob::from_arc_vec(Arc::new(data))),
+            None => anyhow::bail!("value not found"),
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> std::result::Result<Vec<u8>, Self::Error> {
+        let mut id = fnv1a(data);
+        loop {
+            let name = self.name(id);
+            if self.put_if_absent(&name, data)? {
+                break;
+            }
+            match self.retry.run(|| self.client.get(&name))? {
+                Some(existing) if existing != data => id = id.wrapping_add(1),
+                _ => break,
+            }
+        }
+        Ok(id.to_be_bytes().to_vec())
+    }
+
+    fn sync(&self) -> std::result::Result<(), Self::Error> {
+        // an object is persisted once its upload has completed
+        Ok(())
+    }
+}
+
+/// An [ObjectClient] for the S3 REST API, using blocking http requests
+///
+/// Works with AWS S3 and other stores that support conditional writes with `If-None-Match`.
+#[derive(Debug, Clone)]
+pub struct S3Client {
+    bucket: Bucket,
+    credentials: Option<Credentials>,
+    agent: ureq::Agent,
+}
+
+impl S3Client {
+    /// A client for `bucket` at `endpoint`, e.g. `https://s3.eu-central-1.amazonaws.com`
+    ///
+    /// Requests are anonymous if `credentials` is None.
+    pub fn new(
+        endpoint: &str,
+        bucket: &str,
+        region: &str,
+        credentials: Option<Credentials>,
+    ) -> anyhow::Result<Self> {
+        let bucket = Bucket::new(
+            endpoint.parse()?,
+            UrlStyle::Path,
+            bucket.to_owned(),
+            region.to_owned(),
+        )?;
+        Ok(Self {
+            bucket,
+            credentials,
+            agent: ureq::Agent::new(),
+        })
+    }
+}
+
+fn request_error(error: ureq::Error) -> ObjectError {
+    match error {
+        ureq::Error::Status(status, response) => {
+            let error = anyhow::anyhow!(
+                "status {}: {}",
+                status,
+                response.into_string().unwrap_or_default()
+            );
+            // 409 is a conflicting conditional write that is still in progress
+            if status >= 500 || status == 429 || status == 409 {
+                ObjectError::Transient(error)
+            } else {
+                ObjectError::Permanent(error)
+            }
+        }
+        ureq::Error::Transport(transport) => ObjectError::Transient(transport.into()),
+    }
+}
+
+impl ObjectClient for S3Client {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, ObjectError> {
+        let action = self.bucket.get_object(self.credentials.as_ref(), name);
+        let url = action.sign(SIGNATURE_VALIDITY);
+        match self.agent.get(url.as_str()).call() {
+            Ok(response) => {
+                let mut data = Vec::new();
+                response
+                    .into_reader()
+                    .read_to_end(&mut data)
+                    .map_err(|e| ObjectError::Transient(e.into()))?;
+                Ok(Some(data))
+            }
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(request_error(e)),
+        }
+    }
+
+    fn put_if_absent(&self, name: &str, data: &[u8]) -> Result<bool, ObjectError> {
+        let mut action = self.bucket.put_object(self.credentials.as_ref(), name);
+        action.headers_mut().insert("if-none-match", "*");
+        let url = action.sign(SIGNATURE_VALIDITY);
+        let request = self.agent.put(url.as_str()).set("if-none-match", "*");
+        match request.send_bytes(data) {
+            Ok(_) => Ok(true),
+            Err(ureq::Error::Status(412, _)) => Ok(false),
+            Err(e) => Err(request_error(e)),
+        }
+    }
+
+    fn create_multipart(&self, name: &str) -> Result<String, ObjectError> {
+        let action = self
+            .bucket
+            .create_multipart_upload(self.credentials.as_ref(), name);
+        let url = action.sign(SIGNATURE_VALIDITY);
+        let body = self
+            .agent
+            .post(url.as_str())
+            .call()
+            .map_err(request_error)?
+            .into_string()
+            .map_err(|e| ObjectError::Transient(e.into()))?;
+        let response = rusty_s3::actions::CreateMultipartUpload::parse_response(&body)
+            .map_err(|e| ObjectError::Permanent(e.into()))?;
+        Ok(response.upload_id().to_owned())
+    }
+
+    fn upload_part(
+        &self,
+        name: &str,
+        upload_id: &str,
+        part: u16,
+        data: &[u8],
+    ) -> Result<String, ObjectError> {
+        let action = self
+            .bucket
+            .upload_part(self.credentials.as_ref(), name, part, upload_id);
+        let url = action.sign(SIGNATURE_VALIDITY);
+        let response = self
+            .agent
+            .put(url.as_str())
+            .send_bytes(data)
+            .map_err(request_error)?;
+        match response.header("etag") {
+            Some(etag) => Ok(etag.to_owned()),
+            None => Err(ObjectError::Permanent(anyhow::anyhow!("part without etag"))),
+        }
+    }
+
+    fn complete_multipart_if_absent(
+        &self,
+        name: &str,
+        upload_id: &str,
+        etags: &[String],
+    ) -> Result<bool, ObjectError> {
+        let mut action = self.bucket.complete_multipart_upload(
+            self.credentials.as_ref(),
+            name,
+            upload_id,
+            etags.iter().map(String::as_str),
+        );
+        action.headers_mut().insert("if-none-match", "*");
+        let url = action.sign(SIGNATURE_VALIDITY);
+        let request = self.agent.post(url.as_str()).set("if-none-match", "*");
+        match request.send_string(&action.body()) {
+            Ok(response) => {
+                // errors after the upload has started are reported in the body of a 200 response
+                let body = response
+                    .into_string()
+                    .map_err(|e| ObjectError::Transient(e.into()))?;
+                if body.contains("<Error>") {
+                    Err(ObjectError::Transient(anyhow::anyhow!("{}", body)))
+                } else {
+                    Ok(true)
+                }
+            }
+            Err(ureq::Error::Status(412, _)) => Ok(false),
+            Err(e) => Err(request_error(e)),
+        }
+    }
+
+    fn abort_multipart(&self, name: &str, upload_id: &str) -> Result<(), ObjectError> {
+        let action = self
+            .bucket
+            .abort_multipart_upload(self.credentials.as_ref(), name, upload_id);
+        let url = action.sign(SIGNATURE_VALIDITY);
+        self.agent
+            .delete(url.as_str())
+            .call()
+            .map_err(request_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RadixTree;
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+
+    /// A bucket in memory. If flaky, every request fails with a transient error the first time.
+    #[derive(Default)]
+    struct MemBucket {
+        objects: Mutex<BTreeMap<String, Vec<u8>>>,
+        uploads: Mutex<BTreeMap<String, BTreeMap<u16, Vec<u8>>>>,
+        requests: AtomicUsize,
+        flaky: bool,
+        failed: Mutex<BTreeSet<String>>,
+    }
+
+    impl MemBucket {
+        fn flaky() -> Self {
+            Self {
+                flaky: true,
+                ..Default::default()
+            }
+        }
+
+        fn request(&self, request: String) -> Result<(), ObjectError> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            if self.flaky && self.failed.lock().unwrap().insert(request.clone()) {
+                Err(ObjectError::Transient(anyhow::anyhow!(
+                    "{} failed",
+                    request
+                )))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl ObjectClient for MemBucket {
+        fn get(&self, name: &str) -> Result<Option<Vec<u8>>, ObjectError> {
+            self.request(format!("get {}", name))?;
+            Ok(self.objects.lock().unwrap().get(name).cloned())
+        }
+
+        fn put_if_absent(&self, name: &str, data: &[u8]) -> Result<bool, ObjectError> {
+            self.request(format!("put {}", name))?;
+            let mut objects = self.objects.lock().unwrap();
+            if objects.contains_key(name) {
+                return Ok(false);
+            }
+            objects.insert(name.to_owned(), data.to_vec());
+            Ok(true)
+        }
+
+        fn create_multipart(&self, name: &str) -> Result<String, ObjectError> {
+            self.request(format!("create {}", name))?;
+            let mut uploads = self.uploads.lock().unwrap();
+            let upload_id = format!("{}-{}", name, uploads.len());
+            uploads.insert(upload_id.clone(), BTreeMap::new());
+            Ok(upload_id)
+        }
+
+        fn upload_part(
+            &self,
+            _: &str,
+            upload_id: &str,
+            part: u16,
+            data: &[u8],
+        ) -> Result<String, ObjectError> {
+            self.request(format!("part {} {}", upload_id, part))?;
+            let mut uploads = self.uploads.lock().unwrap();
+            let parts = uploads.get_mut(upload_id).unwrap();
+            parts.insert(part, data.to_vec());
+            Ok(format!("{}-{}", upload_id, part))
+        }
+
+        fn complete_multipart_if_absent(
+            &self,
+            name: &str,
+            upload_id: &str,
+            etags: &[String],
+        ) -> Result<bool, ObjectError> {
+            self.request(format!("complete {}", upload_id))?;
+            let mut objects = self.objects.lock().unwrap();
+            if objects.contains_key(name) {
+                return Ok(false);
+            }
+            let parts = self.uploads.lock().unwrap().remove(upload_id).unwrap();
+            assert_eq!(
+                etags,
+                parts
+                    .keys()
+                    .map(|part| format!("{}-{}", upload_id, part))
+                    .collect::<Vec<_>>()
+            );
+            objects.insert(name.to_owned(), parts.into_values().flatten().collect());
+            Ok(true)
+        }
+
+        fn abort_multipart(&self, _: &str, upload_id: &str) -> Result<(), ObjectError> {
+            self.uploads.lock().unwrap().remove(upload_id);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn s3_store() {
+        let store = S3Store::new(MemBucket::default(), "tree/").with_part_size(1000);
+        let tree = (0..100u32)
+            .map(|i| (i.to_string(), vec![i as u8; i as usize * 50]))
+            .collect::<RadixTree>();
+        let mut attached = tree.try_attached(store.clone()).unwrap();
+        let id = attached.try_reattach().unwrap();
+        let loaded = RadixTree::try_load(store.clone(), Some(&id)).unwrap();
+        assert_eq!(loaded.try_detached().unwrap(), tree);
+        let objects = store.client().objects.lock().unwrap();
+        assert!(objects.keys().all(|name| name.starts_with("tree/")));
+        assert!(objects.values().any(|data| data.len() > 1000));
+        drop(objects);
+        // no uploads are left behind
+        assert!(store.client().uploads.lock().unwrap().is_empty());
+        // identical blobs are stored once, also for multipart uploads
+        assert_eq!(store.write(b"abc").unwrap(), store.write(b"abc").unwrap());
+        let large = vec![7u8; 4500];
+        assert_eq!(store.write(&large).unwrap(), store.write(&large).unwrap());
+        assert!(store.client().uploads.lock().unwrap().is_empty());
+        assert!(store.read(&[0; 8]).is_err());
+    }
+
+    #[test]
+    fn s3_store_collision() {
+        let store = S3Store::new(MemBucket::default(), "").with_part_size(10);
+        for data in [&b"abc"[..], &[1u8; 95][..]] {
+            // another blob already has the hash of data
+            let hash = fnv1a(data);
+            store
+                .client()
+                .put_if_absent(&store.name(hash), b"xyz")
+                .unwrap();
+            let id = store.write(data).unwrap();
+            assert_eq!(id, hash.wrapping_add(1).to_be_bytes());
+            assert_eq!(store.write(data).unwrap(), id);
+            assert_eq!(store.read(&id).unwrap().as_ref(), data);
+            assert_eq!(store.read(&hash.to_be_bytes()).unwrap().as_ref(), b"xyz");
+        }
+        assert!(store.client().uploads.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn s3_store_retry() {
+        let retry = Retry {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+        let store = S3Store::new(MemBucket::flaky(), "")
+            .with_part_size(100)
+            .with_retry(retry);
+        let data = (0..1000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let id = store.write(&data).unwrap();
+        assert_eq!(store.read(&id).unwrap().as_ref(), &data[..]);
+        // create, 10 parts, complete and get, each attempted twice
+        assert_eq!(store.client().requests.load(Ordering::SeqCst), 26);
+        // without retries, the first failed request is an error
+        let store = S3Store::new(MemBucket::flaky(), "").with_retry(Retry::none());
+        assert!(store.write(b"abc").is_err());
+    }
+}
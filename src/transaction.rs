@@ -0,0 +1,109 @@
+//! Staging changes to a stored tree, and writing them to the store all at once
+//!
+//! ## Example
+//!
+//! ```rust
+//! # use radixdb::{store::MemStore, transaction::Transaction, RadixTree};
+//! let store = MemStore::default();
+//! let mut txn = Transaction::begin(RadixTree::empty(store));
+//! txn.tree_mut().try_insert("a", "1")?;
+//! let root = txn.try_commit()?;
+//! txn.tree_mut().try_insert("b", "2")?;
+//! txn.rollback();
+//! assert!(!txn.tree().try_contains_key("b")?);
+//! assert_eq!(txn.root(), Some(&root[..]));
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use crate::{store::BlobStore, RadixTree};
+
+/// A tree with staged changes on top of a committed version
+///
+/// Mutations of an attached tree only change nodes in memory, so all changes made through
+/// [Transaction::tree_mut] are staged. [Transaction::try_commit] writes them to the store and returns the
+/// new root id. [Transaction::rollback] discards them, without anything having been written to the store.
+pub struct Transaction<S: BlobStore> {
+    /// the tree as of the last commit
+    committed: RadixTree<S>,
+    /// root id of the last commit
+    root: Option<Vec<u8>>,
+    /// the tree including staged changes
+    tree: RadixTree<S>,
+}
+
+impl<S: BlobStore + Clone> Transaction<S> {
+    /// Start staging changes on top of `tree`
+    pub fn begin(tree: RadixTree<S>) -> Self {
+        Self {
+            committed: tree.clone(),
+            root: None,
+            tree,
+        }
+    }
+
+    /// The tree including the staged changes
+    pub fn tree(&self) -> &RadixTree<S> {
+        &self.tree
+    }
+
+    /// The tree to stage changes on
+    pub fn tree_mut(&mut self) -> &mut RadixTree<S> {
+        &mut self.tree
+    }
+
+    /// The tree as of the last commit
+    pub fn committed(&self) -> &RadixTree<S> {
+        &self.committed
+    }
+
+    /// The root id returned by the last commit, None if nothing has been committed yet
+    pub fn root(&self) -> Option<&[u8]> {
+        self.root.as_deref()
+    }
+
+    /// Write all staged changes to the store, returning the new root id
+    ///
+    /// If this fails, the staged changes are kept, so the commit can be retried or rolled back.
+    pub fn try_commit(&mut self) -> Result<Vec<u8>, S::Error> {
+        let id = self.tree.try_reattach()?;
+        self.committed = self.tree.clone();
+        self.root = Some(id.clone());
+        Ok(id)
+    }
+
+    /// Discard all changes staged since the last commit
+    pub fn rollback(&mut self) {
+        self.tree = self.committed.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemStore;
+
+    #[test]
+    fn commit_rollback() -> anyhow::Result<()> {
+        let store = MemStore::default();
+        let mut txn = Transaction::begin(RadixTree::empty(store.clone()));
+        for i in 0..100u32 {
+            txn.tree_mut().try_insert(i.to_be_bytes(), [1u8; 50])?;
+        }
+        // staged changes are not written
+        assert_eq!(store.count(), 0);
+        txn.rollback();
+        assert!(txn.tree().is_empty());
+        assert_eq!(txn.root(), None);
+        txn.tree_mut().try_insert("a", [1u8; 50])?;
+        let root = txn.try_commit()?;
+        let count = store.count();
+        txn.tree_mut().try_insert("b", [2u8; 50])?;
+        txn.tree_mut().try_remove("a")?;
+        txn.rollback();
+        assert_eq!(store.count(), count);
+        assert_eq!(txn.root(), Some(&root[..]));
+        let loaded = RadixTree::try_load(store, Some(&root))?;
+        assert_eq!(loaded.try_detached()?, txn.tree().try_detached()?);
+        assert!(txn.committed().try_contains_key("a")?);
+        Ok(())
+    }
+}
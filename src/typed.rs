@@ -0,0 +1,482 @@
+//! A typed layer on top of the byte level [RadixTree]
+//!
+//! Keys are still byte slices, but values are encoded and decoded using a [ValueCodec]. A tree that was
+//! wrapped using [TypedTree::new] can contain values that do not decode, so all accessors that decode
+//! return a result.
+//!
+//! ## Example
+//!
+//! ```rust
+//! # use radixdb::typed::TypedTree;
+//! let mut ages = TypedTree::<u64>::default();
+//! ages.insert("alice", &33);
+//! ages.insert("bob", &42);
+//! assert_eq!(ages.get("bob")?, Some(42));
+//! assert_eq!(ages.values().sum::<anyhow::Result<u64>>()?, 75);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use std::{fmt::Debug, marker::PhantomData};
+
+use crate::{
+    node::IterKey,
+    store::{blob_store::OwnedBlob, BlobStore, Detached},
+    RadixTree,
+};
+
+/// Encoding and decoding of values stored in a [TypedTree]
+///
+/// # Zero copy codecs
+///
+/// [ValueCodec::decode_ref] gets the encoded bytes, which for an attached tree point directly into the
+/// store memory, so a codec for archived types such as [rkyv](https://docs.rs/rkyv) can validate and
+/// return a reference without deserializing, see `Rkyv` with the `rkyv` feature.
+///
+/// Archived types have alignment requirements. Inline values and ids are not aligned within a node, so
+/// such a codec has to check the alignment of `data` and fail or copy into an aligned buffer.
+pub trait ValueCodec: Sized {
+    /// A view of an encoded value that borrows from the encoded bytes
+    ///
+    /// Codecs that can not decode without copying just use `Self`.
+    type Ref<'a>;
+
+    /// Append the encoded value to `target`
+    fn encode(&self, target: &mut Vec<u8>);
+
+    /// Decode an owned value
+    fn decode(data: &[u8]) -> anyhow::Result<Self>;
+
+    /// Decode a view of the value, without copying if possible
+    fn decode_ref(data: &[u8]) -> anyhow::Result<Self::Ref<'_>>;
+}
+
+impl ValueCodec for Vec<u8> {
+    type Ref<'a> = &'a [u8];
+
+    fn encode(&self, target: &mut Vec<u8>) {
+        target.extend_from_slice(self);
+    }
+
+    fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        Ok(data.to_vec())
+    }
+
+    fn decode_ref(data: &[u8]) -> anyhow::Result<&[u8]> {
+        Ok(data)
+    }
+}
+
+impl ValueCodec for String {
+    type Ref<'a> = &'a str;
+
+    fn encode(&self, target: &mut Vec<u8>) {
+        target.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        Ok(Self::decode_ref(data)?.to_owned())
+    }
+
+    fn decode_ref(data: &[u8]) -> anyhow::Result<&str> {
+        Ok(std::str::from_utf8(data)?)
+    }
+}
+
+impl ValueCodec for () {
+    type Ref<'a> = ();
+
+    fn encode(&self, _: &mut Vec<u8>) {}
+
+    fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        Self::decode_ref(data)
+    }
+
+    fn decode_ref(data: &[u8]) -> anyhow::Result<()> {
+        anyhow::ensure!(data.is_empty(), "expected empty value");
+        Ok(())
+    }
+}
+
+macro_rules! int_codec {
+    ($($t:ty),*) => {
+        $(
+            /// Integers are encoded as fixed size big endian
+            impl ValueCodec for $t {
+                type Ref<'a> = $t;
+
+                fn encode(&self, target: &mut Vec<u8>) {
+                    target.extend_from_slice(&self.to_be_bytes());
+                }
+
+                fn decode(data: &[u8]) -> anyhow::Result<Self> {
+                    Ok(<$t>::from_be_bytes(data.try_into()?))
+                }
+
+                fn decode_ref(data: &[u8]) -> anyhow::Result<$t> {
+                    Self::decode(data)
+                }
+            }
+        )*
+    };
+}
+
+int_codec!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+/// Values encoded as [rkyv](https://docs.rs/rkyv) archives
+///
+/// [TypedTree::get_ref] validates the archive and returns a reference to the archived value, pointing
+/// directly into the memory of the tree. Values that are not suitably aligned for the archived type,
+/// which can happen for values of up to 8 bytes that are stored inline in a node, fail to decode as a
+/// reference. [TypedTree::get] copies into an aligned buffer, so it always works.
+#[cfg(feature = "rkyv")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rkyv<T>(pub T);
+
+#[cfg(feature = "rkyv")]
+impl<T> ValueCodec for Rkyv<T>
+where
+    T: rkyv::Archive + rkyv::Serialize<rkyv::ser::serializers::AllocSerializer<256>> + 'static,
+    T::Archived: for<'a> rkyv::CheckBytes<rkyv::validation::validators::DefaultValidator<'a>>
+        + rkyv::Deserialize<T, rkyv::Infallible>,
+{
+    type Ref<'a> = &'a T::Archived;
+
+    fn encode(&self, target: &mut Vec<u8>) {
+        let bytes = rkyv::to_bytes::<_, 256>(&self.0).expect("rkyv serialization failed");
+        target.extend_from_slice(&bytes);
+    }
+
+    fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        let mut aligned = rkyv::AlignedVec::with_capacity(data.len());
+        aligned.extend_from_slice(data);
+        let archived = Self::decode_ref(&aligned)?;
+        Ok(Rkyv(rkyv::Deserialize::deserialize(
+            archived,
+            &mut rkyv::Infallible,
+        )?))
+    }
+
+    fn decode_ref(data: &[u8]) -> anyhow::Result<&T::Archived> {
+        rkyv::check_archived_root::<T>(data).map_err(|e| anyhow::anyhow!("invalid archive: {}", e))
+    }
+}
+
+fn encode<V: ValueCodec>(value: &V) -> Vec<u8> {
+    let mut res = Vec::new();
+    value.encode(&mut res);
+    res
+}
+
+/// An encoded value, which can be decoded without copying
+pub struct Encoded<V> {
+    data: OwnedBlob,
+    p: PhantomData<fn() -> V>,
+}
+
+impl<V> Debug for Encoded<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Encoded").field(&self.data).finish()
+    }
+}
+
+impl<V: ValueCodec> Encoded<V> {
+    fn new(data: OwnedBlob) -> Self {
+        Self {
+            data,
+            p: PhantomData,
+        }
+    }
+
+    /// The raw encoded bytes
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Decode a view of the value, borrowing from the encoded bytes
+    pub fn decode_ref(&self) -> anyhow::Result<V::Ref<'_>> {
+        V::decode_ref(&self.data)
+    }
+
+    /// Decode an owned value
+    pub fn decode(&self) -> anyhow::Result<V> {
+        V::decode(&self.data)
+    }
+}
+
+/// A radix tree with byte keys and typed values
+pub struct TypedTree<V, S: BlobStore = Detached> {
+    tree: RadixTree<S>,
+    p: PhantomData<fn() -> V>,
+}
+
+impl<V, S: BlobStore + Clone> Clone for TypedTree<V, S> {
+    fn clone(&self) -> Self {
+        Self::new(self.tree.clone())
+    }
+}
+
+impl<V, S: BlobStore> Debug for TypedTree<V, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("TypedTree").field(&self.tree).finish()
+    }
+}
+
+impl<V, S: BlobStore + Default> Default for TypedTree<V, S> {
+    fn default() -> Self {
+        Self::new(RadixTree::default())
+    }
+}
+
+impl<V, S: BlobStore> From<RadixTree<S>> for TypedTree<V, S> {
+    fn from(tree: RadixTree<S>) -> Self {
+        Self::new(tree)
+    }
+}
+
+impl<V, S: BlobStore> TypedTree<V, S> {
+    /// Wrap a byte level tree. The values should have been encoded with the codec of `V`, values that
+    /// do not decode are reported as errors by the accessors.
+    pub fn new(tree: RadixTree<S>) -> Self {
+        Self {
+            tree,
+            p: PhantomData,
+        }
+    }
+
+    /// The underlying byte level tree
+    pub fn tree(&self) -> &RadixTree<S> {
+        &self.tree
+    }
+
+    /// Unwrap into the underlying byte level tree
+    pub fn into_inner(self) -> RadixTree<S> {
+        self.tree
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+}
+
+impl<V: ValueCodec> TypedTree<V> {
+    pub fn get(&self, key: impl AsRef<[u8]>) -> anyhow::Result<Option<V>> {
+        self.tree.get_slice(key).map(V::decode).transpose()
+    }
+
+    /// Decode a view of the value, borrowing from the tree without copying
+    pub fn get_ref(&self, key: impl AsRef<[u8]>) -> anyhow::Result<Option<V::Ref<'_>>> {
+        self.tree.get_slice(key).map(V::decode_ref).transpose()
+    }
+
+    pub fn contains_key(&self, key: impl AsRef<[u8]>) -> bool {
+        self.tree.contains_key(key)
+    }
+
+    pub fn insert(&mut self, key: impl AsRef<[u8]>, value: &V) {
+        self.tree.insert(key, encode(value))
+    }
+
+    /// Remove an entry, returning true if the key was in the tree
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> bool {
+        self.tree.remove(key).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = anyhow::Result<(IterKey, V)>> {
+        self.tree.iter().map(|(k, v)| Ok((k, V::decode(&v)?)))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = anyhow::Result<V>> {
+        self.tree.values().map(|v| V::decode(&v))
+    }
+
+    pub fn scan_prefix(
+        &self,
+        prefix: impl AsRef<[u8]>,
+    ) -> impl Iterator<Item = anyhow::Result<(IterKey, V)>> + '_ {
+        self.tree
+            .scan_prefix(prefix)
+            .map(|(k, v)| Ok((k, V::decode(&v)?)))
+    }
+}
+
+impl<K: AsRef<[u8]>, V: ValueCodec> FromIterator<(K, V)> for TypedTree<V> {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        let tree = iter
+            .into_iter()
+            .map(|(k, v)| (k, encode(&v)))
+            .collect::<RadixTree>();
+        Self::new(tree)
+    }
+}
+
+#[cfg(feature = "custom-store")]
+impl<V: ValueCodec, S: BlobStore + Clone> TypedTree<V, S> {
+    pub fn try_get(&self, key: impl AsRef<[u8]>) -> Result<Option<V>, S::Error> {
+        Ok(match self.try_get_ref(key)? {
+            Some(v) => Some(v.decode()?),
+            None => None,
+        })
+    }
+
+    /// Get the encoded value, to decode a view of it without copying
+    ///
+    /// For a store that provides zero copy reads, the encoded value points directly into the store memory.
+    pub fn try_get_ref(&self, key: impl AsRef<[u8]>) -> Result<Option<Encoded<V>>, S::Error> {
+        let store = RadixTree::store(&self.tree);
+        Ok(match self.tree.try_get(key)? {
+            Some(v) => Some(Encoded::new(v.load(store)?)),
+            None => None,
+        })
+    }
+
+    pub fn try_contains_key(&self, key: impl AsRef<[u8]>) -> Result<bool, S::Error> {
+        self.tree.try_contains_key(key)
+    }
+
+    pub fn try_insert(&mut self, key: impl AsRef<[u8]>, value: &V) -> Result<(), S::Error> {
+        self.tree.try_insert(key, encode(value))
+    }
+
+    pub fn try_remove(&mut self, key: impl AsRef<[u8]>) -> Result<bool, S::Error> {
+        Ok(self.tree.try_remove(key)?.is_some())
+    }
+
+    pub fn try_iter(&self) -> impl Iterator<Item = Result<(IterKey, V), S::Error>> {
+        let store = RadixTree::store(&self.tree).clone();
+        self.tree.try_iter().map(move |r| {
+            let (k, v) = r?;
+            let v = V::decode(&v.load(&store)?)?;
+            Ok((k, v))
+        })
+    }
+
+    pub fn try_values(&self) -> impl Iterator<Item = Result<V, S::Error>> {
+        let store = RadixTree::store(&self.tree).clone();
+        self.tree
+            .try_values()
+            .map(move |r| Ok(V::decode(&r?.load(&store)?)?))
+    }
+
+    /// Write all changes to the store, see [RadixTree::try_reattach]
+    pub fn try_reattach(&mut self) -> Result<Vec<u8>, S::Error> {
+        self.tree.try_reattach()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemStore;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    fn arb_contents() -> impl Strategy<Value = BTreeMap<Vec<u8>, u64>> {
+        proptest::collection::btree_map(
+            proptest::collection::vec(b'0'..=b'9', 0..9),
+            any::<u64>(),
+            0..10,
+        )
+    }
+
+    #[test]
+    fn borrowed_codecs() {
+        let mut t = TypedTree::<String>::default();
+        t.insert("a", &"hello".to_owned());
+        assert_eq!(t.get_ref("a").unwrap(), Some("hello"));
+        assert_eq!(t.get("a").unwrap(), Some("hello".to_owned()));
+        assert_eq!(t.get_ref("b").unwrap(), None);
+        // the view borrows from the tree
+        let t = TypedTree::<Vec<u8>>::new(crate::radixtree! { "a" => [1u8; 1000] });
+        let view = t.get_ref("a").unwrap().unwrap();
+        assert_eq!(view.as_ptr(), t.tree().get_slice("a").unwrap().as_ptr());
+        // not valid utf8
+        let t = TypedTree::<String>::new(crate::radixtree! { "a" => [0xffu8] });
+        assert!(t.get_ref("a").is_err());
+    }
+
+    #[test]
+    fn invalid_values() {
+        // values that were not encoded with the codec are errors, not panics
+        let t = TypedTree::<u64>::new(crate::radixtree! { "a" => [1u8; 8], "b" => [1u8; 3] });
+        assert_eq!(t.get("a").unwrap(), Some(u64::from_be_bytes([1; 8])));
+        assert!(t.get("b").is_err());
+        assert_eq!(t.iter().filter(|r| r.is_err()).count(), 1);
+        assert_eq!(t.values().filter(|r| r.is_err()).count(), 1);
+        assert!(t.scan_prefix("b").all(|r| r.is_err()));
+        let store = MemStore::default();
+        let t = TypedTree::<u64, _>::new(t.into_inner().try_attached(store).unwrap());
+        assert!(t.try_get("b").is_err());
+        assert_eq!(t.try_iter().filter(|r| r.is_err()).count(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "rkyv")]
+    fn rkyv_codec() {
+        #[derive(Debug, Clone, PartialEq, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+        #[archive(check_bytes)]
+        struct User {
+            id: u64,
+            name: String,
+            roles: Vec<String>,
+        }
+        let alice = Rkyv(User {
+            id: 1,
+            name: "alice".to_owned(),
+            roles: vec!["admin".to_owned(), "reader".to_owned()],
+        });
+        let mut t = TypedTree::<Rkyv<User>>::default();
+        t.insert("alice", &alice);
+        assert_eq!(t.get("alice").unwrap(), Some(alice.clone()));
+        // the archived value points into the value of the tree, nothing is deserialized
+        let archived = t.get_ref("alice").unwrap().unwrap();
+        assert_eq!(archived.id, 1);
+        assert_eq!(archived.name, "alice");
+        assert_eq!(archived.roles[1], "reader");
+        let value = t.tree().get_slice("alice").unwrap();
+        assert!(value
+            .as_ptr_range()
+            .contains(&(archived as *const ArchivedUser as *const u8)));
+        // attached, the archived value points into the store
+        let store = MemStore::default();
+        let t = TypedTree::<Rkyv<User>, _>::new(t.into_inner().try_attached(store).unwrap());
+        assert_eq!(t.try_get("alice").unwrap(), Some(alice));
+        // bytes that are not a valid archive are errors
+        let t = TypedTree::<Rkyv<User>>::new(crate::radixtree! { "a" => [0xffu8; 64] });
+        assert!(t.get("a").is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn typed_roundtrip(contents in arb_contents()) {
+            let t = contents.clone().into_iter().collect::<TypedTree<u64>>();
+            for (k, v) in &contents {
+                prop_assert_eq!(t.get(k).unwrap(), Some(*v));
+            }
+            let entries = t.iter().map(|r| {
+                let (k, v) = r.unwrap();
+                (k.to_vec(), v)
+            }).collect::<BTreeMap<_, _>>();
+            prop_assert_eq!(&entries, &contents);
+            let values = t.values().collect::<anyhow::Result<Vec<_>>>().unwrap();
+            prop_assert_eq!(values, contents.values().cloned().collect::<Vec<_>>());
+        }
+
+        #[test]
+        fn typed_attached(contents in arb_contents()) {
+            let store = MemStore::default();
+            let t = contents.clone().into_iter().collect::<TypedTree<u64>>();
+            let mut t = TypedTree::<u64, _>::new(t.into_inner().try_attached(store).unwrap());
+            for (k, v) in &contents {
+                prop_assert_eq!(t.try_get(k).unwrap(), Some(*v));
+            }
+            t.try_insert(b"x", &1).unwrap();
+            t.try_reattach().unwrap();
+            let mut reference = contents.clone();
+            reference.insert(b"x".to_vec(), 1);
+            let entries = t.try_iter().map(|r| {
+                let (k, v) = r.unwrap();
+                (k.to_vec(), v)
+            }).collect::<BTreeMap<_, _>>();
+            prop_assert_eq!(entries, reference);
+        }
+    }
+}
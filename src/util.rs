@@ -55,3 +55,39 @@ impl<'a> std::fmt::Display for Hex<'a> {
         }
     }
 }
+
+/// Small deterministic pseudo random number generator (splitmix64)
+///
+/// Not suitable for anything security related, but good enough for sampling and testing.
+pub struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Fisher-Yates shuffle
+    pub fn shuffle<T>(&mut self, elems: &mut [T]) {
+        for i in (1..elems.len()).rev() {
+            let j = (self.next_u64() % (i as u64 + 1)) as usize;
+            elems.swap(i, j);
+        }
+    }
+}
+
+/// 64 bit FNV-1a hash, which is stable across platforms and versions, unlike the std hasher
+///
+/// Not suitable for anything security related, but good enough to detect corruption.
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}